@@ -0,0 +1,64 @@
+// Times building a book from a hundred thousand random games of 40 plies, which like the games of
+// large dumps mostly leave the book's known positions after a few moves. Run with
+//
+//     cargo bench --bench build
+
+use rustyglot::books::{BookMap, BuildOptions};
+use rustyglot::conversions::*;
+use rustyglot::pgn::*;
+use rustyglot::rng::Rng;
+
+use std::time::Instant;
+
+const GAMES: usize = 100_000;
+const PLIES: usize = 40;
+const RUNS: u32 = 3;
+
+// Writes the games as pgn with moves picked by a fixed seed, so that every run times the same
+// games
+fn random_pgn() -> String {
+    let mut out = String::new();
+    let mut rng = Rng::new(0x2545F4914F6CDD1D);
+
+    for _ in 0..GAMES {
+        let mut pos = Chess::default();
+        out += "[Result \"1-0\"]\n\n";
+
+        for _ in 0..PLIES {
+            let moves = pos.legal_moves();
+            if moves.is_empty() {
+                break;
+            }
+
+            let mov = &moves[(rng.next_u64() % moves.len() as u64) as usize];
+
+            out += &SanPlus::from_move(pos.clone(), mov).to_string();
+            out.push(' ');
+            pos.play_unchecked(mov);
+        }
+
+        out += "1-0\n\n";
+    }
+
+    out
+}
+
+fn main() {
+    let games = read_games(PgnFilter::new(), random_pgn().as_bytes());
+    let options = BuildOptions::new();
+
+    let start = Instant::now();
+    let mut len = 0;
+    for _ in 0..RUNS {
+        let mut book = BookMap::new();
+        for game in &games {
+            book.add_game(game, &options, 1.0);
+        }
+        len = book.len();
+    }
+    println!(
+        "add_game: {} positions in {:.2?} per run",
+        len,
+        start.elapsed() / RUNS
+    );
+}
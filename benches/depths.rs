@@ -0,0 +1,72 @@
+// Times the depth pass over a book of about a million entries, built from random games so that
+// most positions are reached by one line and a few, near the root, by many. Run with
+//
+//     cargo bench --bench depths
+
+use rustyglot::books::{BookEntry, BookMap};
+use rustyglot::conversions::*;
+
+use std::time::Instant;
+
+const ENTRIES: usize = 1_000_000;
+const PLIES: usize = 40;
+const RUNS: u32 = 5;
+
+// Builds the book from games whose moves are picked by a fixed xorshift generator, so that every
+// run times the same book
+fn random_book() -> BookMap {
+    let mut book = BookMap::new();
+    let mut state = 0x2545F4914F6CDD1Du64;
+    let mut entries = 0;
+
+    while entries < ENTRIES {
+        let mut pos = Chess::default();
+
+        for _ in 0..PLIES {
+            let moves = pos.legal_moves();
+            if moves.is_empty() {
+                break;
+            }
+
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let mov = moves[(state % moves.len() as u64) as usize].clone();
+
+            let hash = book.hash(&pos);
+            let before = book.entries(&pos).len();
+            let entry = BookEntry {
+                mov: to_book_move(UciMove::from_chess960(&mov)),
+                ..BookEntry::new()
+            };
+            book.insert(hash, entry);
+            entries += book.entries(&pos).len() - before;
+
+            pos.play_unchecked(&mov);
+        }
+    }
+
+    book
+}
+
+fn main() {
+    let start = Instant::now();
+    let book = random_book();
+    println!("Built {} positions in {:.2?}", book.len(), start.elapsed());
+
+    let mut book = book;
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        book.set_depths();
+    }
+    println!("set_depths: {:.2?} per run", start.elapsed() / RUNS);
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        book.remove_disconnected();
+    }
+    println!(
+        "remove_disconnected: {:.2?} per run",
+        start.elapsed() / RUNS
+    );
+}
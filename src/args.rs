@@ -1,42 +1,282 @@
 use crate::books::*;
-use crate::conversions::fen_to_chess;
+use crate::commands;
+use crate::conversions::{fen_to_chess, Color, Fen, SanPlus};
+use crate::engine::Engine;
+use crate::error::{input, io, usage};
+use crate::expr::Expr;
+use crate::log;
 use crate::pgn::*;
+use crate::rng::Rng;
+use crate::streams::{is_zip, open_stream, strip_compression};
 
+use std::convert::TryFrom;
 use std::env;
-use std::fs::File;
-use std::io::{self, BufReader, Read, Write};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use shakmaty::{EnPassantMode, Role};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
-enum FileType {
+pub enum FileType {
     Json,
     Pgn,
     Bin,
     Tree(bool),
+    Ndjson,
+    UciLines,
+    Packed,
+    Latex,
+    Anki,
+    Csv,
+    Epd,
 }
 
 use FileType::*;
 
-fn get_input_files(args: &[String]) -> Vec<(FileType, String)> {
-    let types = [Json, Pgn, Bin, Tree(false)];
-    let tags = ["-in-json", "-in-pgn", "-in-bin", "-in-tree"];
-    let exts = [".json", ".pgn", ".bin", ".tree"];
+impl FileType {
+    /// Whether the file holds games to build a book from, rather than a book
+    pub fn is_games(self) -> bool {
+        self == Pgn || self == Ndjson
+    }
+}
+
+pub fn open_input(filename: &str) -> Box<dyn Read> {
+    if filename == "-" {
+        Box::new(io::stdin())
+    } else if let Some(stream) = open_stream(filename) {
+        stream
+    } else {
+        Box::new(
+            File::open(filename)
+                .unwrap_or_else(|_| io(format!("Failure reading file {}", filename))),
+        )
+    }
+}
+
+// Writes to a temporary file next to the output, which replaces the output when the writer is
+// dropped, unless that happens while panicking. A failed run never leaves a partial file behind,
+// and inputs can safely be overwritten.
+struct AtomicFile {
+    file: Option<BufWriter<File>>,
+    temp: String,
+    path: String,
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        let file = self.file.take().unwrap();
+
+        if std::thread::panicking() {
+            drop(file);
+            fs::remove_file(&self.temp);
+            return;
+        }
+
+        file.into_inner()
+            .ok()
+            .and_then(|f| f.sync_all().ok())
+            .unwrap_or_else(|| io(format!("Failure writing file {}", self.path)));
+
+        fs::rename(&self.temp, &self.path)
+            .unwrap_or_else(|_| io(format!("Failure replacing file {}", self.path)));
+    }
+}
+
+pub fn open_output(filename: &str) -> Box<dyn Write> {
+    if filename == "-" {
+        return Box::new(io::stdout());
+    }
+
+    let temp = format!("{}.{}.tmp", filename, std::process::id());
+    let file =
+        File::create(&temp).unwrap_or_else(|_| io(format!("Failure creating file {}", temp)));
+
+    Box::new(AtomicFile {
+        file: Some(BufWriter::new(file)),
+        temp,
+        path: filename.to_string(),
+    })
+}
+
+/// Refuses to overwrite existing files unless -f is given, before any work is done
+pub fn check_outputs<'a, I: IntoIterator<Item = &'a str>>(filenames: I, args: &[String]) {
+    if args.iter().any(|x| x == "-f") {
+        return;
+    }
+
+    for filename in filenames {
+        if filename != "-" && Path::new(filename).exists() {
+            usage(format!(
+                "Output file {} already exists, use -f to overwrite it",
+                filename
+            ));
+        }
+    }
+}
+
+// Whether a and b name the same existing file
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+pub fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let pos = args.iter().position(|x| x == flag)?;
+
+    args.get(pos + 1).map(|x| &x[..])
+}
+
+pub fn flag_values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+    args.iter()
+        .enumerate()
+        .filter(|(_, x)| *x == flag)
+        .filter_map(|(i, _)| args.get(i + 1).map(|x| &x[..]))
+        .collect()
+}
+
+pub fn parse_flag<T: FromStr>(args: &[String], flag: &str) -> Option<T> {
+    flag_value(args, flag).map(|x| parse_value(x, flag))
+}
+
+pub fn parse_value<T: FromStr>(value: &str, flag: &str) -> T {
+    value
+        .parse::<T>()
+        .unwrap_or_else(|_| usage(format!("Invalid value {:?} for {}", value, flag)))
+}
+
+/// Starts the engine given by -engine, searching with -engine-depth, -engine-nodes or
+/// -engine-movetime and configured by any number of -engine-option name=value flags.
+pub fn engine_from_args(args: &[String]) -> Engine {
+    let path = flag_value(args, "-engine")
+        .unwrap_or_else(|| usage("No engine given with -engine".to_string()));
+
+    let limit = if let Some(nodes) = flag_value(args, "-engine-nodes") {
+        format!("nodes {}", nodes)
+    } else if let Some(time) = flag_value(args, "-engine-movetime") {
+        format!("movetime {}", time)
+    } else {
+        format!(
+            "depth {}",
+            flag_value(args, "-engine-depth").unwrap_or("12")
+        )
+    };
+
+    let mut engine = Engine::new(path, &limit);
+
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "-engine-option" {
+            if let Some((name, value)) = args.get(i + 1).and_then(|x| x.split_once('=')) {
+                engine.set_option(name, value);
+            }
+        }
+    }
+
+    engine
+}
+
+// Flags whose values may look like input files
+const VALUE_FLAGS: &[&str] = &[
+    "-engine",
+    "-syzygy",
+    "-disk-store",
+    "-merge-into",
+    "-repair-bin",
+    "-filter-source",
+    "-remove-source",
+    "-apply-edits",
+    "-log-json",
+    "-book-title",
+    "-book-author",
+    "-book-date",
+    "-book-source",
+    "-merge-report",
+    "-progress",
+    "-games",
+    "-player",
+    "-eco-table",
+    "-masters-cache",
+    "-masters-url",
+    "-explorer-cache",
+    "-explorer-url",
+    "-fens",
+    "-bias",
+    "-event-regex",
+    "-site-regex",
+    "-exclude-event-regex",
+    "-exclude-site-regex",
+    "-exclude-players",
+    "-filter-expr",
+    "-map-weights",
+    "-filter-entries",
+    "-scale-by-depth",
+    "-script",
+    "-priority",
+];
+
+// When last_is_output is set, the final argument is never treated as an input file
+pub fn get_input_files(args: &[String], last_is_output: bool) -> Vec<(FileType, String)> {
+    let types = [Json, Pgn, Bin, Tree(true), Tree(false), Ndjson, Packed, Epd];
+    let tags = [
+        "-in-json",
+        "-in-pgn",
+        "-in-bin",
+        "-in-tree-blob",
+        "-in-tree",
+        "-in-ndjson",
+        "-in-packed",
+        "-in-epd",
+    ];
+    let exts = [
+        ".json",
+        ".pgn",
+        ".bin",
+        ".blob.tree",
+        ".tree",
+        ".ndjson",
+        ".rgpack",
+        ".epd",
+    ];
+
+    let end = if last_is_output {
+        args.len().saturating_sub(1)
+    } else {
+        args.len()
+    };
 
     let mut out = Vec::new();
     let mut i = 0;
 
-    while i < args.len() - 1 {
+    while i < end {
         let arg = &args[i];
 
-        if let Some(j) = tags.iter().position(|x| *x == arg) {
+        if arg.starts_with("-out") || VALUE_FLAGS.contains(&&arg[..]) {
+            i += 1;
+        } else if let Some(j) = tags.iter().position(|x| *x == arg) {
             if i < args.len() - 1 {
                 out.push((types[j], args[i + 1].clone()));
                 i += 1;
             }
         } else if let Some(j) = exts
             .iter()
-            .position(|x| arg[arg.len().saturating_sub(x.len())..] == **x)
+            .position(|x| strip_compression(arg).ends_with(x))
         {
             out.push((types[j], args[i].clone()));
+        } else if is_zip(arg) {
+            out.push((Pgn, args[i].clone()));
         }
 
         i += 1
@@ -45,10 +285,40 @@ fn get_input_files(args: &[String]) -> Vec<(FileType, String)> {
     out
 }
 
-fn get_output_files(args: &[String]) -> Vec<(FileType, String)> {
-    let types = [Json, Bin, Tree(true), Tree(false)];
-    let tags = ["-out-json", "-out-bin", "-out-tree-blob", "-out-tree"];
-    let exts = [".json", ".bin", ".blob.tree", ".tree"];
+pub fn get_output_files(args: &[String]) -> Vec<(FileType, String)> {
+    let types = [
+        Json,
+        Bin,
+        Tree(true),
+        Tree(false),
+        UciLines,
+        Packed,
+        Latex,
+        Csv,
+        Anki,
+    ];
+    let tags = [
+        "-out-json",
+        "-out-bin",
+        "-out-tree-blob",
+        "-out-tree",
+        "-out-uci-lines",
+        "-out-packed",
+        "-out-latex",
+        "-out-csv",
+        "-out-anki",
+    ];
+    let exts = [
+        ".json",
+        ".bin",
+        ".blob.tree",
+        ".tree",
+        ".uci",
+        ".rgpack",
+        ".tex",
+        ".moves.csv",
+        ".csv",
+    ];
 
     let mut out = Vec::new();
     let mut i = 0;
@@ -56,16 +326,24 @@ fn get_output_files(args: &[String]) -> Vec<(FileType, String)> {
     while i < args.len() {
         let arg = &args[i];
 
-        if let Some(j) = tags.iter().position(|x| *x == arg) {
+        if VALUE_FLAGS.contains(&&arg[..]) {
+            i += 1;
+        } else if let Some(j) = tags.iter().position(|x| *x == arg) {
             if i < args.len() - 1 {
                 out.push((types[j], args[i + 1].clone()));
                 i += 1;
             }
-        } else if let Some(j) = exts
-            .iter()
-            .position(|x| arg[arg.len().saturating_sub(x.len())..] == **x)
-        {
-            out.push((types[j], args[i].clone()));
+        } else if arg == "-out" {
+            if let Some(file) = args.get(i + 1) {
+                if let Some(j) = exts.iter().position(|x| file.ends_with(x)) {
+                    out.push((types[j], file.clone()));
+                }
+                i += 1;
+            }
+        } else if i == args.len() - 1 {
+            if let Some(j) = exts.iter().position(|x| arg.ends_with(x)) {
+                out.push((types[j], args[i].clone()));
+            }
         }
 
         i += 1
@@ -74,82 +352,397 @@ fn get_output_files(args: &[String]) -> Vec<(FileType, String)> {
     out
 }
 
+pub fn notation(args: &[String]) -> MoveNotation {
+    let style = flag_value(args, "-san-style").map(|x| {
+        SanStyle::from_name(x)
+            .unwrap_or_else(|| usage(format!("Invalid value {:?} for -san-style", x)))
+    });
+
+    match (flag_value(args, "-txt-moves"), style) {
+        (None | Some("san"), None | Some(SanStyle::English)) => MoveNotation::San,
+        (None | Some("san"), Some(style)) => MoveNotation::LocalSan(style),
+        (Some("uci"), None) => MoveNotation::Uci,
+        (Some("uci"), Some(_)) => {
+            usage("-san-style can not be used with -txt-moves uci".to_string())
+        }
+        (Some(x), _) => usage(format!("Invalid value {:?} for -txt-moves", x)),
+    }
+}
+
+fn merge_options(args: &[String]) -> MergeOptions {
+    let weight = match flag_value(args, "-merge-policy") {
+        Some("sum") => MergePolicy::Sum,
+        Some("max") => MergePolicy::Max,
+        Some("min") => MergePolicy::Min,
+        Some("average") => MergePolicy::Average,
+        Some("replace-with-newer") => MergePolicy::ReplaceWithNewer,
+        None if args.iter().any(|x| x == "-combine-entries") => MergePolicy::Sum,
+        None => MergePolicy::KeepFirst,
+        Some(x) => usage(format!("Invalid value {:?} for -merge-policy", x)),
+    };
+
+    let learn = match flag_value(args, "-learn-policy") {
+        None | Some("keep-first") => LearnPolicy::KeepFirst,
+        Some("keep-last") => LearnPolicy::KeepLast,
+        Some("max") => LearnPolicy::Max,
+        Some("min") => LearnPolicy::Min,
+        Some("weighted-average") => LearnPolicy::WeightedAverage,
+        Some(x) => usage(format!("Invalid value {:?} for -learn-policy", x)),
+    };
+
+    MergeOptions { weight, learn }
+}
+
+// How repeated records of a .bin file are combined with -bin-duplicates, if given
+fn duplicate_policy(args: &[String]) -> Option<DuplicatePolicy> {
+    match flag_value(args, "-bin-duplicates")? {
+        "sum" => Some(DuplicatePolicy::Sum),
+        "max" => Some(DuplicatePolicy::Max),
+        "first" => Some(DuplicatePolicy::First),
+        "error" => Some(DuplicatePolicy::Error),
+        x => usage(format!("Invalid value {:?} for -bin-duplicates", x)),
+    }
+}
+
+// Reads the .bin file filename as given by -bin-duplicates, reporting its irregular records
+fn read_bin(filename: &str, mut reader: Box<dyn Read>, policy: DuplicatePolicy) -> BookMap {
+    let (book, issues) = BookMap::try_read_bin(&mut reader, policy)
+        .unwrap_or_else(|e| input(format!("Error reading {}: {}", filename, e)));
+
+    if issues != BinIssues::default() {
+        info!(
+            "Found {} repeated records and {} records out of order in {}",
+            issues.duplicates, issues.unsorted, filename
+        );
+    }
+    log::record("read", "duplicate_records", issues.duplicates);
+    log::record("read", "unsorted_records", issues.unsorted);
+    book
+}
+
+fn bin_order(args: &[String]) -> BinOrder {
+    match flag_value(args, "-bin-order") {
+        Some("weight") => BinOrder::Weight,
+        Some("learn") => BinOrder::Learn,
+        None | Some("stable") => BinOrder::Stable,
+        Some(x) => usage(format!("Invalid value {:?} for -bin-order", x)),
+    }
+}
+
+// Plies after which -out-latex draws diagrams
+fn latex_diagrams(args: &[String]) -> Vec<usize> {
+    flag_value(args, "-latex-diagrams")
+        .into_iter()
+        .flat_map(|x| x.split(','))
+        .map(|x| parse_value(x, "-latex-diagrams"))
+        .collect()
+}
+
+// Side whose moves -out-anki cards ask for
+fn anki_side(args: &[String]) -> Color {
+    match flag_value(args, "-anki-side") {
+        None | Some("white") => Color::White,
+        Some("black") => Color::Black,
+        Some(x) => usage(format!("Invalid value {:?} for -anki-side", x)),
+    }
+}
+
+pub fn repertoire_color(args: &[String]) -> Color {
+    match flag_value(args, "-repertoire-color") {
+        None | Some("white") => Color::White,
+        Some("black") => Color::Black,
+        Some(x) => usage(format!("Invalid value {:?} for -repertoire-color", x)),
+    }
+}
+
+fn cycle_policy(args: &[String]) -> CyclePolicy {
+    match flag_value(args, "-cycles") {
+        None | Some("break") => CyclePolicy::Break,
+        Some("error") => CyclePolicy::Error,
+        Some("mark") => CyclePolicy::Mark,
+        Some(x) => usage(format!("Invalid value {:?} for -cycles", x)),
+    }
+}
+
+// With -txt-recover, malformed entries are reported and skipped instead of aborting
+fn read_tree<R: Read>(filename: &str, reader: R, blob: bool, args: &[String]) -> BookMap {
+    let mut reader = BufReader::new(reader);
+
+    if !args.iter().any(|x| x == "-txt-recover") {
+        let book = if blob {
+            BookMap::try_read_blob(&mut reader)
+        } else {
+            BookMap::try_read_txt(&mut reader, notation(args))
+        };
+
+        return book.unwrap_or_else(|e| input(format!("Error reading {}:{}", filename, e)));
+    }
+
+    let (book, errors) = BookMap::read_tree_recover(&mut reader, notation(args), blob);
+
+    for e in errors {
+        info!("Skipping entry at {}:{}", filename, e);
+    }
+
+    book
+}
+
+fn tracks_sources(args: &[String]) -> bool {
+    args.iter()
+        .any(|x| x == "-track-sources" || x == "-filter-source" || x == "-remove-source")
+}
+
+// Weights are multiplied by this when decaying by date, weighing upsets or scoring results with
+// fractional points, so that games scaled down still contribute and fractions are kept apart
+const DECAY_RESOLUTION: f64 = 100.0;
+
+fn fold_input<R: Read>(
+    filetype: FileType,
+    filter: &PgnFilter,
+    read: R,
+    f: &mut dyn FnMut(PgnGame),
+) {
+    if filetype == Ndjson {
+        fold_ndjson_games(filter.clone(), read, &mut |game| f(game))
+    } else {
+        fold_games(filter.clone(), read, &mut |game| f(game))
+    }
+}
+
 fn book_from_pgns(args: &[String], files: &[(FileType, String)]) -> BookMap {
-    let filter = PgnFilter::from_args(args);
     let mut book = BookMap::new();
+    add_games(&mut book, args, files);
 
-    let frequency = args.iter().any(|a| a == "-frequency");
+    if args.iter().any(|x| x == "-move-time-weights") {
+        book.weight_by_move_time();
+    }
 
-    let depth = if let Some(pos) = args.iter().position(|x| x == "-pgn-depth") {
-        args[pos + 1].parse::<usize>().unwrap_or(usize::MAX)
-    } else {
-        usize::MAX
+    log::record("pgn", "entries", book.len());
+    book
+}
+
+// Adds the games of the pgn and ndjson files to book, returning the number of games
+fn add_games<S: BookStore>(book: &mut S, args: &[String], files: &[(FileType, String)]) -> usize {
+    // Only the fields parsed from the headers are used, so the headers themselves aren't kept
+    let filter = PgnFilter::from_args(args).without_headers();
+
+    let mut options = BuildOptions::new();
+    options.frequency = args.iter().any(|a| a == "-frequency");
+    options.upsets = args.iter().any(|a| a == "-upset-weights");
+
+    if let Some(max_weight) = parse_flag::<u64>(args, "-max-weight-per-game") {
+        options.max_weight = max_weight;
+    }
+
+    for (i, flag) in ["-score-win", "-score-draw", "-score-loss"]
+        .iter()
+        .enumerate()
+    {
+        if let Some(score) = parse_flag::<f64>(args, flag) {
+            if !(score >= 0.0 && score.is_finite()) {
+                usage(format!(
+                    "Invalid value {:?} for {}",
+                    flag_value(args, flag).unwrap(),
+                    flag
+                ));
+            }
+            options.scores[i] = score;
+        }
+    }
+
+    if let Some(pos) = args.iter().position(|x| x == "-pgn-depth") {
+        options.depth = args[pos + 1].parse::<usize>().unwrap_or(usize::MAX);
+    }
+
+    let half_life = parse_flag::<f64>(args, "-date-decay");
+    let fractional = options.scores.iter().any(|x| x.fract() != 0.0);
+    let filenames = files
+        .iter()
+        .filter(|x| x.0.is_games())
+        .map(|x| (x.0, &x.1[..]))
+        .collect::<Vec<_>>();
+
+    // Decaying by age needs the newest date before building, so stdin is buffered to be read twice
+    let mut stdin = Vec::new();
+    if half_life.is_some() && filenames.iter().any(|x| x.1 == "-") {
+        io::stdin().read_to_end(&mut stdin);
+    }
+
+    let reader = |filename: &str| -> Box<dyn Read + '_> {
+        if half_life.is_some() && filename == "-" {
+            Box::new(&stdin[..])
+        } else {
+            open_input(filename)
+        }
     };
 
+    // -max-games counts the games of every file together
+    let limit = |count: usize| filter.clone().with_max_games(filter.max_games() - count);
+    let mut newest = None;
+
+    if half_life.is_some() {
+        let mut count = 0;
+
+        for (filetype, filename) in &filenames {
+            fold_input(*filetype, &limit(count), reader(filename), &mut |game| {
+                newest = newest.max(game.date());
+                count += 1;
+            });
+        }
+    }
+
+    let track = tracks_sources(args);
+    let mut collisions = if args.iter().any(|x| x == "-check-collisions") {
+        Some(CollisionCheck::new())
+    } else {
+        None
+    };
     let mut i = 0;
 
-    for (_, filename) in files.iter().filter(|x| x.0 == Pgn) {
-        let reader: Box<dyn Read> = if filename == "-" {
-            Box::new(io::stdin())
-        } else {
-            Box::new(
-                File::open(filename)
-                    .unwrap_or_else(|_| panic!("Failure reading file {}", filename)),
-            )
-        };
+    for (filetype, filename) in &filenames {
+        if i == filter.max_games() {
+            break;
+        }
+
+        if track {
+            options.source = Some(book.add_source(filename));
+        }
+
+        verbose!("Reading games from {}", filename);
+
+        fold_input(*filetype, &limit(i), reader(filename), &mut |game| {
+            let scale = match (half_life, game.date(), newest) {
+                (Some(half_life), Some(date), Some(newest)) => {
+                    DECAY_RESOLUTION * 0.5f64.powf((newest - date) as f64 / half_life)
+                }
+                (Some(_), _, _) => DECAY_RESOLUTION,
+                _ if options.upsets || fractional => DECAY_RESOLUTION,
+                _ => 1.0,
+            };
+
+            if let Some(check) = &mut collisions {
+                check.add_game(&game, options.depth);
+            }
 
-        fold_games(filter.clone(), reader, &mut |game| {
             i += 1;
-            book.add_game(&game, frequency, depth)
+            book.add_game(&game, &options, scale)
         });
     }
 
-    println!("Wrote entries from {} games", i);
+    info!("Wrote entries from {} games", i);
+    log::record("pgn", "games", i);
 
-    book
+    if let Some(check) = collisions {
+        for (hash, a, b) in check.collisions() {
+            info!("Hash collision {:016x} between {} and {}", hash, a, b);
+        }
+        info!("Found {} hash collisions", check.collisions().len());
+        log::record("pgn", "collisions", check.collisions().len());
+    }
+
+    i
+}
+
+// Reads the book file filename of filetype from reader, adding the number of entries merged
+// within it with differing learn values to conflicts
+fn read_book(
+    filetype: FileType,
+    filename: &str,
+    mut reader: Box<dyn Read>,
+    args: &[String],
+    conflicts: &mut usize,
+) -> BookMap {
+    match filetype {
+        Bin => match duplicate_policy(args) {
+            Some(policy) => read_bin(filename, reader, policy),
+            None => {
+                let mut book = BookMap::new();
+                *conflicts += book.extend_from_reader_with(&mut reader, merge_options(args));
+                book
+            }
+        },
+        Json => BookMap::read_json(&mut BufReader::new(reader)),
+        Epd => BookMap::read_epd(BufReader::new(reader)),
+        Tree(blob) => read_tree(filename, reader, blob, args),
+        Packed => BookMap::try_read_packed(&mut reader)
+            .unwrap_or_else(|e| input(format!("Error reading {}: {}", filename, e))),
+        _ => panic!(),
+    }
 }
 
 fn merge_book_files(book: &mut BookMap, files: &[(FileType, String)], args: &[String]) {
-    let combine = args.contains(&"-combine-entries".to_string());
+    let options = merge_options(args);
+    let track = tracks_sources(args);
     let mut merged = false;
+    let mut conflicts = 0;
+    let mut report = flag_value(args, "-merge-report").map(open_output);
+
+    for (filetype, filename) in files.iter().filter(|x| !x.0.is_games()) {
+        verbose!("Reading book {}", filename);
+        let mut reader = open_input(filename);
 
-    for (filetype, filename) in files.iter().filter(|x| x.0 != Pgn) {
-        let mut reader: Box<dyn Read> = if filename == "-" {
-            Box::new(io::stdin())
+        if *filetype == Bin && !track && report.is_none() && duplicate_policy(args).is_none() {
+            conflicts += book.extend_from_reader_with(&mut reader, options);
         } else {
-            Box::new(
-                File::open(filename)
-                    .unwrap_or_else(|_| panic!("Failure reading file {}", filename)),
-            )
-        };
+            let mut book2 = read_book(*filetype, filename, reader, args, &mut conflicts);
 
-        if *filetype == Bin {
-            if combine {
-                book.extend_from_reader_combine(&mut reader)
-            } else {
-                book.extend_from_reader(&mut reader)
+            if track {
+                book2.tag_source(book.add_source(filename));
             }
-        } else {
-            let book2 = match filetype {
-                Json => BookMap::read_json(&mut BufReader::new(reader)),
-                Tree(_) => BookMap::read_txt(&mut BufReader::new(reader)),
-                _ => panic!(),
-            };
 
-            if combine {
-                book.merge_combine(book2);
-            } else {
-                book.merge(book2);
+            if let Some(report) = report.as_mut().filter(|_| !book.is_empty()) {
+                writeln!(report, "Top moves differing from {}:", filename);
+                let count = book.write_top_move_conflicts(&book2, report);
+                info!("Top moves differ from {} in {} positions", filename, count);
             }
+
+            conflicts += book.merge_with(book2, options);
         }
         merged = true;
     }
+    if let Some(filename) = flag_value(args, "-priority") {
+        verbose!("Reading priority book {}", filename);
+        let filetype = get_input_files(&[filename.to_string()], false)
+            .first()
+            .map(|x| x.0)
+            .filter(|x| !x.is_games())
+            .unwrap_or_else(|| usage(format!("The -priority file {} is not a book", filename)));
+        let mut book2 = read_book(
+            filetype,
+            filename,
+            open_input(filename),
+            args,
+            &mut conflicts,
+        );
+
+        if track {
+            book2.tag_source(book.add_source(filename));
+        }
+
+        book.merge_priority(book2);
+        merged = true;
+    }
     if merged {
         book.set_depths();
+        log::record(
+            "merge",
+            "files",
+            files.iter().filter(|x| !x.0.is_games()).count(),
+        );
+        log::record("merge", "entries", book.len());
+        log::record("merge", "learn_conflicts", conflicts);
+    }
+    if conflicts > 0 {
+        info!(
+            "Merged {} entries with differing learn values, resolved with -learn-policy",
+            conflicts
+        );
     }
 }
 
 fn modify_book(book: &mut BookMap, args: &[String]) {
+    // Books built from games have the depth of the first game reaching each position
+    book.set_depths();
     let mut i = 0;
 
     while i < args.len() {
@@ -161,22 +754,98 @@ fn modify_book(book: &mut BookMap, args: &[String]) {
                     book.set_root(fen_to_chess(&args[i]));
                 }
                 "-min-weight" => {
-                    let weight = args[i].parse::<u64>().unwrap();
+                    let weight = parse_value::<u64>(&args[i], &args[i - 1]);
 
                     book.filter(|entry| entry.weight >= weight);
                 }
                 "-max-weight" => {
-                    let weight = args[i].parse::<u64>().unwrap();
+                    let weight = parse_value::<u64>(&args[i], &args[i - 1]);
 
                     book.filter(|entry| entry.weight <= weight);
                 }
+                "-smooth" => book.smooth(
+                    parse_value(&args[i], "-smooth"),
+                    parse_flag(args, "-smooth-below").unwrap_or(u64::MAX),
+                    args.iter().any(|x| x == "-smooth-legal"),
+                ),
+                "-prefer-deep" => book.prefer_deep_lines(parse_value(&args[i], "-prefer-deep")),
+                "-filter-source" => book.filter_source(&args[i], true),
+                "-remove-source" => book.filter_source(&args[i], false),
+                "-min-move-elo" => {
+                    let elo = parse_value::<u64>(&args[i], &args[i - 1]);
+
+                    book.filter(|entry| entry.average_elo().is_none_or(|e| e >= elo));
+                }
+                "-min-wilson" => {
+                    let score = parse_value::<f64>(&args[i], &args[i - 1]);
+                    let z = parse_flag::<f64>(args, "-wilson-z").unwrap_or(1.96);
+
+                    book.filter(|entry| entry.wilson_lower_bound(z).is_none_or(|s| s >= score));
+                }
+                "-min-sharpness" => {
+                    let sharpness = parse_value::<f64>(&args[i], &args[i - 1]);
+
+                    book.filter(|entry| entry.sharpness().is_none_or(|s| s >= sharpness));
+                }
+                "-max-sharpness" => {
+                    let sharpness = parse_value::<f64>(&args[i], &args[i - 1]);
+
+                    book.filter(|entry| entry.sharpness().is_none_or(|s| s <= sharpness));
+                }
+                "-exclude-move" | "-exclude-san" => {
+                    let san = args[i - 1] == "-exclude-san";
+                    let ban = MoveBan::parse(book, &args[i], san)
+                        .unwrap_or_else(|e| usage(format!("{} for {}", e, args[i - 1])));
+
+                    book.exclude_moves(&[ban]);
+                }
+                "-force-move" | "-force-move-prune" => {
+                    let (fen, san) = args[i].rsplit_once(':').unwrap_or_else(|| {
+                        usage(format!("Expected <fen>:<san> for {}", args[i - 1]))
+                    });
+
+                    let pos = fen_to_chess(fen.trim());
+                    let mov = san
+                        .trim()
+                        .parse::<SanPlus>()
+                        .ok()
+                        .and_then(|s| s.san.to_move(&pos).ok())
+                        .unwrap_or_else(|| {
+                            usage(format!("Invalid move {} for position {}", san, fen))
+                        });
+
+                    book.force_move(&pos, &mov, args[i - 1] == "-force-move-prune");
+                }
+                "-apply-edits" => match book.apply_edits(BufReader::new(open_input(&args[i]))) {
+                    Ok(unmatched) => {
+                        for edit in unmatched {
+                            info!("Skipping edit at {} {}", args[i], edit);
+                        }
+                    }
+                    Err(errors) => {
+                        for error in errors {
+                            info!("Invalid edit at {} {}", args[i], error);
+                        }
+                        input(format!("No edits from {} were applied", args[i]));
+                    }
+                },
+                #[cfg(feature = "script")]
+                "-script" => match book.run_script(BufReader::new(open_input(&args[i]))) {
+                    Ok(removed) => info!("Removed {} moves with {}", removed, args[i]),
+                    Err(errors) => {
+                        for error in errors {
+                            info!("Invalid script at {} {}", args[i], error);
+                        }
+                        input(format!("The script {} was not run", args[i]));
+                    }
+                },
                 "-depth" => {
-                    let depth = args[i].parse::<usize>().unwrap();
+                    let depth = parse_value::<usize>(&args[i], &args[i - 1]);
 
                     book.filter(|entry| entry.depth.unwrap_or(0) < depth);
                 }
                 "-keep-best" => {
-                    let best = args[i].parse::<usize>().unwrap();
+                    let best = parse_value::<usize>(&args[i], &args[i - 1]);
 
                     book.map_nodes(|node| {
                         node.sort_by_key(|x| u64::MAX - x.weight);
@@ -184,18 +853,138 @@ fn modify_book(book: &mut BookMap, args: &[String]) {
                     })
                 }
                 "-keep-worst" => {
-                    let worst = args[i].parse::<usize>().unwrap();
+                    let worst = parse_value::<usize>(&args[i], &args[i - 1]);
 
                     book.map_nodes(|node| {
                         node.sort_by_key(|x| x.weight);
                         node.truncate(worst);
                     })
                 }
+                "-trim-tails" => {
+                    let plies = parse_value::<usize>(&args[i], &args[i - 1]);
+
+                    let count = book.trim_tails(plies);
+                    info!(
+                        "Removed {} moves in tails shorter than {} plies",
+                        count, plies
+                    );
+                }
+                "-complete-lines" => {
+                    let side = repertoire_color(args);
+                    let mut extended = 0;
+
+                    match &args[i][..] {
+                        "extend" => {
+                            let mut engine = engine_from_args(args);
+                            let leaves = book.open_leaves(side);
+                            extended = book.add_engine_moves(&mut engine, &leaves, 1);
+                        }
+                        "trim" => {}
+                        x => usage(format!("Invalid value {:?} for -complete-lines", x)),
+                    }
+
+                    let trimmed = book.trim_open_lines(side);
+                    info!(
+                        "Extended {} lines with engine moves and trimmed {} moves leaving the \
+                         repertoire out of book",
+                        extended, trimmed
+                    );
+                }
+                "-no-early-queen-moves" | "-captures-only-after" => {
+                    let plies = parse_value::<usize>(&args[i], &args[i - 1]);
+                    let queen = args[i - 1] == "-no-early-queen-moves";
+
+                    let count = book.filter_moves(|_, mov, depth| {
+                        let matches = if queen {
+                            mov.role() == Role::Queen
+                        } else {
+                            mov.is_capture()
+                        };
+                        !matches || depth.is_none_or(|d| d >= plies)
+                    });
+                    info!("Removed {} moves with {} {}", count, args[i - 1], plies);
+                }
+                "-max-own-moves" | "-max-opponent-moves" => {
+                    let max = parse_value::<usize>(&args[i], &args[i - 1]);
+                    let (own, opponent) = if args[i - 1] == "-max-own-moves" {
+                        (max, usize::MAX)
+                    } else {
+                        (usize::MAX, max)
+                    };
+
+                    let count = book.limit_branching(repertoire_color(args), own, opponent);
+                    info!("Removed {} moves over {} {}", count, args[i - 1], max);
+                }
+                "-syzygy" => {
+                    let pieces = parse_flag(args, "-syzygy-pieces").unwrap_or(7);
+                    let flag = args.iter().any(|x| x == "-syzygy-flag");
+                    let mut engine = engine_from_args(args);
+                    engine.set_option("SyzygyPath", &args[i]);
+
+                    let losses = book.prune_tablebase_losses(&mut engine, pieces, flag);
+
+                    for loss in &losses {
+                        verbose!(
+                            "Tablebase loss after {} ({}) at {}",
+                            loss.mov,
+                            loss.score,
+                            Fen::from_position(loss.position.clone(), EnPassantMode::Legal)
+                        );
+                    }
+                    info!(
+                        "{} {} moves walking into tablebase losses",
+                        if flag { "Flagged" } else { "Removed" },
+                        losses.len()
+                    );
+                    log::record("modify", "tablebase_losses", losses.len());
+                }
+                "-bias" => {
+                    let factor = parse_flag::<f64>(args, "-bias-factor").unwrap_or(5.0);
+                    let games = read_games(PgnFilter::new(), open_input(&args[i]));
+
+                    let count = book.bias_towards(&games, factor);
+                    info!("Biased {} entries towards {}", count, args[i]);
+                }
                 "-scale-weights" => {
-                    let factor = args[i].parse::<f64>().unwrap();
+                    let factor = parse_value::<f64>(&args[i], &args[i - 1]);
 
                     book.map_entries(|entry| entry.weight = (entry.weight as f64 * factor) as u64)
                 }
+                "-scale-by-depth" => {
+                    let factor = depth_factors(&args[i]);
+
+                    book.map_entries(|entry| {
+                        if let Some(depth) = entry.depth {
+                            entry.weight = (entry.weight as f64 * factor(depth)) as u64;
+                        }
+                    })
+                }
+                "-perturb" => {
+                    let amount = Some(parse_value::<f64>(&args[i], &args[i - 1]))
+                        .filter(|x| (0.0..1.0).contains(x))
+                        .unwrap_or_else(|| {
+                            usage(format!("Invalid value {:?} for -perturb", args[i]))
+                        });
+                    let seed =
+                        parse_flag(args, "-seed").unwrap_or_else(|| Rng::from_time().next_u64());
+
+                    book.perturb_weights(amount, seed);
+                }
+                "-sharpen" => {
+                    let temperature = temperature(&args[i]);
+
+                    book.map_nodes(|entries| sharpen_weights(entries, temperature));
+                }
+                "-map-weights" => {
+                    let expr = entry_expr(&args[i], &args[i - 1]);
+
+                    book.map_entries(|entry| map_weight(entry, &expr));
+                }
+                "-filter-entries" => {
+                    let expr = entry_expr(&args[i], &args[i - 1]);
+
+                    book.filter(|entry| expr.matches(|v| entry.expr_variable(v)));
+                }
                 _ => i -= 1,
             }
         }
@@ -206,6 +995,28 @@ fn modify_book(book: &mut BookMap, args: &[String]) {
             }
             "-white-only" => book.filter(|entry| entry.depth.unwrap_or(1) % 2 == 0),
             "-black-only" => book.filter(|entry| entry.depth.unwrap_or(0) % 2 == 1),
+            "-prune-repetitions" => {
+                let count = book.prune_repetition_traps(repertoire_color(args));
+                info!("Removed {} moves allowing repetitions", count);
+            }
+            "-no-underpromotions" => {
+                let count =
+                    book.filter_moves(|_, mov, _| mov.promotion().is_none_or(|x| x == Role::Queen));
+                info!("Removed {} underpromotions", count);
+            }
+            "-drop-hanging" => {
+                let exceptions = flag_values(args, "-hanging-exception")
+                    .into_iter()
+                    .map(|x| {
+                        MoveBan::parse(book, x, true)
+                            .unwrap_or_else(|e| usage(format!("{} for -hanging-exception", e)))
+                    })
+                    .collect::<Vec<_>>();
+
+                let count = book.drop_hanging(&exceptions);
+                info!("Dropped {} moves losing material by static exchange", count);
+                log::record("modify", "hanging", count);
+            }
             "-clear-learning" => book.map_entries(|entry| entry.learn = 0),
             "-uniform" => book.map_entries(|entry| entry.weight = 1),
             _ => {}
@@ -213,42 +1024,460 @@ fn modify_book(book: &mut BookMap, args: &[String]) {
 
         i += 1
     }
+
+    set_metadata(book, args);
+    log::record("modify", "entries", book.len());
 }
 
-fn write_book(book: &mut BookMap, outputs: &[(FileType, String)]) {
+// Sets the metadata given by -book-title, -book-author, -book-date and -book-source. Books given
+// metadata without a creation date are dated today.
+fn set_metadata(book: &mut BookMap, args: &[String]) {
+    let mut set = false;
+
+    for key in METADATA_KEYS {
+        if let Some(value) = flag_value(args, &format!("-book-{}", key)) {
+            book.metadata_mut().set(key, value);
+            set = true;
+        }
+    }
+
+    if set {
+        book.metadata_mut().date.get_or_insert_with(today);
+    }
+}
+
+pub fn write_book(book: &mut BookMap, outputs: &[(FileType, String)], args: &[String]) {
+    book.set_cycle_policy(cycle_policy(args));
+
     for (filetype, filename) in outputs {
-        let mut writer: Box<dyn Write> = if filename == "-" {
-            Box::new(io::stdout())
-        } else {
-            Box::new(File::create(filename).unwrap())
-        };
+        verbose!("Writing {}", filename);
+        let mut writer = open_output(filename);
 
         match filetype {
-            Bin => book.write(&mut writer),
+            Bin => book.write(&mut writer, bin_order(args)),
             Json => book.write_json(&mut writer),
-            Tree(false) => book.write_txt(&mut writer),
+            Tree(false) => book.write_txt(
+                &mut writer,
+                notation(args),
+                args.iter().any(|x| x == "-mark-transpositions"),
+            ),
             Tree(true) => book.write_blob(&mut writer),
+            UciLines => book.write_uci_lines(&mut writer),
+            Packed => book.write_packed(&mut writer),
+            Latex => book.write_latex(&mut writer, &latex_diagrams(args)),
+            Csv => {
+                let rows = book.write_csv(&mut writer);
+                verbose!("Wrote {} rows", rows);
+            }
+            Anki => {
+                let cards = book.write_anki_deck(&mut writer, anki_side(args));
+                verbose!("Wrote {} cards", cards);
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(bits) = bloom_bits(args) {
+        let filter = book.bloom_filter(bits);
+
+        for filename in bloom_sidecars(outputs, args) {
+            verbose!("Writing {}", filename);
+            filter.write(&mut open_output(&filename));
+        }
+    }
+}
+
+fn bloom_bits(args: &[String]) -> Option<usize> {
+    if args.iter().any(|x| x == "-bloom") {
+        Some(parse_flag(args, "-bloom-bits").unwrap_or(10))
+    } else {
+        None
+    }
+}
+
+/// With -bloom, names of the bloom filter files written next to each .bin output
+pub fn bloom_sidecars(outputs: &[(FileType, String)], args: &[String]) -> Vec<String> {
+    if bloom_bits(args).is_none() {
+        return Vec::new();
+    }
+
+    outputs
+        .iter()
+        .filter(|x| x.0 == Bin && x.1 != "-")
+        .map(|x| format!("{}.bloom", x.1))
+        .collect()
+}
+
+/// Builds a book from every input named in args and applies the modification options to the
+/// result, for commands that operate on an existing book.
+pub fn load_books(args: &[String]) -> BookMap {
+    let inputs = get_input_files(args, false);
+
+    let mut book = if inputs.iter().any(|x| x.0.is_games()) {
+        book_from_pgns(args, &inputs)
+    } else {
+        BookMap::new()
+    };
+
+    merge_book_files(&mut book, &inputs, args);
+    modify_book(&mut book, args);
+    book
+}
+
+// Flags that need the whole book in memory, which -disk-store can't apply
+const WHOLE_BOOK_FLAGS: &[&str] = &[
+    "-set-root",
+    "-priority",
+    "-depth",
+    "-smooth",
+    "-prefer-deep",
+    "-bias",
+    "-filter-source",
+    "-remove-source",
+    "-exclude-move",
+    "-exclude-san",
+    "-force-move",
+    "-force-move-prune",
+    "-apply-edits",
+    "-script",
+    "-syzygy",
+    "-remove-disconnected",
+    "-white-only",
+    "-black-only",
+    "-max-own-moves",
+    "-max-opponent-moves",
+    "-trim-tails",
+    "-complete-lines",
+    "-scale-by-depth",
+    "-perturb",
+    "-no-underpromotions",
+    "-no-early-queen-moves",
+    "-captures-only-after",
+    "-drop-hanging",
+    "-prune-repetitions",
+    "-bloom",
+];
+
+// Parses the schedule of -scale-by-depth: factors for each ply from the root separated by commas,
+// the last of which applies to all deeper plies, or an expression over depth giving the factor in
+// percent. Plies where the expression has no value keep their weights.
+fn depth_factors(value: &str) -> Box<dyn Fn(usize) -> f64> {
+    let invalid = || -> ! { usage(format!("Invalid value {:?} for -scale-by-depth", value)) };
+
+    let factors = value
+        .split(',')
+        .map(|x| x.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>();
+
+    if let Ok(factors) = factors {
+        if factors.iter().any(|x| !(*x >= 0.0 && x.is_finite())) {
+            invalid();
+        }
+        return Box::new(move |depth| factors[depth.min(factors.len() - 1)]);
+    }
+
+    let expr = Expr::parse(value, &["depth"]).unwrap_or_else(|_| invalid());
+
+    Box::new(move |depth| {
+        expr.value(|_| i64::try_from(depth).ok())
+            .map_or(1.0, |x| x.max(0) as f64 / 100.0)
+    })
+}
+
+fn temperature(value: &str) -> f64 {
+    Some(parse_value::<f64>(value, "-sharpen"))
+        .filter(|x| *x > 0.0 && x.is_finite())
+        .unwrap_or_else(|| usage(format!("Invalid value {:?} for -sharpen", value)))
+}
+
+// Parses the expression given to -map-weights or -filter-entries, where -map-weights may start
+// with "weight ="
+fn entry_expr(value: &str, flag: &str) -> Expr {
+    let assigned = value
+        .trim_start()
+        .strip_prefix("weight")
+        .map(|x| x.trim_start())
+        .filter(|x| flag == "-map-weights" && x.starts_with('=') && !x.starts_with("=="));
+
+    Expr::parse(assigned.map_or(value, |x| &x[1..]), ENTRY_VARIABLES)
+        .unwrap_or_else(|e| usage(format!("Invalid value {:?} for {}: {}", value, flag, e)))
+}
+
+// Sets the weight of entry to the value of expr, keeping it when the value is missing and
+// clamping negative values to 0
+fn map_weight(entry: &mut BookEntry, expr: &Expr) {
+    if let Some(weight) = expr.value(|v| entry.expr_variable(v)) {
+        entry.weight = weight.max(0) as u64;
+    }
+}
+
+// Applies the modification options that only look at one position to its entries, in the order
+// they are given
+fn modify_position(entries: &mut Vec<BookEntry>, args: &[String]) {
+    for (i, arg) in args.iter().enumerate() {
+        let value = || &args[(i + 1).min(args.len() - 1)][..];
+
+        match &arg[..] {
+            "-min-weight" => {
+                let weight = parse_value::<u64>(value(), arg);
+                entries.retain(|entry| entry.weight >= weight);
+            }
+            "-max-weight" => {
+                let weight = parse_value::<u64>(value(), arg);
+                entries.retain(|entry| entry.weight <= weight);
+            }
+            "-min-move-elo" => {
+                let elo = parse_value::<u64>(value(), arg);
+                entries.retain(|entry| entry.average_elo().is_none_or(|e| e >= elo));
+            }
+            "-min-wilson" => {
+                let score = parse_value::<f64>(value(), arg);
+                let z = parse_flag::<f64>(args, "-wilson-z").unwrap_or(1.96);
+                entries.retain(|entry| entry.wilson_lower_bound(z).is_none_or(|s| s >= score));
+            }
+            "-min-sharpness" => {
+                let sharpness = parse_value::<f64>(value(), arg);
+                entries.retain(|entry| entry.sharpness().is_none_or(|s| s >= sharpness));
+            }
+            "-max-sharpness" => {
+                let sharpness = parse_value::<f64>(value(), arg);
+                entries.retain(|entry| entry.sharpness().is_none_or(|s| s <= sharpness));
+            }
+            "-keep-best" => {
+                entries.sort_by_key(|x| u64::MAX - x.weight);
+                entries.truncate(parse_value(value(), arg));
+            }
+            "-keep-worst" => {
+                entries.sort_by_key(|x| x.weight);
+                entries.truncate(parse_value(value(), arg));
+            }
+            "-scale-weights" => {
+                let factor = parse_value::<f64>(value(), arg);
+                for entry in entries.iter_mut() {
+                    entry.weight = (entry.weight as f64 * factor) as u64;
+                }
+            }
+            "-sharpen" => sharpen_weights(entries, temperature(value())),
+            "-map-weights" => {
+                let expr = entry_expr(value(), arg);
+                entries
+                    .iter_mut()
+                    .for_each(|entry| map_weight(entry, &expr));
+            }
+            "-filter-entries" => {
+                let expr = entry_expr(value(), arg);
+                entries.retain(|entry| expr.matches(|v| entry.expr_variable(v)));
+            }
+            "-clear-learning" => entries.iter_mut().for_each(|entry| entry.learn = 0),
+            "-uniform" => entries.iter_mut().for_each(|entry| entry.weight = 1),
+            "-move-time-weights" => weight_by_move_time(entries),
             _ => {}
         }
     }
 }
 
+// Builds the book in the directory given by -disk-store, for books too large for memory. Only
+// .bin files can be written, as they are written position by position in order of hash. With
+// -merge-into, the existing book is merged in as it is written, without loading it.
+fn build_on_disk(
+    args: &[String],
+    inputs: &[(FileType, String)],
+    outputs: &[(FileType, String)],
+    dir: &Path,
+) {
+    let mode = if args.iter().any(|x| x == "-disk-store") {
+        "-disk-store"
+    } else {
+        "-merge-into"
+    };
+    if let Some(flag) = WHOLE_BOOK_FLAGS
+        .iter()
+        .find(|x| args.iter().any(|y| y == *x))
+    {
+        usage(format!("{} can't be used with {}", flag, mode));
+    }
+    if let Some(x) = inputs.iter().find(|x| !x.0.is_games() && x.0 != Bin) {
+        usage(format!(
+            "Only pgn and .bin files can be read with {}, not {}",
+            mode, x.1
+        ));
+    }
+    if let Some(x) = outputs.iter().find(|x| x.0 != Bin) {
+        usage(format!(
+            "Only .bin files can be written with {}, not {}",
+            mode, x.1
+        ));
+    }
+    let base = flag_value(args, "-merge-into");
+    if duplicate_policy(args).is_some() && inputs.iter().any(|x| x.0 == Bin) {
+        usage(format!(
+            "-bin-duplicates only applies to the -merge-into book with {}",
+            mode
+        ));
+    }
+    if let Some(filename) = base.filter(|x| !strip_compression(x).ends_with(".bin")) {
+        usage(format!(
+            "Only .bin files can be merged into with -merge-into, not {}",
+            filename
+        ));
+    }
+
+    let limit = parse_flag(args, "-disk-store-positions").unwrap_or(1_000_000);
+    let mut store = DiskStore::new(dir, limit);
+
+    info!("Building book from pgn files...");
+    if inputs.iter().any(|x| x.0.is_games()) {
+        add_games(&mut store, args, inputs);
+    }
+
+    info!("Combining pgn book with other book files...");
+    let options = merge_options(args);
+    for (_, filename) in inputs.iter().filter(|x| x.0 == Bin) {
+        verbose!("Reading book {}", filename);
+        store.extend_from_reader_with(&mut open_input(filename), options);
+    }
+    verbose!("Wrote {} runs to {}", store.runs(), dir.display());
+
+    info!("Writing book to output...");
+    let mut writers = outputs
+        .iter()
+        .map(|x| open_output(&x.1))
+        .collect::<Vec<_>>();
+    let order = bin_order(args);
+    let mut written = 0;
+
+    let write = |hash, mut entries: Vec<BookEntry>| {
+        modify_position(&mut entries, args);
+
+        if entries.is_empty() {
+            return;
+        }
+
+        written += 1;
+        sort_bin_entries(&mut entries, order);
+        for writer in &mut writers {
+            write_bin_position(writer, hash, entries.clone());
+        }
+    };
+    let positions = match base {
+        Some(filename) => {
+            verbose!("Merging into book {}", filename);
+            let duplicates = duplicate_policy(args).unwrap_or(DuplicatePolicy::First);
+            store.merge_runs_into(open_input(filename), filename, duplicates, write)
+        }
+        None => store.merge_runs(write),
+    };
+
+    info!("Wrote {} of {} positions", written, positions);
+    log::record("modify", "entries", written);
+    log::record("write", "outputs", outputs.len());
+}
+
+// Rewrites the .bin file given by -repair-bin sorted by hash, with its repeated records combined
+// as given by -bin-duplicates, by default keeping the first
+fn repair_bin(
+    args: &[String],
+    filename: &str,
+    inputs: &[(FileType, String)],
+    outputs: &[(FileType, String)],
+) {
+    if let Some(x) = inputs.first() {
+        usage(format!(
+            "-repair-bin reads no other inputs, such as {}",
+            x.1
+        ));
+    }
+
+    let policy = duplicate_policy(args).unwrap_or(DuplicatePolicy::First);
+    let mut book = read_bin(filename, open_input(filename), policy);
+    info!("Read {} positions from {}", book.len(), filename);
+
+    write_book(&mut book, outputs, args);
+    log::record("write", "outputs", outputs.len());
+}
+
 pub fn run() {
     let args = env::args().skip(1).collect::<Vec<_>>();
+    log::init(&args);
+
+    if let Some(command) = args.first() {
+        if commands::run_command(command, &args[1..]) {
+            write_log(&args);
+            return;
+        }
+    }
+
+    let inputs = get_input_files(&args, true);
+    // The book merged into or repaired, which is updated in place without outputs
+    let target = flag_value(&args, "-merge-into").or(flag_value(&args, "-repair-bin"));
+    let mut outputs = get_output_files(&args);
+    if let (Some(filename), true) = (target, outputs.is_empty()) {
+        outputs.push((Bin, filename.to_string()));
+    }
+    let sidecars = bloom_sidecars(&outputs, &args);
+    // Inputs are read before their outputs replace them, so converting in place needs no -f
+    let in_place = |filename: &str| {
+        inputs
+            .iter()
+            .map(|x| &x.1[..])
+            .chain(target)
+            .any(|x| same_file(x, filename))
+    };
+    check_outputs(
+        outputs.iter().map(|x| &x.1[..]).filter(|x| !in_place(x)),
+        &args,
+    );
+    check_outputs(sidecars.iter().map(|x| &x[..]), &args);
 
-    let inputs = get_input_files(&args);
-    let outputs = get_output_files(&args);
+    // Reject invalid output options before doing any work
+    notation(&args);
+    bin_order(&args);
+    cycle_policy(&args);
+    latex_diagrams(&args);
+    anki_side(&args);
+    repertoire_color(&args);
+    merge_options(&args);
+    duplicate_policy(&args);
+
+    if let Some(filename) = flag_value(&args, "-repair-bin") {
+        repair_bin(&args, filename, &inputs, &outputs);
+        info!("Done!");
+        write_log(&args);
+        return;
+    }
+    if let Some(dir) = flag_value(&args, "-disk-store") {
+        build_on_disk(&args, &inputs, &outputs, Path::new(dir));
+        info!("Done!");
+        write_log(&args);
+        return;
+    }
+    // The new entries are usually few enough to stay in memory, so the runs go to a temporary
+    // directory unless -disk-store gives one
+    if flag_value(&args, "-merge-into").is_some() {
+        build_on_disk(&args, &inputs, &outputs, &env::temp_dir());
+        info!("Done!");
+        write_log(&args);
+        return;
+    }
 
-    println!("Building book from pgn files...");
+    info!("Building book from pgn files...");
     let mut book = book_from_pgns(&args, &inputs);
 
-    println!("Created {} entries in book", book.len());
+    info!("Created {} entries in book", book.len());
 
-    println!("Combining pgn book with other book files...");
+    info!("Combining pgn book with other book files...");
     merge_book_files(&mut book, &inputs, &args);
-    println!("Applying modifications to book...");
+    info!("Applying modifications to book...");
     modify_book(&mut book, &args);
-    println!("Writing book to output...");
-    write_book(&mut book, &outputs);
-    println!("Done!");
+    info!("Writing book to output...");
+    write_book(&mut book, &outputs, &args);
+    log::record("write", "outputs", outputs.len());
+    info!("Done!");
+    write_log(&args);
+}
+
+fn write_log(args: &[String]) {
+    if let Some(filename) = flag_value(args, "-log-json") {
+        log::write_json(filename);
+    }
 }
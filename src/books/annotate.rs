@@ -0,0 +1,128 @@
+// Writes games with comments giving the book's data on each move, for reviewing played games
+// against a repertoire. Moves in the book get their share of the position's weight, their game
+// results and the most popular alternatives; moves leaving the book get the moves it has instead.
+
+use super::*;
+
+use shakmaty::{Move, Position};
+
+// The number of alternatives listed for each move
+const ALTERNATIVES: usize = 3;
+
+fn share(entry: &BookEntry, total: u64) -> f64 {
+    entry.weight as f64 * 100.0 / total.max(1) as f64
+}
+
+impl BookMap {
+    // The comment for playing mov at pos, or None if pos is not in the book
+    fn move_comment(&self, pos: &Chess, mov: &Move) -> Option<String> {
+        let mut entries = self
+            .entries(pos)
+            .iter()
+            .filter_map(|e| Some((e, from_book_move(e.mov).to_move(pos).ok()?)))
+            .collect::<Vec<_>>();
+
+        if entries.is_empty() {
+            return None;
+        }
+
+        entries.sort_by_key(|x| Reverse(x.0.weight));
+        let total = entries.iter().map(|x| x.0.weight).sum::<u64>();
+
+        let others = entries
+            .iter()
+            .filter(|x| x.1 != *mov)
+            .take(ALTERNATIVES)
+            .map(|(e, m)| {
+                format!(
+                    "{} {:.1}%",
+                    SanPlus::from_move(pos.clone(), m),
+                    share(e, total)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let Some((entry, _)) = entries.iter().find(|x| x.1 == *mov) else {
+            return Some(format!("not in book, book moves: {}", others));
+        };
+
+        let mut out = format!("book {:.1}%, weight {}", share(entry, total), entry.weight);
+
+        if entry.sharpness().is_some() {
+            let [wins, draws, losses] = entry.results;
+            out += &format!(", +{} ={} -{}", wins, draws, losses);
+        }
+
+        if !others.is_empty() {
+            out += &format!(", alternatives: {}", others);
+        }
+
+        Some(out)
+    }
+
+    /// Writes the game as pgn with a comment after each move played from a position in the book
+    pub fn write_annotated<W: Write>(&self, w: &mut W, game: &PgnGame) {
+        for (key, value) in &game.headers {
+            writeln!(w, "[{} {:?}]", key, value);
+        }
+        writeln!(w);
+
+        let mut pos = game.start_position();
+        let mut numbered = false;
+        let mut text = Vec::new();
+
+        for san in &game.moves {
+            let Ok(mov) = san.san.to_move(&pos) else {
+                break;
+            };
+
+            if pos.turn() == Color::White {
+                text.push(format!("{}.", pos.fullmoves()));
+            } else if !numbered {
+                text.push(format!("{}...", pos.fullmoves()));
+            }
+            text.push(san.to_string());
+            numbered = true;
+
+            if let Some(comment) = self.move_comment(&pos, &mov) {
+                text.push(format!("{{ {} }}", comment));
+                // Black moves after a comment are numbered again
+                numbered = false;
+            }
+
+            pos.play_unchecked(&mov);
+        }
+
+        let result = game
+            .headers
+            .iter()
+            .find(|x| x.0 == "Result")
+            .map_or("*", |x| &x.1[..]);
+        text.push(result.to_string());
+
+        writeln!(w, "{}", text.join(" "));
+        writeln!(w);
+    }
+}
+
+#[test]
+fn t_annotate() {
+    let book = BookMap::read_txt(
+        &mut "3 e4\n    3 c5, Nc3\n    e5\nd4".as_bytes(),
+        MoveNotation::San,
+    );
+    let pgn = "[Result \"1-0\"]\n\n1. e4 c5 2. Nf3 d6 1-0";
+    let game = &read_games(PgnFilter::new(), pgn.as_bytes())[0];
+
+    let mut out = Vec::new();
+    book.write_annotated(&mut out, game);
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "[Result \"1-0\"]\n\n\
+         1. e4 { book 75.0%, weight 3, alternatives: d4 25.0% } \
+         1... c5 { book 75.0%, weight 3, alternatives: e5 25.0% } \
+         2. Nf3 { not in book, book moves: Nc3 100.0% } 2... d6 1-0\n\n"
+    );
+}
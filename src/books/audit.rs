@@ -0,0 +1,121 @@
+// Checks a book whose learn values hold engine evals against them, finding the positions where the
+// book's most played move is clearly worse than the best evaluated one. Such moves usually come
+// from popular but dubious lines in the games the book was built from.
+
+use super::txt_books::{fen, numbered_move};
+use super::*;
+
+use std::io::Write;
+
+/// A position where the book's highest weighted move evaluates worse than its best evaluated one
+pub struct Disagreement {
+    /// The moves from the root reaching the position, with move numbers
+    pub line: Vec<String>,
+    pub position: Chess,
+    /// The highest weighted move and the best evaluated one, in san
+    pub book_move: String,
+    pub best_move: String,
+    /// How many centipawns worse the book move evaluates
+    pub loss: i32,
+    /// The total weight of the position's moves, the number of games reaching it in books built
+    /// from games
+    pub total: u64,
+}
+
+impl BookMap {
+    /// Finds the positions reachable from the root where the highest weighted move evaluates at
+    /// least threshold centipawns worse than the best evaluated move, most often reached first
+    pub fn eval_disagreements(&self, threshold: i32) -> Vec<Disagreement> {
+        let mut visited = PositionSet::default();
+        let mut stack = vec![(self.root.clone(), Vec::new())];
+        let mut out = Vec::new();
+
+        while let Some((pos, line)) = stack.pop() {
+            let entries = self.entries(&pos);
+
+            if entries.is_empty() || !visited.insert(self.hash(&pos)) {
+                continue;
+            }
+
+            let book = entries.iter().max_by_key(|e| (e.weight, e.eval())).unwrap();
+            let best = entries.iter().max_by_key(|e| (e.eval(), e.weight)).unwrap();
+            let san = |e: &BookEntry| {
+                let mov = from_book_move(e.mov).to_move(&pos).ok()?;
+                Some(SanPlus::from_move(pos.clone(), &mov).to_string())
+            };
+
+            if best.eval() - book.eval() >= threshold {
+                if let (Some(book_move), Some(best_move)) = (san(book), san(best)) {
+                    out.push(Disagreement {
+                        line: line.clone(),
+                        position: pos.clone(),
+                        book_move,
+                        best_move,
+                        loss: best.eval() - book.eval(),
+                        total: entries.iter().map(|e| e.weight).sum(),
+                    });
+                }
+            }
+
+            for entry in entries.iter().rev() {
+                let Ok(mov) = from_book_move(entry.mov).to_move(&pos) else {
+                    continue;
+                };
+
+                let mut line = line.clone();
+                line.push(numbered_move(&pos, &mov, line.is_empty()));
+                stack.push((pos.clone().play(&mov).unwrap(), line));
+            }
+        }
+
+        out.sort_by_key(|x| (Reverse(x.total), Reverse(x.loss)));
+        out
+    }
+
+    /// Writes the disagreements of eval_disagreements, returning how many were written
+    pub fn write_audit_report<W: Write>(&self, w: &mut W, threshold: i32) -> usize {
+        let disagreements = self.eval_disagreements(threshold);
+
+        for x in &disagreements {
+            writeln!(
+                w,
+                "{}: book plays {}, {} is {} centipawns better, reached with weight {} ({})",
+                if x.line.is_empty() {
+                    "root".to_string()
+                } else {
+                    x.line.join(" ")
+                },
+                x.book_move,
+                x.best_move,
+                x.loss,
+                x.total,
+                fen(&x.position)
+            );
+        }
+
+        disagreements.len()
+    }
+}
+
+#[test]
+fn t_eval_disagreements() {
+    let text = "20 e4 30\n    15 c5 50\n        9 Nf3 40\n        3 Qh5 10\n    5 e5 60\n\
+                2 d4 30\n    2 d5 20\n    1 f5 110";
+    let book = BookMap::read_txt(&mut text.as_bytes(), MoveNotation::San);
+
+    // 1... d5 is 90 centipawns worse than 1... f5, while 2. Nf3 is already the best move
+    let found = book.eval_disagreements(80);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].line, vec!["1. d4"]);
+    assert_eq!(
+        (&found[0].book_move[..], &found[0].best_move[..]),
+        ("d5", "f5")
+    );
+    assert_eq!((found[0].loss, found[0].total), (90, 3));
+
+    // 1... c5 is 10 centipawns worse than 1... e5, in a position reached more often than 1. d4
+    let found = book.eval_disagreements(10);
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].line, vec!["1. e4"]);
+    assert_eq!(found[1].line, vec!["1. d4"]);
+}
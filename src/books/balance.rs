@@ -0,0 +1,175 @@
+// Compares the preparation a book gives each color, so that a book combining a white and a black
+// repertoire can be kept from going much deeper for one of them. A line of the book ending with a
+// side's move ends that side's preparation, leaving the opponent out of book, so the depth of each
+// side's preparation is the depth of the lines ending with its moves.
+
+use super::*;
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+/// The part of a book prepared for one side
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SideCoverage {
+    /// Positions reachable from the root with the side to move and book moves, and their moves
+    pub positions: usize,
+    pub moves: usize,
+    /// Lines ending with a move of the side, and the greatest and total plies from the root to
+    /// their ends
+    pub lines: usize,
+    pub max_depth: usize,
+    pub depth_sum: usize,
+    /// Positions of the reference games with the side to move that are in the book, and all of
+    /// the positions checked
+    pub covered: usize,
+    pub checked: usize,
+}
+
+impl SideCoverage {
+    pub fn average_depth(&self) -> f64 {
+        self.depth_sum as f64 / self.lines.max(1) as f64
+    }
+}
+
+fn side_index(color: Color) -> usize {
+    (color == Color::Black) as usize
+}
+
+impl BookMap {
+    /// The coverage of each side, white first, checking the first plies positions of each of
+    /// games against the book
+    pub fn side_coverage(&self, games: &[PgnGame], plies: usize) -> [SideCoverage; 2] {
+        let tree = self.to_tree();
+        let mut out = [SideCoverage::default(); 2];
+
+        // The fewest plies reaching each position from the root
+        let mut depths = vec![usize::MAX; tree.len()];
+        let mut queue = VecDeque::from([tree.root()]);
+        depths[tree.root()] = 0;
+
+        while let Some(i) = queue.pop_front() {
+            let node = tree.node(i);
+
+            if node.children.is_empty() {
+                // The line ends after a move of the side not to move
+                let side = &mut out[side_index(!node.position.turn())];
+                side.lines += 1;
+                side.max_depth = side.max_depth.max(depths[i]);
+                side.depth_sum += depths[i];
+                continue;
+            }
+
+            let side = &mut out[side_index(node.position.turn())];
+            side.positions += 1;
+            side.moves += node.children.len();
+
+            for (_, child) in &node.children {
+                if depths[*child] == usize::MAX {
+                    depths[*child] = depths[i] + 1;
+                    queue.push_back(*child);
+                }
+            }
+        }
+
+        for game in games {
+            let mut pos = game.start_position();
+
+            for san in game.moves.iter().take(plies) {
+                let side = &mut out[side_index(pos.turn())];
+                side.checked += 1;
+                side.covered += self.map.contains_key(&self.hash(&pos)) as usize;
+
+                let Ok(mov) = san.san.to_move(&pos) else {
+                    break;
+                };
+                pos.play_unchecked(&mov);
+            }
+        }
+
+        out
+    }
+
+    /// When the lines of one side's preparation go deeper than the other's, removes the moves
+    /// ending more than a ply deeper than the other side's deepest line, so that the deeper side's
+    /// lines end with its move after it, along with the lines only reachable through them.
+    /// Returns the number of moves removed.
+    pub fn balance_depths(&mut self) -> usize {
+        let [white, black] = self.side_coverage(&[], 0);
+        let limit = white.max_depth.min(black.max_depth);
+
+        if white.lines == 0 || black.lines == 0 || white.max_depth.abs_diff(black.max_depth) <= 1 {
+            return 0;
+        }
+
+        self.set_depths();
+        let tree = self.to_tree();
+        let mut count = 0;
+
+        for entries in self.map.values_mut() {
+            let len = entries.len();
+            entries.retain(|e| e.depth.is_none_or(|d| d <= limit));
+            count += len - entries.len();
+        }
+
+        self.remove_cut_off(&tree);
+        count
+    }
+
+    /// Writes the coverage of each side, with the share of the positions of games it covers when
+    /// games are given
+    pub fn write_balance_report<W: Write>(&self, w: &mut W, games: &[PgnGame], plies: usize) {
+        let sides = self.side_coverage(games, plies);
+
+        for (name, side) in ["White", "Black"].iter().zip(sides) {
+            write!(
+                w,
+                "{}: {} positions to move in with {} moves, {} lines ending with its moves, \
+                 maximum depth {}, average depth {:.1}",
+                name,
+                side.positions,
+                side.moves,
+                side.lines,
+                side.max_depth,
+                side.average_depth()
+            );
+
+            if !games.is_empty() {
+                write!(
+                    w,
+                    ", covering {:.1}% of {} game positions",
+                    side.covered as f64 * 100.0 / side.checked.max(1) as f64,
+                    side.checked
+                );
+            }
+            writeln!(w);
+        }
+    }
+}
+
+#[test]
+fn t_balance_depths() {
+    // White's Ruy Lopez line ends with its 4th move, black's reply to d4 with its 1st
+    let text = "e4, e5, Nf3, Nc6, Bb5, a6, Ba4\nd4, d5";
+    let mut book = BookMap::read_txt(&mut text.as_bytes(), MoveNotation::San);
+    let games = read_games(
+        PgnFilter::new(),
+        "1. e4 e5 2. Nc3 Nf6 *\n\n1. c4 *".as_bytes(),
+    );
+
+    let [white, black] = book.side_coverage(&games, 4);
+    assert_eq!(
+        (white.positions, white.moves, black.positions, black.moves),
+        (4, 5, 4, 4)
+    );
+    assert_eq!(
+        (white.lines, white.max_depth, black.lines, black.max_depth),
+        (1, 7, 1, 2)
+    );
+    assert_eq!((white.covered, white.checked), (3, 3));
+    assert_eq!((black.covered, black.checked), (1, 2));
+
+    // White's line is cut after 2. Nf3, a ply deeper than black's
+    assert_eq!(book.balance_depths(), 4);
+    assert_eq!(book.map.values().flatten().count(), 5);
+    assert_eq!(book.balance_depths(), 0);
+}
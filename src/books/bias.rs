@@ -0,0 +1,80 @@
+// Overlays a personal repertoire on a book built from many games, by giving the moves of the
+// repertoire's lines more weight than their popularity alone would.
+
+use super::*;
+
+use shakmaty::Position;
+
+impl BookMap {
+    /// Multiplies the weights of book moves played in games by factor, matching moves by the
+    /// position they are played from, so that moves reached through transpositions count too.
+    /// Moves of the games missing from the book are not added. Returns the number of entries
+    /// changed.
+    pub fn bias_towards(&mut self, games: &[PgnGame], factor: f64) -> usize {
+        let mut moves = PositionMap::<Vec<u16>>::default();
+
+        for game in games {
+            let mut pos = game.start_position();
+
+            for san in &game.moves {
+                let Ok(mov) = san.san.to_move(&pos) else {
+                    break;
+                };
+
+                let book_move = to_book_move(UciMove::from_chess960(&mov));
+                let entry = moves.entry(self.hash(&pos)).or_default();
+                if !entry.contains(&book_move) {
+                    entry.push(book_move);
+                }
+                pos.play_unchecked(&mov);
+            }
+        }
+
+        let mut count = 0;
+
+        for (hash, moves) in moves {
+            let Some(entries) = self.map.get_mut(&hash) else {
+                continue;
+            };
+
+            for entry in entries.iter_mut().filter(|e| moves.contains(&e.mov)) {
+                entry.weight = (entry.weight as f64 * factor) as u64;
+                count += 1;
+            }
+        }
+
+        count
+    }
+}
+
+#[test]
+fn t_bias_towards() {
+    let mut book = BookMap::read_txt(
+        &mut "10 e4\n    5 c5, Nf3\n    4 e5\n4 d4, 2 d5".as_bytes(),
+        MoveNotation::San,
+    );
+    let games = read_games(
+        PgnFilter::new(),
+        "1. e4 e5 2. Nc3 *\n\n1. e4 c5 *".as_bytes(),
+    );
+
+    assert_eq!(book.bias_towards(&games, 5.0), 3);
+
+    let weights = |book: &BookMap, pos: &Chess| {
+        let mut out = book
+            .entries(pos)
+            .iter()
+            .map(|e| e.weight)
+            .collect::<Vec<_>>();
+        out.sort();
+        out
+    };
+    let root = Chess::default();
+    let e4 = root
+        .clone()
+        .play(&parse_move(&root, "e4").unwrap())
+        .unwrap();
+
+    assert_eq!(weights(&book, &root), [4, 50]);
+    assert_eq!(weights(&book, &e4), [20, 25]);
+}
@@ -0,0 +1,113 @@
+use super::*;
+
+const MAGIC: &[u8; 8] = b"RGBLOOM1";
+
+/// A compact set of the hashes in a book, which answers whether a position may be in the book
+/// with a small rate of false positives and no false negatives.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for the given number of hashes, using bits_per_hash bits per hash
+    pub fn new(count: usize, bits_per_hash: usize) -> Self {
+        let bits = (count * bits_per_hash).max(64);
+        // Optimal number of hash functions for this many bits per hash
+        let hashes = ((bits_per_hash as f64) * std::f64::consts::LN_2).round() as u32;
+
+        BloomFilter {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes: hashes.clamp(1, 16),
+        }
+    }
+
+    // Zobrist hashes are already uniform, so two halves of one seed the probe sequence
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let step = (hash.rotate_left(32).wrapping_mul(0x9E3779B97F4A7C15)) | 1;
+
+        (0..self.hashes as u64)
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+
+    pub fn insert(&mut self, hash: u64) {
+        for i in self.positions(hash) {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    /// Returns false only if hash is not in the book
+    pub fn maybe_in_book(&self, hash: u64) -> bool {
+        self.positions(hash)
+            .all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) {
+        w.write_all(MAGIC);
+        w.write_all(&self.hashes.to_be_bytes());
+        w.write_all(&(self.bits.len() as u64).to_be_bytes());
+
+        for word in &self.bits {
+            w.write_all(&word.to_be_bytes());
+        }
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> Option<Self> {
+        let mut header = [0u8; 20];
+        r.read_exact(&mut header).ok()?;
+
+        if &header[0..8] != MAGIC {
+            return None;
+        }
+
+        let hashes = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let words = u64::from_be_bytes(header[12..20].try_into().unwrap());
+        let mut bits = Vec::new();
+        let mut buf = [0u8; 8];
+
+        for _ in 0..words {
+            r.read_exact(&mut buf).ok()?;
+            bits.push(u64::from_be_bytes(buf));
+        }
+
+        if bits.is_empty() || hashes == 0 {
+            return None;
+        }
+
+        Some(BloomFilter { bits, hashes })
+    }
+}
+
+impl BookMap {
+    /// Builds a filter of the hashes of every position with entries
+    pub fn bloom_filter(&self, bits_per_hash: usize) -> BloomFilter {
+        let mut out = BloomFilter::new(self.map.len(), bits_per_hash);
+
+        for hash in self.map.keys() {
+            out.insert(*hash);
+        }
+
+        out
+    }
+}
+
+#[test]
+fn t_bloom_filter() {
+    let book = BookMap::read_txt(&mut "e4, e5, Nf3\nd4, d5".as_bytes(), MoveNotation::San);
+    let filter = book.bloom_filter(10);
+
+    let mut written = Vec::new();
+    filter.write(&mut written);
+    let filter = BloomFilter::read(&mut &written[..]).unwrap();
+
+    for hash in book.map.keys() {
+        assert!(filter.maybe_in_book(*hash));
+    }
+
+    let false_positives = (0..10000u64)
+        .map(|i| i.wrapping_mul(0x2545F4914F6CDD1D))
+        .filter(|h| filter.maybe_in_book(*h))
+        .count();
+    assert!(false_positives < 500);
+}
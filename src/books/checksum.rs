@@ -0,0 +1,39 @@
+// CRC-32 (IEEE), as used by zip and png, for detecting truncated or corrupted book files
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+const TABLE: [u32; 256] = make_table();
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, b| {
+        TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[test]
+fn t_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    assert_eq!(crc32(b""), 0);
+}
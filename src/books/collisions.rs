@@ -0,0 +1,77 @@
+use super::*;
+
+use shakmaty::{fen::Epd, EnPassantMode};
+
+/// Remembers a position for each hash seen while building a book, to find distinct positions
+/// that share a Zobrist hash.
+pub struct CollisionCheck {
+    positions: PositionMap<Epd>,
+    collisions: Vec<(u64, Epd, Epd)>,
+}
+
+impl CollisionCheck {
+    pub fn new() -> Self {
+        CollisionCheck {
+            positions: PositionMap::default(),
+            collisions: Vec::new(),
+        }
+    }
+
+    pub fn add_position(&mut self, pos: &Chess) {
+        let hash = book_hash(pos.clone());
+        // Polyglot hashes en passant squares whenever a capture is pseudo-legal
+        let epd = Epd::from_position(pos.clone(), EnPassantMode::PseudoLegal);
+
+        match self.positions.get(&hash) {
+            Some(other) if *other != epd => {
+                if !self.collisions.iter().any(|c| c.0 == hash) {
+                    self.collisions.push((hash, other.clone(), epd));
+                }
+            }
+            Some(_) => {}
+            None => {
+                self.positions.insert(hash, epd);
+            }
+        }
+    }
+
+    pub fn add_game(&mut self, game: &PgnGame, depth: usize) {
+        let mut board = game.start_position();
+
+        for sanplus in game.moves.iter().take(depth) {
+            self.add_position(&board);
+
+            match sanplus.san.to_move(&board) {
+                Ok(mov) => board.play_unchecked(&mov),
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Each hash found for two different positions, with the first two positions seen for it
+    pub fn collisions(&self) -> &[(u64, Epd, Epd)] {
+        &self.collisions
+    }
+}
+
+#[test]
+fn t_collisions() {
+    let mut check = CollisionCheck::new();
+    let pos = Chess::default();
+
+    check.add_position(&pos);
+    check.add_position(&pos);
+    assert!(check.collisions().is_empty());
+
+    // Forge a collision by reusing the start position's hash for another position
+    let other = pos
+        .clone()
+        .play(&"e4".parse::<San>().unwrap().to_move(&pos).unwrap())
+        .unwrap();
+    let epd = Epd::from_position(other, EnPassantMode::PseudoLegal);
+    check.positions.insert(START_HASH, epd);
+
+    check.add_position(&pos);
+    check.add_position(&pos);
+    assert_eq!(check.collisions().len(), 1);
+}
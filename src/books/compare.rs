@@ -0,0 +1,93 @@
+use super::txt_books::fen;
+use super::*;
+
+// The highest weighted entry, with ties broken by move so that reports are stable
+fn top_entry(entries: &[BookEntry]) -> Option<&BookEntry> {
+    entries.iter().max_by_key(|e| (e.weight, Reverse(e.mov)))
+}
+
+fn san(pos: &Chess, entry: &BookEntry) -> Option<San> {
+    let mov = from_book_move(entry.mov).to_move(pos).ok()?;
+    Some(San::from_move(pos, &mov))
+}
+
+impl BookMap {
+    /// Writes the positions reachable from the root in both books where their highest weighted
+    /// moves differ, each with the line reaching it, both moves and their weights, and the fen.
+    /// Returns the number of positions written.
+    pub fn write_top_move_conflicts<W: Write>(&self, other: &BookMap, w: &mut W) -> usize {
+        self.assert_same_hasher(other);
+
+        let mut visited = PositionSet::default();
+        let mut stack = vec![(self.root.clone(), Vec::new())];
+        let mut count = 0;
+
+        while let Some((pos, line)) = stack.pop() {
+            if !visited.insert(self.hash(&pos)) {
+                continue;
+            }
+
+            let ours = self.entries(&pos);
+            let theirs = other.entries(&pos);
+
+            if let (Some(a), Some(b)) = (top_entry(ours), top_entry(theirs)) {
+                if a.mov != b.mov {
+                    let moves = line.iter().map(|x: &San| x.to_string()).collect::<Vec<_>>();
+
+                    writeln!(
+                        w,
+                        "{}: {} {} vs {} {} ({})",
+                        if moves.is_empty() {
+                            "root".to_string()
+                        } else {
+                            moves.join(" ")
+                        },
+                        san(&pos, a).map_or("?".to_string(), |x| x.to_string()),
+                        a.weight,
+                        san(&pos, b).map_or("?".to_string(), |x| x.to_string()),
+                        b.weight,
+                        fen(&pos)
+                    );
+                    count += 1;
+                }
+            }
+
+            // Only lines in both books can reach positions in both
+            for entry in ours.iter().rev() {
+                if !theirs.iter().any(|e| e.mov == entry.mov) {
+                    continue;
+                }
+
+                let Ok(mov) = from_book_move(entry.mov).to_move(&pos) else {
+                    continue;
+                };
+
+                let mut line = line.clone();
+                line.push(San::from_move(&pos, &mov));
+                stack.push((pos.clone().play(&mov).unwrap(), line));
+            }
+        }
+
+        count
+    }
+}
+
+#[test]
+fn t_top_move_conflicts() {
+    let repertoire = BookMap::read_txt(
+        &mut "2 e4\n    5 c5\n    e5\nd4".as_bytes(),
+        MoveNotation::San,
+    );
+    let theory = BookMap::read_txt(
+        &mut "3 e4\n    2 c5\n    4 e5, Nf3\n9 d4".as_bytes(),
+        MoveNotation::San,
+    );
+
+    let mut out = Vec::new();
+    assert_eq!(repertoire.write_top_move_conflicts(&theory, &mut out), 2);
+
+    let report = String::from_utf8(out).unwrap();
+    let lines = report.lines().collect::<Vec<_>>();
+    assert!(lines[0].starts_with("root: e4 2 vs d4 9"));
+    assert!(lines[1].starts_with("e4: c5 5 vs e5 4"));
+}
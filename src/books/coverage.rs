@@ -0,0 +1,97 @@
+// Weights moves by how far the book continues after them, so that choices made by weight favor
+// lines the book knows how to go on with over popular moves whose lines soon run out.
+
+use super::*;
+
+type Memo = PositionMap<Option<usize>>;
+
+impl BookMap {
+    // The number of plies in the longest line of book moves from pos. Positions currently being
+    // searched count as leaves to break cycles.
+    fn coverage_depth(&self, pos: &Chess, memo: &mut Memo) -> usize {
+        let hash = self.hash(pos);
+
+        if let Some(depth) = memo.get(&hash) {
+            return depth.unwrap_or(0);
+        }
+
+        let Some(entries) = self.map.get(&hash) else {
+            return 0;
+        };
+        memo.insert(hash, None);
+
+        let depth = entries
+            .iter()
+            .filter_map(|entry| {
+                let mov = from_book_move(entry.mov).to_move(pos).ok()?;
+                Some(1 + self.coverage_depth(&pos.clone().play(&mov).unwrap(), memo))
+            })
+            .max()
+            .unwrap_or(0);
+
+        memo.insert(hash, Some(depth));
+        depth
+    }
+
+    /// Scales the weight of each move reachable from the root by ((1 + d) / (1 + m))^exponent,
+    /// where d is the length in plies of the longest line of book moves it starts and m the
+    /// longest of its position, so that the move leading deepest into the book keeps its weight
+    /// and moves whose lines end sooner lose weight. Weights stay at least 1 unless they were 0.
+    pub fn prefer_deep_lines(&mut self, exponent: f64) {
+        let mut memo = Memo::default();
+        let mut scales = Vec::new();
+        let tree = self.to_tree();
+
+        for node in (0..tree.len()).map(|i| tree.node(i)) {
+            let depths = node
+                .children
+                .iter()
+                .map(|(entry, _)| {
+                    let mov = from_book_move(entry.mov).to_move(&node.position).unwrap();
+                    let child = node.position.clone().play(&mov).unwrap();
+                    1 + self.coverage_depth(&child, &mut memo)
+                })
+                .collect::<Vec<_>>();
+            let deepest = depths.iter().copied().max().unwrap_or(0);
+
+            for ((entry, _), depth) in node.children.iter().zip(depths) {
+                let scale = ((1 + depth) as f64 / (1 + deepest) as f64).powf(exponent);
+                scales.push((node.hash, entry.mov, scale));
+            }
+        }
+
+        for (hash, mov, scale) in scales {
+            let Some(entry) = self
+                .map
+                .get_mut(&hash)
+                .and_then(|entries| entries.iter_mut().find(|e| e.mov == mov))
+            else {
+                continue;
+            };
+
+            if entry.weight > 0 {
+                entry.weight = ((entry.weight as f64 * scale).round() as u64).max(1);
+            }
+        }
+    }
+}
+
+#[test]
+fn t_prefer_deep_lines() {
+    // e4 is more popular, but its line ends after one reply while d4 goes on for four plies
+    let mut book = BookMap::read_txt(
+        &mut "100 e4, e5\n50 d4, d5, c4, e6".as_bytes(),
+        MoveNotation::San,
+    );
+    book.prefer_deep_lines(1.0);
+
+    let mut weights = book
+        .entries(&Chess::default())
+        .iter()
+        .map(|e| e.weight)
+        .collect::<Vec<_>>();
+    weights.sort();
+
+    // e4 starts a line of 2 plies and d4 one of 4, so e4 is scaled by 3/5
+    assert_eq!(weights, [50, 60]);
+}
@@ -0,0 +1,120 @@
+// Finds the positions where a book is undecided between its two best moves, for reviewing them and
+// breaking the ties deliberately. Moves are compared by weight, or by eval for books whose learn
+// values hold scores.
+
+use super::txt_books::{fen, numbered_move};
+use super::*;
+
+use std::io::Write;
+
+/// How the two best moves of a position are compared
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Closeness {
+    /// The second highest weight is at least this share of the highest
+    WeightRatio(f64),
+    /// The two highest evals differ by at most this many centipawns
+    EvalDiff(i32),
+}
+
+impl BookMap {
+    /// Writes the positions within plies of the root whose moves weigh at least min_total in
+    /// total and whose two best moves are as close as closeness, with the line reaching them and
+    /// both moves. Returns the number of positions written.
+    pub fn write_critical_report<W: Write>(
+        &self,
+        w: &mut W,
+        closeness: Closeness,
+        plies: usize,
+        min_total: u64,
+    ) -> usize {
+        let mut visited = PositionSet::default();
+        let mut stack = vec![(self.root.clone(), Vec::new())];
+        let mut count = 0;
+
+        while let Some((pos, line)) = stack.pop() {
+            let entries = self.entries(&pos);
+
+            if line.len() >= plies || entries.is_empty() || !visited.insert(self.hash(&pos)) {
+                continue;
+            }
+
+            let mut best = entries.iter().collect::<Vec<_>>();
+            match closeness {
+                Closeness::WeightRatio(_) => best.sort_by_key(|e| Reverse(e.weight)),
+                Closeness::EvalDiff(_) => best.sort_by_key(|e| Reverse(e.eval())),
+            }
+
+            let total = entries.iter().map(|e| e.weight).sum::<u64>();
+            let close = best.len() >= 2
+                && total >= min_total
+                && match closeness {
+                    Closeness::WeightRatio(ratio) => {
+                        best[0].weight > 0 && best[1].weight as f64 >= ratio * best[0].weight as f64
+                    }
+                    Closeness::EvalDiff(diff) => best[0].eval() - best[1].eval() <= diff,
+                };
+
+            let moves = best
+                .iter()
+                .take(2)
+                .filter_map(|e| Some((from_book_move(e.mov).to_move(&pos).ok()?, e)))
+                .collect::<Vec<_>>();
+
+            if close && moves.len() == 2 {
+                let describe = |(mov, entry): &(Move, &&BookEntry)| {
+                    let value = match closeness {
+                        Closeness::WeightRatio(_) => format!("weight {}", entry.weight),
+                        Closeness::EvalDiff(_) => format!("eval {}", entry.eval()),
+                    };
+                    format!("{} ({})", SanPlus::from_move(pos.clone(), mov), value)
+                };
+
+                writeln!(
+                    w,
+                    "{}: {} or {} ({})",
+                    if line.is_empty() {
+                        "root".to_string()
+                    } else {
+                        line.join(" ")
+                    },
+                    describe(&moves[0]),
+                    describe(&moves[1]),
+                    fen(&pos)
+                );
+                count += 1;
+            }
+
+            for entry in entries.iter().rev() {
+                let Ok(mov) = from_book_move(entry.mov).to_move(&pos) else {
+                    continue;
+                };
+
+                let mut line = line.clone();
+                line.push(numbered_move(&pos, &mov, line.is_empty()));
+                stack.push((pos.clone().play(&mov).unwrap(), line));
+            }
+        }
+
+        count
+    }
+}
+
+#[test]
+fn t_critical_report() {
+    let book = BookMap::read_txt(
+        &mut "10 e4\n    6 c5\n    5 e5\n9 d4\n    12 d5\n    2 Nf6".as_bytes(),
+        MoveNotation::San,
+    );
+
+    let mut out = Vec::new();
+    let count = book.write_critical_report(&mut out, Closeness::WeightRatio(0.8), 20, 10);
+    let out = String::from_utf8(out).unwrap();
+
+    assert_eq!(count, 2);
+    assert!(out.starts_with("root: e4 (weight 10) or d4 (weight 9)"));
+    assert!(out.contains("1. e4: c5 (weight 6) or e5 (weight 5)"));
+
+    // An eval difference of 0 only finds positions where both moves have the same learn value
+    let count = book.write_critical_report(&mut Vec::new(), Closeness::EvalDiff(0), 20, 0);
+    assert_eq!(count, 3);
+}
@@ -0,0 +1,72 @@
+// Writes the moves of a book as a csv table, one row per move of each position reachable from the
+// root, for spreadsheets and data tools. Columns are the position's fen, the move in san and uci,
+// its weight, learn value and the learn value as an eval, the average Elo of the players making it
+// with the number of games it averages, and the move's wins, draws and losses. Columns without a
+// value for a move, such as the Elo of moves read from .bin files, are left empty.
+
+use super::txt_books::fen;
+use super::*;
+
+impl BookMap {
+    /// Writes the moves of the positions reachable from the root, returning the number of rows
+    pub fn write_csv<W: Write>(&self, w: &mut W) -> usize {
+        writeln!(
+            w,
+            "fen,san,uci,weight,learn,eval,elo,elo_games,wins,draws,losses"
+        );
+
+        let tree = self.to_tree();
+        let mut count = 0;
+
+        for i in 0..tree.len() {
+            let pos = &tree.node(i).position;
+
+            for (entry, _) in &tree.node(i).children {
+                let Ok(mov) = from_book_move(entry.mov).to_move(pos) else {
+                    continue;
+                };
+                let [wins, draws, losses] = entry.results;
+                let games = wins + draws + losses;
+                let optional = |x: Option<u64>| x.map_or(String::new(), |x| x.to_string());
+
+                writeln!(
+                    w,
+                    "{},{},{},{},{},{},{},{},{},{},{}",
+                    fen(pos),
+                    SanPlus::from_move(pos.clone(), &mov),
+                    UciMove::from_standard(&mov),
+                    entry.weight,
+                    entry.learn,
+                    entry.eval(),
+                    optional(entry.average_elo()),
+                    entry.elo_count,
+                    optional((games > 0).then_some(wins)),
+                    optional((games > 0).then_some(draws)),
+                    optional((games > 0).then_some(losses)),
+                );
+                count += 1;
+            }
+        }
+
+        count
+    }
+}
+
+#[test]
+fn t_write_csv() {
+    let mut book = BookMap::read_txt(&mut "3 e4\n    e5".as_bytes(), MoveNotation::San);
+    let hash = book.hash(&book.root);
+    let entry = book.map.get_mut(&hash).unwrap().first_mut().unwrap();
+    (entry.elo_sum, entry.elo_count, entry.results) = (4000, 2, [1, 1, 0]);
+
+    let mut out = Vec::new();
+    assert_eq!(book.write_csv(&mut out), 2);
+
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines[1],
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1,e4,e2e4,3,0,0,2000,2,1,1,0"
+    );
+    assert!(lines[2].ends_with(",e5,e7e5,1,0,0,,0,,,"));
+}
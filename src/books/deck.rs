@@ -0,0 +1,131 @@
+// Writes flashcards for drilling a repertoire as a csv file that Anki imports, with header lines
+// naming the separator and columns. Each position where the repertoire's side is to move becomes a
+// card showing the line reaching it, the opponent's last move, a diagram and the fen, with the
+// highest weighted book move on the back.
+
+use super::txt_books::{fen, numbered_move};
+use super::*;
+
+use shakmaty::Position;
+
+// The board as rows of unicode pieces, seen from side's end
+fn diagram(pos: &Chess, side: Color) -> String {
+    let mut rows = Vec::new();
+
+    for rank in 0..8u32 {
+        let rank = side.fold_wb(7 - rank, rank);
+
+        let row = (0..8u32)
+            .map(|file| {
+                let file = side.fold_wb(file, 7 - file);
+                let sq = Square::new(rank * 8 + file);
+
+                match pos.board().piece_at(sq) {
+                    Some(piece) => {
+                        let i = "kqrbnp".find(piece.role.char()).unwrap();
+                        let pieces = piece.color.fold_wb("♔♕♖♗♘♙", "♚♛♜♝♞♟");
+                        pieces.chars().nth(i).unwrap()
+                    }
+                    None => '·',
+                }
+            })
+            .collect::<String>();
+
+        rows.push(row);
+    }
+
+    format!("<pre>{}</pre>", rows.join("<br>"))
+}
+
+fn csv_field(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+impl BookMap {
+    /// Writes a card for each position reachable from the root where side is to move and the book
+    /// has a move, following only the highest weighted move at those positions and every move at
+    /// the opponent's. Returns the number of cards written.
+    pub fn write_anki_deck<W: Write>(&self, w: &mut W, side: Color) -> usize {
+        writeln!(w, "#separator:Comma");
+        writeln!(w, "#html:true");
+        writeln!(w, "#columns:Front,Back");
+
+        let mut visited = PositionSet::default();
+        // Each position with the numbered moves reaching it and the last of them
+        let mut stack = vec![(self.root.clone(), Vec::<String>::new(), None)];
+        let mut count = 0;
+
+        while let Some((pos, line, last)) = stack.pop() {
+            if !visited.insert(self.hash(&pos)) {
+                continue;
+            }
+
+            let mut entries = self.entries(&pos).to_vec();
+            entries.sort_unstable_by_key(|e| (Reverse(e.weight), e.mov));
+
+            let moves = entries
+                .iter()
+                .filter_map(|e| from_book_move(e.mov).to_move(&pos).ok())
+                .collect::<Vec<_>>();
+
+            let followed = if pos.turn() == side {
+                let Some(mov) = moves.first() else {
+                    continue;
+                };
+
+                let mut front = Vec::new();
+
+                if let Some(last) = last {
+                    front.push(format!("Line: {}", line.join(" ")));
+                    front.push(format!("Opponent played: {}", last));
+                }
+                front.push(diagram(&pos, side));
+                front.push(format!("FEN: {}", fen(&pos)));
+
+                writeln!(
+                    w,
+                    "{},{}",
+                    csv_field(&front.join("<br>")),
+                    csv_field(&SanPlus::from_move(pos.clone(), mov).to_string())
+                );
+                count += 1;
+
+                &moves[..1]
+            } else {
+                &moves[..]
+            };
+
+            // Pushed in reverse so that the heaviest line is written first
+            for mov in followed.iter().rev() {
+                let mut line = line.clone();
+                line.push(numbered_move(&pos, mov, line.is_empty()));
+
+                let san = SanPlus::from_move(pos.clone(), mov).to_string();
+                stack.push((pos.clone().play(mov).unwrap(), line, Some(san)));
+            }
+        }
+
+        count
+    }
+}
+
+#[test]
+fn t_anki_deck() {
+    let book = BookMap::read_txt(
+        &mut "e4\n    3 c5\n        2 Nf3\n        Nc3\n    e5, Nf3".as_bytes(),
+        MoveNotation::San,
+    );
+
+    let mut out = Vec::new();
+    assert_eq!(book.write_anki_deck(&mut out, Color::White), 3);
+
+    let deck = String::from_utf8(out).unwrap();
+    let cards = deck.lines().skip(3).collect::<Vec<_>>();
+    assert!(cards[0].ends_with(",\"e4\""));
+    assert!(cards[1].starts_with("\"Line: 1. e4 c5<br>Opponent played: c5<br><pre>♜♞♝♛♚♝♞♜<br>"));
+    assert!(cards[1].ends_with(",\"Nf3\""));
+    assert!(cards[2].contains("Opponent played: e5"));
+
+    let mut out = Vec::new();
+    assert_eq!(book.write_anki_deck(&mut out, Color::Black), 1);
+}
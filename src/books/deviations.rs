@@ -0,0 +1,218 @@
+// Finds where games leave a book, for reviewing which parts of a repertoire a player forgets. A
+// game deviates at its first move played from a position with book moves that is not one of them;
+// games reaching a position without book moves stayed in the book to its end.
+
+use super::txt_books::numbered_move;
+use super::*;
+
+use shakmaty::Position;
+
+pub struct Deviation {
+    /// The moves of the game before the deviation, numbered
+    pub line: Vec<String>,
+    pub position: Chess,
+    /// The deviating move
+    pub played: SanPlus,
+}
+
+fn header<'a>(game: &'a PgnGame, key: &str) -> &'a str {
+    game.headers
+        .iter()
+        .find(|x| x.0 == key)
+        .map_or("?", |x| &x.1[..])
+}
+
+impl BookMap {
+    /// The first move of game played from a position in the book that is not a book move
+    pub fn deviation(&self, game: &PgnGame) -> Option<Deviation> {
+        let mut pos = game.start_position();
+        let mut line = Vec::new();
+
+        for san in &game.moves {
+            let mov = san.san.to_move(&pos).ok()?;
+            let entries = self.entries(&pos);
+
+            if entries.is_empty() {
+                return None;
+            }
+
+            if !entries
+                .iter()
+                .any(|e| from_book_move(e.mov).to_move(&pos).is_ok_and(|m| m == mov))
+            {
+                return Some(Deviation {
+                    line,
+                    position: pos,
+                    played: san.clone(),
+                });
+            }
+
+            line.push(numbered_move(&pos, &mov, line.is_empty()));
+            pos.play_unchecked(&mov);
+        }
+
+        None
+    }
+
+    // The book moves at pos from most to least weight, with their shares of its weight
+    fn book_moves(&self, pos: &Chess) -> String {
+        let mut entries = self.entries(pos).to_vec();
+        entries.sort_by_key(|e| Reverse(e.weight));
+        let total = entries.iter().map(|e| e.weight).sum::<u64>().max(1);
+
+        entries
+            .iter()
+            .filter_map(|e| {
+                let mov = from_book_move(e.mov).to_move(pos).ok()?;
+                Some(format!(
+                    "{} {:.1}%",
+                    SanPlus::from_move(pos.clone(), &mov),
+                    e.weight as f64 * 100.0 / total as f64
+                ))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Writes the deviation of each game played by player, whose name is compared ignoring case,
+    /// followed by the positions where the player deviated, most frequent first
+    pub fn write_deviation_report<W: Write>(&self, w: &mut W, games: &[PgnGame], player: &str) {
+        // Deviations by the player at each position, with the number of times each move was played
+        let mut forgotten = PositionMap::<(Deviation, Vec<(String, usize)>)>::default();
+        let (mut count, mut by_player, mut by_opponent) = (0, 0, 0);
+
+        for game in games {
+            let color = if header(game, "White").eq_ignore_ascii_case(player) {
+                Color::White
+            } else if header(game, "Black").eq_ignore_ascii_case(player) {
+                Color::Black
+            } else {
+                continue;
+            };
+            count += 1;
+
+            write!(
+                w,
+                "{} - {}, {}: ",
+                header(game, "White"),
+                header(game, "Black"),
+                header(game, "Date")
+            );
+
+            let Some(deviation) = self.deviation(game) else {
+                writeln!(w, "no deviation");
+                continue;
+            };
+
+            let mov = deviation.played.san.to_move(&deviation.position).unwrap();
+            let by = if deviation.position.turn() == color {
+                by_player += 1;
+                "player"
+            } else {
+                by_opponent += 1;
+                "opponent"
+            };
+
+            writeln!(
+                w,
+                "{} by {}{}{} (book: {})",
+                numbered_move(&deviation.position, &mov, true),
+                by,
+                if deviation.line.is_empty() {
+                    ""
+                } else {
+                    " after "
+                },
+                deviation.line.join(" "),
+                self.book_moves(&deviation.position)
+            );
+
+            if deviation.position.turn() == color {
+                let played = deviation.played.to_string();
+                let (_, moves) = forgotten
+                    .entry(self.hash(&deviation.position))
+                    .or_insert_with(|| (deviation, Vec::new()));
+
+                match moves.iter_mut().find(|x| x.0 == played) {
+                    Some(x) => x.1 += 1,
+                    None => moves.push((played, 1)),
+                }
+            }
+        }
+
+        writeln!(
+            w,
+            "\n{} games, {} deviations by the player, {} by opponents",
+            count, by_player, by_opponent
+        );
+
+        if forgotten.is_empty() {
+            return;
+        }
+
+        let mut forgotten = forgotten.into_values().collect::<Vec<_>>();
+        forgotten.sort_by_key(|(d, moves)| {
+            (
+                Reverse(moves.iter().map(|x| x.1).sum::<usize>()),
+                d.line.clone(),
+            )
+        });
+
+        writeln!(
+            w,
+            "\nPositions where the player leaves the book most often:"
+        );
+
+        for (deviation, mut moves) in forgotten {
+            moves.sort_by_key(|x| Reverse(x.1));
+            let total = moves.iter().map(|x| x.1).sum::<usize>();
+
+            writeln!(
+                w,
+                "    {} {}: {}, played {} (book: {})",
+                total,
+                if total == 1 { "game" } else { "games" },
+                if deviation.line.is_empty() {
+                    "start".to_string()
+                } else {
+                    deviation.line.join(" ")
+                },
+                moves
+                    .iter()
+                    .map(|(mov, n)| format!("{} {}", mov, n))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                self.book_moves(&deviation.position)
+            );
+        }
+    }
+}
+
+#[test]
+fn t_deviations() {
+    let book = BookMap::read_txt(
+        &mut "3 e4\n    3 c5, Nf3\n    e5, Nf3\nd4".as_bytes(),
+        MoveNotation::San,
+    );
+    let pgn = "[White \"Me\"]\n[Black \"You\"]\n\n1. e4 c5 2. Nc3 *\n\n\
+               [White \"You\"]\n[Black \"me\"]\n\n1. e4 d5 *\n\n\
+               [White \"Me\"]\n[Black \"Them\"]\n\n1. e4 c5 2. Nc3 *\n\n\
+               [White \"Me\"]\n[Black \"Them\"]\n\n1. e4 e5 2. Nf3 Nc6 *";
+    let games = read_games(PgnFilter::new(), pgn.as_bytes());
+
+    let deviation = book.deviation(&games[0]).unwrap();
+    assert_eq!(deviation.line, ["1. e4", "c5"]);
+    assert_eq!(deviation.played.to_string(), "Nc3");
+    assert!(book.deviation(&games[3]).is_none());
+
+    let mut out = Vec::new();
+    book.write_deviation_report(&mut out, &games, "me");
+    let report = String::from_utf8(out).unwrap();
+
+    assert!(report.contains("Me - You, ?: 2. Nc3 by player after 1. e4 c5 (book: Nf3 100.0%)"));
+    assert!(
+        report.contains("You - me, ?: 1... d5 by player after 1. e4 (book: c5 75.0%, e5 25.0%)")
+    );
+    assert!(report.contains("4 games, 3 deviations by the player, 0 by opponents"));
+    assert!(report.contains("    2 games: 1. e4 c5, played Nc3 2 (book: Nf3 100.0%)"));
+}
@@ -0,0 +1,187 @@
+// Reports how a book's positions, entries and weight divide between openings. Each position
+// reachable from the root is classified by the table entry of the position itself or else that
+// of the line first reaching it from the root, so transpositions into a named opening count for
+// it, and each entry counts for the opening of the position its move leads to.
+
+use super::*;
+
+use std::io::BufRead;
+
+/// ECO codes and opening names for the positions reached by their moves
+pub struct EcoTable {
+    openings: PositionMap<(String, String)>,
+}
+
+impl EcoTable {
+    /// Reads tab separated lines of ECO code, opening name and the moves reaching the opening in
+    /// san with move numbers, as in the lichess chess-openings files, whose header line is skipped
+    pub fn read<R: BufRead>(reader: R) -> Self {
+        let mut openings = PositionMap::default();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.unwrap_or_else(|_| input(format!("Invalid utf-8 on line {}", i + 1)));
+            let fields = line.trim_end().split('\t').collect::<Vec<_>>();
+
+            if line.trim().is_empty() || (i == 0 && fields[0] == "eco") {
+                continue;
+            }
+
+            let invalid = || -> ! { input(format!("Invalid opening on line {}: {}", i + 1, line)) };
+
+            let [code, name, moves, ..] = fields[..] else {
+                invalid()
+            };
+
+            let mut pos = Chess::default();
+            for word in moves.split_whitespace() {
+                let san = word.rsplit('.').next().unwrap();
+
+                if san.is_empty() {
+                    continue;
+                }
+
+                let mov = parse_move(&pos, san).unwrap_or_else(|| invalid());
+                pos.play_unchecked(&mov);
+            }
+
+            openings
+                .entry(book_hash(pos))
+                .or_insert_with(|| (code.to_string(), name.to_string()));
+        }
+
+        EcoTable { openings }
+    }
+
+    pub fn len(&self) -> usize {
+        self.openings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.openings.is_empty()
+    }
+
+    pub fn get(&self, pos: &Chess) -> Option<&(String, String)> {
+        self.openings.get(&book_hash(pos.clone()))
+    }
+}
+
+#[derive(Default)]
+struct EcoCount {
+    name: String,
+    positions: usize,
+    lines: usize,
+    entries: usize,
+    weight: u64,
+}
+
+impl BookMap {
+    /// Writes the number of lines, positions, entries and weight in each opening of table, most
+    /// weight first. With family set, openings are grouped by the part of their name before any
+    /// colon, as in "Sicilian Defense", instead of by ECO code.
+    pub fn write_eco_report<W: Write>(&self, w: &mut W, table: &EcoTable, family: bool) {
+        let tree = self.to_tree();
+
+        // The opening of each node, going down from the root so that parents are classified first
+        let mut class = vec![None; tree.len()];
+        let mut queue = VecDeque::from([tree.root()]);
+        let mut seen = vec![false; tree.len()];
+        seen[tree.root()] = true;
+        let mut order = Vec::new();
+
+        while let Some(index) = queue.pop_front() {
+            let node = tree.node(index);
+            order.push(index);
+
+            if let Some(opening) = table.get(&node.position) {
+                class[index] = Some(opening);
+            }
+
+            for &(_, child) in &node.children {
+                if !seen[child] {
+                    seen[child] = true;
+                    class[child] = class[index];
+                    queue.push_back(child);
+                }
+            }
+        }
+
+        // The key and name each node is counted under, naming codes by their shallowest opening
+        let key = |index: usize| match class[index] {
+            Some((_, name)) if family => (name.split(':').next().unwrap().trim(), ""),
+            Some((code, name)) => (&code[..], &name[..]),
+            None => ("(unclassified)", ""),
+        };
+        let mut counts = HashMap::<&str, EcoCount>::new();
+
+        for index in order {
+            let node = tree.node(index);
+            let (k, name) = key(index);
+
+            let count = counts.entry(k).or_default();
+            if count.positions == 0 {
+                count.name = name.to_string();
+            }
+            count.positions += 1;
+            count.lines += node.children.is_empty() as usize;
+
+            // Moves count for the opening they lead to
+            for (entry, child) in &node.children {
+                let count = counts.entry(key(*child).0).or_default();
+                count.entries += 1;
+                count.weight += entry.weight;
+            }
+        }
+
+        let total = counts.values().map(|c| c.weight).sum::<u64>().max(1);
+        let mut counts = counts.into_iter().collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.weight.cmp(&a.1.weight).then_with(|| a.0.cmp(b.0)));
+
+        for (key, count) in counts {
+            writeln!(
+                w,
+                "{}{}{}: {} lines, {} positions, {} entries, {} weight ({:.1}%)",
+                key,
+                if count.name.is_empty() { "" } else { " " },
+                count.name,
+                count.lines,
+                count.positions,
+                count.entries,
+                count.weight,
+                count.weight as f64 * 100.0 / total as f64
+            );
+        }
+    }
+}
+
+#[test]
+fn t_eco_report() {
+    let table = EcoTable::read(
+        "eco\tname\tpgn\n\
+         B20\tSicilian Defense\t1. e4 c5\n\
+         B27\tSicilian Defense: Hyperaccelerated Fianchetto\t1. e4 c5 2. Nf3 g6\n\
+         C20\tKing's Pawn Game\t1. e4 e5\n"
+            .as_bytes(),
+    );
+    assert_eq!(table.len(), 3);
+
+    let book = BookMap::read_txt(
+        &mut "4 e4\n    3 c5\n        Nf3, g6\n        Nc3\n    e5, Nf3".as_bytes(),
+        MoveNotation::San,
+    );
+
+    let report = |family| {
+        let mut out = Vec::new();
+        book.write_eco_report(&mut out, &table, family);
+        String::from_utf8(out).unwrap()
+    };
+
+    let by_code = report(false);
+    assert!(by_code.starts_with("B20 Sicilian Defense: 1 lines, 3 positions, 3 entries, 5 weight"));
+    assert!(by_code.contains("(unclassified): 0 lines, 2 positions, 1 entries, 4 weight"));
+    assert!(by_code.contains("B27 Sicilian Defense: Hyperaccelerated Fianchetto: 1 lines"));
+
+    let by_family = report(true);
+    assert!(
+        by_family.contains("Sicilian Defense: 2 lines, 4 positions, 4 entries, 6 weight (50.0%)")
+    );
+}
@@ -0,0 +1,733 @@
+use super::tree::BookTree;
+use super::*;
+
+use std::io::BufRead;
+
+use shakmaty::Move;
+
+#[derive(Clone, Debug)]
+enum BanScope {
+    Everywhere,
+    /// Only at the position with this hash
+    Position(u64),
+    /// Only for entries whose depth is within this inclusive range
+    Depths(usize, usize),
+}
+
+#[derive(Clone, Debug)]
+enum BanMove {
+    Uci(UciMove),
+    San(San),
+}
+
+#[derive(Clone, Debug)]
+pub struct MoveBan {
+    mov: BanMove,
+    scope: BanScope,
+}
+
+impl MoveBan {
+    /// Parses "[<scope>:]<move>", where the move is in san if san is set or uci otherwise, and
+    /// the scope is a fen or a depth range such as "4-10", "-10" or "4-".
+    pub fn parse(book: &BookMap, value: &str, san: bool) -> Result<Self, String> {
+        let (scope, mov) = match value.rsplit_once(':') {
+            Some((scope, mov)) => (Some(scope.trim()), mov.trim()),
+            None => (None, value.trim()),
+        };
+
+        let mov = if san {
+            BanMove::San(
+                mov.parse::<SanPlus>()
+                    .map_err(|_| format!("Invalid move {:?}", mov))?
+                    .san,
+            )
+        } else {
+            BanMove::Uci(mov.parse().map_err(|_| format!("Invalid move {:?}", mov))?)
+        };
+
+        let scope = match scope {
+            None => BanScope::Everywhere,
+            Some(scope) => match scope.parse::<Fen>() {
+                Ok(fen) => {
+                    let pos = fen
+                        .into_position(Chess960)
+                        .map_err(|_| format!("Invalid position {:?}", scope))?;
+                    BanScope::Position(book.hash(&pos))
+                }
+                Err(_) => {
+                    let (min, max) = scope.split_once('-').unwrap_or((scope, scope));
+                    let bound = |x: &str, default| match x {
+                        "" => Ok(default),
+                        x => x
+                            .parse::<usize>()
+                            .map_err(|_| format!("Invalid fen or depth range {:?}", scope)),
+                    };
+
+                    BanScope::Depths(bound(min, 0)?, bound(max, usize::MAX)?)
+                }
+            },
+        };
+
+        Ok(MoveBan { mov, scope })
+    }
+
+    pub(super) fn matches(&self, hash: u64, pos: &Chess, mov: &Move, depth: Option<usize>) -> bool {
+        let in_scope = match self.scope {
+            BanScope::Everywhere => true,
+            BanScope::Position(h) => h == hash,
+            BanScope::Depths(min, max) => depth.is_some_and(|d| d >= min && d <= max),
+        };
+
+        in_scope
+            && match &self.mov {
+                BanMove::Uci(uci) => uci.to_move(pos).ok().as_ref() == Some(mov),
+                BanMove::San(san) => san.to_move(pos).ok().as_ref() == Some(mov),
+            }
+    }
+
+    /// Whether the ban removes the book move at a position that is not known, as those not
+    /// reachable from the root are. Only unscoped uci moves can be matched without the position,
+    /// with castling matched as the king taking its rook, as castling is stored in books.
+    fn matches_unknown(&self, mov: u16) -> bool {
+        let uci = match (&self.scope, &self.mov) {
+            (BanScope::Everywhere, BanMove::Uci(uci)) => uci,
+            _ => return false,
+        };
+
+        let uci = match uci.to_string().as_str() {
+            "e1g1" => "e1h1".parse().unwrap(),
+            "e1c1" => "e1a1".parse().unwrap(),
+            "e8g8" => "e8h8".parse().unwrap(),
+            "e8c8" => "e8a8".parse().unwrap(),
+            _ => uci.clone(),
+        };
+
+        matches!(uci, UciMove::Normal { .. }) && to_book_move(uci) == mov
+    }
+}
+
+/// Parses "<move> [weight=<weight>] [learn=<learn>]" with the move in san or uci for pos
+pub fn parse_entry(pos: &Chess, value: &str) -> Result<(Move, Option<u64>, Option<u32>), String> {
+    let mut words = value.split_whitespace();
+    let word = words.next().unwrap_or("");
+
+    let mov = parse_move(pos, word).ok_or_else(|| format!("Invalid move {:?}", word))?;
+
+    let mut weight = None;
+    let mut learn = None;
+
+    for word in words {
+        match word.split_once('=') {
+            Some(("weight", x)) if x.parse::<u64>().is_ok() => weight = x.parse().ok(),
+            Some(("learn", x)) if x.parse::<u32>().is_ok() => learn = x.parse().ok(),
+            _ => return Err(format!("Invalid parameter {:?}", word)),
+        }
+    }
+
+    Ok((mov, weight, learn))
+}
+
+fn single_value<T: std::str::FromStr>(op: &str, words: &[&str]) -> Result<T, String> {
+    match words {
+        [x] => x.parse().map_err(|_| format!("Invalid value {:?}", x)),
+        _ => Err(format!("Expected one value for {}", op)),
+    }
+}
+
+enum EditOp {
+    Add(Option<u64>, Option<u32>),
+    Remove,
+    SetWeight(u64),
+    SetLearn(u32),
+}
+
+struct BookEdit {
+    line: usize,
+    pos: Chess,
+    mov: Move,
+    op: EditOp,
+}
+
+impl BookEdit {
+    // Parses "<fen or moves from the root>: <operation> <move> <parameters>", returning None for
+    // empty lines
+    fn parse(book: &BookMap, line: usize, text: &str) -> Result<Option<Self>, String> {
+        let text = text[..text.find(';').unwrap_or(text.len())].trim();
+
+        if text.is_empty() {
+            return Ok(None);
+        }
+
+        let (position, edit) = text
+            .rsplit_once(':')
+            .ok_or("Expected <position>: <operation> <move>")?;
+
+        let pos = book.parse_position(position)?;
+
+        let mut words = edit.split_whitespace();
+        let op = words.next().unwrap_or("");
+        let word = words.next().unwrap_or("");
+        let rest = words.collect::<Vec<_>>();
+
+        let mov = parse_move(&pos, word).ok_or_else(|| format!("Invalid move {:?}", word))?;
+
+        let op = match op {
+            "add" => {
+                let (_, weight, learn) =
+                    parse_entry(&pos, &format!("{} {}", word, rest.join(" ")))?;
+                EditOp::Add(weight, learn)
+            }
+            "remove" if rest.is_empty() => EditOp::Remove,
+            "set-weight" => EditOp::SetWeight(single_value(op, &rest)?),
+            "set-learn" => EditOp::SetLearn(single_value(op, &rest)?),
+            _ => return Err(format!("Invalid operation {:?}", edit.trim())),
+        };
+
+        Ok(Some(BookEdit { line, pos, mov, op }))
+    }
+}
+
+impl BookMap {
+    // Parses a position given as a fen or as moves from the root
+    pub(super) fn parse_position(&self, position: &str) -> Result<Chess, String> {
+        match position.trim().parse::<Fen>() {
+            Ok(fen) => fen
+                .into_position(Chess960)
+                .map_err(|_| format!("Invalid position {:?}", position)),
+            Err(_) => {
+                let mut pos = self.root.clone();
+
+                for word in position.split_whitespace() {
+                    let mov = parse_move(&pos, word)
+                        .ok_or_else(|| format!("Invalid move {:?} in {:?}", word, position))?;
+                    pos.play_unchecked(&mov);
+                }
+                Ok(pos)
+            }
+        }
+    }
+
+    /// Applies the edits in an edits file, each line holding "<position>: <operation> <move>"
+    /// followed by any "weight=<weight>" and "learn=<learn>" parameters. Positions are fens, or
+    /// moves from the root. If any line is invalid, the book is left unchanged and the errors
+    /// are returned. Otherwise, the edits that matched nothing in the book are returned.
+    pub fn apply_edits<R: BufRead>(&mut self, reader: R) -> Result<Vec<String>, Vec<String>> {
+        let mut edits = Vec::new();
+        let mut errors = Vec::new();
+
+        for (i, line) in reader.lines().enumerate() {
+            let parsed = line
+                .map_err(|e| e.to_string())
+                .and_then(|line| BookEdit::parse(self, i + 1, &line));
+
+            match parsed {
+                Ok(Some(edit)) => edits.push(edit),
+                Ok(None) => {}
+                Err(e) => errors.push(format!("line {}: {}", i + 1, e)),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        // Positions that can be edited, including those reached by a book move without entries
+        let tree = self.to_tree();
+        let mut known = (0..tree.len())
+            .map(|i| tree.node(i).hash)
+            .chain(self.map.keys().copied())
+            .collect::<PositionSet>();
+
+        let mut unmatched = Vec::new();
+
+        for edit in edits {
+            let hash = self.hash(&edit.pos);
+
+            let matched = known.contains(&hash)
+                && match edit.op {
+                    EditOp::Add(weight, learn) => {
+                        self.add_move(&edit.pos, &edit.mov, weight, learn);
+                        known.insert(self.hash(&edit.pos.clone().play(&edit.mov).unwrap()));
+                        true
+                    }
+                    EditOp::Remove => self.remove_move(&edit.pos, &edit.mov),
+                    EditOp::SetWeight(weight) => self
+                        .entry_mut(&edit.pos, &edit.mov)
+                        .map(|e| e.weight = weight)
+                        .is_some(),
+                    EditOp::SetLearn(learn) => self
+                        .entry_mut(&edit.pos, &edit.mov)
+                        .map(|e| e.learn = learn)
+                        .is_some(),
+                };
+
+            if !matched {
+                unmatched.push(format!("line {}: no matching position or move", edit.line));
+            }
+        }
+
+        Ok(unmatched)
+    }
+
+    /// Removes the entries matching any of bans, along with the positions that could only be
+    /// reached through them. Unscoped uci bans also apply to the positions not reachable from the
+    /// root; the others need the position, so only apply to reachable ones.
+    pub fn exclude_moves(&mut self, bans: &[MoveBan]) {
+        self.set_depths();
+        let tree = self.to_tree();
+        let reachable = (0..tree.len())
+            .map(|i| tree.node(i).hash)
+            .collect::<PositionSet>();
+
+        for (hash, entries) in self.map.iter_mut() {
+            if !reachable.contains(hash) {
+                entries.retain(|entry| !bans.iter().any(|ban| ban.matches_unknown(entry.mov)));
+            }
+        }
+
+        for index in 0..tree.len() {
+            let node = tree.node(index);
+
+            if let Some(entries) = self.map.get_mut(&node.hash) {
+                entries.retain(
+                    |entry| match from_book_move(entry.mov).to_move(&node.position) {
+                        Ok(mov) => !bans
+                            .iter()
+                            .any(|ban| ban.matches(node.hash, &node.position, &mov, entry.depth)),
+                        Err(_) => true,
+                    },
+                );
+            }
+        }
+
+        self.remove_cut_off(&tree);
+    }
+
+    /// Keeps the own highest weighted moves at each position reachable from the root where side
+    /// is to move, and the opponent highest weighted moves where the other side is, removing the
+    /// rest along with the lines only reachable through them. Returns the number of moves removed.
+    pub fn limit_branching(&mut self, side: Color, own: usize, opponent: usize) -> usize {
+        let tree = self.to_tree();
+        let mut count = 0;
+
+        for index in 0..tree.len() {
+            let node = tree.node(index);
+            let max = if node.position.turn() == side {
+                own
+            } else {
+                opponent
+            };
+
+            if let Some(entries) = self.map.get_mut(&node.hash) {
+                let len = entries.len();
+                entries.sort_by_key(|x| u64::MAX - x.weight);
+                entries.truncate(max);
+                count += len - entries.len();
+            }
+        }
+
+        self.remove_cut_off(&tree);
+        count
+    }
+
+    /// Removes the tails of fewer than plies moves below branching positions, where a tail is a
+    /// line from a position reached from a position with several moves that has a single move at
+    /// each position until it ends. The move into the tail is kept, so lines end at the branching
+    /// positions' moves. Returns the number of moves removed.
+    pub fn trim_tails(&mut self, plies: usize) -> usize {
+        let tree = self.to_tree();
+        let before = self.map.values().map(|v| v.len()).sum::<usize>();
+
+        // The number of moves from index to the end of its line, if it has a single move at each
+        // position on the way, none of them reached by transposition
+        let tail = |mut index: usize| {
+            for len in 0..tree.len() {
+                match &tree.node(index).children[..] {
+                    [] => return Some(len),
+                    [(_, child)] if tree.node(*child).parents.len() == 1 => index = *child,
+                    _ => return None,
+                }
+            }
+            None
+        };
+
+        for index in 0..tree.len() {
+            let node = tree.node(index);
+
+            if node.children.len() < 2 {
+                continue;
+            }
+
+            for (_, child) in &node.children {
+                let in_tail = tree.node(*child).parents.len() == 1
+                    && tail(*child).is_some_and(|len| len > 0 && len < plies);
+
+                if in_tail {
+                    self.map.remove(&tree.node(*child).hash);
+                }
+            }
+        }
+
+        self.remove_cut_off(&tree);
+        before - self.map.values().map(|v| v.len()).sum::<usize>()
+    }
+
+    /// The positions reachable from the root where side is to move without book moves, ending
+    /// lines with an opponent move that leaves side out of book. Positions where the game is over
+    /// are not included.
+    pub fn open_leaves(&self, side: Color) -> Vec<Chess> {
+        let tree = self.to_tree();
+
+        (0..tree.len())
+            .map(|i| tree.node(i))
+            .filter(|node| {
+                node.children.is_empty()
+                    && node.position.turn() == side
+                    && !node.position.is_game_over()
+            })
+            .map(|node| node.position.clone())
+            .collect()
+    }
+
+    /// Removes the opponent moves into the open leaves of side, so that every line ends with a
+    /// move of side, along with the lines only reachable through them. Returns the number of
+    /// moves removed.
+    pub fn trim_open_lines(&mut self, side: Color) -> usize {
+        let tree = self.to_tree();
+        let mut count = 0;
+
+        for index in 0..tree.len() {
+            let node = tree.node(index);
+
+            let open = |child: usize| {
+                let child = tree.node(child);
+                child.children.is_empty() && !child.position.is_game_over()
+            };
+
+            if node.position.turn() == side || !node.children.iter().any(|(_, x)| open(*x)) {
+                continue;
+            }
+
+            if let Some(entries) = self.map.get_mut(&node.hash) {
+                let len = entries.len();
+                entries.retain(|e| tree.child(index, e.mov).is_none_or(|x| !open(x)));
+                count += len - entries.len();
+            }
+        }
+
+        self.remove_cut_off(&tree);
+        count
+    }
+
+    /// Removes the moves reachable from the root for which f, given the position, the decoded
+    /// move and the entry's depth, returns false, along with the lines only reachable through
+    /// them. Moves that are not legal in their positions are kept. Returns the number of moves
+    /// removed.
+    pub fn filter_moves<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&Chess, &Move, Option<usize>) -> bool,
+    {
+        self.set_depths();
+        let tree = self.to_tree();
+        let mut count = 0;
+
+        for index in 0..tree.len() {
+            let node = tree.node(index);
+
+            if let Some(entries) = self.map.get_mut(&node.hash) {
+                let len = entries.len();
+                entries.retain(|entry| {
+                    from_book_move(entry.mov)
+                        .to_move(&node.position)
+                        .map_or(true, |mov| f(&node.position, &mov, entry.depth))
+                });
+                count += len - entries.len();
+            }
+        }
+
+        self.remove_cut_off(&tree);
+        count
+    }
+
+    // Removes the positions in tree, a tree of the book made before removing entries, that
+    // can no longer be reached from the root
+    pub(super) fn remove_cut_off(&mut self, tree: &BookTree) {
+        let reachable = self.to_tree();
+        let reachable = (0..reachable.len())
+            .map(|i| reachable.node(i).hash)
+            .collect::<PositionSet>();
+
+        for index in 0..tree.len() {
+            let hash = tree.node(index).hash;
+
+            if !reachable.contains(&hash) {
+                self.map.remove(&hash);
+            }
+        }
+        self.map.retain(|_, entries| !entries.is_empty());
+        self.set_depths();
+    }
+
+    /// Makes mov the move played at pos by giving it the weight of every entry there and
+    /// setting the weights of the others to zero, or with prune, removing the others along with
+    /// the lines only reachable through them. The move is added if it is not in the book.
+    pub fn force_move(&mut self, pos: &Chess, mov: &Move, prune: bool) {
+        let hash = self.hash(pos);
+        let book_move = to_book_move(UciMove::from_chess960(mov));
+        let tree = self.to_tree();
+
+        let entries = self.map.entry(hash).or_default();
+        if !entries.iter().any(|e| e.mov == book_move) {
+            entries.push(BookEntry {
+                mov: book_move,
+                ..BookEntry::new()
+            });
+        }
+
+        let total = entries.iter().map(|e| e.weight).sum::<u64>().max(1);
+
+        for entry in entries.iter_mut() {
+            entry.weight = if entry.mov == book_move { total } else { 0 };
+        }
+
+        if prune {
+            entries.retain(|e| e.mov == book_move);
+            self.remove_cut_off(&tree);
+        } else {
+            self.set_depths();
+        }
+    }
+
+    pub fn entry_mut(&mut self, pos: &Chess, mov: &Move) -> Option<&mut BookEntry> {
+        let hash = self.hash(pos);
+        let book_move = to_book_move(UciMove::from_chess960(mov));
+
+        self.map
+            .get_mut(&hash)?
+            .iter_mut()
+            .find(|e| e.mov == book_move)
+    }
+
+    /// Adds mov at pos with a weight of 1 unless given, or updates the given values if the move
+    /// is already in the book.
+    pub fn add_move(&mut self, pos: &Chess, mov: &Move, weight: Option<u64>, learn: Option<u32>) {
+        if self.entry_mut(pos, mov).is_none() {
+            let entry = BookEntry {
+                mov: to_book_move(UciMove::from_chess960(mov)),
+                weight: 1,
+                ..BookEntry::new()
+            };
+            self.insert(self.hash(pos), entry);
+        }
+
+        let entry = self.entry_mut(pos, mov).unwrap();
+
+        if let Some(weight) = weight {
+            entry.weight = weight;
+        }
+        if let Some(learn) = learn {
+            entry.learn = learn;
+        }
+        self.set_depths();
+    }
+
+    /// Removes mov at pos along with the lines only reachable through it, returning false if
+    /// it was not in the book.
+    pub fn remove_move(&mut self, pos: &Chess, mov: &Move) -> bool {
+        let hash = self.hash(pos);
+        let book_move = to_book_move(UciMove::from_chess960(mov));
+        let tree = self.to_tree();
+
+        let entries = match self.map.get_mut(&hash) {
+            Some(entries) => entries,
+            None => return false,
+        };
+
+        let len = entries.len();
+        entries.retain(|e| e.mov != book_move);
+
+        if entries.len() == len {
+            return false;
+        }
+
+        self.remove_cut_off(&tree);
+        true
+    }
+}
+
+#[test]
+fn t_exclude_moves() {
+    let tree = "e4\n    e5, Nf3, Nc6\n    c5, Nf3\nd4, d5, c4";
+    let read = || BookMap::read_txt(&mut tree.as_bytes(), MoveNotation::San);
+    let count = |book: &BookMap| book.map.values().flatten().count();
+
+    let mut book = read();
+    let ban = MoveBan::parse(&book, "Nf3", true).unwrap();
+    book.exclude_moves(&[ban]);
+    assert_eq!(count(&book), 6);
+
+    let mut book = read();
+    let ban = MoveBan::parse(&book, "e2e4", false).unwrap();
+    book.exclude_moves(&[ban]);
+    assert_eq!(count(&book), 3);
+
+    let mut book = read();
+    let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+    let bans = [
+        MoveBan::parse(&book, &format!("{}:c5", fen), true).unwrap(),
+        MoveBan::parse(&book, "2-:c4", true).unwrap(),
+    ];
+    book.exclude_moves(&bans);
+    assert_eq!(count(&book), 6);
+
+    assert!(MoveBan::parse(&book, "x-y:e4", true).is_err());
+
+    // Positions not reachable from the root only have their moves matched by unscoped uci bans
+    let mut book = read();
+    let hash = book.hash(&fen_to_chess("4k3/8/8/8/8/8/8/4K2R w K - 0 1"));
+    for uci in ["e1h1", "e1f1", "h1h2", "h1h3"] {
+        let entry = BookEntry {
+            mov: to_book_move(uci.parse().unwrap()),
+            ..BookEntry::new()
+        };
+        book.insert(hash, entry);
+    }
+
+    let bans = [
+        MoveBan::parse(&book, "e1g1", false).unwrap(),
+        MoveBan::parse(&book, "Kf1", true).unwrap(),
+        MoveBan::parse(&book, "h1h2", false).unwrap(),
+        MoveBan::parse(&book, "-4:h1h3", false).unwrap(),
+    ];
+    book.exclude_moves(&bans);
+    assert_eq!(count(&book), 11);
+    assert_eq!(book.map[&hash].len(), 2);
+}
+
+#[test]
+fn t_force_move() {
+    let tree = "4 e4\n    2 e5, Nf3\n    c5\n2 d4";
+    let mut book = BookMap::read_txt(&mut tree.as_bytes(), MoveNotation::San);
+
+    let pos = Chess::default();
+    let mov = "d4".parse::<San>().unwrap().to_move(&pos).unwrap();
+    book.force_move(&pos, &mov, false);
+
+    let weights = book.map[&START_HASH]
+        .iter()
+        .map(|e| e.weight)
+        .collect::<Vec<_>>();
+    assert_eq!(weights, vec![0, 6]);
+
+    let mov = "c4".parse::<San>().unwrap().to_move(&pos).unwrap();
+    book.force_move(&pos, &mov, true);
+    assert_eq!(book.map.values().flatten().count(), 1);
+}
+
+#[test]
+fn t_add_remove_move() {
+    let mut book = BookMap::read_txt(&mut "e4, e5, Nf3\nd4".as_bytes(), MoveNotation::San);
+    let pos = Chess::default();
+    let e4 = "e4".parse::<San>().unwrap().to_move(&pos).unwrap();
+    let c4 = "c4".parse::<San>().unwrap().to_move(&pos).unwrap();
+
+    book.add_move(&pos, &c4, Some(40), None);
+    book.add_move(&pos, &e4, None, Some(7));
+    assert_eq!(book.entry_mut(&pos, &c4).unwrap().weight, 40);
+    assert_eq!(book.entry_mut(&pos, &e4).unwrap().learn, 7);
+
+    assert!(book.remove_move(&pos, &e4));
+    assert!(!book.remove_move(&pos, &e4));
+    assert_eq!(book.map.values().flatten().count(), 2);
+}
+
+#[test]
+fn t_apply_edits() {
+    let mut book = BookMap::read_txt(&mut "e4, e5, Nf3\nd4".as_bytes(), MoveNotation::San);
+
+    let edits = "e4 e5: add Nc3 weight=3\n: set-weight d4 5 ; comment\n\ne4: remove c5";
+    let unmatched = book.apply_edits(edits.as_bytes()).unwrap();
+    assert_eq!(unmatched.len(), 1);
+    assert_eq!(book.map.values().flatten().count(), 5);
+
+    // Nothing is applied when a line is invalid
+    let errors = book
+        .apply_edits(": remove e4\nd4: add Ke8".as_bytes())
+        .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(book.map.values().flatten().count(), 5);
+}
+
+#[test]
+fn t_limit_branching() {
+    let mut book = BookMap::read_txt(
+        &mut "5 e4\n    3 c5\n        2 Nf3, d6\n        Nc3, Nc6\n    2 e5\n    e6\n2 d4, d5"
+            .as_bytes(),
+        MoveNotation::San,
+    );
+
+    // White keeps e4 and Nf3, black keeps its two most played replies to e4
+    let count = book.limit_branching(Color::White, 1, 2);
+    assert_eq!(count, 3);
+    assert_eq!(book.map.values().flatten().count(), 5);
+    assert_eq!(book.entries(&Chess::default()).len(), 1);
+}
+
+#[test]
+fn t_trim_tails() {
+    let mut book = BookMap::read_txt(
+        &mut "e4\n    c5, Nf3, d6\n    e5, Nf3, Nc6, Bb5, a6, Ba4\nd4, d5".as_bytes(),
+        MoveNotation::San,
+    );
+
+    // The two moves after 1. e4 c5 and the one after 1. d4 go, while the four after 1. e4 e5 stay
+    assert_eq!(book.trim_tails(3), 3);
+    assert_eq!(book.map.values().flatten().count(), 9);
+    assert!(book.map.values().flatten().all(|e| e.depth.is_some()));
+}
+
+#[test]
+fn t_trim_open_lines() {
+    let mut book = BookMap::read_txt(
+        &mut "e4, e5, Nf3\nd4\n    d5\n    Nf6".as_bytes(),
+        MoveNotation::San,
+    );
+
+    assert_eq!(book.open_leaves(Color::White).len(), 2);
+    assert_eq!(book.open_leaves(Color::Black).len(), 1);
+
+    // Both replies to 1. d4 go, leaving white's line ending with 1. d4
+    assert_eq!(book.trim_open_lines(Color::White), 2);
+    assert!(book.open_leaves(Color::White).is_empty());
+    assert_eq!(book.map.values().flatten().count(), 4);
+}
+
+#[test]
+fn t_filter_moves() {
+    let mut book = BookMap::read_txt(
+        &mut "e4, e5, Qh5, Nc6, Qxf7+\nd4, d5, c4, dxc4".as_bytes(),
+        MoveNotation::San,
+    );
+
+    // 2. Qh5 goes with the line after it, then 2... dxc4
+    let queen = |_: &Chess, mov: &Move, depth: Option<usize>| {
+        mov.role() != Role::Queen || depth.is_none_or(|d| d >= 4)
+    };
+    assert_eq!(book.filter_moves(queen), 1);
+    assert_eq!(book.map.values().flatten().count(), 6);
+    assert_eq!(
+        book.filter_moves(|_, mov, depth| !mov.is_capture() || depth >= Some(4)),
+        1
+    );
+    assert_eq!(book.map.values().flatten().count(), 5);
+
+    let mut book = BookMap::read_txt(
+        &mut "8/P6k/8/8/8/8/8/K7 w - - 0 1\na8=N\na8=Q".as_bytes(),
+        MoveNotation::San,
+    );
+    assert_eq!(
+        book.filter_moves(|_, mov, _| mov.promotion() != Some(Role::Knight)),
+        1
+    );
+    assert_eq!(book.map.values().flatten().count(), 1);
+}
@@ -0,0 +1,131 @@
+// Reads EPD files, such as test suites and hand-curated position lists, as books. Each position
+// gets an entry of weight 1 for each of its bm moves, holding its ce eval as the learn value, so
+// positions listed several times add up. Other opcodes are ignored, as are positions without bm.
+
+use super::*;
+
+use std::io::BufRead;
+use std::mem::take;
+
+use shakmaty::{fen::Epd, CastlingMode};
+
+// The operations after the position, each as its opcode followed by its operands, with quotes
+// removed from string operands
+fn operations(s: &str) -> Vec<Vec<String>> {
+    let mut out = Vec::new();
+    let mut operation = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+
+    for c in s.chars().chain([';']) {
+        match c {
+            '"' => quoted = !quoted,
+            ';' | ' ' | '\t' if !quoted => {
+                if !token.is_empty() {
+                    operation.push(take(&mut token));
+                }
+                if c == ';' && !operation.is_empty() {
+                    out.push(take(&mut operation));
+                }
+            }
+            _ => token.push(c),
+        }
+    }
+
+    out
+}
+
+impl BookMap {
+    pub fn read_epd<R: BufRead>(reader: R) -> Self {
+        let mut out = BookMap::new();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.unwrap_or_else(|_| input(format!("Invalid utf-8 on line {}", i + 1)));
+            let invalid = |what: &str| -> ! {
+                input(format!(
+                    "Invalid {} on line {}: {}",
+                    what,
+                    i + 1,
+                    line.trim()
+                ))
+            };
+
+            let mut rest = line.trim();
+
+            if rest.is_empty() {
+                continue;
+            }
+
+            // The board, side to move, castling rights and en passant square
+            let mut fields = Vec::new();
+            for _ in 0..4 {
+                let (field, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                fields.push(field);
+                rest = tail.trim_start();
+            }
+
+            let pos: Chess = fields
+                .join(" ")
+                .parse::<Epd>()
+                .ok()
+                .and_then(|epd| epd.into_position(CastlingMode::Chess960).ok())
+                .unwrap_or_else(|| invalid("position"));
+
+            let operations = operations(rest);
+            let operands = |opcode: &str| {
+                operations
+                    .iter()
+                    .find(|x| x[0] == opcode)
+                    .map_or(&[][..], |x| &x[1..])
+            };
+
+            let learn = match operands("ce") {
+                [] => 0,
+                [ce] => ce.parse::<i32>().unwrap_or_else(|_| invalid("ce")),
+                _ => invalid("ce"),
+            };
+
+            let hash = out.hash(&pos);
+
+            for word in operands("bm") {
+                let mov = parse_move(&pos, word).unwrap_or_else(|| invalid("bm move"));
+
+                out.insert_combine(
+                    hash,
+                    BookEntry {
+                        mov: to_book_move(UciMove::from_chess960(&mov)),
+                        weight: 1,
+                        learn: learn as u32,
+                        ..BookEntry::new()
+                    },
+                );
+            }
+        }
+
+        out
+    }
+}
+
+#[test]
+fn t_read_epd() {
+    let epd = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - bm e4 d4; ce 20;\n\
+               \n\
+               rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - bm c5; ce -15;\n\
+               rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - bm e2e4;\n\
+               8/8/8/8/8/8/8/K6k w - - id \"no moves\";";
+    let book = BookMap::read_epd(epd.as_bytes());
+
+    let root = book.entries(&Chess::default());
+    assert_eq!(root.len(), 2);
+    let e4 = root
+        .iter()
+        .find(|e| from_book_move(e.mov).to_string() == "e2e4")
+        .unwrap();
+    assert_eq!((e4.weight, e4.eval()), (2, 20));
+
+    let pos = fen_to_chess("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+    assert_eq!(book.entries(&pos)[0].eval(), -15);
+    assert_eq!(book.len(), 2);
+
+    assert_eq!(operations("bm Nf3; id \"x;y\";")[1], ["id", "x;y"]);
+}
@@ -0,0 +1,155 @@
+// Static exchange evaluation, for cleaning books built from weak games of moves that leave a piece
+// en prise. The exchange on the square a move lands on is played out with the least valuable
+// attacker first, each side being free to stop capturing, ignoring pins and checks.
+
+use super::*;
+
+use shakmaty::{Bitboard, Move};
+
+fn value(role: Role) -> i32 {
+    match role {
+        Role::Pawn => 1,
+        Role::Knight | Role::Bishop => 3,
+        Role::Rook => 5,
+        Role::Queen => 9,
+        Role::King => 0,
+    }
+}
+
+// The least valuable piece of color attacking sq on the board with only the occupied squares
+fn least_valuable_attacker(
+    pos: &Chess,
+    sq: Square,
+    color: Color,
+    occupied: Bitboard,
+) -> Option<(Square, Role)> {
+    let board = pos.board();
+    let attackers = board.attacks_to(sq, color, occupied) & occupied;
+
+    attackers
+        .into_iter()
+        .map(|x| (x, board.role_at(x).unwrap()))
+        .min_by_key(|&(_, role)| (role == Role::King, value(role)))
+}
+
+/// The material won by playing mov at pos, in pawns, after the exchange it starts on the square it
+/// moves to. Negative values mean the move loses material.
+pub fn static_exchange(pos: &Chess, mov: &Move) -> i32 {
+    let (Some(from), false) = (mov.from(), mov.is_castle()) else {
+        return 0;
+    };
+    let to = mov.to();
+    let board = pos.board();
+
+    let mut occupied = board.occupied() ^ from;
+    if mov.is_en_passant() {
+        occupied ^= Square::from_coords(to.file(), from.rank());
+    }
+
+    // The gain of each capture for the side making it, if the exchange ended after it
+    let mut gains = vec![mov.capture().map_or(0, value)];
+    let promotion = mov.promotion().map_or(0, |role| value(role) - 1);
+    gains[0] += promotion;
+
+    let mut on_square = mov.promotion().unwrap_or(mov.role());
+    let mut side = !pos.turn();
+
+    while let Some((sq, role)) = least_valuable_attacker(pos, to, side, occupied) {
+        // Kings may only capture undefended pieces
+        if role == Role::King && least_valuable_attacker(pos, to, !side, occupied ^ sq).is_some() {
+            break;
+        }
+
+        gains.push(value(on_square) - gains.last().unwrap());
+        on_square = role;
+        occupied ^= sq;
+        side = !side;
+    }
+
+    // Each side stops capturing when continuing would gain less
+    while gains.len() > 1 {
+        let gain = gains.pop().unwrap();
+        let last = gains.last_mut().unwrap();
+        *last = -(-*last).max(gain);
+    }
+
+    gains[0]
+}
+
+impl BookMap {
+    /// Removes the moves reachable from the root that lose material by static exchange evaluation,
+    /// unless they match one of exceptions, along with the lines only reachable through them.
+    /// Returns the number of moves removed.
+    pub fn drop_hanging(&mut self, exceptions: &[MoveBan]) -> usize {
+        self.set_depths();
+        let tree = self.to_tree();
+        let mut count = 0;
+
+        for index in 0..tree.len() {
+            let node = tree.node(index);
+
+            if let Some(entries) = self.map.get_mut(&node.hash) {
+                let len = entries.len();
+
+                entries.retain(
+                    |entry| match from_book_move(entry.mov).to_move(&node.position) {
+                        Ok(mov) => {
+                            static_exchange(&node.position, &mov) >= 0
+                                || exceptions.iter().any(|exception| {
+                                    exception.matches(node.hash, &node.position, &mov, entry.depth)
+                                })
+                        }
+                        Err(_) => true,
+                    },
+                );
+
+                count += len - entries.len();
+            }
+        }
+
+        self.remove_cut_off(&tree);
+        count
+    }
+}
+
+#[test]
+fn t_static_exchange() {
+    let see = |fen: &str, san: &str| {
+        let pos = fen_to_chess(fen);
+        let mov = san.parse::<San>().unwrap().to_move(&pos).unwrap();
+        static_exchange(&pos, &mov)
+    };
+
+    // The king's gambit pawn is taken for free, while trading it for the e pawn is even
+    let kings_gambit = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2";
+    assert_eq!(see(kings_gambit, "f4"), -1);
+    assert_eq!(see(kings_gambit, "d4"), 0);
+    assert_eq!(see(kings_gambit, "Nf3"), 0);
+    // A knight defended by a pawn but attacked by one
+    assert_eq!(
+        see(
+            "rnbqkbnr/ppp1pppp/8/8/1p6/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "Nc3"
+        ),
+        -2
+    );
+    // Queen takes a pawn defended by a pawn
+    assert_eq!(see("4k3/8/2p5/3p4/8/8/8/3QK3 w - - 0 1", "Qxd5"), -8);
+    // Rook takes a pawn defended only by the king, with a second rook behind it
+    assert_eq!(see("8/8/3k4/3p4/8/8/3R4/3RK3 w - - 0 1", "Rxd5+"), 1);
+
+    let read = || {
+        BookMap::read_txt(
+            &mut "e4, e5\n    f4, exf4\n    Nf3, Nc6".as_bytes(),
+            MoveNotation::San,
+        )
+    };
+
+    let mut book = read();
+    assert_eq!(book.drop_hanging(&[]), 1);
+    assert_eq!(book.len(), 4);
+
+    let mut book = read();
+    let exception = MoveBan::parse(&book, "f4", true).unwrap();
+    assert_eq!(book.drop_hanging(&[exception]), 0);
+}
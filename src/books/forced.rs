@@ -0,0 +1,158 @@
+// Extracts the stretches of a book without choices, the parts of a repertoire to memorize exactly.
+// A forced line starts at the root, after a move from a position with several moves, or at a
+// position reached by transposition, and follows the only book move of each position until one
+// has several moves, none, or a move into a transposition.
+
+use super::txt_books::numbered_move;
+use super::*;
+
+/// A line of a book with a single book move at each position
+pub struct ForcedLine {
+    /// The moves from the root reaching the start of the line, with move numbers
+    pub entry: Vec<String>,
+    pub start: Chess,
+    pub moves: Vec<SanPlus>,
+    /// The book moves of the position ending the line, in san
+    pub continuations: Vec<String>,
+}
+
+impl BookMap {
+    /// Finds the forced lines of the book with at least min_moves moves of side, nearest the root
+    /// first
+    pub fn forced_lines(&self, side: Color, min_moves: usize) -> Vec<ForcedLine> {
+        let tree = self.to_tree();
+
+        // The parent and book move of each node on a shortest line from the root, in the order
+        // the nodes are reached
+        let mut from = vec![None; tree.len()];
+        let mut order = vec![tree.root()];
+        let mut seen = vec![false; tree.len()];
+        seen[tree.root()] = true;
+
+        let mut i = 0;
+        while let Some(&index) = order.get(i) {
+            for (entry, child) in &tree.node(index).children {
+                if !seen[*child] {
+                    seen[*child] = true;
+                    from[*child] = Some((index, entry.mov));
+                    order.push(*child);
+                }
+            }
+            i += 1;
+        }
+
+        let san = |pos: &Chess, mov: u16| {
+            let mov = from_book_move(mov).to_move(pos).ok()?;
+            Some((SanPlus::from_move(pos.clone(), &mov), mov))
+        };
+        let mut out = Vec::new();
+
+        for &start in &order {
+            let node = tree.node(start);
+            let entry_point = match &node.parents[..] {
+                [parent] => tree.node(*parent).children.len() >= 2 || start == tree.root(),
+                _ => true,
+            };
+
+            if !entry_point {
+                continue;
+            }
+
+            let mut index = start;
+            let mut moves = Vec::new();
+            let mut own = 0;
+
+            for _ in 0..tree.len() {
+                let node = tree.node(index);
+
+                let [(entry, child)] = &node.children[..] else {
+                    break;
+                };
+                let Some((san, _)) = san(&node.position, entry.mov) else {
+                    break;
+                };
+                if tree.node(*child).parents.len() != 1 || *child == start {
+                    break;
+                }
+
+                own += (node.position.turn() == side) as usize;
+                moves.push(san);
+                index = *child;
+            }
+
+            if moves.is_empty() || own < min_moves {
+                continue;
+            }
+
+            let mut entry = Vec::new();
+            let mut at = start;
+            while let Some((parent, mov)) = from[at] {
+                entry.push((parent, mov));
+                at = parent;
+            }
+            let entry = entry
+                .iter()
+                .rev()
+                .enumerate()
+                .filter_map(|(i, (parent, mov))| {
+                    let pos = &tree.node(*parent).position;
+                    let (_, mov) = san(pos, *mov)?;
+                    Some(numbered_move(pos, &mov, i == 0))
+                })
+                .collect();
+
+            let end = tree.node(index);
+            out.push(ForcedLine {
+                entry,
+                start: node.position.clone(),
+                moves,
+                continuations: end
+                    .children
+                    .iter()
+                    .filter_map(|(e, _)| Some(san(&end.position, e.mov)?.0.to_string()))
+                    .collect(),
+            });
+        }
+
+        out
+    }
+}
+
+#[test]
+fn t_forced_lines() {
+    let book = BookMap::read_txt(
+        &mut "e4\n    c5, Nf3, d6, d4, cxd4, Nxd4\n    e5, Nf3\n        Nc6, Bb5\n        Nf6, Nxe5"
+            .as_bytes(),
+        MoveNotation::San,
+    );
+
+    let lines = book.forced_lines(Color::White, 2);
+    let found = lines
+        .iter()
+        .map(|x| {
+            let moves = x.moves.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+            (
+                x.entry.join(" "),
+                moves.join(" "),
+                x.continuations.join(" "),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // The other lines have a single white move, such as 1. e4 before black branches
+    assert_eq!(found.len(), 1);
+    assert_eq!(
+        found[0],
+        (
+            "1. e4 c5".to_string(),
+            "Nf3 d6 d4 cxd4 Nxd4".to_string(),
+            String::new()
+        )
+    );
+
+    let lines = book.forced_lines(Color::White, 1);
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].entry.is_empty());
+    assert_eq!(lines[0].continuations, ["c5", "e5"]);
+    assert_eq!(book.forced_lines(Color::Black, 1).len(), 1);
+}
@@ -0,0 +1,209 @@
+// Finds the popular opponent moves a repertoire has no answer to, the gaps that leave it out of
+// book in practice. How often opponents play each move comes from the lichess opening explorer or
+// from a book built from reference games, and gaps can be filled as they are found, such as with
+// an engine's best move, so that the lines after the answers are checked too.
+
+use super::txt_books::{fen, numbered_move};
+use super::*;
+
+use std::io::Write;
+
+/// A popular opponent move leading to a position without book moves for the repertoire
+pub struct Gap {
+    /// The moves from the root reaching the position the opponent moves in, with move numbers
+    pub line: Vec<String>,
+    pub position: Chess,
+    /// The opponent move in san, its games and its share of the games of the position
+    pub reply: String,
+    pub games: u64,
+    pub share: f64,
+    /// Whether an answer was added to the book
+    pub filled: bool,
+}
+
+impl BookMap {
+    /// The moves of pos as explorer moves, taking the weights of the book as numbers of games,
+    /// for books built from reference games by frequency
+    pub fn reference_moves(&self, pos: &Chess) -> Vec<MasterMove> {
+        let mut out = self
+            .entries(pos)
+            .iter()
+            .filter_map(|entry| {
+                let mov = from_book_move(entry.mov).to_move(pos).ok()?;
+                let [wins, draws, losses] = entry.results;
+                let games = wins + draws + losses;
+
+                Some(MasterMove {
+                    mov: entry.mov,
+                    san: SanPlus::from_move(pos.clone(), &mov).to_string(),
+                    games: entry.weight,
+                    score: (wins as f64 + draws as f64 / 2.0) / games.max(1) as f64,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        out.sort_by_key(|x| Reverse(x.games));
+        out
+    }
+
+    /// Finds the opponent moves of side's repertoire within plies of the root that are played in
+    /// at least min_share of the games of their position, in positions with at least min_games
+    /// games, and lead to positions without book moves. lookup gives the moves played in each
+    /// position the opponent is to move in, and answer the moves to add for the repertoire after
+    /// each gap, if any. When answers are added, so is the opponent move, with its games as its
+    /// weight, unless it is in the book, and the lines after the answers are searched as well.
+    pub fn find_gaps<F, G>(
+        &mut self,
+        side: Color,
+        plies: usize,
+        min_share: f64,
+        min_games: u64,
+        mut lookup: F,
+        mut answer: G,
+    ) -> Vec<Gap>
+    where
+        F: FnMut(&Chess) -> Vec<MasterMove>,
+        G: FnMut(&Chess) -> Vec<BookEntry>,
+    {
+        let mut visited = PositionSet::default();
+        let mut stack = vec![(self.root.clone(), Vec::new())];
+        let mut out = Vec::new();
+
+        while let Some((pos, line)) = stack.pop() {
+            if line.len() >= plies || pos.is_game_over() || !visited.insert(self.hash(&pos)) {
+                continue;
+            }
+
+            if pos.turn() != side {
+                let moves = lookup(&pos);
+                let total = moves.iter().map(|x| x.games).sum::<u64>();
+
+                for reply in moves.iter().filter(|_| total >= min_games.max(1)) {
+                    let share = reply.games as f64 / total as f64;
+                    let Ok(mov) = from_book_move(reply.mov).to_move(&pos) else {
+                        continue;
+                    };
+                    let next = pos.clone().play(&mov).unwrap();
+
+                    if share < min_share || !self.entries(&next).is_empty() || next.is_game_over() {
+                        continue;
+                    }
+
+                    let answers = answer(&next);
+                    let filled = !answers.is_empty();
+
+                    if filled {
+                        let hash = self.hash(&pos);
+
+                        if !self.entries(&pos).iter().any(|e| e.mov == reply.mov) {
+                            self.insert(
+                                hash,
+                                BookEntry {
+                                    mov: reply.mov,
+                                    weight: reply.games,
+                                    ..BookEntry::new()
+                                },
+                            );
+                        }
+                        let next_hash = self.hash(&next);
+
+                        for entry in answers {
+                            self.insert(next_hash, entry);
+                        }
+                    }
+
+                    out.push(Gap {
+                        line: line.clone(),
+                        position: pos.clone(),
+                        reply: reply.san.clone(),
+                        games: reply.games,
+                        share,
+                        filled,
+                    });
+                }
+            }
+
+            for entry in self.entries(&pos).iter().rev() {
+                let Ok(mov) = from_book_move(entry.mov).to_move(&pos) else {
+                    continue;
+                };
+
+                let mut line = line.clone();
+                line.push(numbered_move(&pos, &mov, line.is_empty()));
+                stack.push((pos.clone().play(&mov).unwrap(), line));
+            }
+        }
+
+        if out.iter().any(|x| x.filled) {
+            self.set_depths();
+        }
+        out
+    }
+}
+
+/// Writes each of gaps with the line reaching it, the opponent move and its share, and whether it
+/// was filled
+pub fn write_gaps<W: Write>(w: &mut W, gaps: &[Gap]) {
+    for gap in gaps {
+        writeln!(
+            w,
+            "{}: {} ({:.1}%, {} games){} ({})",
+            if gap.line.is_empty() {
+                "root".to_string()
+            } else {
+                gap.line.join(" ")
+            },
+            gap.reply,
+            gap.share * 100.0,
+            gap.games,
+            if gap.filled { ", filled" } else { "" },
+            fen(&gap.position)
+        );
+    }
+}
+
+#[test]
+fn t_find_gaps() {
+    let mut book = BookMap::read_txt(
+        &mut "e4\n    c5, Nf3\n    e5, Nf3, Nc6, Bb5".as_bytes(),
+        MoveNotation::San,
+    );
+    let reference = [
+        "e4",
+        "    50 c5",
+        "        Nf3",
+        "            30 d6",
+        "            20 Nc6",
+        "    30 e5",
+        "        Nf3",
+        "            40 Nc6",
+        "    15 e6",
+        "    5 d5",
+    ]
+    .join("\n");
+    let reference = BookMap::read_txt(&mut reference.as_bytes(), MoveNotation::San);
+    let lookup = |pos: &Chess| reference.reference_moves(pos);
+
+    // 1... e6 is a gap, while 1... d5 is played in less than a tenth of the games
+    let gaps = book.find_gaps(Color::White, 20, 0.1, 1, lookup, |_| Vec::new());
+    let replies = gaps.iter().map(|x| &x.reply[..]).collect::<Vec<_>>();
+    assert_eq!(replies, ["e6", "d6", "Nc6"]);
+    assert!(gaps.iter().all(|x| !x.filled));
+
+    // Filling each gap with a move adds the reply and the answer, without new gaps
+    let answer = |pos: &Chess| {
+        let mov = pos.legal_moves()[0].clone();
+        vec![BookEntry {
+            mov: to_book_move(UciMove::from_chess960(&mov)),
+            weight: 1,
+            ..BookEntry::new()
+        }]
+    };
+    let before = book.map.values().flatten().count();
+    let gaps = book.find_gaps(Color::White, 20, 0.1, 1, lookup, answer);
+    assert_eq!(gaps.len(), 3);
+    assert_eq!(book.map.values().flatten().count(), before + 6);
+    assert!(book
+        .find_gaps(Color::White, 20, 0.1, 1, lookup, |_| Vec::new())
+        .is_empty());
+}
@@ -0,0 +1,142 @@
+// Writes a book as a LaTeX document for printing as a repertoire, using the xskak package. Each
+// line of the tree format becomes a paragraph indented by its level, holding a game started from
+// the position the line branches from, so that skak checks and typesets its moves.
+
+use super::txt_books::{fen, numbered_move};
+use super::*;
+
+use shakmaty::Position;
+
+// Escapes the characters with special meanings in LaTeX text
+fn escape_latex(s: &str) -> String {
+    let mut out = String::new();
+
+    for c in s.chars() {
+        match c {
+            '\\' => out += "\\textbackslash{}",
+            '~' => out += "\\textasciitilde{}",
+            '^' => out += "\\textasciicircum{}",
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+// The xskak move id of the next move at pos, as the move number and w or b
+fn move_id(pos: &Chess) -> String {
+    format!("{}{}", pos.fullmoves(), pos.turn().fold_wb('w', 'b'))
+}
+
+impl BookMap {
+    /// Writes the book as a LaTeX document using the xskak package, with a paragraph for each
+    /// line indented by its level and a diagram after the moves played at each ply in diagrams
+    pub fn write_latex<W: Write>(&mut self, w: &mut W, diagrams: &[usize]) {
+        writeln!(w, "\\documentclass{{article}}");
+        writeln!(w, "\\usepackage[utf8]{{inputenc}}");
+        writeln!(w, "\\usepackage{{xskak}}");
+        writeln!(w);
+
+        let title = self.metadata.title.clone();
+
+        if let Some(title) = &title {
+            writeln!(w, "\\title{{{}}}", escape_latex(title));
+            writeln!(
+                w,
+                "\\author{{{}}}",
+                escape_latex(self.metadata.author.as_deref().unwrap_or(""))
+            );
+            writeln!(
+                w,
+                "\\date{{{}}}",
+                escape_latex(self.metadata.date.as_deref().unwrap_or(""))
+            );
+        }
+
+        writeln!(w, "\\begin{{document}}");
+
+        if title.is_some() {
+            writeln!(w, "\\maketitle");
+        }
+
+        let mut depths = Vec::new();
+        let mut open = false;
+
+        let close = |w: &mut W, open: &mut bool| {
+            if *open {
+                writeln!(w, "}}\\par}}");
+                *open = false;
+            }
+        };
+
+        self.traverse_tree(true, |depth, pos, entries, ind, _| {
+            let only_child = entries.len() == 1;
+            let mov = from_book_move(entries[ind].mov).to_move(pos).unwrap();
+
+            while depth >= depths.len() {
+                depths.push(0);
+            }
+
+            if depth > 0 {
+                depths[depth] = depths[depth - 1] + !only_child as usize;
+            }
+
+            let first = !only_child || !open;
+
+            if first {
+                close(w, &mut open);
+                writeln!(
+                    w,
+                    "\\newchessgame[setfen={}, moveid={}]",
+                    fen(pos),
+                    move_id(pos)
+                );
+                write!(
+                    w,
+                    "{{\\leftskip={}em\\noindent\\mainline{{",
+                    depths[depth] * 2
+                );
+                open = true;
+            } else {
+                write!(w, " ");
+            }
+
+            write!(w, "{}", numbered_move(pos, &mov, first));
+
+            if diagrams.contains(&(depth + 1)) {
+                close(w, &mut open);
+                writeln!(w, "\\begin{{center}}");
+                writeln!(
+                    w,
+                    "\\chessboard[setfen={}]",
+                    fen(&pos.clone().play(&mov).unwrap())
+                );
+                writeln!(w, "\\end{{center}}");
+            }
+        });
+
+        close(w, &mut open);
+        writeln!(w, "\\end{{document}}");
+    }
+}
+
+#[test]
+fn t_latex() {
+    let mut book = BookMap::read_txt(
+        &mut "; title: Sicilian & co\n3 e4, c5\n    2 Nf3, d6\n    Nc3".as_bytes(),
+        MoveNotation::San,
+    );
+
+    let mut out = Vec::new();
+    book.write_latex(&mut out, &[4]);
+    let latex = String::from_utf8(out).unwrap();
+
+    assert!(latex.contains("\\title{Sicilian \\& co}"));
+    assert!(latex.contains("\\mainline{1. e4 c5}"));
+    assert!(latex.contains("{\\leftskip=2em\\noindent\\mainline{2. Nf3 d6}\\par}\n\\begin{center}"));
+    assert!(latex.contains("\\mainline{2. Nc3}\\par}\n\\end{document}"));
+}
@@ -0,0 +1,173 @@
+// Compares a repertoire with the practice of masters, from the move statistics of the lichess
+// opening explorer's masters database. Book moves of the repertoire's side that masters rarely
+// play in the position are reported as possibly dubious choices.
+
+use super::txt_books::{fen, numbered_move};
+use super::*;
+
+use serde_json::Value;
+
+/// A move of the masters database, with its games and the score of the side playing it
+pub struct MasterMove {
+    pub mov: u16,
+    pub san: String,
+    pub games: u64,
+    pub score: f64,
+}
+
+/// The moves of an explorer response for pos, most played first. Moves that are not legal in pos
+/// are skipped.
+pub fn master_moves(response: &Value, pos: &Chess) -> Vec<MasterMove> {
+    let mut out = response["moves"]
+        .as_array()
+        .map(|x| &x[..])
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|x| {
+            let mov = x["uci"]
+                .as_str()?
+                .parse::<UciMove>()
+                .ok()?
+                .to_move(pos)
+                .ok()?;
+            let [white, draws, black] = ["white", "draws", "black"].map(|k| x[k].as_u64());
+            let (white, draws, black) = (white?, draws?, black?);
+            let games = white + draws + black;
+            let wins = pos.turn().fold_wb(white, black);
+
+            Some(MasterMove {
+                mov: to_book_move(UciMove::from_chess960(&mov)),
+                san: SanPlus::from_move(pos.clone(), &mov).to_string(),
+                games,
+                score: (wins as f64 + draws as f64 / 2.0) / games.max(1) as f64,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    out.sort_by_key(|x| Reverse(x.games));
+    out
+}
+
+impl BookMap {
+    /// Writes the book moves of side within plies of the root that masters play in less than
+    /// min_share of the master games from their position, for positions with at least min_games
+    /// games, along with the most played master move. lookup gives the masters' moves in each
+    /// position of side with book moves. Returns the number of moves written.
+    pub fn write_masters_report<W, F>(
+        &self,
+        w: &mut W,
+        side: Color,
+        plies: usize,
+        min_share: f64,
+        min_games: u64,
+        mut lookup: F,
+    ) -> usize
+    where
+        W: Write,
+        F: FnMut(&Chess) -> Vec<MasterMove>,
+    {
+        let mut visited = PositionSet::default();
+        let mut stack = vec![(self.root.clone(), Vec::new())];
+        let mut count = 0;
+
+        while let Some((pos, line)) = stack.pop() {
+            let entries = self.entries(&pos);
+
+            if line.len() >= plies || entries.is_empty() || !visited.insert(self.hash(&pos)) {
+                continue;
+            }
+
+            if pos.turn() == side {
+                let masters = lookup(&pos);
+                let total = masters.iter().map(|x| x.games).sum::<u64>();
+
+                if total >= min_games.max(1) {
+                    for entry in entries {
+                        let played = masters.iter().find(|x| x.mov == entry.mov);
+                        let games = played.map_or(0, |x| x.games);
+
+                        if (games as f64) >= min_share * total as f64 {
+                            continue;
+                        }
+
+                        let Ok(mov) = from_book_move(entry.mov).to_move(&pos) else {
+                            continue;
+                        };
+
+                        writeln!(
+                            w,
+                            "{}: book {} ({:.1}% of {} games{}), masters {} ({:.1}%, scoring {:.0}%) ({})",
+                            if line.is_empty() {
+                                "root".to_string()
+                            } else {
+                                line.join(" ")
+                            },
+                            SanPlus::from_move(pos.clone(), &mov),
+                            games as f64 * 100.0 / total as f64,
+                            total,
+                            played.map_or(String::new(), |x| format!(
+                                ", scoring {:.0}%",
+                                x.score * 100.0
+                            )),
+                            masters[0].san,
+                            masters[0].games as f64 * 100.0 / total as f64,
+                            masters[0].score * 100.0,
+                            fen(&pos)
+                        );
+                        count += 1;
+                    }
+                }
+            }
+
+            for entry in entries.iter().rev() {
+                let Ok(mov) = from_book_move(entry.mov).to_move(&pos) else {
+                    continue;
+                };
+
+                let mut line = line.clone();
+                line.push(numbered_move(&pos, &mov, line.is_empty()));
+                stack.push((pos.clone().play(&mov).unwrap(), line));
+            }
+        }
+
+        count
+    }
+}
+
+#[test]
+fn t_masters_report() {
+    let book = BookMap::read_txt(
+        &mut "e4\n    c5, Nc3\n    e5, f4".as_bytes(),
+        MoveNotation::San,
+    );
+    let response = |pos: &Chess| -> Value {
+        let moves = match fen(pos).to_string().split(' ').next().unwrap() {
+            "rnbqkbnr/pp1ppppp/8/2p5/4P3/8/PPPP1PPP/RNBQKBNR" => {
+                r#"[{"uci":"g1f3","white":300,"draws":400,"black":300},
+                    {"uci":"b1c3","white":10,"draws":20,"black":20}]"#
+            }
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR" => {
+                r#"[{"uci":"g1f3","white":500,"draws":400,"black":100},
+                    {"uci":"f2f4","white":40,"draws":10,"black":50}]"#
+            }
+            _ => r#"[{"uci":"e2e4","white":2000,"draws":3000,"black":1500}]"#,
+        };
+        serde_json::from_str(&format!("{{\"moves\":{}}}", moves)).unwrap()
+    };
+
+    let masters = master_moves(&response(&Chess::default()), &Chess::default());
+    assert_eq!((masters[0].san.as_str(), masters[0].games), ("e4", 6500));
+    assert!((masters[0].score - 0.5384).abs() < 0.001);
+
+    let mut out = Vec::new();
+    let count = book.write_masters_report(&mut out, Color::White, 10, 0.05, 100, |pos| {
+        master_moves(&response(pos), pos)
+    });
+    let report = String::from_utf8(out).unwrap();
+
+    // Nc3 is played in under 5% of the games after 1. e4 c5, while f4 is played in about 9%
+    assert_eq!(count, 1);
+    assert!(report.starts_with(
+        "1. e4 c5: book Nc3 (4.8% of 1050 games, scoring 40%), masters Nf3 (95.2%, scoring 50%)"
+    ));
+}
@@ -0,0 +1,207 @@
+use super::*;
+use crate::engine::*;
+
+/// Options controlling how grow selects and expands lines
+pub struct GrowOptions {
+    /// Number of lines selected and expanded
+    pub iterations: usize,
+    /// Number of engine moves added when a position is expanded
+    pub multipv: usize,
+    /// Exploration constant of the PUCT score, higher values trying less visited moves more
+    pub cpuct: f64,
+    /// Maximum length of a selected line
+    pub max_plies: usize,
+    /// Number of iterations between calls to the checkpoint callback, or 0 for none
+    pub checkpoint: usize,
+}
+
+impl GrowOptions {
+    pub fn new() -> Self {
+        GrowOptions {
+            iterations: 1000,
+            multipv: 4,
+            cpuct: 1.5,
+            max_plies: 20,
+            checkpoint: 100,
+        }
+    }
+}
+
+// Expected score for the side with the given centipawn advantage
+fn win_probability(eval: i32) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-eval as f64 / 400.0))
+}
+
+impl BookMap {
+    // Picks the entry maximizing the PUCT score, using weights as visit counts and learn values
+    // as evals, with priors proportional to each move's expected score.
+    fn select_entry(entries: &[BookEntry], cpuct: f64) -> u16 {
+        let total = entries.iter().map(|e| e.weight).sum::<u64>() as f64;
+        let prior_sum = entries
+            .iter()
+            .map(|e| win_probability(e.eval()))
+            .sum::<f64>();
+
+        let puct = |entry: &BookEntry| {
+            let q = win_probability(entry.eval());
+            q + cpuct * (q / prior_sum) * total.sqrt() / (1.0 + entry.weight as f64)
+        };
+
+        entries
+            .iter()
+            .max_by(|a, b| puct(a).partial_cmp(&puct(b)).unwrap())
+            .unwrap()
+            .mov
+    }
+
+    fn grow_iteration(&mut self, engine: &mut Engine, options: &GrowOptions) {
+        let mut pos = self.root.clone();
+        let mut path = Vec::new();
+
+        while path.len() < options.max_plies {
+            let hash = self.hash(&pos);
+
+            if let Some(entries) = self.map.get(&hash) {
+                let mov = Self::select_entry(entries, options.cpuct);
+
+                // Moves of colliding or corrupt .bin entries may be illegal, ending the line
+                let Ok(m) = from_book_move(mov).to_move(&pos) else {
+                    break;
+                };
+
+                pos.play_unchecked(&m);
+                path.push((hash, mov, self.hash(&pos)));
+            } else {
+                if !pos.is_game_over() {
+                    for line in engine.analyse(&pos, options.multipv) {
+                        let Ok(mov) = line.mov.to_move(&pos) else {
+                            continue;
+                        };
+                        let mut entry = BookEntry::new();
+
+                        entry.mov = to_book_move(UciMove::from_chess960(&mov));
+                        entry.depth = Some(path.len());
+                        entry.weight = 1;
+                        entry.set_eval(line.score);
+
+                        self.insert(hash, entry);
+                    }
+                }
+                break;
+            }
+        }
+
+        self.backup(&path);
+    }
+
+    // Backs up visit counts and negamax evals along a selected path of (position hash, move,
+    // hash of the position reached) steps from the root
+    fn backup(&mut self, path: &[(u64, u16, u64)]) {
+        for &(hash, mov, child) in path.iter().rev() {
+            let child_eval = self
+                .map
+                .get(&child)
+                .and_then(|entries| entries.iter().map(|e| e.eval()).max());
+
+            let entry = self
+                .map
+                .get_mut(&hash)
+                .unwrap()
+                .iter_mut()
+                .find(|e| e.mov == mov)
+                .unwrap();
+
+            entry.weight += 1;
+            if let Some(eval) = child_eval {
+                entry.set_eval(-eval);
+            }
+        }
+    }
+
+    /// Grows the book from the root with engine-guided selection and expansion, calling
+    /// checkpoint with the book and the number of finished iterations every
+    /// `options.checkpoint` iterations and once more at the end.
+    pub fn grow<F>(&mut self, engine: &mut Engine, options: &GrowOptions, mut checkpoint: F)
+    where
+        F: FnMut(&mut BookMap, usize),
+    {
+        for i in 1..=options.iterations {
+            self.grow_iteration(engine, options);
+
+            if options.checkpoint > 0 && i % options.checkpoint == 0 && i != options.iterations {
+                checkpoint(self, i);
+            }
+        }
+
+        checkpoint(self, options.iterations);
+    }
+}
+
+#[test]
+fn t_select_entry() {
+    let entry = |mov, weight| BookEntry {
+        mov,
+        weight,
+        ..BookEntry::new()
+    };
+
+    let entries = [entry(1, 5), entry(2, 1), entry(3, 3)];
+    assert_eq!(BookMap::select_entry(&entries, 1.5), 2);
+
+    // A much better eval outweighs fewer visits
+    let mut entries = [entry(1, 5), entry(2, 1)];
+    entries[0].set_eval(400);
+    entries[1].set_eval(-400);
+    assert_eq!(BookMap::select_entry(&entries, 1.5), 1);
+}
+
+#[test]
+fn t_backup() {
+    let mut book = BookMap::new();
+    let mut pos = book.root().clone();
+    let mut path = Vec::new();
+
+    // Inserts the moves with their evals at pos, following the first into the path
+    let mut add = |book: &mut BookMap, moves: &[(&str, i32)]| {
+        let hash = book.hash(&pos);
+        let first = parse_move(&pos, moves[0].0).unwrap();
+
+        for &(san, eval) in moves {
+            let mut entry = BookEntry::new();
+            entry.mov = to_book_move(UciMove::from_chess960(&parse_move(&pos, san).unwrap()));
+            entry.weight = 1;
+            entry.set_eval(eval);
+            book.insert(hash, entry);
+        }
+
+        pos.play_unchecked(&first);
+        path.push((
+            hash,
+            to_book_move(UciMove::from_chess960(&first)),
+            book.hash(&pos),
+        ));
+    };
+
+    add(&mut book, &[("e4", 0), ("d4", 0)]);
+    add(&mut book, &[("e5", 0), ("c5", -20)]);
+    add(&mut book, &[("Nf3", 45)]);
+
+    book.backup(&path);
+
+    let entries = |i: usize| {
+        let (hash, mov, _) = path[i];
+        let entries = &book.map[&hash];
+        let selected = entries.iter().find(|e| e.mov == mov).unwrap();
+        let other = entries.iter().find(|e| e.mov != mov);
+        (
+            (selected.weight, selected.eval()),
+            other.map(|e| (e.weight, e.eval())),
+        )
+    };
+
+    // Nf3 leads to a position without moves, so only its weight changes
+    assert_eq!(entries(2), ((2, 45), None));
+    assert_eq!(entries(1), ((2, -45), Some((1, -20))));
+    // e4 takes the negation of the best reply, c5 rather than e5
+    assert_eq!(entries(0), ((2, 20), Some((1, 0))));
+}
@@ -0,0 +1,186 @@
+// Descriptive fields carried by books in the formats with room for them. Trees and blobs store
+// them as "; key: value" comment lines before the first move, json books as a "metadata" object
+// and packed books in their header. Polyglot .bin files have no place for them.
+
+use super::txt_books::fen;
+use super::*;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Keys of the fields that can be set, in the order they are written
+pub const METADATA_KEYS: [&str; 4] = ["title", "author", "date", "source"];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// Creation date, as yyyy-mm-dd when set by rustyglot
+    pub date: Option<String>,
+    /// Description of what the book was built from
+    pub source: Option<String>,
+}
+
+impl Metadata {
+    fn field(&mut self, key: &str) -> Option<&mut Option<String>> {
+        match key {
+            "title" => Some(&mut self.title),
+            "author" => Some(&mut self.author),
+            "date" => Some(&mut self.date),
+            "source" => Some(&mut self.source),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "title" => self.title.as_deref(),
+            "author" => self.author.as_deref(),
+            "date" => self.date.as_deref(),
+            "source" => self.source.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Sets a field, returning false if key is not the name of one
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        match self.field(key) {
+            Some(field) => {
+                *field = Some(value.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        METADATA_KEYS.iter().all(|key| self.get(key).is_none())
+    }
+
+    /// Fills the fields that are not set from other, so merged books keep the metadata of the
+    /// first book that has each field
+    pub fn fill_from(&mut self, other: &Metadata) {
+        for key in METADATA_KEYS {
+            if let (Some(value), Some(field)) = (other.get(key), self.field(key)) {
+                field.get_or_insert_with(|| value.to_string());
+            }
+        }
+    }
+
+    /// Parses a "; key: value" comment line from a tree file, ignoring other comments
+    pub(super) fn parse_comment(&mut self, line: &str) {
+        let Some((key, value)) = line
+            .trim()
+            .strip_prefix(';')
+            .and_then(|x| x.split_once(':'))
+        else {
+            return;
+        };
+
+        self.set(key.trim(), value.trim());
+    }
+}
+
+/// The current date in UTC, as yyyy-mm-dd
+pub fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    date_from_days(secs / 86400)
+}
+
+// Converts days since 1970-01-01 to a date in the proleptic gregorian calendar
+fn date_from_days(days: u64) -> String {
+    // Count from 0000-03-01, so that leap days fall at the end of each year
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + (month <= 2) as u64;
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+impl BookMap {
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+
+    pub fn entry_count(&self) -> usize {
+        self.map.values().map(|v| v.len()).sum()
+    }
+
+    /// The set metadata fields followed by the root fen and number of entries, as written to
+    /// book headers. Empty if no field is set, so books without metadata are written unchanged.
+    pub(super) fn metadata_fields(&self) -> Vec<(&'static str, String)> {
+        if self.metadata.is_empty() {
+            return Vec::new();
+        }
+
+        let mut out = METADATA_KEYS
+            .iter()
+            .filter_map(|key| Some((*key, self.metadata.get(key)?.to_string())))
+            .collect::<Vec<_>>();
+
+        out.push(("root", fen(&self.root).to_string()));
+        out.push(("entries", self.entry_count().to_string()));
+        out
+    }
+
+    pub fn write_info<W: Write>(&self, w: &mut W) {
+        for key in METADATA_KEYS {
+            if let Some(value) = self.metadata.get(key) {
+                writeln!(w, "{}{}: {}", key[..1].to_uppercase(), &key[1..], value);
+            }
+        }
+
+        writeln!(w, "Root: {}", fen(&self.root));
+        writeln!(w, "Positions: {}", self.map.len());
+        writeln!(w, "Entries: {}", self.entry_count());
+    }
+}
+
+#[test]
+fn t_metadata() {
+    assert_eq!(date_from_days(0), "1970-01-01");
+    assert_eq!(date_from_days(19782), "2024-02-29");
+
+    let mut book = BookMap::read_txt(
+        &mut "; title: Sicilian\n; author: Someone\n; a comment\ne4, c5".as_bytes(),
+        MoveNotation::San,
+    );
+    assert_eq!(book.metadata().title.as_deref(), Some("Sicilian"));
+    assert_eq!(book.metadata().author.as_deref(), Some("Someone"));
+    assert_eq!(book.metadata().date, None);
+
+    let mut out = Vec::new();
+    book.write_blob(&mut out);
+    let blob = BookMap::try_read_blob(&mut &out[..]).unwrap();
+    assert_eq!(blob.metadata(), book.metadata());
+
+    let mut out = Vec::new();
+    book.write_packed(&mut out);
+    let packed = BookMap::try_read_packed(&mut &out[..]).unwrap();
+    assert_eq!(packed.metadata(), book.metadata());
+
+    let mut out = Vec::new();
+    book.write_json(&mut out);
+    let json = BookMap::read_json(&mut &out[..]);
+    assert_eq!(json.metadata(), book.metadata());
+
+    let mut other = Metadata::default();
+    other.set("title", "Other");
+    other.set("date", "2024-02-29");
+    book.metadata_mut().fill_from(&other);
+    assert_eq!(book.metadata().title.as_deref(), Some("Sicilian"));
+    assert_eq!(book.metadata().date.as_deref(), Some("2024-02-29"));
+}
@@ -0,0 +1,155 @@
+use super::*;
+
+pub struct BookLine {
+    pub score: i32,
+    pub moves: Vec<SanPlus>,
+}
+
+type Memo = PositionMap<Option<(i32, u16)>>;
+
+impl BookEntry {
+    /// Interprets the learn field as a signed centipawn evaluation of the position after this
+    /// move, from the point of view of the side playing it.
+    pub fn eval(&self) -> i32 {
+        self.learn as i32
+    }
+
+    pub fn set_eval(&mut self, eval: i32) {
+        self.learn = eval as u32;
+    }
+}
+
+impl BookMap {
+    // Returns the minimax score and best move for the side to move at pos, or None if pos is not
+    // in the book. Positions currently being searched are treated as leaves to break cycles.
+    fn negamax(&self, pos: &Chess, memo: &mut Memo) -> Option<(i32, u16)> {
+        let hash = self.hash(pos);
+
+        if let Some(result) = memo.get(&hash) {
+            return *result;
+        }
+
+        let entries = self.map.get(&hash)?;
+        memo.insert(hash, None);
+
+        let mut best = None;
+
+        for entry in entries {
+            let score = self.entry_score(pos, entry, memo);
+
+            if best.is_none_or(|(s, _)| score > s) {
+                best = Some((score, entry.mov));
+            }
+        }
+
+        memo.insert(hash, best);
+        best
+    }
+
+    fn entry_score(&self, pos: &Chess, entry: &BookEntry, memo: &mut Memo) -> i32 {
+        let mov = from_book_move(entry.mov).to_move(pos).unwrap();
+        let child = pos.clone().play(&mov).unwrap();
+
+        match self.negamax(&child, memo) {
+            Some((score, _)) => -score,
+            None => entry.eval(),
+        }
+    }
+
+    fn principal_variation(&self, mut pos: Chess, memo: &mut Memo) -> Vec<(Chess, u16)> {
+        let mut out = Vec::new();
+        let mut seen = Vec::new();
+
+        while let Some((_, mov)) = self.negamax(&pos, memo) {
+            let hash = self.hash(&pos);
+
+            if seen.contains(&hash) {
+                break;
+            }
+            seen.push(hash);
+
+            let m = from_book_move(mov).to_move(&pos).unwrap();
+            out.push((pos.clone(), mov));
+            pos.play_unchecked(&m);
+        }
+
+        out
+    }
+
+    fn to_line(&self, plies: &[(Chess, u16)], score: i32) -> BookLine {
+        let moves = plies
+            .iter()
+            .map(|(pos, mov)| {
+                let m = from_book_move(*mov).to_move(pos).unwrap();
+                SanPlus::from_move(pos.clone(), &m)
+            })
+            .collect();
+
+        BookLine { score, moves }
+    }
+
+    /// Computes the principal variation through the book when learn values hold evaluations,
+    /// followed by up to `alternatives` lines that deviate from it at the smallest cost to the
+    /// deviating side. Scores are from the point of view of the side to move at the root.
+    pub fn best_lines(&self, alternatives: usize) -> Vec<BookLine> {
+        let mut memo = Memo::default();
+
+        let root_score = match self.negamax(&self.root, &mut memo) {
+            Some((score, _)) => score,
+            None => return Vec::new(),
+        };
+
+        let pv = self.principal_variation(self.root.clone(), &mut memo);
+        let mut deviations = Vec::new();
+
+        for (ply, (pos, best)) in pv.iter().enumerate() {
+            let node_score = self.negamax(pos, &mut memo).unwrap().0;
+
+            for entry in &self.map[&self.hash(pos)] {
+                if entry.mov != *best {
+                    let loss = node_score - self.entry_score(pos, entry, &mut memo);
+                    deviations.push((loss, ply, entry.mov));
+                }
+            }
+        }
+
+        deviations.sort_by_key(|(loss, ply, _)| (*loss, *ply));
+        deviations.truncate(alternatives);
+
+        let mut out = vec![self.to_line(&pv, root_score)];
+
+        for (loss, ply, mov) in deviations {
+            let (pos, _) = &pv[ply];
+            let m = from_book_move(mov).to_move(pos).unwrap();
+
+            let mut line = pv[..ply].to_vec();
+            line.push((pos.clone(), mov));
+            line.extend(self.principal_variation(pos.clone().play(&m).unwrap(), &mut memo));
+
+            let score = if ply % 2 == 0 {
+                root_score - loss
+            } else {
+                root_score + loss
+            };
+
+            out.push(self.to_line(&line, score));
+        }
+
+        out
+    }
+}
+
+#[test]
+fn t_best_lines() {
+    let tree = "e4 30\n    e5 20, Nf3 40\n    c5 25, Nf3 30\nd4 20, Nf6 15\n";
+    let book = BookMap::read_txt(&mut tree.as_bytes(), MoveNotation::San);
+
+    let lines = book.best_lines(1);
+    let moves = |line: &BookLine| line.moves.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].score, 30);
+    assert_eq!(moves(&lines[0]), ["e4", "c5", "Nf3"]);
+    assert_eq!(lines[1].score, 40);
+    assert_eq!(moves(&lines[1]), ["e4", "e5", "Nf3"]);
+}
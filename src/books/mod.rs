@@ -1,14 +1,91 @@
 use crate::conversions::*;
+use crate::error::input;
 use crate::pgn::*;
+use crate::rng::Rng;
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::io::{Read, Write};
+use std::rc::Rc;
 
+mod annotate;
+mod audit;
+mod balance;
+mod bias;
+mod bloom;
+mod checksum;
+mod collisions;
+mod compare;
+mod coverage;
+mod critical;
+mod csv;
+mod deck;
+mod deviations;
+mod eco;
+mod edit;
+mod epd;
+mod exchange;
+mod forced;
+mod gaps;
+mod latex;
+mod masters;
+#[cfg(feature = "engine")]
+mod mcts;
+mod metadata;
+mod minimax;
+mod packed;
+mod positions;
+mod repetition;
+#[cfg(feature = "script")]
+mod script;
+mod smooth;
+mod stats;
+mod store;
+mod suite;
+#[cfg(feature = "engine")]
+mod syzygy;
+mod table;
+mod tree;
 mod txt_books;
+mod txt_reader;
+
+pub use audit::Disagreement;
+pub use balance::SideCoverage;
+pub use bloom::BloomFilter;
+pub use collisions::CollisionCheck;
+pub use critical::Closeness;
+pub use deviations::Deviation;
+pub use eco::EcoTable;
+pub use edit::{parse_entry, MoveBan};
+pub use forced::ForcedLine;
+pub use gaps::{write_gaps, Gap};
+pub use masters::{master_moves, MasterMove};
+#[cfg(feature = "engine")]
+pub use mcts::GrowOptions;
+pub use metadata::{today, Metadata, METADATA_KEYS};
+pub use minimax::BookLine;
+#[cfg(feature = "engine")]
+pub use positions::engine_entries;
+pub use positions::read_positions;
+pub use repetition::RepetitionTrap;
+#[cfg(feature = "script")]
+pub use script::NODE_VARIABLES;
+pub use store::{BookStore, DiskStore};
+pub use suite::Opening;
+#[cfg(feature = "engine")]
+pub use syzygy::TablebaseLoss;
+pub use table::TableRow;
+pub use txt_books::{MoveNotation, SanStyle};
 
 const U16_MAX: u64 = u16::MAX as u64;
 
+/// Values of an entry that -map-weights and -filter-entries expressions can use, in the order of
+/// BookEntry::expr_variable
+pub const ENTRY_VARIABLES: &[&str] = &[
+    "weight", "learn", "eval", "depth", "wins", "draws", "losses", "games", "elo", "time",
+];
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct BookEntry {
     pub mov: u16,
@@ -16,11 +93,200 @@ pub struct BookEntry {
     pub visited: bool,
     pub weight: u64,
     pub learn: u32,
+    // Sum and count of the Elo ratings of players who made this move
+    pub elo_sum: u64,
+    pub elo_count: u64,
+    // Sum of the milliseconds spent playing this move and number of times it was timed, from
+    // clock comments
+    pub time_sum: u64,
+    pub time_count: u64,
+    // Games won, drawn and lost by the side making this move
+    pub results: [u64; 3],
+    // Weight contributed by each input, as indices into BookMap::sources
+    pub sources: Vec<(u16, u64)>,
+}
+
+/// Sorts the entries of a position into the order they are written to .bin files
+pub fn sort_bin_entries(entries: &mut [BookEntry], order: BinOrder) {
+    entries.sort_unstable();
+
+    // Ties are broken by move through the stable sort
+    match order {
+        BinOrder::Weight => entries.sort_by_key(|e| Reverse((e.weight, e.eval()))),
+        BinOrder::Learn => entries.sort_by_key(|e| Reverse((e.eval(), e.weight))),
+        BinOrder::Stable => {}
+    }
+}
+
+/// Writes the entries of the position with hash as .bin records, scaling weights down to 16 bits
+/// in proportion to the largest
+pub fn write_bin_position<W: Write>(writer: &mut W, hash: u64, entries: Vec<BookEntry>) {
+    let hash_bytes = hash.to_be_bytes();
+    let max_weight = entries.iter().map(|e| e.weight).max().unwrap_or(0);
+
+    for mut entry in entries {
+        if max_weight > U16_MAX {
+            entry.weight *= U16_MAX;
+            entry.weight /= max_weight;
+        }
+        writer.write_all(&hash_bytes);
+        writer.write_all(&entry.to_bytes());
+    }
+}
+
+/// Scales the weight of each timed move by how much faster it was played than the average timed
+/// move among entries, the entries of a position
+pub fn weight_by_move_time(entries: &mut [BookEntry]) {
+    let sum = entries.iter().map(|e| e.time_sum).sum::<u64>();
+    let count = entries.iter().map(|e| e.time_count).sum::<u64>();
+
+    if count == 0 {
+        return;
+    }
+
+    let average = sum as f64 / count as f64 / 1000.0;
+
+    for entry in entries.iter_mut().filter(|e| e.time_count > 0) {
+        let time = entry.time_sum as f64 / entry.time_count as f64 / 1000.0;
+        entry.weight = (entry.weight as f64 * (average + 1.0) / (time + 1.0)).round() as u64;
+    }
+}
+
+/// Raises the weights of entries, the entries of a position, to the power 1 / temperature and
+/// scales them back to the same total, so that temperatures below 1 concentrate the weight on the
+/// top moves and temperatures above 1 spread it out. Moves with weight keep at least 1.
+pub fn sharpen_weights(entries: &mut [BookEntry], temperature: f64) {
+    let total = entries.iter().map(|e| e.weight).sum::<u64>();
+    let max = entries.iter().map(|e| e.weight).max().unwrap_or(0);
+
+    if total == 0 {
+        return;
+    }
+
+    // Powers are taken of the weights relative to the largest, which stay within range
+    let powers = entries
+        .iter()
+        .map(|e| (e.weight as f64 / max as f64).powf(1.0 / temperature))
+        .collect::<Vec<_>>();
+    let sum = powers.iter().sum::<f64>();
+
+    for (entry, power) in entries.iter_mut().zip(powers) {
+        if entry.weight > 0 {
+            entry.weight = ((power / sum * total as f64).round() as u64).max(1);
+        }
+    }
+}
+
+/// Options controlling how games are turned into book entries
+#[derive(Clone)]
+pub struct BuildOptions {
+    /// Weigh moves by one point per occurrence rather than by results
+    pub frequency: bool,
+    /// Points for the side making a move in games it won, drew and lost. Weights are rounded to
+    /// whole numbers for each game, so fractional points need a scale keeping them apart.
+    pub scores: [f64; 3],
+    /// Multiply the points of each side by twice its opponent's expected score from their Elo
+    /// difference, so that results against stronger opponents count for more
+    pub upsets: bool,
+    /// Largest weight a single game gives a move, after scaling
+    pub max_weight: u64,
+    /// Number of half-moves read from each game
+    pub depth: usize,
+    /// Source that entries are tagged with
+    pub source: Option<u16>,
+}
+
+/// Order of the entries for each position in .bin files, as some engines only play the first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinOrder {
+    /// Highest weight first, then highest learn value
+    Weight,
+    /// Highest eval first, treating learn values as evals, then highest weight
+    Learn,
+    /// By move, independent of weights
+    Stable,
+}
+
+/// What exports do with a move returning to a position earlier in the line being written, which
+/// books can contain through repetitions or a root set to a later position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CyclePolicy {
+    /// End the line after the move
+    Break,
+    /// Fail with an input error
+    Error,
+    /// End the line after the move, telling the format to mark it where it has room to
+    Mark,
+}
+
+/// How the weight of an entry is combined with that of the same move from a book merged into it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergePolicy {
+    /// Ignore the merged entry, including its elo and sources
+    KeepFirst,
+    Sum,
+    Max,
+    Min,
+    /// The mean of the two weights, rounded down
+    Average,
+    /// The weight of the merged book
+    ReplaceWithNewer,
 }
 
+/// How the learn value of an entry is combined with that of the same move from a book merged into
+/// it. Learn values are compared and averaged as signed evals.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LearnPolicy {
+    KeepFirst,
+    KeepLast,
+    Max,
+    Min,
+    /// The mean of the two learn values, weighted by the weights of the entries before merging
+    WeightedAverage,
+}
+
+/// Options controlling how entries for the same move are combined when merging books
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MergeOptions {
+    pub weight: MergePolicy,
+    pub learn: LearnPolicy,
+}
+
+/// How records of a single .bin file for the same position and move are combined as it is read.
+/// Books written by other tools sometimes repeat them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicatePolicy {
+    Sum,
+    /// The record with the largest weight, including its learn value
+    Max,
+    First,
+    /// Reject files with repeated records or records out of order
+    Error,
+}
+
+/// The irregular records found reading a .bin file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BinIssues {
+    /// Records for a position and move read before
+    pub duplicates: usize,
+    /// Records with a lower hash than the record before, which Polyglot's binary search misses
+    pub unsorted: usize,
+}
+
+/// A map keyed by position hashes, which are already uniformly distributed and are used as their
+/// own hashes
+pub type PositionMap<V> = HashMap<u64, V, nohash_hasher::BuildNoHashHasher<u64>>;
+/// A set of position hashes, used as their own hashes like the keys of PositionMap
+pub type PositionSet = HashSet<u64, nohash_hasher::BuildNoHashHasher<u64>>;
+
 pub struct BookMap {
-    map: HashMap<u64, Vec<BookEntry>, nohash_hasher::BuildNoHashHasher<u64>>,
+    map: PositionMap<Vec<BookEntry>>,
     root: Chess,
+    // Names of the inputs tracked by BookEntry::sources
+    sources: Vec<String>,
+    metadata: Metadata,
+    hasher: Rc<dyn PositionHasher>,
+    cycles: CyclePolicy,
 }
 
 impl BookEntry {
@@ -31,15 +297,133 @@ impl BookEntry {
             weight: 0,
             learn: 0,
             visited: false,
+            elo_sum: 0,
+            elo_count: 0,
+            time_sum: 0,
+            time_count: 0,
+            results: [0; 3],
+            sources: Vec::new(),
         }
     }
 
+    pub fn average_elo(&self) -> Option<u64> {
+        self.elo_sum.checked_div(self.elo_count)
+    }
+
+    /// The value of the variable at index i of ENTRY_VARIABLES, if the entry has it. The time is
+    /// the average in milliseconds spent on the move.
+    pub fn expr_variable(&self, i: usize) -> Option<i64> {
+        let [wins, draws, losses] = self.results;
+
+        let value = match ENTRY_VARIABLES[i] {
+            "weight" => Some(self.weight),
+            "learn" => Some(self.learn as u64),
+            "eval" => return Some(self.eval() as i64),
+            "depth" => self.depth.map(|x| x as u64),
+            "wins" => Some(wins),
+            "draws" => Some(draws),
+            "losses" => Some(losses),
+            "games" => Some(wins + draws + losses),
+            "elo" => self.average_elo(),
+            _ => self.time_sum.checked_div(self.time_count),
+        };
+
+        value.and_then(|x| x.try_into().ok())
+    }
+
+    /// The lower bound of the Wilson score interval for the share of points scored by the side
+    /// making this move, counting draws as half points, with z standard deviations. None if the
+    /// move has no game results.
+    pub fn wilson_lower_bound(&self, z: f64) -> Option<f64> {
+        let [wins, draws, losses] = self.results;
+        let n = (wins + draws + losses) as f64;
+
+        if n == 0.0 {
+            return None;
+        }
+
+        let p = (wins as f64 + draws as f64 / 2.0) / n;
+        let z2 = z * z;
+        let spread = z * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+
+        Some((p + z2 / (2.0 * n) - spread) / (1.0 + z2 / n))
+    }
+
+    /// The share of the games containing this move that were decisive, from 0 for a move that
+    /// only drew to 1 for one that never did. None if the move has no game results.
+    pub fn sharpness(&self) -> Option<f64> {
+        let [wins, draws, losses] = self.results;
+        let decisive = wins + losses;
+
+        if decisive + draws == 0 {
+            return None;
+        }
+
+        Some(decisive as f64 / (decisive + draws) as f64)
+    }
+
     pub fn combine(&mut self, other: &BookEntry) -> bool {
         if self.mov != other.mov {
             return false;
         }
 
         self.weight += other.weight;
+        self.elo_sum += other.elo_sum;
+        self.elo_count += other.elo_count;
+        self.time_sum += other.time_sum;
+        self.time_count += other.time_count;
+
+        for (a, b) in self.results.iter_mut().zip(other.results) {
+            *a += b;
+        }
+
+        for (source, weight) in &other.sources {
+            match self.sources.iter_mut().find(|s| s.0 == *source) {
+                Some(s) => s.1 += weight,
+                None => self.sources.push((*source, *weight)),
+            }
+        }
+        true
+    }
+
+    /// Like combine, with the weights and learn values combined as given by options
+    pub fn merge_with(&mut self, other: &BookEntry, options: MergeOptions) -> bool {
+        if self.mov != other.mov {
+            return false;
+        }
+
+        let (weight, eval) = (self.weight, self.eval());
+
+        if options.weight != MergePolicy::KeepFirst {
+            self.combine(other);
+        }
+
+        self.weight = match options.weight {
+            MergePolicy::KeepFirst => weight,
+            MergePolicy::Sum => weight + other.weight,
+            MergePolicy::Max => weight.max(other.weight),
+            MergePolicy::Min => weight.min(other.weight),
+            MergePolicy::Average => (weight + other.weight) / 2,
+            MergePolicy::ReplaceWithNewer => other.weight,
+        };
+
+        let learn = match options.learn {
+            LearnPolicy::KeepFirst => eval,
+            LearnPolicy::KeepLast => other.eval(),
+            LearnPolicy::Max => eval.max(other.eval()),
+            LearnPolicy::Min => eval.min(other.eval()),
+            LearnPolicy::WeightedAverage => {
+                let (a, b) = (weight as i128, other.weight as i128);
+                let sum = eval as i128 * a + other.eval() as i128 * b;
+
+                if a + b == 0 {
+                    ((eval as i128 + other.eval() as i128) / 2) as i32
+                } else {
+                    (sum / (a + b)) as i32
+                }
+            }
+        };
+        self.learn = learn as u32;
         true
     }
 
@@ -64,11 +448,60 @@ impl BookEntry {
     }
 }
 
+impl BuildOptions {
+    pub fn new() -> Self {
+        BuildOptions {
+            frequency: false,
+            scores: [2.0, 1.0, 0.0],
+            upsets: false,
+            max_weight: u64::MAX,
+            depth: usize::MAX,
+            source: None,
+        }
+    }
+}
+
+impl MergeOptions {
+    /// Keeps the first entry for each move, as when reading books
+    pub fn new() -> Self {
+        MergeOptions {
+            weight: MergePolicy::KeepFirst,
+            learn: LearnPolicy::KeepFirst,
+        }
+    }
+
+    /// Adds weights, keeping the first learn value
+    pub fn combine() -> Self {
+        MergeOptions {
+            weight: MergePolicy::Sum,
+            ..MergeOptions::new()
+        }
+    }
+}
+
+impl DuplicatePolicy {
+    /// Combines duplicate into entry, an earlier record for the same move, returning false if
+    /// duplicates are rejected
+    pub fn combine(self, entry: &mut BookEntry, duplicate: BookEntry) -> bool {
+        match self {
+            DuplicatePolicy::Sum => entry.weight += duplicate.weight,
+            DuplicatePolicy::Max if duplicate.weight > entry.weight => *entry = duplicate,
+            DuplicatePolicy::Max | DuplicatePolicy::First => {}
+            DuplicatePolicy::Error => return false,
+        }
+        true
+    }
+}
+
 impl BookMap {
     pub fn new() -> Self {
         BookMap {
-            map: HashMap::with_hasher(nohash_hasher::BuildNoHashHasher::default()),
+            map: PositionMap::default(),
             root: Chess::default(),
+            sources: Vec::new(),
+            metadata: Metadata::default(),
+            hasher: Rc::new(PolyglotHasher),
+            cycles: CyclePolicy::Break,
         }
     }
 
@@ -76,17 +509,110 @@ impl BookMap {
         self.map.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn hash(&self, pos: &Chess) -> u64 {
+        self.hasher.hash(pos)
+    }
+
+    pub fn hasher(&self) -> &Rc<dyn PositionHasher> {
+        &self.hasher
+    }
+
+    /// Switches to another hasher, rekeying the entries reachable from the root and dropping the
+    /// rest, whose positions are unknown.
+    pub fn set_hasher(&mut self, hasher: Rc<dyn PositionHasher>) {
+        let mut tree = self.to_tree();
+        tree.set_hasher(hasher);
+        *self = tree.to_map();
+    }
+
+    fn assert_polyglot(&self) {
+        assert_eq!(
+            self.hasher.name(),
+            PolyglotHasher.name(),
+            "Only books using the Polyglot hash can be used as .bin files"
+        );
+    }
+
+    fn assert_same_hasher(&self, other: &BookMap) {
+        assert_eq!(
+            self.hasher.name(),
+            other.hasher.name(),
+            "Cannot merge books using different hashes"
+        );
+    }
+
+    /// Registers an input name and returns its index for tagging entries
+    pub fn add_source(&mut self, name: &str) -> u16 {
+        self.sources.push(name.to_string());
+        (self.sources.len() - 1) as u16
+    }
+
+    pub fn source_names(&self) -> &[String] {
+        &self.sources
+    }
+
+    /// Attributes all weight in the book to a single source
+    pub fn tag_source(&mut self, source: u16) {
+        self.map_entries(|entry| entry.sources = vec![(source, entry.weight)]);
+    }
+
+    /// Reduces every entry to the weight contributed by the named source if keep is set, or
+    /// removes that source's contributions otherwise, dropping entries left without weight.
+    pub fn filter_source(&mut self, name: &str, keep: bool) {
+        let source = self
+            .sources
+            .iter()
+            .position(|s| s == name)
+            .unwrap_or_else(|| panic!("Unknown source {}", name)) as u16;
+
+        self.filter(|entry| !entry.sources.is_empty());
+        self.map_nodes(|node| {
+            for entry in node.iter_mut() {
+                entry.sources.retain(|s| (s.0 == source) == keep);
+                entry.weight = entry.sources.iter().map(|s| s.1).sum();
+            }
+            node.retain(|entry| !entry.sources.is_empty());
+        });
+    }
+
+    pub fn root(&self) -> &Chess {
+        &self.root
+    }
+
+    /// The entries for pos, which are empty if it is not in the book
+    pub fn entries(&self, pos: &Chess) -> &[BookEntry] {
+        self.map.get(&self.hash(pos)).map_or(&[], |v| &v[..])
+    }
+
     pub fn insert_combine(&mut self, hash: u64, entry: BookEntry) {
-        if let Some(v) = self.map.get_mut(&hash) {
-            for entry2 in v.iter_mut() {
-                if entry2.combine(&entry) {
-                    return;
-                }
+        self.insert_with(hash, entry, MergeOptions::combine());
+    }
+
+    /// Inserts entry, merging it into an existing entry for the same move as given by options.
+    /// Returns true if it was merged into an entry with a different learn value.
+    pub fn insert_with(&mut self, hash: u64, entry: BookEntry, options: MergeOptions) -> bool {
+        // A single lookup, as this is the hot path of building books from games
+        let v = match self.map.entry(hash) {
+            Entry::Occupied(v) => v.into_mut(),
+            Entry::Vacant(v) => {
+                v.insert(vec![entry]);
+                return false;
+            }
+        };
+
+        for entry2 in v.iter_mut() {
+            let conflict = entry2.learn != entry.learn;
+
+            if entry2.merge_with(&entry, options) {
+                return conflict;
             }
-            v.push(entry);
-        } else {
-            self.map.insert(hash, vec![entry]);
         }
+        v.push(entry);
+        false
     }
 
     pub fn insert(&mut self, hash: u64, entry: BookEntry) {
@@ -103,14 +629,30 @@ impl BookMap {
     }
 
     pub fn merge_combine(&mut self, other: BookMap) {
+        self.merge_with(other, MergeOptions::combine());
+    }
+
+    /// Adds the entries of other, combining entries for moves in both books as given by options.
+    /// Returns the number of merged entries whose learn values differed.
+    pub fn merge_with(&mut self, other: BookMap, options: MergeOptions) -> usize {
+        self.assert_same_hasher(&other);
+        self.metadata.fill_from(&other.metadata);
+
+        let mut conflicts = 0;
+
         for (hash, v) in other.map {
             for entry in v {
-                self.insert_combine(hash, entry);
+                conflicts += self.insert_with(hash, entry, options) as usize;
             }
         }
+
+        conflicts
     }
 
     pub fn merge(&mut self, other: BookMap) {
+        self.assert_same_hasher(&other);
+        self.metadata.fill_from(&other.metadata);
+
         for (hash, v) in other.map {
             for entry in v {
                 self.insert(hash, entry);
@@ -118,29 +660,82 @@ impl BookMap {
         }
     }
 
-    fn traverse_tree<F>(&mut self, mut f: F)
+    /// Adds the positions of other, replacing all of the moves of positions in both books with
+    /// other's, so that this book only fills the positions other doesn't cover
+    pub fn merge_priority(&mut self, other: BookMap) {
+        self.assert_same_hasher(&other);
+        self.metadata.fill_from(&other.metadata);
+
+        for (hash, v) in other.map {
+            if !v.is_empty() {
+                self.map.insert(hash, v);
+            }
+        }
+    }
+
+    pub fn set_cycle_policy(&mut self, cycles: CyclePolicy) {
+        self.cycles = cycles;
+    }
+
+    // Calls f with the depth, position, entries and index of each entry reachable from the root,
+    // depth first, sorting each position's entries by descending weight first if sorted. Lines
+    // end at positions already visited and at moves closing a cycle, for which f is told to mark
+    // the move under CyclePolicy::Mark.
+    fn traverse_tree<F>(&mut self, sorted: bool, mut f: F)
     where
-        F: FnMut(usize, &Chess, &mut Vec<BookEntry>, usize),
+        F: FnMut(usize, &Chess, &[BookEntry], usize, bool),
     {
-        let mut stack = vec![(self.root.clone(), 0)];
+        let cycles = self.cycles;
+        let hasher = self.hasher.clone();
+        let mut stack = vec![(self.root.clone(), self.hash(&self.root), 0)];
+        // Hashes of the positions with a frame on the stack, which make up the current line
+        let mut line = PositionSet::default();
+
+        while let Some((pos, hash, ind)) = stack.pop() {
+            line.remove(&hash);
+
+            let Some(entries) = self.map.get_mut(&hash) else {
+                continue;
+            };
+
+            if entries.is_empty() {
+                self.map.remove(&hash);
+                continue;
+            }
+
+            if sorted && ind == 0 {
+                entries.sort_unstable_by_key(|entry| Reverse(entry.weight));
+            }
 
-        while let Some((pos, ind)) = stack.pop() {
-            let hash = book_hash(pos.clone());
+            let next = entries.iter().position(|e| !e.visited).map(|next| {
+                let mov = from_book_move(entries[next].mov).to_move(&pos).unwrap();
+                entries[next].visited = true;
 
-            if let Some(entries) = self.map.get_mut(&hash) {
-                if ind < entries.len() {
-                    f(stack.len(), &pos, entries, ind);
+                let child = pos.clone().play(&mov).unwrap();
+                let child_hash = hasher.hash(&child);
+                let cycle = line.contains(&child_hash);
+
+                if cycle && cycles == CyclePolicy::Error {
+                    input(format!(
+                        "Book has a cycle, {} returns to an earlier position in the line from {}",
+                        San::from_move(&pos, &mov),
+                        txt_books::fen(&pos)
+                    ));
                 }
+                (next, child, child_hash, cycle)
+            });
 
-                if let Some(ind) = entries.iter().position(|e| !e.visited) {
-                    let mov = from_book_move(entries[ind].mov).to_move(&pos).unwrap();
+            if ind < entries.len() {
+                let mark = next.as_ref().is_some_and(|x| x.3) && cycles == CyclePolicy::Mark;
+                f(stack.len(), &pos, entries, ind, mark);
+            }
 
-                    entries[ind].visited = true;
+            if let Some((next, child, child_hash, cycle)) = next {
+                line.insert(hash);
+                stack.push((pos, hash, next + 1));
 
-                    stack.push((pos.clone(), ind + 1));
-                    stack.push((pos.play(&mov).unwrap(), 0));
-                } else if entries.is_empty() {
-                    self.map.remove(&hash);
+                if !cycle {
+                    stack.push((child, child_hash, 0));
                 }
             }
         }
@@ -178,12 +773,51 @@ impl BookMap {
         })
     }
 
+    /// Sets the depth of every entry to the fewest moves that reach its position from the root,
+    /// so that transposed positions get the same depth whichever line reaches them first.
+    /// Entries unreachable from the root get no depth.
     pub fn set_depths(&mut self) {
         self.map_entries(|entry| entry.depth = None);
 
-        self.traverse_tree(|depth, _, entries, ind| {
-            entries[ind].depth = Some(depth);
-        });
+        // Positions are queued in order of depth, so each is first reached by a shortest line.
+        // Depths are set as positions are queued, which marks them as seen, and positions without
+        // entries are never queued as they lead nowhere.
+        let root = self.hash(&self.root);
+        let Some(entries) = self.map.get_mut(&root) else {
+            return;
+        };
+        for entry in entries.iter_mut() {
+            entry.depth = Some(0);
+        }
+
+        let mut queue = VecDeque::from([(self.root.clone(), root, 0)]);
+        let mut moves = Vec::new();
+
+        while let Some((pos, hash, depth)) = queue.pop_front() {
+            moves.clear();
+            moves.extend(self.map[&hash].iter().map(|e| e.mov));
+
+            for mov in &moves {
+                let Ok(mov) = from_book_move(*mov).to_move(&pos) else {
+                    continue;
+                };
+                let mut child = pos.clone();
+                child.play_unchecked(&mov);
+
+                let hash = self.hasher.hash(&child);
+                let Some(entries) = self.map.get_mut(&hash) else {
+                    continue;
+                };
+                if entries.first().is_none_or(|e| e.depth.is_some()) {
+                    continue;
+                }
+
+                for entry in entries.iter_mut() {
+                    entry.depth = Some(depth + 1);
+                }
+                queue.push_back((child, hash, depth + 1));
+            }
+        }
     }
 
     pub fn set_root(&mut self, root: Chess) {
@@ -198,14 +832,15 @@ impl BookMap {
         self.filter(|entry| entry.depth.is_some());
     }
 
-    pub fn write<W: Write>(&self, writer: &mut W) {
+    pub fn write<W: Write>(&self, writer: &mut W, order: BinOrder) {
+        self.assert_polyglot();
+
         let mut vec = self
             .map
             .iter()
             .map(|(hash, entries)| {
                 let mut entries = entries.clone();
-                entries.sort_unstable();
-
+                sort_bin_entries(&mut entries, order);
                 (hash, entries)
             })
             .collect::<Vec<_>>();
@@ -213,21 +848,13 @@ impl BookMap {
         vec.sort_unstable();
 
         for (hash, entries) in vec {
-            let hash_bytes = hash.to_be_bytes();
-            let max_weight = entries.iter().map(|e| e.weight).max().unwrap();
-
-            for mut entry in entries {
-                if max_weight > U16_MAX {
-                    entry.weight *= U16_MAX;
-                    entry.weight /= max_weight;
-                }
-                writer.write_all(&hash_bytes);
-                writer.write_all(&entry.to_bytes());
-            }
+            write_bin_position(writer, *hash, entries);
         }
     }
 
     pub fn extend_from_reader_combine<R: Read>(&mut self, reader: &mut R) {
+        self.assert_polyglot();
+
         let mut buf = [0u8; 16];
 
         while let Ok(()) = reader.read_exact(&mut buf[..]) {
@@ -238,55 +865,377 @@ impl BookMap {
         }
     }
 
-    pub fn extend_from_reader<R: Read>(&mut self, reader: &mut R) {
+    /// Reads a .bin file into the book, merging entries as given by options and returning the
+    /// number of merged entries whose learn values differed
+    pub fn extend_from_reader_with<R: Read>(
+        &mut self,
+        reader: &mut R,
+        options: MergeOptions,
+    ) -> usize {
+        self.assert_polyglot();
+
         let mut buf = [0u8; 16];
+        let mut conflicts = 0;
 
         while let Ok(()) = reader.read_exact(&mut buf[..]) {
             let hash = u64::from_be_bytes(buf[0..8].try_into().unwrap());
             let entry = BookEntry::from_bytes(&buf[8..]);
 
-            self.insert(hash, entry);
+            conflicts += self.insert_with(hash, entry, options) as usize;
         }
+
+        conflicts
     }
 
-    pub fn add_game(&mut self, game: &PgnGame, frequency: bool, depth: usize) {
-        let mut board = Chess::default();
+    /// Reads a .bin file into a new book, combining the records for the same position and move as
+    /// given by policy, and counting the repeated records and those out of order. Fails at the
+    /// first such record with DuplicatePolicy::Error.
+    pub fn try_read_bin<R: Read>(
+        reader: &mut R,
+        policy: DuplicatePolicy,
+    ) -> Result<(Self, BinIssues), String> {
+        let mut book = BookMap::new();
+        let mut issues = BinIssues::default();
+        let mut buf = [0u8; 16];
+        let mut last = 0;
 
-        for (depth, sanplus) in game.moves.iter().take(depth).enumerate() {
-            let hash = book_hash(board.clone());
+        for record in 0.. {
+            if reader.read_exact(&mut buf[..]).is_err() {
+                break;
+            }
+            let hash = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+            let entry = BookEntry::from_bytes(&buf[8..]);
 
-            let mov = sanplus.san.to_move(&board).unwrap();
-            let uci = UciMove::from_chess960(&mov);
-            board = board.play(&mov).unwrap();
+            if hash < last {
+                issues.unsorted += 1;
 
-            let weight = if frequency {
-                1
-            } else if let Outcome::Decisive { winner } = game.outcome {
-                if (winner == Color::White) == (depth % 2 == 0) {
-                    2
-                } else {
-                    0
+                if policy == DuplicatePolicy::Error {
+                    return Err(format!("Record {} is not sorted by key", record));
                 }
-            } else {
-                1
-            };
+            }
+            last = hash;
+
+            let entries = book.map.entry(hash).or_default();
+            match entries.iter_mut().find(|e| e.mov == entry.mov) {
+                Some(e) => {
+                    issues.duplicates += 1;
 
-            self.insert_combine(
-                hash,
-                BookEntry {
-                    mov: to_book_move(uci),
-                    visited: false,
-                    depth: Some(depth),
-                    weight,
-                    learn: 0,
-                },
-            )
+                    if !policy.combine(e, entry) {
+                        return Err(format!("Record {} repeats a key and move", record));
+                    }
+                }
+                None => entries.push(entry),
+            }
         }
+
+        Ok((book, issues))
     }
 
-    pub fn extend_from_games(&mut self, games: &[PgnGame], frequency: bool, depth: usize) {
+    pub fn extend_from_reader<R: Read>(&mut self, reader: &mut R) {
+        self.assert_polyglot();
+
+        let mut buf = [0u8; 16];
+
+        while let Ok(()) = reader.read_exact(&mut buf[..]) {
+            let hash = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+            let entry = BookEntry::from_bytes(&buf[8..]);
+
+            self.insert(hash, entry);
+        }
+    }
+
+    /// Adds the moves of game to the book, multiplying the weight each move receives by scale.
+    pub fn add_game(&mut self, game: &PgnGame, options: &BuildOptions, scale: f64) {
+        BookStore::add_game(self, game, options, scale)
+    }
+
+    /// Scales the weight of each timed move by how much faster it was played than the average
+    /// timed move in its position. A second is added to both times, so that premoves do not get
+    /// unbounded weight.
+    pub fn weight_by_move_time(&mut self) {
+        self.map_nodes(|node| weight_by_move_time(node));
+    }
+
+    /// Multiplies each weight by a random factor between 1 - amount and 1 + amount, for variants
+    /// of a book that play slightly different lines. The factors of each position are drawn from
+    /// seed and the position's hash, so that a seed perturbs a position the same way in any book.
+    /// Moves with weight keep at least 1.
+    pub fn perturb_weights(&mut self, amount: f64, seed: u64) {
+        for (hash, entries) in self.map.iter_mut() {
+            let mut rng = Rng::new(seed ^ hash);
+
+            for entry in entries.iter_mut().filter(|e| e.weight > 0) {
+                let factor = 1.0 + amount * (2.0 * rng.next_f64() - 1.0);
+                entry.weight = ((entry.weight as f64 * factor).round() as u64).max(1);
+            }
+        }
+    }
+
+    pub fn extend_from_games(&mut self, games: &[PgnGame], options: &BuildOptions) {
         for game in games.iter() {
-            self.add_game(game, frequency, depth);
+            self.add_game(game, options, 1.0);
         }
     }
 }
+
+#[test]
+fn t_merge_policy() {
+    let merged = |weight, learn| {
+        let mut book = BookMap::read_txt(&mut "4 e4 10\n2 d4".as_bytes(), MoveNotation::San);
+        let mut other = BookMap::read_txt(&mut "12 e4 1\n1 c4".as_bytes(), MoveNotation::San);
+        other.map_entries(|e| e.learn = (-30 * e.learn as i32) as u32);
+        let conflicts = book.merge_with(other, MergeOptions { weight, learn });
+        assert_eq!(conflicts, 1);
+
+        let mut entries = book.map[&START_HASH]
+            .iter()
+            .map(|e| (e.weight, e.eval()))
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    };
+
+    use LearnPolicy::{KeepFirst, KeepLast, WeightedAverage};
+
+    assert_eq!(
+        merged(MergePolicy::KeepFirst, KeepFirst),
+        [(1, 0), (2, 0), (4, 10)]
+    );
+    assert_eq!(
+        merged(MergePolicy::Sum, KeepFirst),
+        [(1, 0), (2, 0), (16, 10)]
+    );
+    assert_eq!(
+        merged(MergePolicy::Max, KeepLast),
+        [(1, 0), (2, 0), (12, -30)]
+    );
+    assert_eq!(
+        merged(MergePolicy::Min, LearnPolicy::Max),
+        [(1, 0), (2, 0), (4, 10)]
+    );
+    assert_eq!(
+        merged(MergePolicy::Average, LearnPolicy::Min),
+        [(1, 0), (2, 0), (8, -30)]
+    );
+    assert_eq!(
+        merged(MergePolicy::ReplaceWithNewer, WeightedAverage),
+        [(1, 0), (2, 0), (12, -20)]
+    );
+}
+
+#[test]
+fn t_merge_priority() {
+    let read = |text: &str| BookMap::read_txt(&mut text.as_bytes(), MoveNotation::San);
+
+    let mut book = read("10 e4\n    6 c5\n    4 e5, 3 Nf3\n5 d4, 5 d5");
+    book.merge_priority(read("e4, e5\n2 Nf3"));
+
+    // The root and 1. e4 get the priority book's moves, while 1. e4 e5 and 1. d4 keep the base's
+    let weights = |book: &BookMap, moves: &[&str]| {
+        let mut pos = book.root.clone();
+        for san in moves {
+            pos.play_unchecked(&parse_move(&pos, san).unwrap());
+        }
+        book.entries(&pos)
+            .iter()
+            .map(|e| e.weight)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(weights(&book, &[]), [1, 2]);
+    assert_eq!(weights(&book, &["e4"]), [1]);
+    assert_eq!(weights(&book, &["e4", "e5"]), [3]);
+    assert_eq!(weights(&book, &["d4"]), [5]);
+}
+
+#[test]
+fn t_perturb_weights() {
+    let read = || {
+        BookMap::read_txt(
+            &mut "1000 e4\n    1000 e5\n500 d4\n1 c4".as_bytes(),
+            MoveNotation::San,
+        )
+    };
+    let weights = |book: &BookMap| {
+        let mut weights = book
+            .map
+            .values()
+            .flatten()
+            .map(|e| e.weight)
+            .collect::<Vec<_>>();
+        weights.sort();
+        weights
+    };
+
+    let mut a = read();
+    let mut b = read();
+    a.perturb_weights(0.1, 42);
+    b.perturb_weights(0.1, 42);
+    assert_eq!(weights(&a), weights(&b));
+
+    let [c4, d4, e4, e5] = weights(&a)[..] else {
+        panic!()
+    };
+    assert_eq!(c4, 1);
+    assert!((450..=550).contains(&d4));
+    assert!((900..=1100).contains(&e4) && (900..=1100).contains(&e5));
+
+    b.perturb_weights(0.1, 43);
+    assert_ne!(weights(&a), weights(&b));
+}
+
+#[test]
+fn t_upset_weights() {
+    let pgn = "[WhiteElo \"1600\"]\n[BlackElo \"2000\"]\n[Result \"1-0\"]\n\n1. e4 e5 1-0";
+    let games = read_games(PgnFilter::new(), pgn.as_bytes());
+
+    let mut options = BuildOptions::new();
+    options.upsets = true;
+
+    let mut book = BookMap::new();
+    book.add_game(&games[0], &options, 100.0);
+
+    // White scores 1 in 11 against a player rated 400 higher
+    let weights = book
+        .map
+        .values()
+        .flatten()
+        .map(|e| e.weight)
+        .collect::<Vec<_>>();
+    assert!(weights.contains(&364) && weights.contains(&0));
+}
+
+#[test]
+fn t_wilson_lower_bound() {
+    let entry = |results| BookEntry {
+        results,
+        ..BookEntry::new()
+    };
+
+    assert_eq!(entry([0, 0, 0]).wilson_lower_bound(1.96), None);
+
+    // One win is far less convincing than 60 out of 100
+    let lucky = entry([1, 0, 0]).wilson_lower_bound(1.96).unwrap();
+    let solid = entry([60, 0, 40]).wilson_lower_bound(1.96).unwrap();
+    assert!(lucky < 0.25 && (0.5..0.6).contains(&solid));
+
+    assert_eq!(entry([3, 4, 1]).sharpness(), Some(0.5));
+}
+
+#[test]
+fn t_set_depths() {
+    // The knights return to the start, so the root is reached again four moves deep
+    let mut book = BookMap::read_txt(
+        &mut "Nf3, Nf6, Ng1, Ng8, e4, e5".as_bytes(),
+        MoveNotation::San,
+    );
+    book.set_depths();
+
+    let root = book.entries(&Chess::default());
+    assert_eq!(root.len(), 2);
+    assert!(root.iter().all(|e| e.depth == Some(0)));
+    assert_eq!(
+        book.map.values().flatten().filter_map(|e| e.depth).max(),
+        Some(3)
+    );
+}
+
+#[test]
+fn t_cycles() {
+    // The knights return to the root, which also starts a second line
+    let mut book = BookMap::read_txt(
+        &mut "2 Nf3, Nf6, Ng1, Ng8\ne4, e5".as_bytes(),
+        MoveNotation::San,
+    );
+    book.set_cycle_policy(CyclePolicy::Mark);
+
+    let mut out = Vec::new();
+    book.write_txt(&mut out, MoveNotation::San, false);
+    let tree = String::from_utf8(out).unwrap();
+    assert_eq!(tree.trim(), "2 Nf3, Nf6, Ng1, Ng8 ; cycle\ne4, e5");
+
+    let read = BookMap::read_txt(&mut tree.as_bytes(), MoveNotation::San);
+    assert_eq!(read.map, book.map);
+}
+
+#[test]
+fn t_transposition_references() {
+    // 1. d4 Nf6 2. c4 e6 and 1. c4 e6 2. d4 Nf6 reach the same position
+    let tree = "2 d4, Nf6, c4, e6\n        Nc3, Bb4\nc4, e6, d4, Nf6";
+    let mut book = BookMap::read_txt(&mut tree.as_bytes(), MoveNotation::San);
+
+    let mut out = Vec::new();
+    book.write_txt(&mut out, MoveNotation::San, true);
+    let marked = String::from_utf8(out).unwrap();
+    assert!(marked.contains("c4, e6, d4, Nf6 ; see line 2"));
+    assert_eq!(marked.matches("Nc3").count(), 1);
+    assert_eq!(
+        marked.lines().nth(1).unwrap().trim(),
+        "2 d4, Nf6, c4, e6, 1 Nc3, Bb4"
+    );
+
+    let read = BookMap::read_txt(&mut marked.as_bytes(), MoveNotation::San);
+    assert_eq!(read.map, book.map);
+}
+
+#[test]
+fn t_sharpen_weights() {
+    let mut entries = [4, 2, 2, 0].map(|weight| BookEntry {
+        weight,
+        ..BookEntry::new()
+    });
+    let weights = |entries: &[BookEntry]| entries.iter().map(|e| e.weight).collect::<Vec<_>>();
+
+    sharpen_weights(&mut entries, 0.5);
+    assert_eq!(weights(&entries), [5, 1, 1, 0]);
+
+    sharpen_weights(&mut entries, 1.0);
+    assert_eq!(weights(&entries), [5, 1, 1, 0]);
+
+    // Nearly flat, while the unplayed move stays out
+    sharpen_weights(&mut entries, 100.0);
+    assert_eq!(weights(&entries), [2, 2, 2, 0]);
+}
+
+#[test]
+fn t_try_read_bin() {
+    let record = |hash: u64, mov: u16, weight: u64| {
+        let entry = BookEntry {
+            mov,
+            weight,
+            ..BookEntry::new()
+        };
+        [&hash.to_be_bytes()[..], &entry.to_bytes()].concat()
+    };
+    // A repeated move of the second key, then a record of the first key out of order
+    let bin = [
+        record(1, 10, 3),
+        record(2, 10, 2),
+        record(2, 10, 5),
+        record(2, 11, 1),
+        record(1, 11, 4),
+    ]
+    .concat();
+    let read = |policy| BookMap::try_read_bin(&mut &bin[..], policy);
+    let weights = |book: &BookMap, hash| {
+        let mut entries = book.map[&hash].clone();
+        entries.sort();
+        entries.iter().map(|e| e.weight).collect::<Vec<_>>()
+    };
+
+    let (book, issues) = read(DuplicatePolicy::Sum).unwrap();
+    assert_eq!(
+        issues,
+        BinIssues {
+            duplicates: 1,
+            unsorted: 1
+        }
+    );
+    assert_eq!(weights(&book, 1), [3, 4]);
+    assert_eq!(weights(&book, 2), [7, 1]);
+
+    assert_eq!(weights(&read(DuplicatePolicy::Max).unwrap().0, 2), [5, 1]);
+    assert_eq!(weights(&read(DuplicatePolicy::First).unwrap().0, 2), [2, 1]);
+    assert!(read(DuplicatePolicy::Error).is_err());
+    assert!(BookMap::try_read_bin(&mut &bin[..32], DuplicatePolicy::Error).is_ok());
+}
@@ -0,0 +1,395 @@
+// A compact alternative to .bin files for large books. Positions are sorted by hash and split
+// into blocks, each storing its hashes as varint deltas from the previous one, followed by an
+// index of the first hash and offset of every block so that single positions can be probed
+// without reading the whole file:
+//
+//     magic "RGPK", version byte
+//     varint length and bytes of the root fen, empty for the standard position
+//     varint number of metadata fields, then per field its key and value as varint length and
+//         bytes
+//     blocks of up to BLOCK_POSITIONS positions, each as
+//         varint number of positions
+//         per position: varint hash delta (from 0 at the start of a block), varint entry count,
+//             and per entry: varint move, varint weight, varint learn value
+//     index: varint block count, then per block the first hash and offset as 8 byte big endian
+//     offset of the index as 8 byte big endian
+//
+// The header, each block and the index are followed by the 4 byte big endian crc32 of their
+// bytes, so that truncated or corrupted books fail to read.
+//
+// Unlike .bin files, weights are stored without being scaled down to 16 bits. Blocks are not
+// compressed, as probing decodes them in process and the crate has no compression library; the
+// command line reads packed books compressed as a whole, such as book.rgpack.zst.
+
+use super::checksum::crc32;
+use super::txt_books::fen;
+use super::*;
+
+use std::io::{Seek, SeekFrom};
+
+use shakmaty::fen::Fen;
+
+const MAGIC: &[u8; 4] = b"RGPK";
+const VERSION: u8 = 3;
+const BLOCK_POSITIONS: usize = 4096;
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_varint(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+// Reads varints and fixed size numbers from a buffer, failing on truncated data
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut out = 0u64;
+
+        for shift in (0..64).step_by(7) {
+            let byte = *self.bytes.get(self.pos).ok_or("Unexpected end of file")?;
+            self.pos += 1;
+            out |= ((byte & 0x7f) as u64) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(out);
+            }
+        }
+
+        Err("Invalid varint".to_string())
+    }
+
+    // Reads a checksum and compares it to that of the bytes from start to the checksum
+    fn checksum(&mut self, start: usize, section: &str) -> Result<(), String> {
+        let end = self.pos;
+        let expected = u32::from_be_bytes(self.bytes(4)?.try_into().unwrap());
+
+        if crc32(&self.bytes[start..end]) != expected {
+            return Err(format!(
+                "Checksum mismatch in {}, the book is truncated or corrupted",
+                section
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + 8)
+            .ok_or("Unexpected end of file")?;
+        self.pos += 8;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.varint()? as usize;
+        let bytes = self.bytes(len)?;
+
+        String::from_utf8(bytes.to_vec()).map_err(|_| "Invalid string".to_string())
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        let out = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or("Unexpected end of file")?;
+        self.pos += len;
+        Ok(out)
+    }
+}
+
+// Reads the root position and metadata
+fn read_header(cursor: &mut Cursor) -> Result<(Chess, Metadata), String> {
+    if cursor.bytes(4)? != MAGIC {
+        return Err("Not a packed book".to_string());
+    }
+
+    let version = cursor.bytes(1)?[0];
+    if version != VERSION {
+        return Err(format!("Unsupported packed book version {}", version));
+    }
+
+    let root = cursor.string().map_err(|_| "Invalid root fen")?;
+    let mut metadata = Metadata::default();
+
+    for _ in 0..cursor.varint()? {
+        let (key, value) = (cursor.string()?, cursor.string()?);
+        metadata.set(&key, &value);
+    }
+    cursor.checksum(0, "header")?;
+
+    if root.is_empty() {
+        return Ok((Chess::default(), metadata));
+    }
+
+    let root = root
+        .parse::<Fen>()
+        .ok()
+        .and_then(|f| f.into_position(Chess960).ok())
+        .ok_or_else(|| format!("Invalid root fen {}", root))?;
+
+    Ok((root, metadata))
+}
+
+// Reads the positions of the block starting at the cursor
+fn read_block(cursor: &mut Cursor, out: &mut Vec<(u64, BookEntry)>) -> Result<(), String> {
+    let start = cursor.pos;
+    let positions = cursor.varint()?;
+    let mut hash = 0u64;
+
+    for _ in 0..positions {
+        hash = hash.wrapping_add(cursor.varint()?);
+
+        for _ in 0..cursor.varint()? {
+            let entry = BookEntry {
+                mov: cursor.varint()? as u16,
+                weight: cursor.varint()?,
+                learn: cursor.varint()? as u32,
+                ..BookEntry::new()
+            };
+            out.push((hash, entry));
+        }
+    }
+
+    cursor.checksum(start, "block")
+}
+
+fn read_index(cursor: &mut Cursor) -> Result<Vec<(u64, u64)>, String> {
+    let start = cursor.pos;
+    let mut out = Vec::new();
+
+    for _ in 0..cursor.varint()? {
+        out.push((cursor.u64()?, cursor.u64()?));
+    }
+
+    cursor.checksum(start, "index")?;
+    Ok(out)
+}
+
+fn push_checksum(out: &mut Vec<u8>, start: usize) {
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+impl BookMap {
+    pub fn write_packed<W: Write>(&self, w: &mut W) {
+        self.assert_polyglot();
+
+        let mut hashes = self.map.keys().copied().collect::<Vec<_>>();
+        hashes.sort_unstable();
+
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+
+        let root = if book_hash(self.root.clone()) == START_HASH {
+            String::new()
+        } else {
+            fen(&self.root).to_string()
+        };
+        write_string(&mut out, &root);
+
+        let fields = self.metadata_fields();
+        write_varint(&mut out, fields.len() as u64);
+
+        for (key, value) in fields {
+            write_string(&mut out, key);
+            write_string(&mut out, &value);
+        }
+        push_checksum(&mut out, 0);
+
+        let mut index = Vec::new();
+
+        for block in hashes.chunks(BLOCK_POSITIONS) {
+            let start = out.len();
+            index.push((block[0], start as u64));
+            write_varint(&mut out, block.len() as u64);
+
+            let mut last = 0u64;
+
+            for hash in block {
+                let mut entries = self.map[hash].clone();
+                entries.sort_unstable_by_key(|e| (Reverse(e.weight), e.mov));
+
+                write_varint(&mut out, hash.wrapping_sub(last));
+                write_varint(&mut out, entries.len() as u64);
+                last = *hash;
+
+                for entry in entries {
+                    write_varint(&mut out, entry.mov as u64);
+                    write_varint(&mut out, entry.weight);
+                    write_varint(&mut out, entry.learn as u64);
+                }
+            }
+            push_checksum(&mut out, start);
+        }
+
+        let index_offset = out.len() as u64;
+        write_varint(&mut out, index.len() as u64);
+
+        for (hash, offset) in index {
+            out.extend_from_slice(&hash.to_be_bytes());
+            out.extend_from_slice(&offset.to_be_bytes());
+        }
+        push_checksum(&mut out, index_offset as usize);
+        out.extend_from_slice(&index_offset.to_be_bytes());
+
+        w.write_all(&out);
+    }
+
+    pub fn try_read_packed<R: Read>(reader: &mut R) -> Result<Self, String> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+
+        let mut cursor = Cursor {
+            bytes: &bytes,
+            pos: 0,
+        };
+        let mut out = BookMap::new();
+        (out.root, out.metadata) = read_header(&mut cursor)?;
+
+        let end = bytes.len().checked_sub(8).ok_or("Unexpected end of file")?;
+        let index_offset = u64::from_be_bytes(bytes[end..].try_into().unwrap()) as usize;
+        let mut entries = Vec::new();
+
+        while cursor.pos < index_offset.min(end) {
+            read_block(&mut cursor, &mut entries)?;
+        }
+
+        if cursor.pos != index_offset {
+            return Err("Blocks do not end at the index".to_string());
+        }
+        read_index(&mut cursor)?;
+
+        for (hash, entry) in entries {
+            out.insert(hash, entry);
+        }
+
+        out.set_depths();
+        Ok(out)
+    }
+
+    /// Reads the entries for one hash from a packed book through its index
+    pub fn probe_packed<R: Read + Seek>(
+        reader: &mut R,
+        hash: u64,
+    ) -> Result<Vec<BookEntry>, String> {
+        let io_error = |e: std::io::Error| e.to_string();
+
+        let len = reader.seek(SeekFrom::End(-8)).map_err(io_error)?;
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf).map_err(io_error)?;
+        let index_offset = u64::from_be_bytes(buf);
+
+        let mut index = vec![0u8; len.saturating_sub(index_offset) as usize];
+        reader
+            .seek(SeekFrom::Start(index_offset))
+            .map_err(io_error)?;
+        reader.read_exact(&mut index).map_err(io_error)?;
+
+        let index = read_index(&mut Cursor {
+            bytes: &index,
+            pos: 0,
+        })?;
+
+        // The block holding hash is the last starting at or below it
+        let Some(offset) = index
+            .iter()
+            .take_while(|(first, _)| *first <= hash)
+            .last()
+            .map(|x| x.1)
+        else {
+            return Ok(Vec::new());
+        };
+
+        if offset > index_offset {
+            return Err("Blocks do not end at the index".to_string());
+        }
+
+        let mut bytes = Vec::new();
+        reader.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+        reader
+            .take(index_offset - offset)
+            .read_to_end(&mut bytes)
+            .map_err(io_error)?;
+
+        let mut entries = Vec::new();
+        read_block(
+            &mut Cursor {
+                bytes: &bytes,
+                pos: 0,
+            },
+            &mut entries,
+        )?;
+
+        Ok(entries
+            .into_iter()
+            .filter(|e| e.0 == hash)
+            .map(|e| e.1)
+            .collect())
+    }
+}
+
+#[test]
+fn t_packed() {
+    let book = BookMap::read_txt(
+        &mut "3 e4, 2 e5, Nf3 40\n    c5\n70000 d4".as_bytes(),
+        MoveNotation::San,
+    );
+
+    let mut packed = Vec::new();
+    book.write_packed(&mut packed);
+
+    let read = BookMap::try_read_packed(&mut &packed[..]).unwrap();
+    assert_eq!(read.map.len(), book.map.len());
+    for (hash, entries) in &book.map {
+        let mut a = entries
+            .iter()
+            .map(|e| (e.mov, e.weight, e.learn))
+            .collect::<Vec<_>>();
+        let mut b = read.map[hash]
+            .iter()
+            .map(|e| (e.mov, e.weight, e.learn))
+            .collect::<Vec<_>>();
+        a.sort();
+        b.sort();
+        assert_eq!(a, b);
+    }
+
+    let probed = BookMap::probe_packed(&mut std::io::Cursor::new(&packed), START_HASH).unwrap();
+    assert_eq!(probed.len(), 2);
+    assert_eq!(probed[0].weight, 70000);
+    assert!(BookMap::probe_packed(&mut std::io::Cursor::new(&packed), 1)
+        .unwrap()
+        .is_empty());
+
+    assert!(BookMap::try_read_packed(&mut &packed[..packed.len() - 3]).is_err());
+
+    let mut corrupted = packed.clone();
+    corrupted[12] ^= 1;
+    assert!(BookMap::try_read_packed(&mut &corrupted[..])
+        .err()
+        .unwrap()
+        .contains("Checksum mismatch"));
+
+    // An index whose block lies past it, as in a truncated book, fails to probe
+    let mut bad_index = vec![0; 10];
+    write_varint(&mut bad_index, 1);
+    bad_index.extend_from_slice(&0u64.to_be_bytes());
+    bad_index.extend_from_slice(&1000u64.to_be_bytes());
+    push_checksum(&mut bad_index, 10);
+    bad_index.extend_from_slice(&10u64.to_be_bytes());
+    assert!(BookMap::probe_packed(&mut std::io::Cursor::new(&bad_index), 5).is_err());
+}
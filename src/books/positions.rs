@@ -0,0 +1,126 @@
+// Builds books from lists of positions, such as test positions or puzzles, by asking an engine for
+// its best moves in each of them.
+
+use super::*;
+
+use std::io::BufRead;
+
+use shakmaty::{
+    fen::{Epd, Fen},
+    CastlingMode,
+};
+
+#[cfg(feature = "engine")]
+use crate::engine::Engine;
+#[cfg(feature = "engine")]
+use shakmaty::Position;
+
+/// Reads a position per line as a fen, or as an epd whose operations are ignored, skipping empty
+/// lines
+pub fn read_positions<R: BufRead>(reader: R) -> Vec<Chess> {
+    let mut out = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.unwrap_or_else(|_| input(format!("Invalid utf-8 on line {}", i + 1)));
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let epd = || {
+            let fields = line.split_whitespace().take(4).collect::<Vec<_>>();
+            fields
+                .join(" ")
+                .parse::<Epd>()
+                .ok()?
+                .into_position(CastlingMode::Chess960)
+                .ok()
+        };
+
+        let pos = line
+            .parse::<Fen>()
+            .ok()
+            .and_then(|fen| fen.into_position(CastlingMode::Chess960).ok())
+            .or_else(epd)
+            .unwrap_or_else(|| input(format!("Invalid position on line {}: {}", i + 1, line)));
+
+        out.push(pos);
+    }
+
+    out
+}
+
+/// The engine's best multipv moves in pos as entries, with weights from multipv for the best move
+/// down to 1 and the engine's scores as learn values. Illegal engine moves are skipped.
+#[cfg(feature = "engine")]
+pub fn engine_entries(engine: &mut Engine, pos: &Chess, multipv: usize) -> Vec<BookEntry> {
+    engine
+        .analyse(pos, multipv)
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let mov = line.mov.to_move(pos).ok()?;
+            let mut entry = BookEntry {
+                mov: to_book_move(UciMove::from_chess960(&mov)),
+                weight: (multipv - i) as u64,
+                ..BookEntry::new()
+            };
+            entry.set_eval(line.score);
+            Some(entry)
+        })
+        .collect()
+}
+
+#[cfg(feature = "engine")]
+impl BookMap {
+    /// Adds the engine's best multipv moves at each of positions, replacing their book moves, with
+    /// weights from multipv for the best move down to 1 and the engine's scores as learn values.
+    /// Positions where the game is over are skipped, as are illegal engine moves. Returns the
+    /// number of positions given moves.
+    pub fn add_engine_moves(
+        &mut self,
+        engine: &mut Engine,
+        positions: &[Chess],
+        multipv: usize,
+    ) -> usize {
+        let mut count = 0;
+
+        for pos in positions {
+            if pos.is_game_over() {
+                continue;
+            }
+
+            let entries = engine_entries(engine, pos, multipv);
+
+            if !entries.is_empty() {
+                let hash = self.hash(pos);
+                self.map.insert(hash, entries);
+                count += 1;
+            }
+        }
+
+        count
+    }
+}
+
+#[test]
+fn t_read_positions() {
+    use shakmaty::Position;
+
+    let positions = read_positions(
+        "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1\n\n\
+         rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - bm Nf3; id \"open\";\n"
+            .as_bytes(),
+    );
+
+    assert_eq!(positions.len(), 2);
+    assert_eq!(positions[0].turn(), Color::Black);
+    assert_eq!(positions[1], {
+        let mut pos = Chess::default();
+        for san in ["e4", "e5"] {
+            pos.play_unchecked(&parse_move(&pos, san).unwrap());
+        }
+        pos
+    });
+}
@@ -0,0 +1,194 @@
+// Finds lines where the opponent of a repertoire side can repeat positions, which books built from
+// blitz games inherit from games drawn by repetition. A book move returning to a position earlier
+// in its line closes a cycle of book moves, which the opponent can repeat into a threefold
+// repetition as long as the repertoire side keeps to the book. The repetition is a perpetual when
+// every opponent move of the cycle gives check.
+
+use super::tree::BookTree;
+use super::txt_books::numbered_move;
+use super::*;
+
+use std::io::Write;
+
+/// A line from the root ending with a move that returns to an earlier position
+pub struct RepetitionTrap {
+    /// The moves of the line with move numbers, as in "1. Nf3", "Nf6"
+    pub moves: Vec<String>,
+    /// Whether every opponent move of the repeated part of the line gives check
+    pub perpetual: bool,
+    // Hash of the position and book move of the repertoire side's last move in the cycle
+    own_move: Option<(u64, u16)>,
+}
+
+// Finds the traps in tree by following the moves from the root depth first, where a move to a
+// position on the current line closes a cycle. Each move is followed once, and every cycle
+// reachable from the root has at least one move found closing it.
+fn find_traps(tree: &BookTree, side: Color) -> Vec<RepetitionTrap> {
+    // 0 for nodes not reached yet, 1 for those on the current line and 2 for finished ones
+    let mut state = vec![0u8; tree.len()];
+    // Each node of the current line and the index of the child being followed
+    let mut stack = vec![(tree.root(), 0)];
+    let mut out = Vec::new();
+
+    state[tree.root()] = 1;
+
+    while let Some(&(index, child)) = stack.last() {
+        let node = tree.node(index);
+
+        let Some((entry, next)) = node.children.get(child) else {
+            state[index] = 2;
+            stack.pop();
+            continue;
+        };
+
+        stack.last_mut().unwrap().1 += 1;
+
+        match state[*next] {
+            0 => {
+                state[*next] = 1;
+                stack.push((*next, 0));
+            }
+            1 => {
+                let start = stack.iter().position(|x| x.0 == *next).unwrap();
+                out.push(trap(tree, side, &stack, start, entry));
+            }
+            _ => {}
+        }
+    }
+
+    out
+}
+
+// The trap of the line through the nodes of stack, each followed by the child before its index,
+// and closed by entry from the last node back to the node at start
+fn trap(
+    tree: &BookTree,
+    side: Color,
+    stack: &[(usize, usize)],
+    start: usize,
+    closing: &BookEntry,
+) -> RepetitionTrap {
+    let mut moves = Vec::new();
+    let mut perpetual = true;
+    let mut own_move = None;
+
+    for (i, (index, child)) in stack.iter().enumerate() {
+        let node = tree.node(*index);
+        let (entry, next) = if i + 1 == stack.len() {
+            (closing, stack[start].0)
+        } else {
+            let (entry, next) = &node.children[child - 1];
+            (entry, *next)
+        };
+
+        if let Ok(mov) = from_book_move(entry.mov).to_move(&node.position) {
+            moves.push(numbered_move(&node.position, &mov, i == 0));
+        }
+
+        if i >= start {
+            if node.position.turn() == side {
+                own_move = Some((node.hash, entry.mov));
+            } else if !tree.node(next).position.is_check() {
+                perpetual = false;
+            }
+        }
+    }
+
+    RepetitionTrap {
+        moves,
+        perpetual,
+        own_move,
+    }
+}
+
+impl BookMap {
+    /// Finds the lines reachable from the root where the opponent of side can repeat positions,
+    /// with at least one for each cycle of book moves
+    pub fn repetition_traps(&self, side: Color) -> Vec<RepetitionTrap> {
+        find_traps(&self.to_tree(), side)
+    }
+
+    /// Removes the last move of side before each repetition the opponent can force, along with
+    /// the lines only reachable through them, until none are left. Returns the number of moves
+    /// removed.
+    pub fn prune_repetition_traps(&mut self, side: Color) -> usize {
+        let tree = self.to_tree();
+        let mut count = 0;
+
+        loop {
+            let moves = find_traps(&self.to_tree(), side)
+                .into_iter()
+                .filter_map(|x| x.own_move)
+                .collect::<Vec<_>>();
+
+            let before = count;
+
+            for (hash, mov) in moves {
+                if let Some(entries) = self.map.get_mut(&hash) {
+                    let len = entries.len();
+                    entries.retain(|e| e.mov != mov);
+                    count += len - entries.len();
+                }
+            }
+
+            if count == before {
+                break;
+            }
+        }
+
+        self.remove_cut_off(&tree);
+        count
+    }
+
+    /// Writes each line where the opponent of side can repeat positions, followed by totals
+    pub fn write_repetition_report<W: Write>(&self, w: &mut W, side: Color) {
+        let traps = self.repetition_traps(side);
+
+        for trap in &traps {
+            let kind = if trap.perpetual {
+                "perpetual"
+            } else {
+                "repetition"
+            };
+            writeln!(w, "{}: {}", kind, trap.moves.join(" "));
+        }
+
+        writeln!(
+            w,
+            "\n{} lines allow repetitions, {} of them by perpetual check",
+            traps.len(),
+            traps.iter().filter(|x| x.perpetual).count()
+        );
+    }
+}
+
+#[test]
+fn t_repetition_traps() {
+    let traps = |text: &str, side| {
+        BookMap::read_txt(&mut text.as_bytes(), MoveNotation::San).repetition_traps(side)
+    };
+
+    let knights = traps("Nf3, Nf6, Ng1, Ng8, Nf3\n3 e4, e5", Color::White);
+    assert_eq!(knights.len(), 1);
+    assert_eq!(knights[0].moves.join(" "), "1. Nf3 Nf6 2. Ng1 Ng8");
+    assert!(!knights[0].perpetual);
+
+    // White's queen checks from f8 and f5, while black's king moves between h8 and h7
+    let checks = "7k/6p1/7p/8/8/8/8/K4Q2 w - - 0 1\nQf8+, Kh7, Qf5+, Kh8, Qf8+";
+    let perpetual = traps(checks, Color::Black);
+    assert_eq!(
+        perpetual[0].moves.join(" "),
+        "1. Qf8+ Kh7 2. Qf5+ Kh8 3. Qf8+"
+    );
+    assert!(perpetual[0].perpetual);
+    assert!(!traps(checks, Color::White)[0].perpetual);
+
+    // White's Ng1 goes, leaving 1. Nf3 Nf6
+    let mut book = BookMap::read_txt(
+        &mut "Nf3, Nf6, Ng1, Ng8, Nf3\n3 e4, e5".as_bytes(),
+        MoveNotation::San,
+    );
+    assert_eq!(book.prune_repetition_traps(Color::White), 1);
+    assert!(book.repetition_traps(Color::White).is_empty());
+    assert_eq!(book.map.values().flatten().count(), 4);
+}
@@ -0,0 +1,263 @@
+// Scripts of transformations applied to every move of a book, for changes the other options can't
+// express. Each line of a script is "[<position>:] <action> [if <condition>]", where the action
+// is "weight = <expression>", "eval = <expression>", "learn = <expression>" or "remove", and the
+// expressions are those of src/expr.rs. eval sets the learn value as a signed eval, as eval reads
+// it, while learn sets it as the unsigned number the learn variable reads. Lines are applied in
+// order to every position reachable from the root, or only to the given position, so later lines
+// see the changes of earlier ones.
+
+use super::*;
+use crate::expr::Expr;
+
+use std::io::BufRead;
+
+/// Values of a move's position that script expressions can use besides ENTRY_VARIABLES: its
+/// number of moves, their total weight, the move's rank by weight from 1, and whether white is to
+/// move
+pub const NODE_VARIABLES: &[&str] = &["moves", "total", "rank", "white"];
+
+enum Action {
+    Weight(Expr),
+    Eval(Expr),
+    Learn(Expr),
+    Remove,
+}
+
+struct Statement {
+    // Hash of the only position the statement applies to
+    position: Option<u64>,
+    action: Action,
+    condition: Option<Expr>,
+}
+
+// The value of the variable at index i of ENTRY_VARIABLES followed by NODE_VARIABLES
+fn variable(pos: &Chess, entries: &[BookEntry], entry: &BookEntry, i: usize) -> Option<i64> {
+    if i < ENTRY_VARIABLES.len() {
+        return entry.expr_variable(i);
+    }
+
+    let value = match NODE_VARIABLES[i - ENTRY_VARIABLES.len()] {
+        "moves" => entries.len() as u64,
+        "total" => entries.iter().map(|e| e.weight).sum(),
+        "rank" => 1 + entries.iter().filter(|e| e.weight > entry.weight).count() as u64,
+        _ => (pos.turn() == Color::White) as u64,
+    };
+
+    value.try_into().ok()
+}
+
+impl Statement {
+    fn parse(book: &BookMap, text: &str) -> Result<Option<Self>, String> {
+        let text = text[..text.find(';').unwrap_or(text.len())].trim();
+        let names = ENTRY_VARIABLES
+            .iter()
+            .chain(NODE_VARIABLES)
+            .copied()
+            .collect::<Vec<_>>();
+
+        if text.is_empty() {
+            return Ok(None);
+        }
+
+        let (position, text) = match text.split_once(':') {
+            Some((position, text)) => (Some(book.hash(&book.parse_position(position)?)), text),
+            None => (None, text),
+        };
+
+        let (action, condition) = match text.split_once(" if ") {
+            Some((action, condition)) => (action.trim(), Some(Expr::parse(condition, &names)?)),
+            None => (text.trim(), None),
+        };
+
+        let action = match action.split_once('=') {
+            Some((field, expr)) if !expr.starts_with('=') => {
+                let expr = Expr::parse(expr, &names)?;
+
+                match field.trim() {
+                    "weight" => Action::Weight(expr),
+                    "eval" => Action::Eval(expr),
+                    "learn" => Action::Learn(expr),
+                    _ => return Err(format!("Unknown field {:?}", field.trim())),
+                }
+            }
+            _ if action == "remove" => Action::Remove,
+            _ => return Err(format!("Invalid action {:?}", action)),
+        };
+
+        Ok(Some(Statement {
+            position,
+            action,
+            condition,
+        }))
+    }
+
+    // Applies the statement to the entries of pos, returning how many were removed
+    fn apply(&self, pos: &Chess, entries: &mut Vec<BookEntry>) -> usize {
+        let before = entries.clone();
+        let value =
+            |expr: &Expr, entry: &BookEntry| expr.value(|i| variable(pos, &before, entry, i));
+        let matches = |entry: &BookEntry| {
+            self.condition
+                .as_ref()
+                .is_none_or(|x| x.matches(|i| variable(pos, &before, entry, i)))
+        };
+
+        match &self.action {
+            Action::Remove => {
+                let len = entries.len();
+                entries.retain(|x| !matches(x));
+                len - entries.len()
+            }
+            Action::Weight(expr) | Action::Eval(expr) | Action::Learn(expr) => {
+                for entry in entries.iter_mut().filter(|x| matches(x)) {
+                    let Some(x) = value(expr, entry) else {
+                        continue;
+                    };
+
+                    match self.action {
+                        Action::Weight(_) => entry.weight = x.max(0) as u64,
+                        Action::Eval(_) => {
+                            entry.set_eval(x.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+                        }
+                        _ => entry.learn = x.clamp(0, u32::MAX as i64) as u32,
+                    }
+                }
+                0
+            }
+        }
+    }
+}
+
+impl BookMap {
+    // Like each_node, also passing the hash of each position
+    fn each_hashed_node<F>(&mut self, mut f: F)
+    where
+        F: FnMut(u64, &Chess, &mut Vec<BookEntry>),
+    {
+        let tree = self.to_tree();
+
+        for i in 0..tree.len() {
+            let node = tree.node(i);
+
+            if let Some(entries) = self.map.get_mut(&node.hash) {
+                f(node.hash, &node.position, entries);
+            }
+        }
+    }
+
+    /// Calls f with each position reachable from the root and its entries, which it may change
+    pub fn each_node<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Chess, &mut Vec<BookEntry>),
+    {
+        self.each_hashed_node(|_, pos, entries| f(pos, entries));
+    }
+
+    /// Calls f with each entry reachable from the root and the position it is played from
+    pub fn each_entry<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Chess, &mut BookEntry),
+    {
+        self.each_node(|pos, entries| entries.iter_mut().for_each(|e| f(pos, e)));
+    }
+
+    /// The highest weighted entry for pos, if it is in the book
+    pub fn probe(&self, pos: &Chess) -> Option<&BookEntry> {
+        self.entries(pos).iter().max_by_key(|e| e.weight)
+    }
+
+    /// Removes the reachable entries for which f returns true, along with the lines only
+    /// reachable through them, and returns the number of entries removed
+    pub fn remove<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&Chess, &BookEntry) -> bool,
+    {
+        let tree = self.to_tree();
+        let mut removed = 0;
+
+        self.each_node(|pos, entries| {
+            let len = entries.len();
+            entries.retain(|e| !f(pos, e));
+            removed += len - entries.len();
+        });
+
+        self.remove_cut_off(&tree);
+        removed
+    }
+
+    /// Runs the script read from reader, returning the number of moves removed. If any line is
+    /// invalid, the book is left unchanged and the errors are returned.
+    pub fn run_script<R: BufRead>(&mut self, reader: R) -> Result<usize, Vec<String>> {
+        let mut statements = Vec::new();
+        let mut errors = Vec::new();
+
+        for (i, line) in reader.lines().enumerate() {
+            let parsed = line
+                .map_err(|e| e.to_string())
+                .and_then(|line| Statement::parse(self, &line));
+
+            match parsed {
+                Ok(Some(statement)) => statements.push(statement),
+                Ok(None) => {}
+                Err(e) => errors.push(format!("line {}: {}", i + 1, e)),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let tree = self.to_tree();
+        let mut removed = 0;
+
+        for statement in &statements {
+            self.each_hashed_node(|hash, pos, entries| {
+                if statement.position.is_none_or(|x| x == hash) {
+                    removed += statement.apply(pos, entries);
+                }
+            });
+        }
+
+        self.remove_cut_off(&tree);
+        Ok(removed)
+    }
+}
+
+#[test]
+fn t_run_script() {
+    let mut book = BookMap::read_txt(
+        &mut "5 e4\n    3 c5\n        2 Nf3, d6\n    2 e5\n    1 e6\n2 d4, d5".as_bytes(),
+        MoveNotation::San,
+    );
+
+    let script = "; double white's moves, then drop black's least played reply\n\
+                  weight = weight * 2 if white\n\
+                  remove if !white && rank == moves && moves > 1\n\
+                  e4 c5: learn = 10\n\
+                  d4: eval = eval - 50";
+    assert_eq!(book.run_script(script.as_bytes()), Ok(1));
+
+    let root = Chess::default();
+    assert_eq!(book.probe(&root).unwrap().weight, 10);
+    assert_eq!(book.map.values().flatten().count(), 7);
+    assert_eq!(
+        book.map
+            .values()
+            .flatten()
+            .filter(|e| e.learn == 10)
+            .count(),
+        1
+    );
+    // Evals below zero are kept as negative evals rather than clamped to 0
+    assert_eq!(
+        book.map
+            .values()
+            .flatten()
+            .filter(|e| e.eval() == -50)
+            .count(),
+        1
+    );
+
+    let errors = book.run_script("weight = wins +\nlearn = 1 if\ndepth = 1".as_bytes());
+    assert_eq!(errors.unwrap_err().len(), 3);
+}
@@ -0,0 +1,71 @@
+use super::*;
+
+use shakmaty::Position;
+
+impl BookMap {
+    /// Adds k to the weight of every move at positions whose moves weigh less than below in
+    /// total, so that positions reached by few games do not give all of their weight to the
+    /// moves that happened to be played. If legal is set, every legal move in positions reachable
+    /// from the root is added with weight k, instead of only the moves already in the book.
+    pub fn smooth(&mut self, k: u64, below: u64, legal: bool) {
+        if legal {
+            let mut positions = Vec::new();
+
+            self.traverse_tree(false, |_, pos, entries, ind, _| {
+                if ind == 0 && entries.iter().map(|e| e.weight).sum::<u64>() < below {
+                    positions.push(pos.clone());
+                }
+            });
+
+            for pos in positions {
+                let hash = self.hash(&pos);
+
+                for mov in pos.legal_moves() {
+                    let entry = BookEntry {
+                        mov: to_book_move(UciMove::from_chess960(&mov)),
+                        ..BookEntry::new()
+                    };
+                    self.insert(hash, entry);
+                }
+            }
+        }
+
+        self.map_nodes(|node| {
+            if node.iter().map(|e| e.weight).sum::<u64>() < below {
+                for entry in node {
+                    entry.weight += k;
+                }
+            }
+        });
+
+        self.set_depths();
+    }
+}
+
+#[test]
+fn t_smooth() {
+    let mut book = BookMap::read_txt(&mut "9 e4, 3 e5\n1 d4".as_bytes(), MoveNotation::San);
+    let root = Chess::default();
+    let e4 = "e4".parse::<San>().unwrap().to_move(&root).unwrap();
+    let after_e4 = root.clone().play(&e4).unwrap();
+
+    let weights = |book: &BookMap, pos: &Chess| {
+        let mut out = book
+            .entries(pos)
+            .iter()
+            .map(|e| e.weight)
+            .collect::<Vec<_>>();
+        out.sort();
+        out
+    };
+
+    // Only the position after e4 weighs less than 10
+    book.smooth(1, 10, false);
+    assert_eq!(weights(&book, &root), [1, 9]);
+    assert_eq!(weights(&book, &after_e4), [4]);
+
+    book.smooth(1, 100, true);
+    assert_eq!(weights(&book, &root).len(), 20);
+    assert_eq!(weights(&book, &root)[18..], [2, 10]);
+    assert_eq!(weights(&book, &after_e4).len(), 20);
+}
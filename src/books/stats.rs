@@ -0,0 +1,228 @@
+use super::tree::BookTree;
+use super::txt_books::numbered_move;
+use super::*;
+
+// The number of distinct lines of book moves from the root to each node of tree, saturating, with
+// the moves closing cycles left out so that lines don't repeat positions. Nodes are counted in
+// the reverse of the order a depth first search finishes them, in which every move but those
+// closing cycles goes to a later node.
+fn path_counts(tree: &BookTree) -> Vec<u128> {
+    let mut finished = Vec::new();
+    let mut seen = vec![false; tree.len()];
+    let mut stack = vec![(tree.root(), 0)];
+    seen[tree.root()] = true;
+
+    while let Some(&(index, child)) = stack.last() {
+        match tree.node(index).children.get(child) {
+            Some((_, next)) => {
+                stack.last_mut().unwrap().1 += 1;
+
+                if !seen[*next] {
+                    seen[*next] = true;
+                    stack.push((*next, 0));
+                }
+            }
+            None => {
+                finished.push(index);
+                stack.pop();
+            }
+        }
+    }
+
+    let mut rank = vec![usize::MAX; tree.len()];
+    for (i, index) in finished.iter().rev().enumerate() {
+        rank[*index] = i;
+    }
+
+    let mut counts = vec![0u128; tree.len()];
+    counts[tree.root()] = 1;
+
+    for &index in finished.iter().rev() {
+        for (_, child) in &tree.node(index).children {
+            if rank[*child] > rank[index] {
+                counts[*child] = counts[*child].saturating_add(counts[index]);
+            }
+        }
+    }
+
+    counts
+}
+
+impl BookMap {
+    // The positions with the most lines by counts, with a move order through each of up to three
+    // of their parents
+    fn write_most_transposed<W: Write>(&self, w: &mut W, tree: &BookTree, counts: &[u128]) {
+        // The parent and move of each node on a shortest line from the root
+        let mut from = vec![None; tree.len()];
+        let mut queue = VecDeque::from([tree.root()]);
+        let mut seen = vec![false; tree.len()];
+        seen[tree.root()] = true;
+
+        while let Some(index) = queue.pop_front() {
+            for (entry, child) in &tree.node(index).children {
+                if !seen[*child] {
+                    seen[*child] = true;
+                    from[*child] = Some((index, entry.mov));
+                    queue.push_back(*child);
+                }
+            }
+        }
+
+        let line = |mut index: usize, mov: u16| {
+            let mut moves = vec![(index, mov)];
+            while let Some(step) = from[index] {
+                moves.push(step);
+                index = step.0;
+            }
+
+            moves
+                .iter()
+                .rev()
+                .enumerate()
+                .filter_map(|(i, (index, mov))| {
+                    let pos = &tree.node(*index).position;
+                    let mov = from_book_move(*mov).to_move(pos).ok()?;
+                    Some(numbered_move(pos, &mov, i == 0))
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        let mut most = (0..tree.len())
+            .filter(|i| counts[*i] > 1 && tree.node(*i).parents.len() > 1)
+            .collect::<Vec<_>>();
+        most.sort_by_key(|i| Reverse(counts[*i]));
+
+        if most.is_empty() {
+            return;
+        }
+
+        writeln!(w, "\nMost transposed positions:");
+
+        for &index in most.iter().take(5) {
+            writeln!(w, "    {} lines:", counts[index]);
+
+            for &parent in tree.node(index).parents.iter().take(3) {
+                let Some((entry, _)) = tree.node(parent).children.iter().find(|x| x.1 == index)
+                else {
+                    continue;
+                };
+                writeln!(w, "        {}", line(parent, entry.mov));
+            }
+        }
+    }
+
+    // Positions, entries and weight at each depth reachable from the root, where the moves from
+    // positions at depth d are those played at ply d + 1
+    fn write_depth_table<W: Write>(&self, w: &mut W, max_depth: usize) {
+        let mut rows = vec![(0usize, 0usize, 0u64); max_depth + 1];
+
+        for entries in self.map.values() {
+            if let Some(depth) = entries[0].depth {
+                let row = &mut rows[depth];
+                row.0 += 1;
+                row.1 += entries.len();
+                row.2 += entries.iter().map(|e| e.weight).sum::<u64>();
+            }
+        }
+
+        writeln!(w, "\nBy ply:");
+        writeln!(
+            w,
+            "    {:>5} {:>10} {:>10} {:>14} {:>10}",
+            "ply", "positions", "entries", "weight", "branching"
+        );
+
+        for (depth, (positions, entries, weight)) in rows.into_iter().enumerate() {
+            writeln!(
+                w,
+                "    {:>5} {:>10} {:>10} {:>14} {:>10.2}",
+                depth + 1,
+                positions,
+                entries,
+                weight,
+                entries as f64 / positions as f64
+            );
+        }
+    }
+
+    pub fn write_stats<W: Write>(&mut self, w: &mut W) {
+        self.set_depths();
+
+        let entries = self.map.values().map(|v| v.len()).sum::<usize>();
+        let weight = self.map.values().flatten().map(|e| e.weight).sum::<u64>();
+        let connected = self.map.values().filter(|v| v[0].depth.is_some()).count();
+        let max_depth = self.map.values().flatten().filter_map(|e| e.depth).max();
+
+        let tree = self.to_tree();
+        let transpositions = (0..tree.len())
+            .filter(|i| tree.node(*i).parents.len() > 1)
+            .count();
+
+        let counts = path_counts(&tree);
+        let reachable = counts.iter().filter(|x| **x > 0).count();
+        let lines = counts.iter().fold(0u128, |a, b| a.saturating_add(*b));
+
+        writeln!(w, "Positions: {}", self.map.len());
+        writeln!(w, "Positions reachable from root: {}", connected);
+        writeln!(w, "Entries: {}", entries);
+        writeln!(w, "Total weight: {}", weight);
+        writeln!(w, "Positions reached by transposition: {}", transpositions);
+        writeln!(
+            w,
+            "Positions reachable by several lines: {}",
+            counts.iter().filter(|x| **x > 1).count()
+        );
+        writeln!(
+            w,
+            "Positions of the book expanded into a tree of lines: {} ({:.2} per position)",
+            lines,
+            lines as f64 / reachable.max(1) as f64
+        );
+
+        if let Some(depth) = max_depth {
+            writeln!(w, "Maximum depth: {}", depth + 1);
+            self.write_depth_table(w, depth);
+        }
+
+        self.write_most_transposed(w, &tree, &counts);
+
+        if !self.sources.is_empty() {
+            writeln!(w, "\nSource contributions:");
+
+            for (i, name) in self.sources.iter().enumerate() {
+                let contributions = self
+                    .map
+                    .values()
+                    .flatten()
+                    .filter_map(|e| e.sources.iter().find(|s| s.0 as usize == i))
+                    .collect::<Vec<_>>();
+
+                let weight = contributions.iter().map(|s| s.1).sum::<u64>();
+
+                writeln!(
+                    w,
+                    "    {}: {} entries, {} weight",
+                    name,
+                    contributions.len(),
+                    weight
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn t_path_counts() {
+    // 1. Nf3 d5 2. d4 and 1. d4 d5 2. Nf3 transpose, as do the lines after them
+    let book = BookMap::read_txt(
+        &mut "Nf3, d5, d4, Nf6, c4\nd4, d5\n    Nf3\n    c4".as_bytes(),
+        MoveNotation::San,
+    );
+    let tree = book.to_tree();
+    let counts = path_counts(&tree);
+
+    let mut sorted = counts.clone();
+    sorted.sort();
+    assert_eq!(sorted, [1, 1, 1, 1, 1, 1, 2, 2, 2]);
+}
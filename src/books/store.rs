@@ -0,0 +1,562 @@
+// Storage for the entries of books while they are built and merged. BookMap holds a book in
+// memory, while DiskStore builds books too large for memory as sorted runs on disk: entries are
+// gathered in memory up to a number of positions, then written to a run file in order of hash, and
+// the runs are merged as the book is read out, also in order of hash as .bin files store it.
+//
+// Each run file holds its positions as the 8 byte hash, 4 byte entry count and the entries, with
+// every field of an entry as little endian numbers, so that merging runs loses nothing. An existing
+// .bin book can be merged in alongside the runs, as it is also sorted by hash.
+
+use super::*;
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::error::{input, io};
+
+pub trait BookStore {
+    fn hash(&self, pos: &Chess) -> u64;
+
+    /// Plays mov, which must be legal, at pos, returning the hash of the new position given the
+    /// hash of pos
+    fn play(&self, pos: &mut Chess, hash: u64, mov: &Move) -> u64;
+
+    /// Inserts entry, merging it into an existing entry for the same move as given by options.
+    /// Returns true if it was merged into an entry with a different learn value.
+    fn insert_with(&mut self, hash: u64, entry: BookEntry, options: MergeOptions) -> bool;
+
+    fn add_source(&mut self, name: &str) -> u16;
+
+    fn insert_combine(&mut self, hash: u64, entry: BookEntry) {
+        self.insert_with(hash, entry, MergeOptions::combine());
+    }
+
+    /// Adds the moves of game to the book, multiplying the weight each move receives by scale.
+    fn add_game(&mut self, game: &PgnGame, options: &BuildOptions, scale: f64) {
+        let mut board = game.start_position();
+        let times = game.move_times();
+
+        // The ratings only change the weights of upsets, so each side's factor is found once
+        let upset = |color| match game.expected_score(color) {
+            Some(expected) if options.upsets => 2.0 * (1.0 - expected),
+            _ => 1.0,
+        };
+        let upsets = [upset(Color::White), upset(Color::Black)];
+
+        let mut next = self.hash(&board);
+
+        for (depth, sanplus) in game.moves.iter().take(options.depth).enumerate() {
+            let hash = next;
+            let color = board.turn();
+
+            let mov = sanplus.san.to_move(&board).unwrap();
+            let uci = UciMove::from_chess960(&mov);
+            // The move was found among the legal moves, so it need not be checked again, and the
+            // hash is updated with the squares it changes
+            next = self.play(&mut board, hash, &mov);
+
+            // Index into scores and results of the outcome for the side making the move
+            let result = match game.outcome {
+                Outcome::Decisive { winner } if (winner == Color::White) == (depth % 2 == 0) => 0,
+                Outcome::Decisive { .. } => 2,
+                Outcome::Draw => 1,
+            };
+
+            let score = if options.frequency {
+                1.0
+            } else {
+                options.scores[result]
+            };
+            let mut results = [0; 3];
+            results[result] = 1;
+
+            let upset = color.fold_wb(upsets[0], upsets[1]);
+            let weight = ((score * scale * upset).round() as u64).min(options.max_weight);
+
+            let elo = game.elo(color);
+            let time = times.get(depth).copied().flatten();
+
+            self.insert_combine(
+                hash,
+                BookEntry {
+                    mov: to_book_move(uci),
+                    depth: Some(depth),
+                    weight,
+                    elo_sum: elo.unwrap_or(0) as u64,
+                    elo_count: elo.is_some() as u64,
+                    time_sum: time.map_or(0, |t| (t * 1000.0).round() as u64),
+                    time_count: time.is_some() as u64,
+                    results,
+                    sources: options
+                        .source
+                        .map(|s| vec![(s, weight)])
+                        .unwrap_or_default(),
+                    ..BookEntry::new()
+                },
+            )
+        }
+    }
+}
+
+impl BookStore for BookMap {
+    fn hash(&self, pos: &Chess) -> u64 {
+        BookMap::hash(self, pos)
+    }
+
+    fn play(&self, pos: &mut Chess, hash: u64, mov: &Move) -> u64 {
+        self.hasher.play(pos, hash, mov)
+    }
+
+    fn insert_with(&mut self, hash: u64, entry: BookEntry, options: MergeOptions) -> bool {
+        BookMap::insert_with(self, hash, entry, options)
+    }
+
+    fn add_source(&mut self, name: &str) -> u16 {
+        BookMap::add_source(self, name)
+    }
+}
+
+fn write_entry<W: Write>(w: &mut W, entry: &BookEntry) {
+    let depth = entry.depth.map_or(u64::MAX, |d| d as u64);
+    w.write_all(&entry.mov.to_le_bytes());
+    w.write_all(&entry.learn.to_le_bytes());
+
+    for n in [
+        depth,
+        entry.weight,
+        entry.elo_sum,
+        entry.elo_count,
+        entry.time_sum,
+        entry.time_count,
+    ]
+    .iter()
+    .chain(&entry.results)
+    {
+        w.write_all(&n.to_le_bytes());
+    }
+
+    w.write_all(&(entry.sources.len() as u16).to_le_bytes());
+    for (source, weight) in &entry.sources {
+        w.write_all(&source.to_le_bytes());
+        w.write_all(&weight.to_le_bytes());
+    }
+}
+
+fn read_bytes<R: Read, const N: usize>(r: &mut R) -> std::io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_entry<R: Read>(r: &mut R) -> std::io::Result<BookEntry> {
+    let mut entry = BookEntry::new();
+    entry.mov = u16::from_le_bytes(read_bytes(r)?);
+    entry.learn = u32::from_le_bytes(read_bytes(r)?);
+
+    let mut read_u64 = || read_bytes(r).map(u64::from_le_bytes);
+    let depth = read_u64()?;
+    entry.depth = (depth != u64::MAX).then_some(depth as usize);
+    entry.weight = read_u64()?;
+    entry.elo_sum = read_u64()?;
+    entry.elo_count = read_u64()?;
+    entry.time_sum = read_u64()?;
+    entry.time_count = read_u64()?;
+    for result in entry.results.iter_mut() {
+        *result = read_u64()?;
+    }
+
+    for _ in 0..u16::from_le_bytes(read_bytes(r)?) {
+        let source = u16::from_le_bytes(read_bytes(r)?);
+        let weight = u64::from_le_bytes(read_bytes(r)?);
+        entry.sources.push((source, weight));
+    }
+
+    Ok(entry)
+}
+
+// A sorted file being merged, a run file or a .bin book, with its next position
+struct Run<'a> {
+    reader: BufReader<Box<dyn Read + 'a>>,
+    name: String,
+    options: MergeOptions,
+    bin: bool,
+    // How a .bin book's repeated records for a move are combined
+    duplicates: DuplicatePolicy,
+    // The first record of the position after next of a .bin book, read to find where next ends
+    pending: Option<(u64, BookEntry)>,
+    next: Option<(u64, Vec<BookEntry>)>,
+}
+
+impl Run<'_> {
+    fn advance(&mut self) {
+        if self.bin {
+            return self.advance_bin();
+        }
+
+        let (reader, name) = (&mut self.reader, &self.name);
+        let fail = |_: std::io::Error| -> ! { io(format!("Failure reading {}", name)) };
+
+        self.next = match read_bytes(reader) {
+            Ok(hash) => {
+                let count = read_bytes(reader).unwrap_or_else(|e| fail(e));
+                let entries = (0..u32::from_le_bytes(count))
+                    .map(|_| read_entry(reader).unwrap_or_else(|e| fail(e)))
+                    .collect();
+
+                Some((u64::from_le_bytes(hash), entries))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            Err(e) => fail(e),
+        };
+    }
+
+    // Reads the records of the next position of a .bin book, which are consecutive as the book is
+    // sorted by hash
+    fn advance_bin(&mut self) {
+        let mut next = self.pending.take().map(|(hash, entry)| (hash, vec![entry]));
+
+        loop {
+            let buf: [u8; 16] = match read_bytes(&mut self.reader) {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(_) => io(format!("Failure reading {}", self.name)),
+            };
+            let hash = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+            let entry = BookEntry::from_bytes(&buf[8..]);
+
+            match &mut next {
+                Some((h, entries)) if *h == hash => {
+                    let mov = entry.mov;
+                    match entries.iter_mut().find(|e| e.mov == mov) {
+                        Some(e) => {
+                            if !self.duplicates.combine(e, entry) {
+                                input(format!(
+                                    "{} repeats key {:016x} and move {}, see -repair-bin",
+                                    self.name, hash, mov
+                                ));
+                            }
+                        }
+                        None => entries.push(entry),
+                    }
+                }
+                Some((h, _)) if *h < hash => {
+                    self.pending = Some((hash, entry));
+                    break;
+                }
+                Some(_) => input(format!(
+                    "{} is not sorted by hash, see -repair-bin",
+                    self.name
+                )),
+                None => next = Some((hash, vec![entry])),
+            }
+        }
+
+        self.next = next;
+    }
+}
+
+// The next position of a run, ordered so that the heap pops the lowest hash first, and among
+// equal hashes the earliest run
+#[derive(PartialEq, Eq)]
+struct Head(u64, usize);
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.0, other.1).cmp(&(self.0, self.1))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Builds a Polyglot hashed book as sorted runs in a directory, holding at most a given number of
+/// positions in memory.
+pub struct DiskStore {
+    dir: PathBuf,
+    limit: usize,
+    buffer: BookMap,
+    // The options the entries in buffer were inserted with, as runs are merged with a single one
+    options: Option<MergeOptions>,
+    runs: Vec<(PathBuf, MergeOptions)>,
+}
+
+impl DiskStore {
+    pub fn new<P: AsRef<Path>>(dir: P, limit: usize) -> Self {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .unwrap_or_else(|_| io(format!("Failure creating directory {}", dir.display())));
+
+        DiskStore {
+            dir,
+            limit: limit.max(1),
+            buffer: BookMap::new(),
+            options: None,
+            runs: Vec::new(),
+        }
+    }
+
+    /// The number of run files written so far
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    // Writes the buffered positions to a new run file in order of hash
+    fn spill(&mut self) {
+        let Some(options) = self.options.take() else {
+            return;
+        };
+
+        let path = self.dir.join(format!(
+            "run-{}-{}.tmp",
+            std::process::id(),
+            self.runs.len()
+        ));
+        let file = File::create(&path)
+            .unwrap_or_else(|_| io(format!("Failure creating file {}", path.display())));
+        let mut w = BufWriter::new(file);
+
+        let mut positions = std::mem::take(&mut self.buffer.map)
+            .into_iter()
+            .collect::<Vec<_>>();
+        positions.sort_unstable_by_key(|x| x.0);
+
+        for (hash, entries) in positions {
+            w.write_all(&hash.to_le_bytes());
+            w.write_all(&(entries.len() as u32).to_le_bytes());
+
+            for entry in &entries {
+                write_entry(&mut w, entry);
+            }
+        }
+
+        w.flush()
+            .unwrap_or_else(|_| io(format!("Failure writing file {}", path.display())));
+        self.runs.push((path, options));
+    }
+
+    /// Reads a .bin file into the store, merging entries as given by options
+    pub fn extend_from_reader_with<R: Read>(&mut self, reader: &mut R, options: MergeOptions) {
+        let mut buf = [0u8; 16];
+
+        while let Ok(()) = reader.read_exact(&mut buf[..]) {
+            let hash = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+            let entry = BookEntry::from_bytes(&buf[8..]);
+
+            self.insert_with(hash, entry, options);
+        }
+    }
+
+    /// Calls f with the hash and entries of each position in order of hash, merging the entries
+    /// that runs have for the same move in the order they were inserted. Returns the number of
+    /// positions.
+    pub fn merge_runs<F: FnMut(u64, Vec<BookEntry>)>(self, f: F) -> usize {
+        self.merge_sources(None, f)
+    }
+
+    /// Like merge_runs, with the .bin book read from base, which must be sorted by hash, merged in
+    /// before the runs as an earlier book, combining its repeated records for a move as given by
+    /// duplicates. The book is read a position at a time alongside the runs, so that entries can
+    /// be added to a book too large for memory without loading it.
+    pub fn merge_runs_into<R: Read, F: FnMut(u64, Vec<BookEntry>)>(
+        self,
+        base: R,
+        name: &str,
+        duplicates: DuplicatePolicy,
+        f: F,
+    ) -> usize {
+        self.merge_sources(Some((Box::new(base), name, duplicates)), f)
+    }
+
+    fn merge_sources<'a, F: FnMut(u64, Vec<BookEntry>)>(
+        mut self,
+        base: Option<(Box<dyn Read + 'a>, &str, DuplicatePolicy)>,
+        mut f: F,
+    ) -> usize {
+        self.spill();
+
+        let base = base.map(|(reader, name, duplicates)| Run {
+            reader: BufReader::new(reader),
+            name: name.to_string(),
+            options: MergeOptions::combine(),
+            bin: true,
+            duplicates,
+            pending: None,
+            next: None,
+        });
+        let mut runs = base
+            .into_iter()
+            .chain(self.runs.iter().map(|(path, options)| {
+                let file = File::open(path)
+                    .unwrap_or_else(|_| io(format!("Failure reading {}", path.display())));
+                Run {
+                    reader: BufReader::new(Box::new(file) as Box<dyn Read>),
+                    name: path.display().to_string(),
+                    options: *options,
+                    bin: false,
+                    duplicates: DuplicatePolicy::First,
+                    pending: None,
+                    next: None,
+                }
+            }))
+            .collect::<Vec<_>>();
+        for run in &mut runs {
+            run.advance();
+        }
+
+        let mut heap = runs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, run)| Some(Head(run.next.as_ref()?.0, i)))
+            .collect::<BinaryHeap<_>>();
+
+        let mut count = 0;
+
+        while let Some(Head(hash, i)) = heap.pop() {
+            let (_, mut entries) = runs[i].next.take().unwrap();
+            runs[i].advance();
+            if let Some((next, _)) = &runs[i].next {
+                heap.push(Head(*next, i));
+            }
+
+            // Later runs with the same position are merged in as later books are
+            while heap.peek().is_some_and(|head| head.0 == hash) {
+                let Head(_, j) = heap.pop().unwrap();
+                let (_, other) = runs[j].next.take().unwrap();
+
+                for entry in other {
+                    match entries.iter_mut().find(|e| e.mov == entry.mov) {
+                        Some(e) => {
+                            e.merge_with(&entry, runs[j].options);
+                        }
+                        None => entries.push(entry),
+                    }
+                }
+
+                runs[j].advance();
+                if let Some((next, _)) = &runs[j].next {
+                    heap.push(Head(*next, j));
+                }
+            }
+
+            count += 1;
+            f(hash, entries);
+        }
+
+        count
+    }
+}
+
+impl BookStore for DiskStore {
+    fn hash(&self, pos: &Chess) -> u64 {
+        self.buffer.hash(pos)
+    }
+
+    fn play(&self, pos: &mut Chess, hash: u64, mov: &Move) -> u64 {
+        self.buffer.play(pos, hash, mov)
+    }
+
+    fn insert_with(&mut self, hash: u64, entry: BookEntry, options: MergeOptions) -> bool {
+        if self.options.is_some_and(|o| o != options) || self.buffer.len() >= self.limit {
+            self.spill();
+        }
+
+        self.options = Some(options);
+        self.buffer.insert_with(hash, entry, options)
+    }
+
+    fn add_source(&mut self, name: &str) -> u16 {
+        self.buffer.add_source(name)
+    }
+}
+
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        for (path, _) in &self.runs {
+            fs::remove_file(path);
+        }
+    }
+}
+
+#[test]
+fn t_disk_store() {
+    let dir = std::env::temp_dir().join(format!("rustyglot-store-{}", std::process::id()));
+    let games = read_games(
+        PgnFilter::new(),
+        "1. e4 e5 2. Nf3 *\n\n1. e4 c5 *\n\n1. d4 d5 *\n\n1. e4 e5 2. Nc3 *".as_bytes(),
+    );
+
+    let mut book = BookMap::new();
+    let mut store = DiskStore::new(&dir, 2);
+    for game in &games {
+        book.add_game(game, &BuildOptions::new(), 1.0);
+        BookStore::add_game(&mut store, game, &BuildOptions::new(), 1.0);
+    }
+
+    // A later book with a different merge policy goes to its own runs
+    let options = MergeOptions {
+        weight: MergePolicy::Max,
+        learn: LearnPolicy::KeepFirst,
+    };
+    let mut other = Vec::new();
+    BookMap::read_txt(&mut "5 e4\n2 d4".as_bytes(), MoveNotation::San)
+        .write(&mut other, BinOrder::Stable);
+    book.extend_from_reader_with(&mut &other[..], options);
+    store.extend_from_reader_with(&mut &other[..], options);
+    assert!(store.runs() >= 2);
+
+    let mut merged = Vec::new();
+    let count = store.merge_runs(|hash, entries| merged.push((hash, entries)));
+
+    assert_eq!(count, book.len());
+    assert!(merged.windows(2).all(|x| x[0].0 < x[1].0));
+    for (hash, entries) in &merged {
+        let mut expected = book.map[hash].clone();
+        let mut entries = entries.clone();
+        expected.sort();
+        entries.sort();
+        assert_eq!(entries, expected);
+    }
+
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    fs::remove_dir(&dir);
+}
+
+#[test]
+fn t_merge_runs_into() {
+    let dir = std::env::temp_dir().join(format!("rustyglot-into-{}", std::process::id()));
+    let mut base = Vec::new();
+    BookMap::read_txt(&mut "5 e4\n    3 e5\n3 d4".as_bytes(), MoveNotation::San)
+        .write(&mut base, BinOrder::Stable);
+    let mut book = BookMap::new();
+    book.extend_from_reader_with(&mut &base[..], MergeOptions::combine());
+
+    let mut other = Vec::new();
+    BookMap::read_txt(&mut "2 e4\n    4 c5\n1 c4".as_bytes(), MoveNotation::San)
+        .write(&mut other, BinOrder::Stable);
+    let mut store = DiskStore::new(&dir, 1);
+    store.extend_from_reader_with(&mut &other[..], MergeOptions::combine());
+    book.extend_from_reader_with(&mut &other[..], MergeOptions::combine());
+
+    let mut merged = Vec::new();
+    let count = store.merge_runs_into(
+        &base[..],
+        "base",
+        DuplicatePolicy::Error,
+        |hash, entries| merged.push((hash, entries)),
+    );
+
+    assert_eq!(count, book.len());
+    for (hash, entries) in &mut merged {
+        let mut expected = book.map[hash].clone();
+        expected.sort();
+        entries.sort();
+        assert_eq!(*entries, expected);
+    }
+    fs::remove_dir(&dir);
+}
@@ -0,0 +1,134 @@
+use super::*;
+
+pub struct Opening {
+    pub moves: Vec<SanPlus>,
+    pub position: Chess,
+    /// Probability of reaching the position when playing moves in proportion to their weights
+    pub share: f64,
+    /// Eval of the final position from white's point of view, taken from the last move's learn
+    pub eval: i32,
+}
+
+impl BookMap {
+    /// Collects every line of exactly `plies` moves from the root, keeping only the most popular
+    /// line to each final position, sorted from most to least popular.
+    ///
+    /// Lines are extended a ply at a time, keeping only the most popular line to each position at
+    /// every ply, as the most popular line to a final position extends the most popular line to
+    /// the position before it. This bounds the work by positions times plies even in books full of
+    /// transpositions, whose lines grow exponentially with the number of plies.
+    pub fn openings(&self, plies: usize) -> Vec<Opening> {
+        let root = Opening {
+            moves: Vec::new(),
+            position: self.root.clone(),
+            share: 1.0,
+            eval: 0,
+        };
+        let mut layer = vec![root];
+
+        for _ in 0..plies {
+            let mut next = PositionMap::<Opening>::default();
+
+            for opening in layer {
+                let pos = &opening.position;
+                let entries = match self.map.get(&self.hash(pos)) {
+                    Some(entries) => entries,
+                    None => continue,
+                };
+
+                let total = entries.iter().map(|e| e.weight).sum::<u64>();
+
+                for entry in entries {
+                    let entry_share = if total == 0 {
+                        1.0 / entries.len() as f64
+                    } else {
+                        entry.weight as f64 / total as f64
+                    };
+                    let share = opening.share * entry_share;
+
+                    let mov = from_book_move(entry.mov).to_move(pos).unwrap();
+                    let position = pos.clone().play(&mov).unwrap();
+                    let hash = self.hash(&position);
+
+                    if next.get(&hash).is_some_and(|x| x.share >= share) {
+                        continue;
+                    }
+
+                    let mut moves = opening.moves.clone();
+                    moves.push(SanPlus::from_move(pos.clone(), &mov));
+
+                    let eval = pos.turn().fold_wb(entry.eval(), -entry.eval());
+
+                    next.insert(
+                        hash,
+                        Opening {
+                            moves,
+                            position,
+                            share,
+                            eval,
+                        },
+                    );
+                }
+            }
+
+            layer = next.into_values().collect();
+        }
+
+        let mut out = layer;
+        out.sort_by(|a, b| b.share.partial_cmp(&a.share).unwrap());
+        out
+    }
+}
+
+#[test]
+fn t_openings() {
+    let book = BookMap::read_txt(
+        &mut "3 Nf3\n    Nf6\n        Nc3\n1 Nc3\n    Nf6\n        Nf3\n        e4".as_bytes(),
+        MoveNotation::San,
+    );
+    let openings = book.openings(3);
+    let lines = openings
+        .iter()
+        .map(|x| {
+            x.moves
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(lines, ["Nf3 Nf6 Nc3", "Nc3 Nf6 e4"]);
+    assert_eq!(openings[0].share, 0.75);
+    assert_eq!(openings[1].share, 0.125);
+
+    // Knights moving back and forth reach few positions by very many lines
+    let mut book = BookMap::new();
+    let squares = ["b1", "c3", "g1", "f3", "b8", "c6", "g8", "f6"];
+    let mut stack = vec![book.root().clone()];
+
+    while let Some(pos) = stack.pop() {
+        let hash = book.hash(&pos);
+        if book.map.contains_key(&hash) {
+            continue;
+        }
+
+        for mov in pos.legal_moves() {
+            let uci = UciMove::from_standard(&mov).to_string();
+            if squares.contains(&&uci[..2]) && squares.contains(&&uci[2..]) {
+                let entry = BookEntry {
+                    mov: to_book_move(UciMove::from_standard(&mov)),
+                    weight: 1,
+                    ..BookEntry::new()
+                };
+                book.insert(hash, entry);
+                stack.push(pos.clone().play(&mov).unwrap());
+            }
+        }
+    }
+
+    // Each knight is on one of two squares, and after 40 plies each side has made an even number
+    // of knight moves, which leaves two of the four placements of each side's knights
+    assert_eq!(book.len(), 16);
+    assert_eq!(book.openings(40).len(), 4);
+}
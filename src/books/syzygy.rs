@@ -0,0 +1,89 @@
+// Finds book moves walking into positions that tablebases show lost for the side playing them,
+// such as gambit lines simplifying into lost endgames. Tablebases are probed by a uci engine
+// pointed at them through its SyzygyPath option, which then reports tablebase results as scores
+// beyond any evaluation, as Stockfish reports wins as 20000 centipawns less the plies to reach
+// them, or as mates.
+
+use super::*;
+use crate::engine::Engine;
+
+use shakmaty::Position;
+
+// Scores at least this far from zero are taken as tablebase results
+const TABLEBASE_SCORE: i32 = 19000;
+
+pub struct TablebaseLoss {
+    pub position: Chess,
+    pub mov: SanPlus,
+    /// Score of the position after the move for the side playing it
+    pub score: i32,
+}
+
+impl BookMap {
+    /// Probes the position after each move reachable from the root that leaves at most
+    /// max_pieces pieces, removing the moves reaching tablebase losses along with the lines only
+    /// reachable through them. When flag is set, the moves are kept with the score of the loss
+    /// as their learn value instead.
+    pub fn prune_tablebase_losses(
+        &mut self,
+        engine: &mut Engine,
+        max_pieces: usize,
+        flag: bool,
+    ) -> Vec<TablebaseLoss> {
+        let tree = self.to_tree();
+        let mut scores = PositionMap::default();
+        let mut out = Vec::new();
+        // The position and move of each loss
+        let mut losses = Vec::new();
+
+        for index in 0..tree.len() {
+            let node = tree.node(index);
+
+            for (entry, child) in &node.children {
+                let child = tree.node(*child);
+
+                if child.position.board().occupied().count() > max_pieces
+                    || child.position.is_game_over()
+                {
+                    continue;
+                }
+
+                let score = *scores.entry(child.hash).or_insert_with(|| {
+                    engine
+                        .analyse(&child.position, 1)
+                        .first()
+                        .map_or(0, |line| line.score)
+                });
+
+                if score >= TABLEBASE_SCORE {
+                    let mov = from_book_move(entry.mov).to_move(&node.position).unwrap();
+
+                    out.push(TablebaseLoss {
+                        position: node.position.clone(),
+                        mov: SanPlus::from_move(node.position.clone(), &mov),
+                        score: -score,
+                    });
+                    losses.push((node.hash, entry.mov, -score));
+                }
+            }
+        }
+
+        for (hash, mov, score) in losses {
+            let entries = self.map.get_mut(&hash).unwrap();
+
+            if flag {
+                for entry in entries.iter_mut().filter(|e| e.mov == mov) {
+                    entry.set_eval(score);
+                }
+            } else {
+                entries.retain(|e| e.mov != mov);
+            }
+        }
+
+        if !flag {
+            self.remove_cut_off(&tree);
+        }
+
+        out
+    }
+}
@@ -0,0 +1,221 @@
+// Renders the first plies of a book as a classical opening table, with a column for each ply and
+// a row for each line. Moves a row shares with the row above are left blank, so that each row
+// reads as a branch from the one before it.
+
+use super::*;
+
+use shakmaty::Position;
+
+pub struct TableRow {
+    pub moves: Vec<SanPlus>,
+    /// Probability of the line when playing moves in proportion to their weights
+    pub share: f64,
+}
+
+// The label of the column for each ply, as "1." for white moves and "1..." for black moves
+fn headers(root: &Chess, plies: usize) -> Vec<String> {
+    let mut turn = root.turn();
+    let mut number = root.fullmoves().get();
+
+    (0..plies)
+        .map(|_| {
+            let out = turn.fold_wb(format!("{}.", number), format!("{}...", number));
+
+            if turn == Color::Black {
+                number += 1;
+            }
+            turn = !turn;
+            out
+        })
+        .collect()
+}
+
+// The cells of each row, with moves shared with the row above left empty
+fn cells(rows: &[TableRow], plies: usize) -> Vec<Vec<String>> {
+    let mut out = Vec::new();
+    let mut last: &[SanPlus] = &[];
+
+    for row in rows {
+        let shared = row
+            .moves
+            .iter()
+            .zip(last)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let mut cells = (0..plies)
+            .map(|i| match row.moves.get(i) {
+                Some(mov) if i >= shared => mov.to_string(),
+                _ => String::new(),
+            })
+            .collect::<Vec<_>>();
+        cells.push(format!("{:.1}%", row.share * 100.0));
+
+        out.push(cells);
+        last = &row.moves;
+    }
+
+    out
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl BookMap {
+    /// The lines of up to plies moves from the root, following moves from most to least weight.
+    /// Moves whose line share falls below min_share are left out, ending the line before them.
+    pub fn opening_table(&self, plies: usize, min_share: f64) -> Vec<TableRow> {
+        let mut out = Vec::new();
+        let mut stack = vec![(self.root.clone(), Vec::new(), 1.0)];
+
+        while let Some((pos, moves, share)) = stack.pop() {
+            let mut entries = if moves.len() < plies {
+                self.entries(&pos).to_vec()
+            } else {
+                Vec::new()
+            };
+            let total = entries.iter().map(|e| e.weight).sum::<u64>();
+            let count = entries.len();
+
+            entries.sort_unstable_by_key(|e| (Reverse(e.weight), e.mov));
+
+            let mut children = Vec::new();
+
+            for entry in entries {
+                let Ok(mov) = from_book_move(entry.mov).to_move(&pos) else {
+                    continue;
+                };
+
+                let entry_share = if total == 0 {
+                    1.0 / count as f64
+                } else {
+                    entry.weight as f64 / total as f64
+                };
+
+                if share * entry_share < min_share {
+                    continue;
+                }
+
+                let mut moves = moves.clone();
+                moves.push(SanPlus::from_move(pos.clone(), &mov));
+                children.push((pos.clone().play(&mov).unwrap(), moves, share * entry_share));
+            }
+
+            if children.is_empty() {
+                if !moves.is_empty() {
+                    out.push(TableRow { moves, share });
+                }
+            } else {
+                // Pushed in reverse so that the heaviest line is popped first
+                stack.extend(children.into_iter().rev());
+            }
+        }
+
+        out
+    }
+
+    pub fn write_table<W: Write>(&self, w: &mut W, rows: &[TableRow], plies: usize) {
+        let plies = plies.min(rows.iter().map(|r| r.moves.len()).max().unwrap_or(0));
+
+        let mut header = headers(&self.root, plies);
+        header.push("%".to_string());
+        let mut cells = cells(rows, plies);
+        cells.insert(0, header);
+
+        let widths = (0..=plies)
+            .map(|i| {
+                cells
+                    .iter()
+                    .map(|row| row[i].chars().count())
+                    .max()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        for row in &cells {
+            let mut line = String::new();
+
+            for (i, cell) in row.iter().enumerate() {
+                if i == plies {
+                    line += &format!("{:>1$}", cell, widths[i]);
+                } else {
+                    line += &format!("{:<1$}  ", cell, widths[i]);
+                }
+            }
+
+            writeln!(w, "{}", line.trim_end());
+        }
+    }
+
+    pub fn write_table_html<W: Write>(&self, w: &mut W, rows: &[TableRow], plies: usize) {
+        let plies = plies.min(rows.iter().map(|r| r.moves.len()).max().unwrap_or(0));
+        let title = self.metadata.title.as_deref().unwrap_or("Opening table");
+
+        writeln!(w, "<!DOCTYPE html>");
+        writeln!(w, "<html>");
+        writeln!(w, "<head>");
+        writeln!(w, "<meta charset=\"utf-8\">");
+        writeln!(w, "<title>{}</title>", escape_html(title));
+        writeln!(
+            w,
+            "<style>td, th {{ padding: 0 0.6em; text-align: left; }} td.share {{ text-align: right; }}</style>"
+        );
+        writeln!(w, "</head>");
+        writeln!(w, "<body>");
+        writeln!(w, "<table>");
+
+        write!(w, "<thead><tr>");
+        for header in headers(&self.root, plies) {
+            write!(w, "<th>{}</th>", header);
+        }
+        writeln!(w, "<th>%</th></tr></thead>");
+
+        writeln!(w, "<tbody>");
+        for row in cells(rows, plies) {
+            write!(w, "<tr>");
+            for (i, cell) in row.iter().enumerate() {
+                if i == plies {
+                    write!(w, "<td class=\"share\">{}</td>", cell);
+                } else {
+                    write!(w, "<td>{}</td>", escape_html(cell));
+                }
+            }
+            writeln!(w, "</tr>");
+        }
+        writeln!(w, "</tbody>");
+
+        writeln!(w, "</table>");
+        writeln!(w, "</body>");
+        writeln!(w, "</html>");
+    }
+}
+
+#[test]
+fn t_opening_table() {
+    let book = BookMap::read_txt(
+        &mut "3 e4\n    3 c5, Nf3\n    e5\n1 d4, d5".as_bytes(),
+        MoveNotation::San,
+    );
+
+    let rows = book.opening_table(2, 0.0);
+    let lines = rows
+        .iter()
+        .map(|r| r.moves.iter().map(|m| m.to_string()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(lines, [["e4", "c5"], ["e4", "e5"], ["d4", "d5"]]);
+    assert!((rows[0].share - 0.5625).abs() < 1e-9);
+
+    let mut out = Vec::new();
+    book.write_table(&mut out, &rows, 2);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "1.  1...      %\ne4  c5    56.2%\n    e5    18.8%\nd4  d5    25.0%\n"
+    );
+
+    assert_eq!(book.opening_table(2, 0.2).len(), 2);
+    assert_eq!(book.opening_table(4, 0.3).len(), 1);
+}
@@ -0,0 +1,259 @@
+use super::*;
+
+use std::collections::hash_map::Entry;
+use std::rc::Rc;
+
+pub struct TreeNode {
+    pub hash: u64,
+    pub position: Chess,
+    /// Each entry with the index of the node it leads to
+    pub children: Vec<(BookEntry, usize)>,
+    pub parents: Vec<usize>,
+}
+
+/// A book with explicit links between positions. Transpositions share a node, so the links form
+/// a DAG, which may still contain cycles through repeated positions.
+pub struct BookTree {
+    nodes: Vec<TreeNode>,
+    index: PositionMap<usize>,
+    sources: Vec<String>,
+    hasher: Rc<dyn PositionHasher>,
+}
+
+impl BookTree {
+    fn with_root(book: &BookMap) -> Self {
+        let mut out = BookTree {
+            nodes: Vec::new(),
+            index: PositionMap::default(),
+            sources: book.sources.clone(),
+            hasher: book.hasher.clone(),
+        };
+
+        out.add_node(book.root.clone());
+        out
+    }
+
+    // Returns the index of the node for pos, and whether it was newly created
+    fn add_node(&mut self, pos: Chess) -> (usize, bool) {
+        let hash = self.hasher.hash(&pos);
+
+        match self.index.entry(hash) {
+            Entry::Occupied(e) => (*e.get(), false),
+            Entry::Vacant(e) => {
+                e.insert(self.nodes.len());
+                self.nodes.push(TreeNode {
+                    hash,
+                    position: pos,
+                    children: Vec::new(),
+                    parents: Vec::new(),
+                });
+                (self.nodes.len() - 1, true)
+            }
+        }
+    }
+
+    // Adds entry below node unless it already has that move, creating the child node if needed,
+    // and returns the index of the child if it was created.
+    fn add_child(&mut self, node: usize, entry: BookEntry) -> Option<usize> {
+        if self.nodes[node]
+            .children
+            .iter()
+            .any(|c| c.0.mov == entry.mov)
+        {
+            return None;
+        }
+
+        let pos = &self.nodes[node].position;
+        let mov = from_book_move(entry.mov).to_move(pos).ok()?;
+        let (child, created) = self.add_node(pos.clone().play(&mov).unwrap());
+
+        self.nodes[node].children.push((entry, child));
+        self.nodes[child].parents.push(node);
+
+        if created {
+            Some(child)
+        } else {
+            None
+        }
+    }
+
+    pub fn root(&self) -> usize {
+        0
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn node(&self, index: usize) -> &TreeNode {
+        &self.nodes[index]
+    }
+
+    pub fn node_mut(&mut self, index: usize) -> &mut TreeNode {
+        &mut self.nodes[index]
+    }
+
+    /// Changes the hasher used for the nodes' hashes and for books made from the tree
+    pub fn set_hasher(&mut self, hasher: Rc<dyn PositionHasher>) {
+        self.index.clear();
+
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            node.hash = hasher.hash(&node.position);
+            self.index.insert(node.hash, i);
+        }
+        self.hasher = hasher;
+    }
+
+    pub fn find(&self, hash: u64) -> Option<usize> {
+        self.index.get(&hash).copied()
+    }
+
+    /// Returns the node reached by playing mov from node, if it is in the book
+    pub fn child(&self, index: usize, mov: u16) -> Option<usize> {
+        self.nodes[index]
+            .children
+            .iter()
+            .find(|c| c.0.mov == mov)
+            .map(|c| c.1)
+    }
+
+    /// Indices of the nodes reachable from index, including itself, each listed once
+    pub fn descendants(&self, index: usize) -> Vec<usize> {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut out = vec![index];
+        let mut i = 0;
+
+        seen[index] = true;
+
+        while i < out.len() {
+            for (_, child) in &self.nodes[out[i]].children {
+                if !seen[*child] {
+                    seen[*child] = true;
+                    out.push(*child);
+                }
+            }
+            i += 1;
+        }
+
+        out
+    }
+
+    /// Copies the part of the book reachable from index into a book rooted at its position
+    pub fn subtree(&self, index: usize) -> BookMap {
+        let mut out = BookMap::new();
+        out.root = self.nodes[index].position.clone();
+        out.sources = self.sources.clone();
+        out.hasher = self.hasher.clone();
+
+        for i in self.descendants(index) {
+            for (entry, _) in &self.nodes[i].children {
+                out.insert(self.nodes[i].hash, entry.clone());
+            }
+        }
+
+        out.set_depths();
+        out
+    }
+
+    /// Adds the lines of other below the node at index, which must hold other's root position.
+    /// Moves already in the tree keep their entries.
+    pub fn graft(&mut self, index: usize, other: &BookTree) {
+        assert_eq!(
+            self.nodes[index].hash,
+            self.hasher.hash(&other.nodes[other.root()].position),
+            "Grafted tree must start from the position it is grafted onto"
+        );
+
+        let mut mapping = vec![None; other.nodes.len()];
+        mapping[other.root()] = Some(index);
+
+        let mut stack = vec![other.root()];
+
+        while let Some(i) = stack.pop() {
+            let node = mapping[i].unwrap();
+
+            for (entry, child) in &other.nodes[i].children {
+                self.add_child(node, entry.clone());
+
+                if mapping[*child].is_none() {
+                    mapping[*child] = self.child(node, entry.mov);
+                    stack.push(*child);
+                }
+            }
+        }
+    }
+
+    pub fn to_map(&self) -> BookMap {
+        self.subtree(self.root())
+    }
+}
+
+impl BookMap {
+    /// Builds the tree of positions reachable from the root
+    pub fn to_tree(&self) -> BookTree {
+        let mut out = BookTree::with_root(self);
+        let mut stack = vec![out.root()];
+
+        while let Some(node) = stack.pop() {
+            for entry in self.map.get(&out.nodes[node].hash).into_iter().flatten() {
+                if let Some(child) = out.add_child(node, entry.clone()) {
+                    stack.push(child);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[test]
+fn t_book_tree() {
+    let book = BookMap::read_txt(
+        &mut "e4, e5, Nf3, Nc6\nNf3, Nc6, e4, e5\n    d4".as_bytes(),
+        MoveNotation::San,
+    );
+    let tree = book.to_tree();
+
+    // Both move orders reach the position after 1.e4 e5 2.Nf3 Nc6
+    assert_eq!(tree.len(), 9);
+    let end = tree
+        .descendants(tree.root())
+        .into_iter()
+        .find(|i| tree.node(*i).parents.len() == 2);
+    assert!(end.is_some());
+    assert_eq!(tree.to_map().map, book.map);
+
+    let e4 = tree
+        .child(tree.root(), book.map[&START_HASH][0].mov)
+        .unwrap();
+    let sub = tree.subtree(e4);
+    assert_eq!(sub.map.values().flatten().count(), 4);
+
+    let mut grafted = BookMap::read_txt(&mut "e4".as_bytes(), MoveNotation::San).to_tree();
+    grafted.graft(1, &sub.to_tree());
+    assert_eq!(grafted.to_map().map.values().flatten().count(), 5);
+}
+
+#[test]
+fn t_set_hasher() {
+    struct Flipped;
+
+    impl PositionHasher for Flipped {
+        fn name(&self) -> &'static str {
+            "flipped"
+        }
+
+        fn hash(&self, pos: &Chess) -> u64 {
+            !book_hash(pos.clone())
+        }
+    }
+
+    let mut book = BookMap::read_txt(&mut "e4, e5\nd4".as_bytes(), MoveNotation::San);
+    let original = book.map.clone();
+
+    book.set_hasher(Rc::new(Flipped));
+    assert!(book.map.contains_key(&!START_HASH));
+
+    book.set_hasher(Rc::new(PolyglotHasher));
+    assert_eq!(book.map, original);
+}
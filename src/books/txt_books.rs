@@ -1,18 +1,131 @@
+use super::checksum::crc32;
 use super::*;
 
-use std::cmp::Reverse;
 use std::io::{BufRead, Write};
 
 use serde::de::Deserialize;
 use serde_json::Value;
-use shakmaty::{fen::Fen, EnPassantMode};
+use shakmaty::{fen::Fen, EnPassantMode, Move};
 
-fn fen(pos: &Chess) -> Fen {
+// Blobs start with a comment naming the format and its version
+pub(super) const BLOB_MAGIC: &str = "; rustyglot blob";
+pub(super) const BLOB_VERSION: &str = "1";
+
+pub(super) fn fen(pos: &Chess) -> Fen {
     Fen::from_position(pos.clone(), EnPassantMode::Legal)
 }
 
+// The move as written in a line of moves, numbered if it is white's or the first of the line
+pub(super) fn numbered_move(pos: &Chess, mov: &Move, first: bool) -> String {
+    let san = SanPlus::from_move(pos.clone(), mov);
+
+    match pos.turn() {
+        Color::White => format!("{}. {}", pos.fullmoves(), san),
+        Color::Black if first => format!("{}... {}", pos.fullmoves(), san),
+        Color::Black => san.to_string(),
+    }
+}
+
+/// Notation used for moves in tree files
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MoveNotation {
+    San,
+    Uci,
+    /// San with another language's piece letters, or figurines. Trees written this way are for
+    /// reading by people, and are read back as english san.
+    LocalSan(SanStyle),
+}
+
+impl MoveNotation {
+    fn format(self, pos: &Chess, mov: &Move) -> String {
+        match self {
+            MoveNotation::San => SanPlus::from_move(pos.clone(), mov).to_string(),
+            MoveNotation::Uci => UciMove::from_standard(mov).to_string(),
+            MoveNotation::LocalSan(style) => {
+                style.localize(&SanPlus::from_move(pos.clone(), mov).to_string())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SanStyle {
+    English,
+    Figurine,
+    German,
+    French,
+    Spanish,
+    Italian,
+    Dutch,
+}
+
+impl SanStyle {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "english" => SanStyle::English,
+            "figurine" => SanStyle::Figurine,
+            "german" => SanStyle::German,
+            "french" => SanStyle::French,
+            "spanish" => SanStyle::Spanish,
+            "italian" => SanStyle::Italian,
+            "dutch" => SanStyle::Dutch,
+            _ => return None,
+        })
+    }
+
+    // Symbols for the king, queen, rook, bishop and knight
+    fn pieces(self) -> [char; 5] {
+        match self {
+            SanStyle::English => ['K', 'Q', 'R', 'B', 'N'],
+            SanStyle::Figurine => ['♔', '♕', '♖', '♗', '♘'],
+            SanStyle::German => ['K', 'D', 'T', 'L', 'S'],
+            SanStyle::French => ['R', 'D', 'T', 'F', 'C'],
+            SanStyle::Spanish | SanStyle::Italian => ['R', 'D', 'T', 'A', 'C'],
+            SanStyle::Dutch => ['K', 'D', 'T', 'L', 'P'],
+        }
+    }
+
+    /// Replaces the piece letters of an english san move, including promotions
+    pub fn localize(self, san: &str) -> String {
+        san.chars()
+            .map(|c| match "KQRBN".find(c) {
+                Some(i) => self.pieces()[i],
+                None => c,
+            })
+            .collect()
+    }
+}
+
+// Counts the lines written, so that later lines can refer to them
+struct LineCounter<'a, W: Write> {
+    inner: &'a mut W,
+    newlines: usize,
+}
+
+impl<W: Write> Write for LineCounter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.newlines += buf[..n].iter().filter(|b| **b == b'\n').count();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 impl BookMap {
-    pub fn write_txt<W: Write>(&mut self, mut w: &mut W) {
+    /// Writes the book as a tree. With transpositions set, lines reaching a position whose moves
+    /// were already written end with a comment giving the line where they start, as
+    /// "; see line 42", instead of repeating the position's first move. Readers ignore the
+    /// comment, joining the lines by position as for any other transposition.
+    pub fn write_txt<W: Write>(&mut self, w: &mut W, notation: MoveNotation, transpositions: bool) {
+        let mut w = LineCounter {
+            inner: w,
+            newlines: 0,
+        };
+        self.write_metadata_comments(&mut w);
+
         if book_hash(self.root.clone()) != START_HASH {
             writeln!(w, "{}", fen(&self.root));
         }
@@ -21,16 +134,22 @@ impl BookMap {
         let mut last_depth = 0;
         let mut depths = Vec::new();
 
-        self.traverse_tree(|depth, pos, entries, ind| {
-            if ind == 0 {
-                entries.sort_unstable_by_key(|entry| Reverse(entry.weight));
+        // The line where the moves of each written position start
+        let hasher = self.hasher.clone();
+        let mut lines = PositionMap::<usize>::default();
+
+        self.traverse_tree(true, |depth, pos, entries, ind, cycle| {
+            // Revisited positions would otherwise repeat their first move
+            if transpositions && ind == 0 && lines.contains_key(&hasher.hash(pos)) {
+                last_depth = depth;
+                return;
             }
 
             let only_child = entries.len() == 1;
             let entry = &entries[ind];
 
             let mov = from_book_move(entry.mov).to_move(pos).unwrap();
-            let san = SanPlus::from_move(pos.clone(), &mov);
+            let san = notation.format(pos, &mov);
 
             while depth >= depths.len() {
                 depths.push(0);
@@ -62,10 +181,43 @@ impl BookMap {
             if entry.learn != 0 {
                 write!(&mut w, " {}", entry.learn);
             }
+
+            if transpositions {
+                if ind == 0 {
+                    lines.insert(hasher.hash(pos), w.newlines + 1);
+                }
+
+                let child = pos.clone().play(&mov).unwrap();
+                if let Some(line) = lines.get(&hasher.hash(&child)).filter(|_| !cycle) {
+                    write!(&mut w, " ; see line {}", line);
+                }
+            }
+
+            if cycle {
+                write!(&mut w, " ; cycle");
+            }
         });
     }
 
-    pub fn write_blob<W: Write>(&mut self, mut w: &mut W) {
+    /// Writes the compact tree format, after a comment line with the format version and a
+    /// checksum of the rest of the file
+    pub fn write_blob<W: Write>(&mut self, w: &mut W) {
+        let mut body = Vec::new();
+        self.write_blob_body(&mut body);
+
+        writeln!(
+            w,
+            "{} {} crc32 {:08x}",
+            BLOB_MAGIC,
+            BLOB_VERSION,
+            crc32(&body)
+        );
+        w.write_all(&body);
+    }
+
+    fn write_blob_body(&mut self, mut w: &mut Vec<u8>) {
+        self.write_metadata_comments(w);
+
         if book_hash(self.root.clone()) != START_HASH {
             writeln!(w, "{}", fen(&self.root));
         }
@@ -74,11 +226,7 @@ impl BookMap {
         let mut last_depth = 0;
         let mut depths = Vec::new();
 
-        self.traverse_tree(|depth, pos, entries, ind| {
-            if ind == 0 {
-                entries.sort_unstable_by_key(|entry| Reverse(entry.weight));
-            }
-
+        self.traverse_tree(true, |depth, pos, entries, ind, _| {
             let only_child = entries.len() == 1;
             let entry = &entries[ind];
 
@@ -123,12 +271,98 @@ impl BookMap {
         });
     }
 
+    // Metadata goes in comment lines before the root fen, where the reader looks for it
+    fn write_metadata_comments<W: Write>(&self, w: &mut W) {
+        for (key, value) in self.metadata_fields() {
+            writeln!(w, "; {}: {}", key, value);
+        }
+    }
+
+    /// Writes each entry reachable from the root as "hash, fen, uci, weight, learn", with the hash
+    /// of its position in hex and its move in uci with castling as the king's move, as engines
+    /// probe them, for tracking down disagreements about probe results. Positions are written in
+    /// the order a depth first walk from the root reaches them, with their entries by descending
+    /// weight.
+    pub fn write_hash_dump<W: Write>(&self, w: &mut W) {
+        let tree = self.to_tree();
+
+        for node in (0..tree.len()).map(|i| tree.node(i)) {
+            let fen = fen(&node.position);
+            let mut children = node.children.iter().map(|c| &c.0).collect::<Vec<_>>();
+            children.sort_by_key(|e| Reverse(e.weight));
+
+            for entry in children {
+                let Ok(mov) = from_book_move(entry.mov).to_move(&node.position) else {
+                    continue;
+                };
+
+                writeln!(
+                    w,
+                    "{:016x}, {}, {}, {}, {}",
+                    node.hash,
+                    fen,
+                    UciMove::from_standard(&mov),
+                    entry.weight,
+                    entry.learn
+                );
+            }
+        }
+    }
+
+    /// Writes each line from the root to a leaf as its weight followed by its moves in uci, with
+    /// lines ordered by the weights of their moves. Lines reaching a transposition that was
+    /// already written end there.
+    pub fn write_uci_lines<W: Write>(&mut self, w: &mut W) {
+        let mut line = Vec::new();
+        let mut weight = 0;
+
+        let write_line = |w: &mut W, line: &[String], weight: u64| {
+            writeln!(w, "{} {}", weight, line.join(" "));
+        };
+
+        self.traverse_tree(true, |depth, pos, entries, ind, _| {
+            if depth < line.len() {
+                write_line(w, &line, weight);
+                line.truncate(depth);
+            }
+
+            let entry = &entries[ind];
+            let mov = from_book_move(entry.mov).to_move(pos).unwrap();
+
+            line.push(UciMove::from_standard(&mov).to_string());
+            weight = entry.weight;
+        });
+
+        if !line.is_empty() {
+            write_line(w, &line, weight);
+        }
+    }
+
     pub fn write_json<W: Write>(&mut self, mut w: &mut W) {
-        write!(w, "{{\"rootFen\":{:?},\"tree\":{{", fen(&self.root));
+        write!(w, "{{");
+
+        let fields = self.metadata_fields();
+        if !fields.is_empty() {
+            let metadata = fields
+                .into_iter()
+                .map(|(key, value)| match key {
+                    "entries" => (key.to_string(), Value::from(self.entry_count())),
+                    _ => (key.to_string(), Value::from(value)),
+                })
+                .collect::<serde_json::Map<_, _>>();
+
+            write!(w, "\"metadata\":{},", Value::Object(metadata));
+        }
+
+        write!(
+            w,
+            "\"rootFen\":{:?},\"tree\":{{",
+            fen(&self.root).to_string()
+        );
 
         let mut last_depth = -1;
 
-        self.traverse_tree(|depth, pos, entries, ind| {
+        self.traverse_tree(false, |depth, pos, entries, ind, cycle| {
             let entry = &entries[ind];
 
             let mov = from_book_move(entry.mov).to_move(pos).unwrap();
@@ -143,170 +377,37 @@ impl BookMap {
 
             write!(
                 &mut w,
-                "\"{}\":{{\"weight\":{},\"learn\":{},\"children\":{{",
+                "\"{}\":{{\"weight\":{},\"learn\":{},",
                 san, entry.weight, entry.learn
             );
 
-            last_depth = depth as isize;
-        });
-
-        write!(&mut w, "{}", "}}".repeat(last_depth as usize + 2));
-    }
-}
-
-fn process_line(line: &mut String) -> usize {
-    let mut indent = 0;
-    let mut start = 0;
-
-    for c in line.chars() {
-        match c {
-            ' ' => indent += 1,
-            '\t' => indent += 4,
-            _ => break,
-        }
-        start += 1;
-    }
-
-    let end = line.find(";").unwrap_or(line.len());
-    *line = line[start..end].trim().to_string();
-    line.push('\n');
-    indent
-}
-
-impl BookMap {
-    pub fn read_txt<R: BufRead>(reader: &mut R) -> Self {
-        let mut out = BookMap::new();
-        let mut stack: Vec<(Chess, usize)> = Vec::new();
-        let mut pos = Chess::default();
-        let mut paren_indent = 0;
-        let mut root = true;
-
-        for (line_number, line) in reader.lines().enumerate() {
-            let mut line = line.unwrap();
-            let indent = process_line(&mut line) + paren_indent;
-
-            if line.trim().is_empty() {
-                continue;
+            if let Some(elo) = entry.average_elo() {
+                write!(&mut w, "\"elo\":{},\"eloGames\":{},", elo, entry.elo_count);
             }
 
-            if root {
-                if let Ok(fen) = line.parse::<Fen>() {
-                    pos = fen.into_position(Chess960).expect("Invalid root position");
-                    out.root = pos.clone();
-                    root = false;
-                    continue;
-                }
+            if let Some(sharpness) = entry.sharpness() {
+                let [wins, draws, losses] = entry.results;
+                write!(
+                    &mut w,
+                    "\"results\":[{},{},{}],\"sharpness\":{:.3},",
+                    wins, draws, losses, sharpness
+                );
             }
 
-            root = false;
-
-            let mut weight = 1;
-            let mut san = None;
-            let mut learn = 0;
-            let mut entrystart = 0;
-            let mut wordstart = 0;
-            let mut first_entry = true;
-            let mut read_weight = true;
-
-            for (i, c) in line.chars().enumerate() {
-                if " \n\t,/()".contains(c) || (!c.is_ascii_digit() && read_weight) {
-                    let word = &line[wordstart..i];
-
-                    if let Ok(n) = word.parse::<u64>() {
-                        if san.is_none() {
-                            weight = n;
-                        } else {
-                            learn = n as u32;
-                        }
-                    } else if let Ok(s) = word.parse::<SanPlus>() {
-                        san = Some(s);
-                    } else if !word.is_empty() {
-                        panic!("Invalid token {:?} at {}:{}", word, line_number + 1, i + 1);
-                    }
-
-                    wordstart = i;
-
-                    if c.is_ascii_alphabetic() {
-                        read_weight = false;
-                    }
-                }
-                if "\n,/()".contains(c) && entrystart != i {
-                    if first_entry {
-                        while let Some((_, indent2)) = stack.last() {
-                            if *indent2 < indent + paren_indent {
-                                break;
-                            } else {
-                                pos = stack.pop().unwrap().0;
-                            }
-                        }
-
-                        first_entry = false;
-                    }
-
-                    let s = san.unwrap_or_else(|| {
-                        panic!(
-                            "Entry {:?} has no move at {}:{}",
-                            &line[entrystart..i],
-                            line_number + 1,
-                            i + 1
-                        )
-                    });
-
-                    let mov = s.san.to_move(&pos).unwrap_or_else(|_| {
-                        panic!(
-                            "Invalid move {} for position {:?} at {}:{}",
-                            s,
-                            fen(&pos),
-                            line_number + 1,
-                            i + 1
-                        )
-                    });
-
-                    let book_move = to_book_move(UciMove::from_chess960(&mov));
-
-                    let entry = BookEntry {
-                        mov: book_move,
-                        visited: false,
-                        depth: Some(stack.len()),
-                        weight,
-                        learn,
-                    };
-
-                    out.insert(book_hash(pos.clone()), entry);
-                    stack.push((pos.clone(), indent + paren_indent));
-                    pos.play_unchecked(&mov);
-
-                    san = None;
-                    learn = 0;
-                    read_weight = true;
-                }
-                if "\n,/()".contains(c) {
-                    entrystart = i + 1;
-                    wordstart = i + 1;
-                }
-                match c {
-                    '/' => {
-                        first_entry = true;
-                        weight = 1
-                    }
-                    '(' => {
-                        paren_indent += 4;
-                        first_entry = true;
-                        weight = 1
-                    }
-                    ')' => {
-                        paren_indent -= 4;
-                        first_entry = true;
-                        weight = 1
-                    }
-                    _ => {}
-                }
+            if cycle {
+                write!(&mut w, "\"cycle\":true,");
             }
-        }
 
-        out
+            write!(&mut w, "\"children\":{{");
+
+            last_depth = depth as isize;
+        });
+
+        write!(&mut w, "{}", "}}".repeat(last_depth as usize + 2));
     }
+}
 
+impl BookMap {
     pub fn read_json<R: BufRead>(reader: R) -> Self {
         let mut deserializer = serde_json::Deserializer::from_reader(reader);
         deserializer.disable_recursion_limit();
@@ -327,6 +428,14 @@ impl BookMap {
 
         out.root = root.into_position(Chess960).expect("Invalid root position");
 
+        if let Some(metadata) = json.get("metadata").and_then(|x| x.as_object()) {
+            for (key, value) in metadata {
+                if let Some(value) = value.as_str() {
+                    out.metadata.set(key, value);
+                }
+            }
+        }
+
         let tree = json
             .as_object()
             .unwrap()
@@ -355,15 +464,29 @@ impl BookMap {
             let weight = entry.get("weight").unwrap().as_u64().unwrap();
             let learn = entry.get("learn").unwrap().as_u64().unwrap();
 
+            let elo = entry.get("elo").and_then(|x| x.as_u64());
+            let elo_games =
+                elo.map(|_| entry.get("eloGames").and_then(|x| x.as_u64()).unwrap_or(1));
+            let mut results = [0; 3];
+
+            if let Some(counts) = entry.get("results").and_then(|x| x.as_array()) {
+                for (result, count) in results.iter_mut().zip(counts) {
+                    *result = count.as_u64().unwrap_or(0);
+                }
+            }
+
             let out_entry = BookEntry {
                 mov: book_move,
-                visited: false,
                 depth: Some(stack.len()),
                 weight,
                 learn: learn as u32,
+                elo_sum: elo.unwrap_or(0) * elo_games.unwrap_or(0),
+                elo_count: elo_games.unwrap_or(0),
+                results,
+                ..BookEntry::new()
             };
 
-            out.insert(book_hash(pos.clone()), out_entry);
+            out.insert(out.hash(&pos), out_entry);
             stack.push((pos.clone(), entries, ind + 1));
 
             let pos = pos.clone().play(&mov).unwrap();
@@ -381,3 +504,17 @@ impl BookMap {
         out
     }
 }
+
+#[test]
+fn t_json_elo_games() {
+    let mut book = BookMap::read_txt(&mut "3 e4".as_bytes(), MoveNotation::San);
+    let hash = book.hash(&book.root);
+    let entry = &mut book.map.get_mut(&hash).unwrap()[0];
+    (entry.elo_sum, entry.elo_count) = (6000, 3);
+
+    let mut json = Vec::new();
+    book.write_json(&mut json);
+    let read = BookMap::read_json(&json[..]);
+    let entry = &read.map[&hash][0];
+    assert_eq!((entry.elo_sum, entry.elo_count), (6000, 3));
+}
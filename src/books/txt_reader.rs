@@ -0,0 +1,535 @@
+use super::checksum::crc32;
+use super::txt_books::{fen, BLOB_MAGIC, BLOB_VERSION};
+use super::*;
+
+use std::fmt;
+use std::io::BufRead;
+
+use shakmaty::{fen::Fen, Move};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxtError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TxtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+// A move token from a tree file, which may be in either notation
+struct TxtMove {
+    word: String,
+    san: Option<SanPlus>,
+    uci: Option<UciMove>,
+}
+
+impl TxtMove {
+    fn parse(word: &str) -> Option<Self> {
+        let san = word.parse::<SanPlus>().ok();
+        let uci = word.parse::<UciMove>().ok();
+
+        if san.is_none() && uci.is_none() {
+            return None;
+        }
+
+        Some(TxtMove {
+            word: word.to_string(),
+            san,
+            uci,
+        })
+    }
+
+    // Tries the given notation first, so that tokens valid in both resolve as intended
+    fn to_move(&self, pos: &Chess, notation: MoveNotation) -> Option<Move> {
+        let san = || self.san.as_ref().and_then(|s| s.san.to_move(pos).ok());
+        let uci = || self.uci.as_ref().and_then(|u| u.to_move(pos).ok());
+
+        match notation {
+            MoveNotation::San | MoveNotation::LocalSan(_) => san().or_else(uci),
+            MoveNotation::Uci => uci().or_else(san),
+        }
+    }
+}
+
+enum Token {
+    Number(u64),
+    Move(TxtMove),
+    // One of ",/()"
+    Separator(char),
+    Invalid(String),
+}
+
+// Weights may be written directly in front of moves, as in blob trees
+fn word_tokens(word: &str, column: usize, out: &mut Vec<(usize, Token)>) {
+    let digits = word.len() - word.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let weight = word[..digits].parse::<u64>();
+
+    if digits == word.len() {
+        match weight {
+            Ok(n) => out.push((column, Token::Number(n))),
+            Err(_) => out.push((column, Token::Invalid(word.to_string()))),
+        }
+    } else if let (Ok(n), Some(mov)) = (&weight, TxtMove::parse(&word[digits..])) {
+        out.push((column, Token::Number(*n)));
+        out.push((column + digits, Token::Move(mov)));
+    } else if let Some(mov) = TxtMove::parse(word) {
+        out.push((column, Token::Move(mov)));
+    } else {
+        out.push((column, Token::Invalid(word.to_string())));
+    }
+}
+
+// Splits a line into tokens along with their 1-based columns
+fn tokenize(line: &str) -> Vec<(usize, Token)> {
+    let mut out = Vec::new();
+    let mut word_start = 0;
+
+    for (i, c) in line.char_indices() {
+        if c.is_whitespace() || ",/()".contains(c) {
+            if word_start < i {
+                word_tokens(&line[word_start..i], word_start + 1, &mut out);
+            }
+            word_start = i + c.len_utf8();
+
+            if !c.is_whitespace() {
+                out.push((i + 1, Token::Separator(c)));
+            }
+        }
+    }
+
+    if word_start < line.len() {
+        word_tokens(&line[word_start..], word_start + 1, &mut out);
+    }
+
+    out
+}
+
+struct Entry {
+    line: usize,
+    column: usize,
+    weight: Option<u64>,
+    mov: Option<TxtMove>,
+    learn: u32,
+    error: Option<String>,
+}
+
+struct TxtParser {
+    book: BookMap,
+    notation: MoveNotation,
+    stack: Vec<(Chess, usize)>,
+    pos: Chess,
+    paren_indent: usize,
+    // In blobs only parentheses give structure, and entries may continue across lines
+    blob: bool,
+    entry: Option<Entry>,
+    weight: u64,
+    first: bool,
+    // Once an entry fails, following entries are skipped until the next one that starts a line
+    // of moves at the same or a lower indent
+    skip_above: Option<usize>,
+}
+
+impl TxtParser {
+    // Adds an entry given its indent, and whether it starts a new line of moves rather than
+    // following the previous entry after a ','
+    fn add_entry(
+        &mut self,
+        entry: &Entry,
+        weight: u64,
+        indent: usize,
+        first: bool,
+    ) -> Result<(), String> {
+        if let Some(skip) = self.skip_above {
+            if !first || indent > skip {
+                return Ok(());
+            }
+            self.skip_above = None;
+        }
+
+        if first {
+            while let Some((_, indent2)) = self.stack.last() {
+                if *indent2 < indent {
+                    break;
+                }
+                self.pos = self.stack.pop().unwrap().0;
+            }
+        }
+
+        if let Some(error) = &entry.error {
+            return Err(error.clone());
+        }
+
+        let txt_move = entry.mov.as_ref().ok_or("Entry has no move")?;
+        let mov = txt_move.to_move(&self.pos, self.notation).ok_or_else(|| {
+            format!(
+                "Invalid move {} for position {}",
+                txt_move.word,
+                fen(&self.pos)
+            )
+        })?;
+
+        let book_entry = BookEntry {
+            mov: to_book_move(UciMove::from_chess960(&mov)),
+            depth: Some(self.stack.len()),
+            weight,
+            learn: entry.learn,
+            ..BookEntry::new()
+        };
+
+        self.book.insert(self.book.hash(&self.pos), book_entry);
+        self.stack.push((self.pos.clone(), indent));
+        self.pos.play_unchecked(&mov);
+
+        Ok(())
+    }
+
+    // Ends the current entry at a separator, adding it to the book
+    fn separator<F>(
+        &mut self,
+        c: char,
+        line_indent: usize,
+        line: usize,
+        column: usize,
+        on_error: &mut F,
+    ) -> Result<(), TxtError>
+    where
+        F: FnMut(TxtError) -> Result<(), TxtError>,
+    {
+        if c == ')' && self.paren_indent == 0 {
+            on_error(TxtError {
+                line,
+                column,
+                message: "Unmatched ')'".to_string(),
+            })?;
+        }
+
+        if let Some(entry) = self.entry.take() {
+            let indent = line_indent + self.paren_indent;
+            self.weight = entry.weight.unwrap_or(self.weight);
+
+            if let Err(message) = self.add_entry(&entry, self.weight, indent, self.first) {
+                self.skip_above = Some(indent);
+
+                on_error(TxtError {
+                    line: entry.line,
+                    column: entry.column,
+                    message,
+                })?;
+            }
+            self.first = false;
+        }
+
+        match c {
+            '(' => self.paren_indent += 4,
+            ')' => self.paren_indent = self.paren_indent.saturating_sub(4),
+            _ => {}
+        }
+
+        // Weights carry over to moves continuing a line after ','
+        if c != ',' {
+            self.weight = 1;
+            self.first = true;
+        }
+
+        Ok(())
+    }
+
+    // Parses a line with comments removed, passing each error to on_error and stopping if it
+    // returns one
+    fn parse_line<F>(
+        &mut self,
+        line: &str,
+        line_number: usize,
+        on_error: &mut F,
+    ) -> Result<(), TxtError>
+    where
+        F: FnMut(TxtError) -> Result<(), TxtError>,
+    {
+        let line_indent = if self.blob {
+            0
+        } else {
+            line.chars()
+                .take_while(|c| c.is_whitespace())
+                .map(|c| if c == '\t' { 4 } else { 1 })
+                .sum::<usize>()
+                + self.paren_indent
+        };
+
+        for (column, token) in tokenize(line) {
+            let current = match token {
+                Token::Separator(c) => {
+                    self.separator(c, line_indent, line_number, column, on_error)?;
+                    continue;
+                }
+                _ => self.entry.get_or_insert(Entry {
+                    line: line_number,
+                    column,
+                    weight: None,
+                    mov: None,
+                    learn: 0,
+                    error: None,
+                }),
+            };
+
+            let error = match token {
+                Token::Number(n) if current.mov.is_none() && current.weight.is_none() => {
+                    current.weight = Some(n);
+                    None
+                }
+                Token::Number(n) if current.mov.is_some() && current.learn == 0 => {
+                    current.learn = n as u32;
+                    None
+                }
+                Token::Number(n) => Some(format!("Unexpected number {}", n)),
+                Token::Move(mov) if current.mov.is_none() => {
+                    current.mov = Some(mov);
+                    None
+                }
+                Token::Move(mov) => Some(format!("Unexpected move {}", mov.word)),
+                Token::Invalid(word) => Some(format!("Invalid token {:?}", word)),
+                Token::Separator(_) => unreachable!(),
+            };
+
+            if current.error.is_none() && error.is_some() {
+                current.error = error;
+                current.line = line_number;
+                current.column = column;
+            }
+        }
+
+        if !self.blob {
+            self.separator('\n', line_indent, line_number, line.len() + 1, on_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Reads a blob, verifying the checksum in its header comment. Blobs written before the header
+// was added are read without verification.
+fn read_blob_bytes<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, TxtError> {
+    let error = |message: String| TxtError {
+        line: 1,
+        column: 1,
+        message,
+    };
+
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(|e| error(e.to_string()))?;
+
+    let header_end = bytes
+        .iter()
+        .position(|b| *b == b'\n')
+        .unwrap_or(bytes.len());
+    let header = String::from_utf8_lossy(&bytes[..header_end]);
+
+    if let Some(rest) = header.strip_prefix(BLOB_MAGIC) {
+        let words = rest.split_whitespace().collect::<Vec<_>>();
+
+        if words.first() != Some(&BLOB_VERSION) {
+            return Err(error(format!(
+                "Unsupported blob version {}",
+                words.first().unwrap_or(&"")
+            )));
+        }
+
+        let expected = match words[1..] {
+            ["crc32", hex] => u32::from_str_radix(hex, 16).ok(),
+            _ => None,
+        }
+        .ok_or_else(|| error("Invalid blob header".to_string()))?;
+
+        let body = bytes.get(header_end + 1..).unwrap_or(&[]);
+
+        if crc32(body) != expected {
+            return Err(error(
+                "Checksum mismatch, the blob is truncated or corrupted".to_string(),
+            ));
+        }
+    }
+
+    Ok(bytes)
+}
+
+impl BookMap {
+    fn parse_txt<R, F>(
+        reader: &mut R,
+        notation: MoveNotation,
+        blob: bool,
+        mut on_error: F,
+    ) -> Result<Self, TxtError>
+    where
+        R: BufRead,
+        F: FnMut(TxtError) -> Result<(), TxtError>,
+    {
+        let mut parser = TxtParser {
+            book: BookMap::new(),
+            notation,
+            stack: Vec::new(),
+            pos: Chess::default(),
+            paren_indent: 0,
+            blob,
+            entry: None,
+            weight: 1,
+            first: true,
+            skip_above: None,
+        };
+
+        let mut root = true;
+        let mut lines = 0;
+
+        for (i, line) in reader.lines().enumerate() {
+            let line_number = i + 1;
+            lines = line_number;
+
+            let line = line.map_err(|e| TxtError {
+                line: line_number,
+                column: 1,
+                message: e.to_string(),
+            })?;
+
+            if root {
+                parser.book.metadata.parse_comment(&line);
+            }
+
+            let line = &line[..line.find(';').unwrap_or(line.len())];
+            let content = line.trim();
+
+            if content.is_empty() {
+                continue;
+            }
+
+            if root {
+                root = false;
+
+                if let Ok(fen) = content.parse::<Fen>() {
+                    match fen.into_position::<Chess>(Chess960) {
+                        Ok(pos) => {
+                            parser.pos = pos.clone();
+                            parser.book.root = pos;
+                        }
+                        Err(_) => on_error(TxtError {
+                            line: line_number,
+                            column: 1,
+                            message: "Invalid root position".to_string(),
+                        })?,
+                    }
+                    continue;
+                }
+            }
+
+            parser.parse_line(line, line_number, &mut on_error)?;
+        }
+
+        parser.separator('\n', 0, lines, 1, &mut on_error)?;
+
+        Ok(parser.book)
+    }
+
+    /// Reads a tree file, failing on the first malformed entry
+    pub fn try_read_txt<R: BufRead>(
+        reader: &mut R,
+        notation: MoveNotation,
+    ) -> Result<Self, TxtError> {
+        Self::parse_txt(reader, notation, false, Err)
+    }
+
+    /// Reads the compact format written by write_blob, where only parentheses and separators
+    /// give the structure of the tree, so line breaks and indentation are ignored
+    pub fn try_read_blob<R: BufRead>(reader: &mut R) -> Result<Self, TxtError> {
+        let bytes = read_blob_bytes(reader)?;
+        Self::parse_txt(&mut &bytes[..], MoveNotation::San, true, Err)
+    }
+
+    /// Reads a tree file, or a blob if `blob` is set, skipping malformed entries along with the
+    /// moves below them and returning the errors found
+    pub fn read_tree_recover<R: BufRead>(
+        reader: &mut R,
+        notation: MoveNotation,
+        blob: bool,
+    ) -> (Self, Vec<TxtError>) {
+        let mut errors = Vec::new();
+
+        let book = if blob {
+            read_blob_bytes(reader).and_then(|bytes| {
+                Self::parse_txt(&mut &bytes[..], notation, blob, |e| {
+                    errors.push(e);
+                    Ok(())
+                })
+            })
+        } else {
+            Self::parse_txt(reader, notation, blob, |e| {
+                errors.push(e);
+                Ok(())
+            })
+        };
+
+        match book {
+            Ok(book) => (book, errors),
+            Err(e) => {
+                errors.push(e);
+                (BookMap::new(), errors)
+            }
+        }
+    }
+
+    pub fn read_txt<R: BufRead>(reader: &mut R, notation: MoveNotation) -> Self {
+        Self::try_read_txt(reader, notation).unwrap_or_else(|e| panic!("Error reading tree: {}", e))
+    }
+
+    pub fn read_blob<R: BufRead>(reader: &mut R) -> Self {
+        Self::try_read_blob(reader).unwrap_or_else(|e| panic!("Error reading blob: {}", e))
+    }
+}
+
+#[test]
+fn t_read_txt() {
+    let read = |tree: &str| BookMap::try_read_txt(&mut tree.as_bytes(), MoveNotation::San);
+
+    let tree = "4 e4\n    2 c5, Nf3 ; comment\n    e5, 4 Nf3\n        Nc6 30\n2 d4(Nf6,c4/d5)\n";
+    let book = read(tree).unwrap();
+    assert_eq!(book.map.values().flatten().count(), 10);
+
+    let blob = read("4e4(2c5,Nf3/e5,4Nf3,1Nc6 30)/2d4(Nf6,c4/d5)").unwrap();
+    assert_eq!(blob.map, book.map);
+
+    let err = read("e4\n    e5, Ke3\n").err().unwrap();
+    assert_eq!((err.line, err.column), (2, 9));
+
+    let err = read("e4, 3 4 e5").err().unwrap();
+    assert_eq!((err.line, err.column), (1, 7));
+
+    let recover = "e4, Qh5\n    e5\nd4\n";
+    let (book, errors) =
+        BookMap::read_tree_recover(&mut recover.as_bytes(), MoveNotation::San, false);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(book.map.values().flatten().count(), 2);
+}
+
+#[test]
+fn t_read_blob() {
+    let blob = "4e4(2c5,Nf3/e5,4Nf3,1Nc6 30)/2d4(Nf6,c4/d5)";
+    let wrapped = "4e4(2c5,Nf3/e5,\n    4Nf3,1Nc6\n 30)/2d4(Nf6,c4/d5)";
+
+    let mut book = BookMap::try_read_blob(&mut blob.as_bytes()).unwrap();
+    let book2 = BookMap::try_read_blob(&mut wrapped.as_bytes()).unwrap();
+    assert_eq!(book.map.values().flatten().count(), 10);
+    assert_eq!(book.map, book2.map);
+
+    let mut out = Vec::new();
+    book.write_blob(&mut out);
+    assert_eq!(BookMap::try_read_blob(&mut &out[..]).unwrap().map, book.map);
+
+    let truncated = &out[..out.len() - 4];
+    assert!(BookMap::try_read_blob(&mut &truncated[..])
+        .err()
+        .unwrap()
+        .message
+        .starts_with("Checksum mismatch"));
+}
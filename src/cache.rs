@@ -0,0 +1,149 @@
+// Least recently used cache keyed by position hashes, for repeated probes of the same positions.
+// Entries form a doubly linked list through their indices, most recently used first.
+
+use crate::books::PositionMap;
+
+struct Node<V> {
+    hash: u64,
+    value: V,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+pub struct LruCache<V> {
+    index: PositionMap<usize>,
+    nodes: Vec<Node<V>>,
+    head: Option<usize>,
+    tail: Option<usize>,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl<V> LruCache<V> {
+    /// A cache holding at most capacity values, which caches nothing if capacity is 0
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            index: PositionMap::default(),
+            nodes: Vec::new(),
+            head: None,
+            tail: None,
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Changes the capacity, dropping all cached values
+    pub fn set_capacity(&mut self, capacity: usize) {
+        *self = LruCache {
+            hits: self.hits,
+            misses: self.misses,
+            ..LruCache::new(capacity)
+        };
+    }
+
+    /// Drops all cached values, keeping the capacity
+    pub fn clear(&mut self) {
+        self.set_capacity(self.capacity);
+    }
+
+    /// Returns the number of lookups that found a value and that did not
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = (self.nodes[i].prev, self.nodes[i].next);
+
+        match prev {
+            Some(p) => self.nodes[p].next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(n) => self.nodes[n].prev = prev,
+            None => self.tail = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.nodes[i].prev = None;
+        self.nodes[i].next = self.head;
+
+        if let Some(h) = self.head {
+            self.nodes[h].prev = Some(i);
+        }
+        self.head = Some(i);
+
+        if self.tail.is_none() {
+            self.tail = Some(i);
+        }
+    }
+
+    /// Returns the value for hash, computing and caching it with f if it is not cached
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, hash: u64, f: F) -> &V {
+        if let Some(&i) = self.index.get(&hash) {
+            self.hits += 1;
+            self.unlink(i);
+            self.push_front(i);
+            return &self.nodes[i].value;
+        }
+
+        self.misses += 1;
+        let value = f();
+
+        let i = if self.nodes.len() < self.capacity.max(1) {
+            self.nodes.push(Node {
+                hash,
+                value,
+                prev: None,
+                next: None,
+            });
+            self.nodes.len() - 1
+        } else {
+            // Reuse the least recently used node
+            let i = self.tail.unwrap();
+            self.unlink(i);
+            self.index.remove(&self.nodes[i].hash);
+            self.nodes[i].hash = hash;
+            self.nodes[i].value = value;
+            i
+        };
+
+        self.push_front(i);
+
+        if self.capacity > 0 {
+            self.index.insert(hash, i);
+        }
+        &self.nodes[i].value
+    }
+}
+
+#[test]
+fn t_lru_cache() {
+    let mut cache = LruCache::new(2);
+
+    assert_eq!(*cache.get_or_insert_with(1, || "a"), "a");
+    assert_eq!(*cache.get_or_insert_with(2, || "b"), "b");
+    assert_eq!(*cache.get_or_insert_with(1, || "x"), "a");
+
+    // 2 is now the least recently used, so it is evicted
+    cache.get_or_insert_with(3, || "c");
+    assert_eq!(*cache.get_or_insert_with(2, || "y"), "y");
+    assert_eq!(*cache.get_or_insert_with(3, || "z"), "c");
+
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.stats(), (2, 4));
+
+    let mut off = LruCache::new(0);
+    off.get_or_insert_with(1, || "a");
+    assert_eq!(*off.get_or_insert_with(1, || "b"), "b");
+}
@@ -0,0 +1,1090 @@
+use crate::args::*;
+use crate::books::*;
+use crate::conversions::*;
+use crate::error::{input, io, usage, INPUT};
+use crate::pgn::*;
+use crate::rng::Rng;
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+
+use shakmaty::{fen::Epd, EnPassantMode, Move};
+
+// Returns false if command is not the name of a command, in which case the arguments are handled
+// as a normal conversion.
+pub fn run_command(command: &str, args: &[String]) -> bool {
+    match command {
+        "analyse" => analyse(args),
+        "annotate" => annotate(args),
+        "audit" => audit(args),
+        "balance" => balance(args),
+        "best-line" => best_line(args),
+        "check" | "--check" => check(args),
+        "critical" => critical(args),
+        "deviations" => deviations(args),
+        "dump-hashes" => dump_hashes(args),
+        "eco" => eco(args),
+        "edit" => edit(args),
+        "explore" => explore(args),
+        "forced-lines" => forced_lines(args),
+        "gaps" => gaps(args),
+        "grow" => grow(args),
+        "masters" => masters(args),
+        "repetitions" => repetitions(args),
+        "info" => load_books(args).write_info(&mut std::io::stdout()),
+        "stats" => load_books(args).write_stats(&mut std::io::stdout()),
+        "suite" => suite(args),
+        "table" => table(args),
+        "train" => train(args),
+        _ => return false,
+    }
+
+    true
+}
+
+fn root_headers(root: &Chess) -> Vec<(String, String)> {
+    if book_hash(root.clone()) == START_HASH {
+        Vec::new()
+    } else {
+        vec![
+            ("SetUp".to_string(), "1".to_string()),
+            ("FEN".to_string(), fen_string(root)),
+        ]
+    }
+}
+
+fn line_game(root: &Chess, name: &str, eval: i32, moves: Vec<SanPlus>) -> PgnGame {
+    let mut headers = vec![
+        ("Event".to_string(), name.to_string()),
+        ("White".to_string(), "?".to_string()),
+        ("Black".to_string(), "?".to_string()),
+        ("Result".to_string(), "*".to_string()),
+        ("Eval".to_string(), eval.to_string()),
+    ];
+    headers.extend(root_headers(root));
+
+    PgnGame::from_moves(headers, moves)
+}
+
+fn write_lines<W: Write>(w: &mut W, root: &Chess, lines: &[BookLine], epd: bool) {
+    let root_epd = Epd::from_position(root.clone(), EnPassantMode::Legal);
+
+    for (i, line) in lines.iter().enumerate() {
+        let name = if i == 0 {
+            "Best line".to_string()
+        } else {
+            format!("Alternative {}", i)
+        };
+
+        let sans = line
+            .moves
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        if epd {
+            writeln!(
+                w,
+                "{} bm {}; ce {}; pv {}; id \"{}\";",
+                root_epd,
+                sans.split(' ').next().unwrap_or(""),
+                line.score,
+                sans,
+                name
+            );
+        } else {
+            let game = line_game(root, &name, line.score, line.moves.clone());
+            writeln!(w, "{}", game);
+        }
+    }
+}
+
+// Writes the games of each -games file with comments from the book built from the inputs
+fn annotate(args: &[String]) {
+    let games = flag_values(args, "-games");
+    if games.is_empty() {
+        usage("Give the games to annotate with -games".to_string());
+    }
+
+    let output = flag_value(args, "-out").unwrap_or("-");
+    check_outputs([output], args);
+
+    let book = load_books(args);
+    let mut writer = open_output(output);
+
+    for filename in games {
+        fold_games(PgnFilter::new(), open_input(filename), &mut |game| {
+            book.write_annotated(&mut writer, &game)
+        });
+    }
+}
+
+// Writes the coverage of each side, then writes the book with the deeper side's lines cut to the
+// other's depth when outputs are given
+fn balance(args: &[String]) {
+    let outputs = get_output_files(args);
+    let sidecars = bloom_sidecars(&outputs, args);
+    check_outputs(outputs.iter().map(|x| &x.1[..]), args);
+    check_outputs(sidecars.iter().map(|x| &x[..]), args);
+
+    let plies = parse_flag(args, "-plies").unwrap_or(20);
+    let games = flag_values(args, "-games")
+        .into_iter()
+        .flat_map(|file| read_games(PgnFilter::new(), open_input(file)))
+        .collect::<Vec<_>>();
+
+    let mut book = load_books(args);
+    book.write_balance_report(&mut io::stdout(), &games, plies);
+
+    if !outputs.is_empty() {
+        let count = book.balance_depths();
+        info!(
+            "Removed {} moves to balance the depths of both sides",
+            count
+        );
+        write_book(&mut book, &outputs, args);
+    }
+}
+
+fn best_line(args: &[String]) {
+    let output = flag_value(args, "-out").unwrap_or("-");
+    let epd = output.ends_with(".epd") || args.iter().any(|x| x == "-epd");
+    check_outputs([output], args);
+
+    let book = load_books(args);
+    let alternatives = parse_flag(args, "-alternatives").unwrap_or(0);
+
+    let lines = book.best_lines(alternatives);
+    write_lines(&mut open_output(output), book.root(), &lines, epd);
+}
+
+// Reports the positions where the most played move evaluates at least -threshold worse than the
+// best evaluated one
+fn audit(args: &[String]) {
+    let threshold = parse_flag(args, "-threshold").unwrap_or(80);
+
+    let output = flag_value(args, "-out").unwrap_or("-");
+    check_outputs([output], args);
+
+    let book = load_books(args);
+    let count = book.write_audit_report(&mut open_output(output), threshold);
+    info!(
+        "Found {} positions where the most played move is at least {} centipawns worse than the best",
+        count, threshold
+    );
+}
+
+// Reports the positions where the two best moves are close, comparing them by eval when
+// -max-eval-diff is given and by weight otherwise
+fn critical(args: &[String]) {
+    let closeness = match parse_flag(args, "-max-eval-diff") {
+        Some(diff) => Closeness::EvalDiff(diff),
+        None => Closeness::WeightRatio(parse_flag(args, "-ratio").unwrap_or(0.8)),
+    };
+    let plies = parse_flag(args, "-plies").unwrap_or(20);
+    let min_total = parse_flag(args, "-min-total").unwrap_or(10);
+
+    let output = flag_value(args, "-out").unwrap_or("-");
+    check_outputs([output], args);
+
+    let book = load_books(args);
+    let count = book.write_critical_report(&mut open_output(output), closeness, plies, min_total);
+    info!("Found {} undecided positions", count);
+}
+
+fn dump_hashes(args: &[String]) {
+    let output = flag_value(args, "-out").unwrap_or("-");
+    check_outputs([output], args);
+
+    load_books(args).write_hash_dump(&mut open_output(output));
+}
+
+fn repetitions(args: &[String]) {
+    let output = flag_value(args, "-out").unwrap_or("-");
+    check_outputs([output], args);
+
+    load_books(args).write_repetition_report(&mut open_output(output), repertoire_color(args));
+}
+
+// Validates tree and blob files without building a book, exiting with an error status if any are
+// invalid
+fn check(args: &[String]) {
+    let mut failed = false;
+
+    for (filetype, filename) in get_input_files(args, false) {
+        if let FileType::Tree(blob) = filetype {
+            let mut reader = BufReader::new(open_input(&filename));
+            let (book, errors) = BookMap::read_tree_recover(&mut reader, notation(args), blob);
+
+            for e in &errors {
+                println!("{}:{}", filename, e);
+            }
+
+            if errors.is_empty() {
+                println!(
+                    "{}: ok, {} positions, {} entries",
+                    filename,
+                    book.len(),
+                    book.entry_count()
+                );
+            } else {
+                println!("{}: {} errors", filename, errors.len());
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(INPUT);
+    }
+}
+
+fn parse_edit(pos: &Chess, value: &str) -> (Move, Option<u64>, Option<u32>) {
+    parse_entry(pos, value)
+        .unwrap_or_else(|e| usage(format!("{} for position {}", e, fen_string(pos))))
+}
+
+fn fen_string(pos: &Chess) -> String {
+    Fen::from_position(pos.clone(), EnPassantMode::Legal).to_string()
+}
+
+// Flags of edit and train may also be written with two dashes
+fn single_dash(args: &[String]) -> Vec<String> {
+    args.iter()
+        .map(|x| {
+            x.strip_prefix('-')
+                .filter(|x| x.starts_with('-'))
+                .unwrap_or(x)
+        })
+        .map(|x| x.to_string())
+        .collect()
+}
+
+// Reports where the -player's games from the -games files leave the book built from the inputs
+fn deviations(args: &[String]) {
+    let Some(player) = flag_value(args, "-player") else {
+        usage("Give the player whose games are checked with -player".to_string());
+    };
+    let files = flag_values(args, "-games");
+    if files.is_empty() {
+        usage("Give the player's games with -games".to_string());
+    }
+
+    let output = flag_value(args, "-out").unwrap_or("-");
+    check_outputs([output], args);
+
+    let book = load_books(args);
+    let games = files
+        .into_iter()
+        .flat_map(|file| read_games(PgnFilter::new(), open_input(file)))
+        .collect::<Vec<_>>();
+
+    book.write_deviation_report(&mut open_output(output), &games, player);
+}
+
+fn eco(args: &[String]) {
+    let Some(table) = flag_value(args, "-eco-table") else {
+        usage("Give the table classifying openings with -eco-table".to_string());
+    };
+
+    let output = flag_value(args, "-out").unwrap_or("-");
+    check_outputs([output], args);
+
+    let table = EcoTable::read(BufReader::new(open_input(table)));
+    let book = load_books(args);
+
+    book.write_eco_report(
+        &mut open_output(output),
+        &table,
+        args.iter().any(|x| x == "-by-family"),
+    );
+}
+
+// Fen with the characters that can't appear in a url query escaped
+fn url_fen(pos: &Chess) -> String {
+    fen_string(pos).replace(' ', "%20")
+}
+
+// Reads the explorer responses saved by earlier runs, one json object with the epd of the position
+// as its fen and the response per line
+fn read_masters_cache(filename: &str) -> HashMap<String, serde_json::Value> {
+    let Ok(file) = std::fs::File::open(filename) else {
+        return HashMap::new();
+    };
+
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let line = line.ok()?;
+            (!line.trim().is_empty()).then_some(())?;
+
+            let mut record = serde_json::from_str::<serde_json::Value>(&line).unwrap_or_else(|e| {
+                input(format!(
+                    "Invalid cache line {} in {}: {}",
+                    i + 1,
+                    filename,
+                    e
+                ))
+            });
+            let fen = record["fen"].as_str()?.to_string();
+            Some((fen, record["response"].take()))
+        })
+        .collect()
+}
+
+// Move statistics from a lichess opening explorer endpoint, with the responses read from and
+// appended to a cache file if one is given
+struct Explorer<'a> {
+    url: &'a str,
+    cache_file: Option<&'a str>,
+    cache: HashMap<String, serde_json::Value>,
+    // Positions whose responses were fetched rather than read from the cache
+    fetched: Vec<String>,
+}
+
+impl<'a> Explorer<'a> {
+    fn new(url: &'a str, cache_file: Option<&'a str>) -> Self {
+        Explorer {
+            url,
+            cache_file,
+            cache: cache_file.map(read_masters_cache).unwrap_or_default(),
+            fetched: Vec::new(),
+        }
+    }
+
+    fn moves(&mut self, pos: &Chess) -> Vec<MasterMove> {
+        // Responses don't depend on the move counters, so positions are cached by epd
+        let fen = Epd::from_position(pos.clone(), EnPassantMode::Legal).to_string();
+        let (url, fetched) = (self.url, &mut self.fetched);
+        let response = self.cache.entry(fen.clone()).or_insert_with(|| {
+            verbose!("Querying the explorer for {}", fen);
+            let mut reader = open_input(&format!("{}?fen={}", url, url_fen(pos)));
+            let response = serde_json::from_reader::<_, serde_json::Value>(&mut reader)
+                .unwrap_or_else(|e| input(format!("Invalid explorer response for {}: {}", fen, e)));
+
+            fetched.push(fen.clone());
+            response
+        });
+
+        master_moves(response, pos)
+    }
+
+    fn save(&self) {
+        let Some(filename) = self.cache_file.filter(|_| !self.fetched.is_empty()) else {
+            return;
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)
+            .unwrap_or_else(|_| io(format!("Failure writing file {}", filename)));
+
+        for fen in &self.fetched {
+            let record = serde_json::json!({"fen": fen, "response": self.cache[fen]});
+            writeln!(file, "{}", record);
+        }
+    }
+}
+
+// Reports the book moves of -color that masters rarely play, querying the lichess masters explorer
+// for positions missing from the -masters-cache file, which then saves them for later runs
+fn masters(args: &[String]) {
+    let side = match flag_value(args, "-color") {
+        None | Some("white") => Color::White,
+        Some("black") => Color::Black,
+        Some(x) => usage(format!("Invalid value {:?} for -color", x)),
+    };
+    let plies = parse_flag(args, "-plies").unwrap_or(20);
+    let min_share = parse_flag::<f64>(args, "-min-share").unwrap_or(5.0) / 100.0;
+    let min_games = parse_flag(args, "-min-games").unwrap_or(100);
+    let url = flag_value(args, "-masters-url").unwrap_or("https://explorer.lichess.ovh/masters");
+    let cache_file = flag_value(args, "-masters-cache");
+
+    let output = flag_value(args, "-out").unwrap_or("-");
+    check_outputs([output], args);
+
+    let book = load_books(args);
+    let mut explorer = Explorer::new(url, cache_file);
+
+    let count = book.write_masters_report(
+        &mut open_output(output),
+        side,
+        plies,
+        min_share,
+        min_games,
+        |pos| explorer.moves(pos),
+    );
+    explorer.save();
+
+    info!("Found {} book moves rarely played by masters", count);
+}
+
+// Applies each -add and -remove to the position of the -fen before it, or the root, and writes
+// the book back to its input file unless outputs are given.
+fn edit(args: &[String]) {
+    let args = single_dash(args);
+
+    let inputs = get_input_files(&args, false);
+    let mut outputs = get_output_files(&args);
+
+    let new_outputs = outputs.iter().filter(|x| !inputs.contains(x));
+    check_outputs(new_outputs.map(|x| &x.1[..]), &args);
+
+    if outputs.is_empty() {
+        outputs = inputs;
+        outputs.retain(|x| !x.0.is_games());
+
+        if outputs.len() != 1 {
+            usage(
+                "Give an output file with -out when not editing exactly one book file".to_string(),
+            );
+        }
+    }
+
+    let mut book = load_books(&args);
+    let mut pos = book.root().clone();
+
+    for i in 0..args.len().saturating_sub(1) {
+        let value = &args[i + 1];
+
+        match &args[i][..] {
+            "-fen" => pos = fen_to_chess(value),
+            "-add" => {
+                let (mov, weight, learn) = parse_edit(&pos, value);
+                book.add_move(&pos, &mov, weight, learn);
+            }
+            "-remove" => {
+                let (mov, _, _) = parse_edit(&pos, value);
+
+                if !book.remove_move(&pos, &mov) {
+                    info!("{} is not in the book at {}", value, fen_string(&pos));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    write_book(&mut book, &outputs, &args);
+}
+
+fn write_book_moves<W: Write>(w: &mut W, book: &BookMap, pos: &Chess) {
+    let mut entries = book.entries(pos).to_vec();
+    entries.sort_by_key(|e| std::cmp::Reverse(e.weight));
+
+    let total = entries.iter().map(|e| e.weight).sum::<u64>().max(1);
+
+    if entries.is_empty() {
+        writeln!(w, "No book moves");
+    }
+
+    for entry in entries {
+        let Ok(mov) = from_book_move(entry.mov).to_move(pos) else {
+            continue;
+        };
+
+        write!(
+            w,
+            "{:>8} {:>6.1}%  weight {}",
+            SanPlus::from_move(pos.clone(), &mov).to_string(),
+            entry.weight as f64 * 100.0 / total as f64,
+            entry.weight
+        );
+
+        if entry.learn != 0 {
+            write!(w, ", eval {}", entry.eval());
+        }
+        if let Some(sharpness) = entry.sharpness() {
+            let [wins, draws, losses] = entry.results;
+            write!(
+                w,
+                ", +{} ={} -{}, sharpness {:.2}",
+                wins, draws, losses, sharpness
+            );
+        }
+        writeln!(w);
+    }
+}
+
+// Reads moves and commands from stdin, printing the book moves of each position reached
+fn explore(args: &[String]) {
+    explore_book(&load_books(args), io::stdin().lock(), io::stdout());
+}
+
+// Runs an explore session on the book, reading commands from input until it ends or quit
+fn explore_book<R: BufRead, W: Write>(book: &BookMap, mut input: R, mut stdout: W) {
+    let mut line: Vec<(Chess, SanPlus)> = Vec::new();
+    let mut pos = book.root().clone();
+
+    writeln!(
+        stdout,
+        "Enter moves in san or uci, undo, fen, save-line <file>, or quit"
+    );
+    write_book_moves(&mut stdout, book, &pos);
+
+    loop {
+        write!(stdout, "> ");
+        stdout.flush();
+
+        let mut command = String::new();
+        if input.read_line(&mut command).unwrap_or(0) == 0 {
+            break;
+        }
+
+        let words = command.split_whitespace().collect::<Vec<_>>();
+
+        match words[..] {
+            [] => continue,
+            ["quit"] | ["exit"] => break,
+            ["fen"] => {
+                writeln!(stdout, "{}", fen_string(&pos));
+                continue;
+            }
+            ["undo"] => match line.pop() {
+                Some((prev, _)) => pos = prev,
+                None => {
+                    writeln!(stdout, "Already at the root");
+                    continue;
+                }
+            },
+            ["save-line", filename] => {
+                let moves = line.iter().map(|x| x.1.clone()).collect();
+                let mut headers = vec![("Event".to_string(), "Explored line".to_string())];
+                headers.extend(root_headers(book.root()));
+
+                match OpenOptions::new().create(true).append(true).open(filename) {
+                    Ok(mut file) => {
+                        writeln!(file, "{}", PgnGame::from_moves(headers, moves));
+                        writeln!(stdout, "Saved line to {}", filename);
+                    }
+                    Err(_) => {
+                        writeln!(stdout, "Failure writing file {}", filename);
+                    }
+                }
+                continue;
+            }
+            [word] => match parse_move(&pos, word) {
+                Some(mov) => {
+                    let san = SanPlus::from_move(pos.clone(), &mov);
+                    let next = pos.clone().play(&mov).unwrap();
+                    line.push((std::mem::replace(&mut pos, next), san));
+                }
+                None => {
+                    writeln!(stdout, "Invalid move or command {}", word);
+                    continue;
+                }
+            },
+            _ => {
+                writeln!(stdout, "Invalid move or command {}", command.trim());
+                continue;
+            }
+        }
+
+        let moves = line.iter().map(|x| x.1.to_string()).collect::<Vec<_>>();
+        writeln!(stdout, "Line: {}", moves.join(" "));
+        write_book_moves(&mut stdout, book, &pos);
+    }
+}
+
+// Writes the lines of the book without choices as pgn games starting from their positions, with
+// the moves reaching them and the moves where they end as headers
+fn forced_lines(args: &[String]) {
+    let output = flag_value(args, "-out").unwrap_or("-");
+    check_outputs([output], args);
+
+    let book = load_books(args);
+    let min_moves = parse_flag(args, "-min-moves").unwrap_or(2);
+    let lines = book.forced_lines(repertoire_color(args), min_moves);
+    let mut writer = open_output(output);
+
+    for (i, line) in lines.iter().enumerate() {
+        let mut headers = vec![
+            ("Event".to_string(), format!("Forced line {}", i + 1)),
+            ("White".to_string(), "?".to_string()),
+            ("Black".to_string(), "?".to_string()),
+            ("Result".to_string(), "*".to_string()),
+        ];
+
+        if !line.entry.is_empty() {
+            headers.push(("Entry".to_string(), line.entry.join(" ")));
+        }
+        if !line.continuations.is_empty() {
+            headers.push(("Continuations".to_string(), line.continuations.join(" ")));
+        }
+        headers.extend(root_headers(&line.start));
+
+        let game = PgnGame::from_moves(headers, line.moves.clone());
+        writeln!(writer, "{}", game);
+    }
+
+    info!("Found {} forced lines", lines.len());
+}
+
+// Lists the popular opponent moves the repertoire has no answer to, filling them with the best
+// move of the -engine when outputs are given
+fn gaps(args: &[String]) {
+    let outputs = get_output_files(args);
+    let sidecars = bloom_sidecars(&outputs, args);
+    check_outputs(outputs.iter().map(|x| &x.1[..]), args);
+    check_outputs(sidecars.iter().map(|x| &x[..]), args);
+
+    let side = repertoire_color(args);
+    let plies = parse_flag(args, "-plies").unwrap_or(20);
+    let min_share = parse_flag::<f64>(args, "-min-share").unwrap_or(10.0) / 100.0;
+    let min_games = parse_flag(args, "-min-games").unwrap_or(20);
+    let url = flag_value(args, "-explorer-url").unwrap_or("https://explorer.lichess.ovh/lichess");
+    let mut explorer = Explorer::new(url, flag_value(args, "-explorer-cache"));
+
+    // Reference games are counted by frequency, so that weights are numbers of games
+    let mut reference = BookMap::new();
+    let mut options = BuildOptions::new();
+    options.frequency = true;
+
+    for file in flag_values(args, "-games") {
+        for game in read_games(PgnFilter::new(), open_input(file)) {
+            reference.add_game(&game, &options, 1.0);
+        }
+    }
+
+    let mut book = load_books(args);
+    let mut engine = (!outputs.is_empty()).then(|| engine_from_args(args));
+
+    let lookup = |pos: &Chess| {
+        if flag_value(args, "-games").is_some() {
+            reference.reference_moves(pos)
+        } else {
+            explorer.moves(pos)
+        }
+    };
+    let answer = |pos: &Chess| match engine.as_mut() {
+        Some(engine) => engine_entries(engine, pos, 1),
+        None => Vec::new(),
+    };
+
+    let gaps = book.find_gaps(side, plies, min_share, min_games, lookup, answer);
+    explorer.save();
+    write_gaps(&mut io::stdout(), &gaps);
+
+    info!(
+        "Found {} popular opponent moves without book answers, filled {}",
+        gaps.len(),
+        gaps.iter().filter(|x| x.filled).count()
+    );
+
+    if !outputs.is_empty() {
+        write_book(&mut book, &outputs, args);
+    }
+}
+
+fn grow(args: &[String]) {
+    let outputs = get_output_files(args);
+    let sidecars = bloom_sidecars(&outputs, args);
+    check_outputs(outputs.iter().map(|x| &x.1[..]), args);
+    check_outputs(sidecars.iter().map(|x| &x[..]), args);
+
+    let mut book = load_books(args);
+    let mut engine = engine_from_args(args);
+
+    let mut options = GrowOptions::new();
+    options.iterations = parse_flag(args, "-iterations").unwrap_or(options.iterations);
+    options.multipv = parse_flag(args, "-multipv").unwrap_or(options.multipv);
+    options.cpuct = parse_flag(args, "-cpuct").unwrap_or(options.cpuct);
+    options.max_plies = parse_flag(args, "-max-plies").unwrap_or(options.max_plies);
+    options.checkpoint = parse_flag(args, "-checkpoint").unwrap_or(options.checkpoint);
+
+    book.grow(&mut engine, &options, |book, i| {
+        info!("Writing book after {} iterations...", i);
+        write_book(book, &outputs, args);
+    });
+}
+
+fn analyse(args: &[String]) {
+    let outputs = get_output_files(args);
+    let sidecars = bloom_sidecars(&outputs, args);
+    check_outputs(outputs.iter().map(|x| &x.1[..]), args);
+    check_outputs(sidecars.iter().map(|x| &x[..]), args);
+
+    let Some(file) = flag_value(args, "-fens") else {
+        usage("The analyse command needs a file of positions given with -fens".to_string())
+    };
+    let positions = read_positions(BufReader::new(open_input(file)));
+    let multipv = parse_flag(args, "-multipv").unwrap_or(4);
+
+    let mut book = BookMap::new();
+    let mut engine = engine_from_args(args);
+    let count = book.add_engine_moves(&mut engine, &positions, multipv);
+    info!("Analysed {} of {} positions", count, positions.len());
+
+    write_book(&mut book, &outputs, args);
+}
+
+fn write_suite<W: Write>(w: &mut W, root: &Chess, suite: &[Opening], epd: bool) {
+    for (i, opening) in suite.iter().enumerate() {
+        let sans = opening
+            .moves
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        if epd {
+            let pos = &opening.position;
+            let stm_eval = pos.turn().fold_wb(opening.eval, -opening.eval);
+
+            writeln!(
+                w,
+                "{} ce {}; c0 \"{}\";",
+                Epd::from_position(pos.clone(), EnPassantMode::Legal),
+                stm_eval,
+                sans
+            );
+        } else {
+            let name = format!("Opening {}", i + 1);
+            let game = line_game(root, &name, opening.eval, opening.moves.clone());
+            writeln!(w, "{}", game);
+        }
+    }
+}
+
+// Whether suite keeps an opening with the given eval from white's point of view, by -window or
+// the advantage band
+fn suite_filter(args: &[String]) -> impl Fn(i32) -> bool {
+    let window: i32 = parse_flag(args, "-window").unwrap_or(30);
+
+    // An advantage band, given from the favored side's point of view, replaces the window
+    let min_eval: Option<i32> = parse_flag(args, "-min-eval");
+    let max_eval: Option<i32> = parse_flag(args, "-max-eval");
+    let (white, black) = match flag_value(args, "-advantage").unwrap_or("white") {
+        "white" => (true, false),
+        "black" => (false, true),
+        "either" => (true, true),
+        x => usage(format!("Invalid value {:?} for -advantage", x)),
+    };
+
+    move |eval: i32| {
+        if min_eval.is_none() && max_eval.is_none() {
+            return eval.abs() <= window;
+        }
+
+        let in_band =
+            |e: i32| e >= min_eval.unwrap_or(i32::MIN) && e <= max_eval.unwrap_or(i32::MAX);
+
+        (white && in_band(eval)) || (black && in_band(-eval))
+    }
+}
+
+fn suite(args: &[String]) {
+    let mut outputs = flag_values(args, "-out");
+    if outputs.is_empty() {
+        outputs.push("-");
+    }
+    check_outputs(outputs.iter().copied(), args);
+
+    let book = load_books(args);
+
+    let plies = parse_flag(args, "-plies").unwrap_or(8);
+    let count = parse_flag(args, "-count").unwrap_or(100);
+    let accept = suite_filter(args);
+
+    let mut engine = flag_value(args, "-engine").map(|_| engine_from_args(args));
+    let mut suite = Vec::new();
+
+    for mut opening in book.openings(plies) {
+        if suite.len() >= count {
+            break;
+        }
+
+        if let Some(engine) = &mut engine {
+            let turn = opening.position.turn();
+
+            match engine.analyse(&opening.position, 1).first() {
+                Some(line) => opening.eval = turn.fold_wb(line.score, -line.score),
+                None => continue,
+            }
+        }
+
+        if accept(opening.eval) {
+            suite.push(opening);
+        }
+    }
+
+    for output in outputs {
+        let epd = output.ends_with(".epd") || (output == "-" && args.iter().any(|x| x == "-epd"));
+        write_suite(&mut open_output(output), book.root(), &suite, epd);
+    }
+}
+
+fn table(args: &[String]) {
+    let output = flag_value(args, "-out").unwrap_or("-");
+    let html = output.ends_with(".html") || args.iter().any(|x| x == "-html");
+    check_outputs([output], args);
+
+    let book = load_books(args);
+    let plies = parse_flag(args, "-plies").unwrap_or(6);
+    let min_share = parse_flag::<f64>(args, "-min-share").unwrap_or(0.0) / 100.0;
+
+    let rows = book.opening_table(plies, min_share);
+    let mut writer = open_output(output);
+
+    if html {
+        book.write_table_html(&mut writer, &rows, plies);
+    } else {
+        book.write_table(&mut writer, &rows, plies);
+    }
+}
+
+// The board from side's end, with the ranks and files labelled
+fn write_board<W: Write>(w: &mut W, pos: &Chess, side: Color) {
+    for rank in 0..8u32 {
+        let rank = side.fold_wb(7 - rank, rank);
+        write!(w, "{} ", rank + 1);
+
+        for file in 0..8u32 {
+            let file = side.fold_wb(file, 7 - file);
+
+            match pos.board().piece_at(Square::new(rank * 8 + file)) {
+                Some(piece) => write!(w, " {}", piece.char()),
+                None => write!(w, " ."),
+            };
+        }
+        writeln!(w);
+    }
+
+    let files = side.fold_wb("abcdefgh", "hgfedcba");
+    writeln!(
+        w,
+        "  {}",
+        files.chars().map(|c| format!(" {}", c)).collect::<String>()
+    );
+}
+
+// Right and wrong answers per position, kept in the -progress file as lines of hex hash, right
+// and wrong counts
+type Progress = PositionMap<(u32, u32)>;
+
+fn read_progress(filename: &str) -> Progress {
+    let Ok(text) = std::fs::read_to_string(filename) else {
+        return Progress::default();
+    };
+
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+
+            let parsed = match fields[..] {
+                [hash, right, wrong] => u64::from_str_radix(hash, 16)
+                    .ok()
+                    .zip(right.parse().ok().zip(wrong.parse().ok())),
+                _ => None,
+            };
+
+            parsed
+                .unwrap_or_else(|| input(format!("{}:{}: Invalid progress line", filename, i + 1)))
+        })
+        .collect()
+}
+
+fn write_progress(filename: &str, progress: &Progress) {
+    let mut out = progress.iter().collect::<Vec<_>>();
+    out.sort_unstable();
+
+    let mut writer = open_output(filename);
+    for (hash, (right, wrong)) in out {
+        writeln!(writer, "{:016x} {} {}", hash, right, wrong);
+    }
+}
+
+// Quizzes the user on the book moves of -color along random lines, choosing the opponent's moves
+// in proportion to their weights and more often towards positions that were answered wrongly
+fn train(args: &[String]) {
+    let args = single_dash(args);
+    let side = match flag_value(&args, "-color") {
+        None | Some("white") => Color::White,
+        Some("black") => Color::Black,
+        Some(x) => usage(format!("Invalid value {:?} for -color", x)),
+    };
+    let progress_file = flag_value(&args, "-progress");
+
+    let mut rng = parse_flag(&args, "-seed").map_or_else(Rng::from_time, Rng::new);
+
+    let book = load_books(&args);
+    let mut progress = progress_file.map(read_progress).unwrap_or_default();
+    let (mut right, mut wrong) = (0, 0);
+    let mut stdout = io::stdout();
+
+    writeln!(stdout, "Enter the book move in san or uci, or quit");
+
+    // Lines that ended without a question, to stop when the book has none to ask
+    let mut idle = 0;
+
+    'lines: loop {
+        let mut pos = book.root().clone();
+        let mut line = Vec::new();
+        let asked = right + wrong;
+
+        if idle >= 1000 {
+            writeln!(
+                stdout,
+                "No positions with book moves for the -color to answer"
+            );
+            break;
+        }
+
+        loop {
+            let entries = book
+                .entries(&pos)
+                .iter()
+                .filter_map(|e| Some((e, from_book_move(e.mov).to_move(&pos).ok()?)))
+                .collect::<Vec<_>>();
+
+            if entries.is_empty() {
+                if right + wrong == asked {
+                    idle += 1;
+                } else {
+                    idle = 0;
+                    writeln!(
+                        stdout,
+                        "End of line, {} right and {} wrong so far",
+                        right, wrong
+                    );
+                }
+                continue 'lines;
+            }
+
+            let mov = if pos.turn() == side {
+                writeln!(stdout);
+                write_board(&mut stdout, &pos, side);
+                if !line.is_empty() {
+                    writeln!(stdout, "Line: {}", line.join(" "));
+                }
+                let mut answer = String::new();
+
+                while answer.trim().is_empty() {
+                    write!(stdout, "Your move> ");
+                    stdout.flush();
+
+                    answer.clear();
+                    if io::stdin().lock().read_line(&mut answer).unwrap_or(0) == 0 {
+                        break 'lines;
+                    }
+                }
+                let answer = answer.trim();
+
+                if answer == "quit" || answer == "exit" {
+                    break 'lines;
+                }
+
+                let stats = progress.entry(book.hash(&pos)).or_default();
+                let best = entries.iter().max_by_key(|x| x.0.weight).unwrap().1.clone();
+
+                match parse_move(&pos, answer).filter(|m| entries.iter().any(|x| x.1 == *m)) {
+                    Some(mov) => {
+                        writeln!(stdout, "Correct");
+                        stats.0 += 1;
+                        right += 1;
+                        mov
+                    }
+                    None => {
+                        writeln!(stdout, "Wrong, the book moves are:");
+                        write_book_moves(&mut stdout, &book, &pos);
+                        stats.1 += 1;
+                        wrong += 1;
+                        best
+                    }
+                }
+            } else {
+                // Each miss at the position a move leads to makes it one time more likely
+                let weights = entries
+                    .iter()
+                    .map(|(e, mov)| {
+                        let next = book.hash(&pos.clone().play(mov).unwrap());
+                        let misses = progress.get(&next).map_or(0, |x| x.1);
+                        (e.weight + 1) as f64 * (1 + misses) as f64
+                    })
+                    .collect::<Vec<_>>();
+
+                let mov = entries[rng.pick(&weights)].1.clone();
+                writeln!(
+                    stdout,
+                    "Opponent plays {}",
+                    SanPlus::from_move(pos.clone(), &mov)
+                );
+                mov
+            };
+
+            line.push(SanPlus::from_move(pos.clone(), &mov).to_string());
+            pos.play_unchecked(&mov);
+        }
+    }
+
+    writeln!(stdout, "\n{} right and {} wrong", right, wrong);
+
+    if let Some(filename) = progress_file {
+        write_progress(filename, &progress);
+    }
+}
+
+#[test]
+fn t_explore_book() {
+    let mut book = BookMap::new();
+    let entry = |uci: &str, weight| BookEntry {
+        mov: to_book_move(uci.parse().unwrap()),
+        weight,
+        ..BookEntry::new()
+    };
+    let e4 = book
+        .root()
+        .clone()
+        .play(&parse_move(book.root(), "e4").unwrap())
+        .unwrap();
+    let mut e5 = entry("e7e5", 2);
+    e5.set_eval(-25);
+
+    book.insert(book.hash(book.root()), entry("e2e4", 3));
+    book.insert(book.hash(book.root()), entry("d2d4", 1));
+    book.insert(book.hash(&e4), e5);
+    book.insert(book.hash(&e4), entry("c7c5", 1));
+
+    let mut out = Vec::new();
+    explore_book(
+        &book,
+        "e4\nfen\nundo\nundo\nquit\ne4\n".as_bytes(),
+        &mut out,
+    );
+    let out = String::from_utf8(out).unwrap();
+
+    assert!(out.contains("      e4   75.0%  weight 3\n"));
+    assert!(out.contains("      e5   66.7%  weight 2, eval -25\n"));
+    assert!(out.contains("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"));
+    assert!(out.contains("Already at the root"));
+    assert_eq!(out.matches("Line: e4\n").count(), 1);
+}
+
+#[test]
+fn t_suite_filter() {
+    let accepted = |args: &str| {
+        let args = args
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let accept = suite_filter(&args);
+        [-200, -100, -40, -30, 0, 30, 40, 100, 200]
+            .iter()
+            .copied()
+            .filter(|&x| accept(x))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(accepted(""), [-30, 0, 30]);
+    assert_eq!(accepted("-window 40"), [-40, -30, 0, 30, 40]);
+    assert_eq!(accepted("-min-eval 50 -max-eval 150"), [100]);
+    assert_eq!(accepted("-min-eval 50 -advantage black"), [-200, -100]);
+    assert_eq!(
+        accepted("-window 300 -min-eval 40 -max-eval 100 -advantage either"),
+        [-100, -40, 40, 100]
+    );
+}
@@ -7,7 +7,7 @@ pub use shakmaty::{
     uci::UciMove,
     CastlingMode,
     CastlingMode::*,
-    CastlingSide, Chess, Color, Outcome, Position, Role, Square,
+    CastlingSide, Chess, Color, Move, Outcome, Position, Role, Square,
 };
 
 pub const START_HASH: u64 = 0x463b96181691fc9c;
@@ -801,21 +801,17 @@ const RANDOM_ENPASSANT: [u64; 8] = [
 const RANDOM_TURN: u64 = 0xF8D626AAAF278509;
 
 pub fn book_hash(board: Chess) -> u64 {
-    let mut out = 0;
-
-    // Piece
-    for (sq, piece) in board.board().clone().into_iter() {
-        let mut idx = (usize::from(piece.role) - 1) * 2;
-
-        if piece.color == Color::White {
-            idx += 1;
-        }
+    PolyglotHasher.hash(&board)
+}
 
-        out ^= RANDOM_PIECE[64 * idx + usize::from(sq)];
-    }
+fn piece_key(role: Role, color: Color, sq: Square) -> u64 {
+    let idx = (usize::from(role) - 1) * 2 + (color == Color::White) as usize;
+    RANDOM_PIECE[64 * idx + usize::from(sq)]
+}
 
-    // Castle
+fn castle_key(board: &Chess) -> u64 {
     let castles = board.castles();
+    let mut out = 0;
 
     if castles.has(Color::White, CastlingSide::KingSide) {
         out ^= RANDOM_CASTLE[0];
@@ -830,9 +826,26 @@ pub fn book_hash(board: Chess) -> u64 {
         out ^= RANDOM_CASTLE[3];
     }
 
-    // En Passant
-    if let Some(sq) = board.ep_square(EnPassantMode::Legal) {
-        out ^= RANDOM_ENPASSANT[usize::from(sq.file())];
+    out
+}
+
+fn en_passant_key(board: &Chess) -> u64 {
+    board
+        .ep_square(EnPassantMode::Legal)
+        .map_or(0, |sq| RANDOM_ENPASSANT[usize::from(sq.file())])
+}
+
+fn polyglot_hash(board: &Chess) -> u64 {
+    let mut out = castle_key(board) ^ en_passant_key(board);
+
+    // Piece, going through the bitboard of each piece rather than looking up the piece on each
+    // occupied square
+    for role in Role::ALL {
+        for color in Color::ALL {
+            for sq in board.board().by_piece(role.of(color)) {
+                out ^= piece_key(role, color, sq);
+            }
+        }
     }
 
     // Player's Turn
@@ -843,6 +856,81 @@ pub fn book_hash(board: Chess) -> u64 {
     out
 }
 
+// Plays mov at board, updating hash, the polyglot hash of board, with the keys of the squares,
+// castling rights and en passant square that change instead of hashing the new position
+fn play_polyglot(board: &mut Chess, hash: u64, mov: &Move) -> u64 {
+    let color = board.turn();
+    let mut out = hash ^ RANDOM_TURN ^ castle_key(board) ^ en_passant_key(board);
+
+    match *mov {
+        Move::Normal {
+            role,
+            from,
+            capture,
+            to,
+            promotion,
+        } => {
+            out ^= piece_key(role, color, from) ^ piece_key(promotion.unwrap_or(role), color, to);
+
+            if let Some(captured) = capture {
+                out ^= piece_key(captured, !color, to);
+            }
+        }
+        Move::EnPassant { from, to } => {
+            out ^= piece_key(Role::Pawn, color, from)
+                ^ piece_key(Role::Pawn, color, to)
+                ^ piece_key(
+                    Role::Pawn,
+                    !color,
+                    Square::from_coords(to.file(), from.rank()),
+                );
+        }
+        Move::Castle { king, rook } => {
+            let side = CastlingSide::from_queen_side(rook < king);
+
+            out ^= piece_key(Role::King, color, king)
+                ^ piece_key(Role::King, color, side.king_to(color))
+                ^ piece_key(Role::Rook, color, rook)
+                ^ piece_key(Role::Rook, color, side.rook_to(color));
+        }
+        Move::Put { .. } => unreachable!(),
+    }
+
+    board.play_unchecked(mov);
+    out ^ castle_key(board) ^ en_passant_key(board)
+}
+
+/// Computes the keys that book entries are stored under. Only books using the Polyglot hash can be
+/// read from or written to .bin files.
+pub trait PositionHasher {
+    fn name(&self) -> &'static str;
+    fn hash(&self, pos: &Chess) -> u64;
+
+    /// Plays mov, which must be legal, at pos, returning the hash of the new position given the
+    /// hash of pos. Hashers that can update hashes move by move override this to avoid hashing
+    /// every position of a game from scratch.
+    fn play(&self, pos: &mut Chess, _hash: u64, mov: &Move) -> u64 {
+        pos.play_unchecked(mov);
+        self.hash(pos)
+    }
+}
+
+pub struct PolyglotHasher;
+
+impl PositionHasher for PolyglotHasher {
+    fn name(&self) -> &'static str {
+        "polyglot"
+    }
+
+    fn hash(&self, pos: &Chess) -> u64 {
+        polyglot_hash(pos)
+    }
+
+    fn play(&self, pos: &mut Chess, hash: u64, mov: &Move) -> u64 {
+        play_polyglot(pos, hash, mov)
+    }
+}
+
 pub fn to_book_move(mov: UciMove) -> u16 {
     if let UciMove::Normal {
         from: sq1,
@@ -889,9 +977,17 @@ pub fn from_book_move(mov: u16) -> UciMove {
 
 pub fn fen_to_chess(fen: &str) -> Chess {
     fen.parse::<Fen>()
-        .unwrap()
-        .into_position(CastlingMode::Chess960)
-        .unwrap()
+        .ok()
+        .and_then(|f| f.into_position(CastlingMode::Chess960).ok())
+        .unwrap_or_else(|| crate::error::usage(format!("Invalid fen {:?}", fen)))
+}
+
+/// Reads a move for pos in either san or uci
+pub fn parse_move(pos: &Chess, word: &str) -> Option<shakmaty::Move> {
+    word.parse::<SanPlus>()
+        .ok()
+        .and_then(|s| s.san.to_move(pos).ok())
+        .or_else(|| word.parse::<UciMove>().ok()?.to_move(pos).ok())
 }
 
 #[test]
@@ -942,6 +1038,29 @@ fn t_hash() {
     }
 }
 
+#[test]
+fn t_incremental_hash() {
+    // Castling both ways, en passant captures and non captures, promotions with and without
+    // capture and rooks captured on their starting squares
+    let games = [
+        "e4 d5 e5 f5 exf6 Nxf6 Nf3 e6 Bb5+ c6 O-O Qa5 d4 Be7 Nc3 O-O Bd3 b5 a4 b4 Ne2 Ba6 Bxa6",
+        "d4 e5 dxe5 f6 exf6 Nc6 fxg7 Be7 gxh8=Q Kf8 Qxg8+ Kxg8 Nc3 d6 Bf4 Be6 Qd2 Qd7 O-O-O Rb8",
+        "a4 h5 a5 b5 axb6 h4 g4 hxg3 bxc7 gxh2 cxb8=N hxg1=Q Rxa7 Qxf2+ Kxf2 Rxh1",
+    ];
+
+    for game in games {
+        let mut pos = Chess::default();
+        let mut hash = polyglot_hash(&pos);
+
+        for san in game.split(' ') {
+            let mov = san.parse::<San>().unwrap().to_move(&pos).unwrap();
+            hash = PolyglotHasher.play(&mut pos, hash, &mov);
+
+            assert_eq!(hash, polyglot_hash(&pos), "after {} in {}", san, game);
+        }
+    }
+}
+
 #[test]
 fn t_book_move() {
     let mov = UciMove::Normal {
@@ -0,0 +1,195 @@
+use crate::conversions::*;
+
+#[cfg(feature = "engine")]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(feature = "engine")]
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+#[cfg(feature = "engine")]
+use shakmaty::EnPassantMode;
+
+/// Score given to a mate in zero, mates further away score one less per ply.
+pub const MATE_SCORE: i32 = 32000;
+
+#[cfg(feature = "engine")]
+pub struct Engine {
+    process: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    limit: String,
+    multipv: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct EngineLine {
+    pub mov: UciMove,
+    /// Centipawn score from the point of view of the side to move
+    pub score: i32,
+    pub pv: Vec<UciMove>,
+}
+
+#[cfg(feature = "engine")]
+impl Engine {
+    /// Starts the engine at path, which then searches each position with the given `go` limit,
+    /// such as "depth 12" or "movetime 500".
+    pub fn new(path: &str, limit: &str) -> Self {
+        let mut process = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|_| panic!("Failure starting engine {}", path));
+
+        let input = process.stdin.take().unwrap();
+        let output = BufReader::new(process.stdout.take().unwrap());
+
+        let mut out = Engine {
+            process,
+            input,
+            output,
+            limit: limit.to_string(),
+            multipv: 1,
+        };
+
+        out.send("uci");
+        out.wait_for("uciok");
+        out.send("ucinewgame");
+        out.sync();
+        out
+    }
+
+    fn send(&mut self, command: &str) {
+        writeln!(self.input, "{}", command).expect("Engine closed its input");
+        self.input.flush();
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+
+        if self.output.read_line(&mut line).unwrap_or(0) == 0 {
+            panic!("Engine exited unexpectedly");
+        }
+
+        line.trim().to_string()
+    }
+
+    fn wait_for(&mut self, token: &str) -> String {
+        loop {
+            let line = self.read_line();
+
+            if line.split_whitespace().next() == Some(token) {
+                return line;
+            }
+        }
+    }
+
+    fn sync(&mut self) {
+        self.send("isready");
+        self.wait_for("readyok");
+    }
+
+    pub fn set_option(&mut self, name: &str, value: &str) {
+        self.send(&format!("setoption name {} value {}", name, value));
+        self.sync();
+    }
+
+    /// Searches pos and returns up to multipv lines, best first.
+    pub fn analyse(&mut self, pos: &Chess, multipv: usize) -> Vec<EngineLine> {
+        if self.multipv != multipv {
+            self.set_option("MultiPV", &multipv.to_string());
+            self.multipv = multipv;
+        }
+
+        let fen = Fen::from_position(pos.clone(), EnPassantMode::Legal);
+        self.send(&format!("position fen {}", fen));
+        self.send(&format!("go {}", self.limit));
+
+        let mut lines: Vec<Option<EngineLine>> = vec![None; multipv.max(1)];
+
+        loop {
+            let line = self.read_line();
+            let mut tokens = line.split_whitespace();
+
+            match tokens.next() {
+                Some("bestmove") => break,
+                Some("info") => {}
+                _ => continue,
+            }
+
+            if let Some((index, engine_line)) = parse_info(tokens) {
+                if index >= 1 && index <= lines.len() {
+                    lines[index - 1] = Some(engine_line);
+                }
+            }
+        }
+
+        lines.into_iter().flatten().collect()
+    }
+}
+
+// Parses the tokens after "info" into the multipv index and line they describe, if they include
+// an exact score and a principal variation.
+fn parse_info<'a, I: Iterator<Item = &'a str>>(mut tokens: I) -> Option<(usize, EngineLine)> {
+    let mut index = 1;
+    let mut score = None;
+    let mut pv = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "multipv" => index = tokens.next().and_then(|x| x.parse().ok()).unwrap_or(1),
+            "score" => {
+                let kind = tokens.next();
+                let value = tokens.next().and_then(|x| x.parse::<i32>().ok());
+
+                score = match (kind, value) {
+                    (Some("cp"), Some(cp)) => Some(cp),
+                    (Some("mate"), Some(n)) if n > 0 => Some(MATE_SCORE - 2 * n + 1),
+                    (Some("mate"), Some(n)) => Some(-MATE_SCORE - 2 * n),
+                    _ => None,
+                };
+            }
+            "lowerbound" | "upperbound" => score = None,
+            "pv" => {
+                pv = tokens
+                    .by_ref()
+                    .filter_map(|x| x.parse::<UciMove>().ok())
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+
+    let mov = pv.first()?.clone();
+    Some((
+        index,
+        EngineLine {
+            mov,
+            score: score?,
+            pv,
+        },
+    ))
+}
+
+#[cfg(feature = "engine")]
+impl Drop for Engine {
+    fn drop(&mut self) {
+        writeln!(self.input, "quit");
+        self.process.wait();
+    }
+}
+
+#[test]
+fn t_parse_info() {
+    let line = "depth 20 seldepth 25 multipv 2 score cp -31 nodes 1000 pv e7e5 g1f3 b8c6";
+    let (index, parsed) = parse_info(line.split_whitespace()).unwrap();
+
+    assert_eq!(index, 2);
+    assert_eq!(parsed.score, -31);
+    assert_eq!(parsed.mov.to_string(), "e7e5");
+    assert_eq!(parsed.pv.len(), 3);
+
+    let (_, mate) = parse_info("depth 5 score mate 2 pv d8h4".split_whitespace()).unwrap();
+    assert_eq!(mate.score, MATE_SCORE - 3);
+
+    assert!(parse_info("depth 5 score cp 10 lowerbound pv d8h4".split_whitespace()).is_none());
+    assert!(parse_info("depth 5 currmove e2e4".split_whitespace()).is_none());
+}
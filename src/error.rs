@@ -0,0 +1,63 @@
+// Failures caused by the user's flags or files unwind with a CliError, which main reports as a
+// single line before exiting with the error's code. Other panics are bugs, and exit with 1.
+
+use std::any::Any;
+use std::env;
+use std::panic;
+
+/// Internal errors and failures without a more specific code, including engine failures
+pub const FAILURE: i32 = 1;
+/// Invalid flags or flag values
+pub const USAGE: i32 = 2;
+/// Files that could not be opened, created or written
+pub const IO: i32 = 3;
+/// Malformed input files
+pub const INPUT: i32 = 4;
+
+pub struct CliError {
+    pub code: i32,
+    pub message: String,
+}
+
+pub fn fail(code: i32, message: String) -> ! {
+    panic::panic_any(CliError { code, message })
+}
+
+pub fn usage(message: String) -> ! {
+    fail(USAGE, message)
+}
+
+pub fn io(message: String) -> ! {
+    fail(IO, message)
+}
+
+pub fn input(message: String) -> ! {
+    fail(INPUT, message)
+}
+
+/// Silences the default panic message, which is kept for bugs when RUST_BACKTRACE is set
+pub fn install_hook() {
+    let default = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        if !info.payload().is::<CliError>() && env::var_os("RUST_BACKTRACE").is_some() {
+            default(info);
+        }
+    }));
+}
+
+/// Prints the error carried by a panic and returns the exit code for it
+pub fn report(payload: Box<dyn Any + Send>) -> i32 {
+    let (code, message) = if let Some(e) = payload.downcast_ref::<CliError>() {
+        (e.code, e.message.clone())
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        (FAILURE, s.clone())
+    } else if let Some(s) = payload.downcast_ref::<&str>() {
+        (FAILURE, s.to_string())
+    } else {
+        (FAILURE, "unknown error".to_string())
+    };
+
+    eprintln!("rustyglot: {}", message);
+    code
+}
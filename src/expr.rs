@@ -0,0 +1,309 @@
+// Expressions over named integer values, such as "white_elo > 2400 && time >= 600 && !draw" for
+// selecting games or "wins * 2 + draws" for weighting moves. Expressions are built from integers,
+// variables, the arithmetic operators + - * /, the comparisons < <= > >= == !=, the operators
+// ! && || and parentheses. Nonzero values are true. Arithmetic and comparisons with missing values,
+// and division by zero, give missing values, which are false.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Var(usize),
+    Cmp(Cmp),
+    Arith(Arith),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Num(i64),
+    // Index of the variable in the names the expression was parsed with
+    Var(usize),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Cmp(Box<Node>, Cmp, Box<Node>),
+    Arith(Box<Node>, Arith, Box<Node>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    root: Node,
+}
+
+fn tokenize(text: &str, names: &[&str]) -> Result<Vec<Token>, String> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let len = chars[i..]
+                .iter()
+                .take_while(|x| x.is_ascii_alphanumeric() || **x == '_')
+                .count();
+            let word = chars[i..i + len].iter().collect::<String>();
+
+            out.push(if let Ok(n) = word.parse() {
+                Token::Num(n)
+            } else {
+                let var = names
+                    .iter()
+                    .position(|x| *x == word)
+                    .ok_or_else(|| format!("Unknown variable {}", word))?;
+                Token::Var(var)
+            });
+            i += len;
+            continue;
+        }
+
+        let (token, len) = match (c, next) {
+            ('<', Some('=')) => (Token::Cmp(Cmp::Le), 2),
+            ('>', Some('=')) => (Token::Cmp(Cmp::Ge), 2),
+            ('=', Some('=')) => (Token::Cmp(Cmp::Eq), 2),
+            ('!', Some('=')) => (Token::Cmp(Cmp::Ne), 2),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('<', _) => (Token::Cmp(Cmp::Lt), 1),
+            ('>', _) => (Token::Cmp(Cmp::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('+', _) => (Token::Arith(Arith::Add), 1),
+            ('-', _) => (Token::Arith(Arith::Sub), 1),
+            ('*', _) => (Token::Arith(Arith::Mul), 1),
+            ('/', _) => (Token::Arith(Arith::Div), 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            _ => return Err(format!("Unexpected character {:?}", c)),
+        };
+
+        out.push(token);
+        i += len;
+    }
+
+    Ok(out)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    i: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.i).copied()
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut node = self.and()?;
+
+        while self.peek() == Some(Token::Or) {
+            self.i += 1;
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+
+        while self.peek() == Some(Token::And) {
+            self.i += 1;
+            node = Node::And(Box::new(node), Box::new(self.unary()?));
+        }
+
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.peek() == Some(Token::Not) {
+            self.i += 1;
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+
+        let node = self.sum()?;
+
+        if let Some(Token::Cmp(cmp)) = self.peek() {
+            self.i += 1;
+            return Ok(Node::Cmp(Box::new(node), cmp, Box::new(self.sum()?)));
+        }
+
+        Ok(node)
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        let mut node = self.product()?;
+
+        while let Some(Token::Arith(op @ (Arith::Add | Arith::Sub))) = self.peek() {
+            self.i += 1;
+            node = Node::Arith(Box::new(node), op, Box::new(self.product()?));
+        }
+
+        Ok(node)
+    }
+
+    fn product(&mut self) -> Result<Node, String> {
+        let mut node = self.primary()?;
+
+        while let Some(Token::Arith(op @ (Arith::Mul | Arith::Div))) = self.peek() {
+            self.i += 1;
+            node = Node::Arith(Box::new(node), op, Box::new(self.primary()?));
+        }
+
+        Ok(node)
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        let token = self.peek().ok_or("Unexpected end of expression")?;
+        self.i += 1;
+
+        match token {
+            Token::Num(n) => Ok(Node::Num(n)),
+            Token::Var(v) => Ok(Node::Var(v)),
+            Token::Arith(Arith::Sub) => Ok(Node::Arith(
+                Box::new(Node::Num(0)),
+                Arith::Sub,
+                Box::new(self.primary()?),
+            )),
+            Token::Open => {
+                let node = self.or()?;
+                if self.peek() != Some(Token::Close) {
+                    return Err("Unclosed (".to_string());
+                }
+                self.i += 1;
+                Ok(node)
+            }
+            _ => Err(format!("Unexpected {:?}", token)),
+        }
+    }
+}
+
+fn truthy(value: Option<i64>) -> bool {
+    value.is_some_and(|x| x != 0)
+}
+
+impl Node {
+    fn eval(&self, value: &impl Fn(usize) -> Option<i64>) -> Option<i64> {
+        match self {
+            Node::Num(n) => Some(*n),
+            Node::Var(v) => value(*v),
+            Node::Not(x) => x.eval(value).map(|x| (x == 0) as i64),
+            Node::And(a, b) => Some((truthy(a.eval(value)) && truthy(b.eval(value))) as i64),
+            Node::Or(a, b) => Some((truthy(a.eval(value)) || truthy(b.eval(value))) as i64),
+            Node::Cmp(a, cmp, b) => {
+                let (a, b) = (a.eval(value)?, b.eval(value)?);
+
+                Some(match cmp {
+                    Cmp::Lt => a < b,
+                    Cmp::Le => a <= b,
+                    Cmp::Gt => a > b,
+                    Cmp::Ge => a >= b,
+                    Cmp::Eq => a == b,
+                    Cmp::Ne => a != b,
+                } as i64)
+            }
+            Node::Arith(a, op, b) => {
+                let (a, b) = (a.eval(value)?, b.eval(value)?);
+
+                match op {
+                    Arith::Add => a.checked_add(b),
+                    Arith::Sub => a.checked_sub(b),
+                    Arith::Mul => a.checked_mul(b),
+                    Arith::Div => a.checked_div(b),
+                }
+            }
+        }
+    }
+}
+
+impl Expr {
+    /// Parses an expression whose variables are the given names
+    pub fn parse(text: &str, names: &[&str]) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text, names)?,
+            i: 0,
+        };
+
+        let root = parser.or()?;
+
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected {:?}", token));
+        }
+
+        Ok(Expr { root })
+    }
+
+    /// Evaluates the expression, getting the value of each variable from its index in the names
+    pub fn value(&self, value: impl Fn(usize) -> Option<i64>) -> Option<i64> {
+        self.root.eval(&value)
+    }
+
+    /// Whether the expression evaluates to a true value
+    pub fn matches(&self, value: impl Fn(usize) -> Option<i64>) -> bool {
+        truthy(self.root.eval(&value))
+    }
+}
+
+#[test]
+fn t_expr() {
+    let names = ["elo", "time", "draw", "missing"];
+    let values = [2500, 600, 0];
+    let matches = |text: &str| {
+        Expr::parse(text, &names)
+            .unwrap()
+            .matches(|i| values.get(i).copied())
+    };
+
+    assert!(matches("elo > 2400 && time >= 600 && !draw"));
+    assert!(!matches("elo > 2400 && time > 600"));
+    assert!(matches("draw || (elo >= 2500 && elo != 2400)"));
+    assert!(!matches("missing < 1") && !matches("missing >= 1"));
+    assert!(!matches("!missing"));
+    assert!(matches(
+        "elo - time * 2 == 1300 && (elo - time) * 2 == 3800"
+    ));
+    assert!(!matches("elo / draw > 0") && !matches("missing + 1 > 0"));
+
+    let value = |text: &str| {
+        Expr::parse(text, &names)
+            .unwrap()
+            .value(|i| values.get(i).copied())
+    };
+    assert_eq!(value("elo / 100 + time / 100 - 1"), Some(30));
+    assert_eq!(value("missing * 2"), None);
+    assert_eq!(value("-time / -2"), Some(300));
+
+    assert!(Expr::parse("elo >", &names).is_err());
+    assert!(Expr::parse("(elo > 1", &names).is_err());
+    assert!(Expr::parse("rating > 1", &names).is_err());
+    assert!(Expr::parse("elo = 1", &names).is_err());
+}
@@ -0,0 +1,795 @@
+// C interface for probing books from engines. rustyglot_probe and the stats functions may be called
+// from several threads at once, such as an engine's search threads, while the functions taking a
+// non-const book need it exclusively. The declarations for C are:
+//
+//     typedef struct RustyglotBook RustyglotBook;
+//
+//     typedef struct {
+//         char uci[6];
+//         uint64_t weight;
+//         uint32_t learn;
+//     } RustyglotMove;
+//
+//     RustyglotBook *rustyglot_open(const char *path);
+//     RustyglotBook *rustyglot_open_chain(const char *const *paths, size_t count);
+//     size_t rustyglot_probe(const RustyglotBook *book, const char *fen,
+//                            RustyglotMove *moves, size_t capacity);
+//     void rustyglot_close(RustyglotBook *book);
+//     int rustyglot_reload(RustyglotBook *book);
+//
+//     void rustyglot_set_cache_capacity(RustyglotBook *book, size_t capacity);
+//     void rustyglot_cache_stats(const RustyglotBook *book, uint64_t *hits, uint64_t *misses);
+//     size_t rustyglot_answer_stats(const RustyglotBook *book, uint64_t *answered,
+//                                   size_t capacity);
+//
+//     typedef struct RustyglotPack RustyglotPack;
+//
+//     RustyglotPack *rustyglot_pack_open(const char *path);
+//     const RustyglotBook *rustyglot_pack_select(const RustyglotPack *pack, const char *opponent,
+//                                                int rating);
+//     int rustyglot_pack_reload(RustyglotPack *pack);
+//     void rustyglot_pack_close(RustyglotPack *pack);
+//
+//     typedef struct RustyglotBloom RustyglotBloom;
+//
+//     RustyglotBloom *rustyglot_bloom_open(const char *path);
+//     int rustyglot_maybe_in_book(const RustyglotBloom *bloom, uint64_t hash);
+//     void rustyglot_bloom_close(RustyglotBloom *bloom);
+
+use crate::books::{BloomFilter, BookMap, MoveNotation};
+use crate::cache::LruCache;
+use crate::conversions::*;
+use crate::pack::BookPack;
+
+use std::convert::TryFrom;
+
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+
+use shakmaty::fen::Fen;
+
+// The index of the book answering a probe, with its moves in uci and their weights and learn
+// values
+type Probe = (usize, Vec<(String, u64, u32)>);
+
+/// A book opened by rustyglot_open, or a chain of books opened by rustyglot_open_chain
+pub struct RustyglotBook {
+    // Probes are answered by the first book with moves for the position
+    books: Vec<BookMap>,
+    // Probe results by position hash, with the index of the book answering, so engines probing
+    // the same lines skip sorting and converting their entries. Engines may probe from several
+    // search threads at once.
+    cache: Mutex<LruCache<Probe>>,
+    // Number of probes each book answered
+    answered: Vec<AtomicU64>,
+    // The path of each book with its modification time when it was read, for reloading
+    files: Vec<(String, Option<SystemTime>)>,
+}
+
+impl RustyglotBook {
+    // Reads the books at paths, or returns None if any of them can not be read
+    fn open(paths: &[&str]) -> Option<Self> {
+        let mut books = Vec::new();
+        let mut files = Vec::new();
+
+        for path in paths {
+            let modified = modified(path);
+            books.push(panic::catch_unwind(|| read_book(path)).ok().flatten()?);
+            files.push((path.to_string(), modified));
+        }
+
+        Some(RustyglotBook {
+            answered: books.iter().map(|_| AtomicU64::new(0)).collect(),
+            books,
+            cache: Mutex::new(LruCache::new(DEFAULT_CACHE_CAPACITY)),
+            files,
+        })
+    }
+
+    // Rereads the books whose files were modified, as rustyglot_reload
+    fn reload(&mut self) -> c_int {
+        let mut count = 0;
+        let mut failed = false;
+
+        for (i, (path, time)) in self.files.iter_mut().enumerate() {
+            let modified = modified(path);
+
+            if modified == *time {
+                continue;
+            }
+
+            match panic::catch_unwind(|| read_book(path)).ok().flatten() {
+                Some(new) => {
+                    self.books[i] = new;
+                    *time = modified;
+                    count += 1;
+                }
+                None => failed = true,
+            }
+        }
+
+        if count > 0 {
+            lock(&mut self.cache).clear();
+        }
+
+        if failed {
+            -1
+        } else {
+            count
+        }
+    }
+}
+
+// The cache of a book held exclusively, which a probe that panicked may have left poisoned
+fn lock(cache: &mut Mutex<LruCache<Probe>>) -> &mut LruCache<Probe> {
+    cache.get_mut().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+const DEFAULT_CACHE_CAPACITY: usize = 4096;
+
+#[repr(C)]
+pub struct RustyglotMove {
+    /// Move in uci with castling as the king's move, nul terminated
+    pub uci: [c_char; 6],
+    pub weight: u64,
+    pub learn: u32,
+}
+
+// Books are read in the format given by their extension, as on the command line
+fn read_book(path: &str) -> Option<BookMap> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+
+    if path.ends_with(".bin") {
+        let mut book = BookMap::new();
+        book.extend_from_reader(&mut reader);
+        Some(book)
+    } else if path.ends_with(".blob.tree") {
+        BookMap::try_read_blob(&mut reader).ok()
+    } else if path.ends_with(".tree") {
+        BookMap::try_read_txt(&mut reader, MoveNotation::San).ok()
+    } else if path.ends_with(".json") {
+        Some(BookMap::read_json(reader))
+    } else {
+        None
+    }
+}
+
+/// Opens a .bin, .tree, .blob.tree or .json book, returning null if it can not be read.
+///
+/// # Safety
+///
+/// path must be a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_open(path: *const c_char) -> *mut RustyglotBook {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    match RustyglotBook::open(&[path]) {
+        Some(book) => Box::into_raw(Box::new(book)),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Opens count books, such as a personal book, then a theory book, then a wide book built from
+/// many games, probed in order so that each position is answered by the first book with moves
+/// for it. Returns null if any of the books can not be read.
+///
+/// # Safety
+///
+/// paths must point to count nul terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_open_chain(
+    paths: *const *const c_char,
+    count: usize,
+) -> *mut RustyglotBook {
+    if paths.is_null() || count == 0 {
+        return ptr::null_mut();
+    }
+
+    let mut names = Vec::new();
+
+    for i in 0..count {
+        let path = *paths.add(i);
+        if path.is_null() {
+            return ptr::null_mut();
+        }
+
+        match CStr::from_ptr(path).to_str() {
+            Ok(path) => names.push(path),
+            Err(_) => return ptr::null_mut(),
+        }
+    }
+
+    match RustyglotBook::open(&names) {
+        Some(book) => Box::into_raw(Box::new(book)),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Rereads the books whose files were modified since they were read, so that long running
+/// engines pick up repertoire updates without restarting, and drops the cached probe results if
+/// any were. Returns the number of books reread, or -1 if a modified book could not be read, in
+/// which case the books that could be are replaced and the others kept as they were.
+///
+/// # Safety
+///
+/// book must come from rustyglot_open or rustyglot_open_chain and not be closed, and must not be
+/// in use by another thread.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_reload(book: *mut RustyglotBook) -> c_int {
+    if book.is_null() {
+        return 0;
+    }
+
+    (*book).reload()
+}
+
+/// Writes up to capacity of the book moves for the position given by fen into moves, ordered by
+/// descending weight, and returns the number of book moves for the position, which may be more
+/// than capacity. Returns 0 for invalid positions. Chains answer with the moves of their first
+/// book containing the position.
+///
+/// # Safety
+///
+/// book must come from rustyglot_open or rustyglot_open_chain and not be closed, fen must be a
+/// nul terminated string, and moves must point to space for capacity moves.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_probe(
+    book: *const RustyglotBook,
+    fen: *const c_char,
+    moves: *mut RustyglotMove,
+    capacity: usize,
+) -> usize {
+    if book.is_null() || fen.is_null() {
+        return 0;
+    }
+
+    let RustyglotBook {
+        books,
+        cache,
+        answered,
+        ..
+    } = &*book;
+    let pos = match CStr::from_ptr(fen)
+        .to_str()
+        .ok()
+        .and_then(|fen| fen.parse::<Fen>().ok())
+        .and_then(|fen| fen.into_position::<Chess>(Chess960).ok())
+    {
+        Some(pos) => pos,
+        None => return 0,
+    };
+
+    let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+    let found = panic::catch_unwind(AssertUnwindSafe(|| {
+        cache
+            .get_or_insert_with(books[0].hash(&pos), || {
+                let (index, book) = books
+                    .iter()
+                    .enumerate()
+                    .find(|(_, book)| !book.entries(&pos).is_empty())
+                    .unwrap_or((books.len(), &books[0]));
+
+                let mut entries = book.entries(&pos).to_vec();
+                entries.sort_by_key(|e| std::cmp::Reverse(e.weight));
+
+                let moves = entries
+                    .into_iter()
+                    .filter_map(|e| {
+                        let mov = from_book_move(e.mov).to_move(&pos).ok()?;
+                        Some((UciMove::from_standard(&mov).to_string(), e.weight, e.learn))
+                    })
+                    .collect::<Vec<_>>();
+                (index, moves)
+            })
+            .clone()
+    }));
+
+    let (index, found) = match found {
+        Ok(found) => found,
+        Err(_) => return 0,
+    };
+
+    if let Some(count) = answered.get(index) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if !moves.is_null() {
+        for (i, (uci, weight, learn)) in found.iter().take(capacity).enumerate() {
+            let mut out = RustyglotMove {
+                uci: [0; 6],
+                weight: *weight,
+                learn: *learn,
+            };
+
+            for (c, b) in out.uci.iter_mut().zip(uci.bytes()) {
+                *c = b as c_char;
+            }
+            ptr::write(moves.add(i), out);
+        }
+    }
+
+    found.len()
+}
+
+/// Frees a book returned by rustyglot_open or rustyglot_open_chain. Null is ignored.
+///
+/// # Safety
+///
+/// book must come from rustyglot_open or rustyglot_open_chain and not already be closed.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_close(book: *mut RustyglotBook) {
+    if !book.is_null() {
+        drop(Box::from_raw(book));
+    }
+}
+
+/// Sets the number of positions whose probe results are cached, dropping the cached results.
+/// A capacity of 0 disables caching.
+///
+/// # Safety
+///
+/// book must come from rustyglot_open or rustyglot_open_chain and not be closed, and must not be
+/// in use by another thread.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_set_cache_capacity(book: *mut RustyglotBook, capacity: usize) {
+    if !book.is_null() {
+        lock(&mut (*book).cache).set_capacity(capacity);
+    }
+}
+
+/// Reports the number of probes answered from the cache and the number that were not.
+///
+/// # Safety
+///
+/// book must come from rustyglot_open or rustyglot_open_chain and not be closed, and hits and
+/// misses must be valid pointers or null.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_cache_stats(
+    book: *const RustyglotBook,
+    hits: *mut u64,
+    misses: *mut u64,
+) {
+    if book.is_null() {
+        return;
+    }
+
+    let (h, m) = (*book)
+        .cache
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .stats();
+
+    if !hits.is_null() {
+        *hits = h;
+    }
+    if !misses.is_null() {
+        *misses = m;
+    }
+}
+
+/// Writes the number of probes answered by each book of a chain, in the order the books were
+/// given, into up to capacity elements of answered, and returns the number of books. Probes no
+/// book could answer are not counted.
+///
+/// # Safety
+///
+/// book must come from rustyglot_open or rustyglot_open_chain and not be closed, and answered
+/// must point to space for capacity counts or be null.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_answer_stats(
+    book: *const RustyglotBook,
+    answered: *mut u64,
+    capacity: usize,
+) -> usize {
+    if book.is_null() {
+        return 0;
+    }
+
+    let counts = &(*book).answered;
+
+    if !answered.is_null() {
+        for (i, count) in counts.iter().take(capacity).enumerate() {
+            *answered.add(i) = count.load(Ordering::Relaxed);
+        }
+    }
+
+    counts.len()
+}
+
+/// A book pack opened by rustyglot_pack_open, with its member books
+pub struct RustyglotPack {
+    pack: BookPack,
+    books: Vec<(String, RustyglotBook)>,
+}
+
+/// Opens the manifest of a book pack, described in src/pack.rs, along with its books, whose paths
+/// are relative to the manifest's directory. Returns null if the manifest or any of its books can
+/// not be read.
+///
+/// # Safety
+///
+/// path must be a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_pack_open(path: *const c_char) -> *mut RustyglotPack {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => Path::new(path),
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let pack = match File::open(path)
+        .ok()
+        .and_then(|file| BookPack::read(BufReader::new(file)).ok())
+    {
+        Some(pack) => pack,
+        None => return ptr::null_mut(),
+    };
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut books = Vec::new();
+
+    for member in pack.paths() {
+        let full = dir.join(member);
+
+        match full.to_str().and_then(|full| RustyglotBook::open(&[full])) {
+            Some(book) => books.push((member.to_string(), book)),
+            None => return ptr::null_mut(),
+        }
+    }
+
+    Box::into_raw(Box::new(RustyglotPack { pack, books }))
+}
+
+/// Returns the book of the pack to play against the opponent with the given name and rating:
+/// the book named for the opponent, else that of the first rating band containing the rating,
+/// else the default book, or null if there is none. opponent may be null and rating negative
+/// when unknown. The book stays valid until the pack is closed, and must not be closed or
+/// reloaded itself; rustyglot_pack_reload rereads the books of a pack.
+///
+/// # Safety
+///
+/// pack must come from rustyglot_pack_open and not be closed, and opponent must be a nul
+/// terminated string or null.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_pack_select(
+    pack: *const RustyglotPack,
+    opponent: *const c_char,
+    rating: c_int,
+) -> *const RustyglotBook {
+    if pack.is_null() {
+        return ptr::null();
+    }
+
+    let pack = &*pack;
+    let opponent = if opponent.is_null() {
+        None
+    } else {
+        CStr::from_ptr(opponent).to_str().ok()
+    };
+
+    pack.pack
+        .select(opponent, u32::try_from(rating).ok())
+        .and_then(|path| pack.books.iter().find(|(p, _)| p == path))
+        .map_or(ptr::null(), |(_, book)| book as *const RustyglotBook)
+}
+
+/// Rereads the books of the pack whose files were modified since they were read, as
+/// rustyglot_reload does for a single book, keeping the books returned by rustyglot_pack_select
+/// valid. Returns the number of books reread, or -1 if a modified book could not be read. The
+/// manifest itself is not reread.
+///
+/// # Safety
+///
+/// pack must come from rustyglot_pack_open and not be closed, and neither it nor its books may be
+/// in use by another thread.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_pack_reload(pack: *mut RustyglotPack) -> c_int {
+    if pack.is_null() {
+        return 0;
+    }
+
+    let mut count = 0;
+    let mut failed = false;
+
+    for (_, book) in &mut (*pack).books {
+        match book.reload() {
+            -1 => failed = true,
+            n => count += n,
+        }
+    }
+
+    if failed {
+        -1
+    } else {
+        count
+    }
+}
+
+/// Frees a pack returned by rustyglot_pack_open, along with its books. Null is ignored.
+///
+/// # Safety
+///
+/// pack must come from rustyglot_pack_open and not already be closed.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_pack_close(pack: *mut RustyglotPack) {
+    if !pack.is_null() {
+        drop(Box::from_raw(pack));
+    }
+}
+
+/// A bloom filter written with -bloom, opened by rustyglot_bloom_open
+pub struct RustyglotBloom {
+    filter: BloomFilter,
+}
+
+/// Opens a .bin.bloom file written next to a .bin book, returning null if it can not be read.
+///
+/// # Safety
+///
+/// path must be a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_bloom_open(path: *const c_char) -> *mut RustyglotBloom {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+
+    let filter = CStr::from_ptr(path)
+        .to_str()
+        .ok()
+        .and_then(|path| File::open(path).ok())
+        .and_then(|file| BloomFilter::read(&mut BufReader::new(file)));
+
+    match filter {
+        Some(filter) => Box::into_raw(Box::new(RustyglotBloom { filter })),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Returns 0 if the position with the given polyglot hash is not in the book, and 1 if it may be.
+///
+/// # Safety
+///
+/// bloom must come from rustyglot_bloom_open and not be closed.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_maybe_in_book(bloom: *const RustyglotBloom, hash: u64) -> i32 {
+    if bloom.is_null() {
+        return 1;
+    }
+
+    (*bloom).filter.maybe_in_book(hash) as i32
+}
+
+/// Frees a filter returned by rustyglot_bloom_open. Null is ignored.
+///
+/// # Safety
+///
+/// bloom must come from rustyglot_bloom_open and not already be closed.
+#[no_mangle]
+pub unsafe extern "C" fn rustyglot_bloom_close(bloom: *mut RustyglotBloom) {
+    if !bloom.is_null() {
+        drop(Box::from_raw(bloom));
+    }
+}
+
+#[test]
+fn t_probe() {
+    use std::ffi::CString;
+
+    let path = std::env::temp_dir().join(format!("rustyglot-ffi-{}.tree", std::process::id()));
+    std::fs::write(&path, "3 e4, e5\n1 d4\n").unwrap();
+
+    let path_c = CString::new(path.to_str().unwrap()).unwrap();
+    let fen = CString::new("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+
+    unsafe {
+        let book = rustyglot_open(path_c.as_ptr());
+        assert!(!book.is_null());
+
+        let mut moves = [RustyglotMove {
+            uci: [0; 6],
+            weight: 0,
+            learn: 0,
+        }];
+        assert_eq!(
+            rustyglot_probe(book, fen.as_ptr(), moves.as_mut_ptr(), 1),
+            2
+        );
+        assert_eq!(CStr::from_ptr(moves[0].uci.as_ptr()).to_str(), Ok("e2e4"));
+        assert_eq!(moves[0].weight, 3);
+
+        rustyglot_probe(book, fen.as_ptr(), moves.as_mut_ptr(), 1);
+        let (mut hits, mut misses) = (0, 0);
+        rustyglot_cache_stats(book, &mut hits, &mut misses);
+        assert_eq!((hits, misses), (1, 1));
+
+        // Unmodified books are not reread, and modified ones are
+        assert_eq!(rustyglot_reload(book), 0);
+        std::fs::write(&path, "5 d4\n").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(rustyglot_reload(book), 1);
+        assert_eq!(
+            rustyglot_probe(book, fen.as_ptr(), moves.as_mut_ptr(), 1),
+            1
+        );
+        assert_eq!(moves[0].weight, 5);
+
+        rustyglot_close(book);
+    }
+
+    std::fs::remove_file(path);
+}
+
+#[test]
+fn t_probe_chain() {
+    use std::ffi::CString;
+
+    let dir = std::env::temp_dir();
+    let paths =
+        [("personal", "e4, c5\n"), ("theory", "3 e4, e5\n1 d4, d5\n")].map(|(name, tree)| {
+            let path = dir.join(format!(
+                "rustyglot-chain-{}-{}.tree",
+                name,
+                std::process::id()
+            ));
+            std::fs::write(&path, tree).unwrap();
+            path
+        });
+    let paths_c = paths
+        .iter()
+        .map(|p| CString::new(p.to_str().unwrap()).unwrap())
+        .collect::<Vec<_>>();
+    let ptrs = paths_c.iter().map(|p| p.as_ptr()).collect::<Vec<_>>();
+
+    let probe = |book, fen: &str| {
+        let fen = CString::new(fen).unwrap();
+        let mut moves = [RustyglotMove {
+            uci: [0; 6],
+            weight: 0,
+            learn: 0,
+        }];
+        let count = unsafe { rustyglot_probe(book, fen.as_ptr(), moves.as_mut_ptr(), 1) };
+        let uci = unsafe { CStr::from_ptr(moves[0].uci.as_ptr()) };
+        (count, uci.to_str().unwrap().to_string())
+    };
+
+    unsafe {
+        let book = rustyglot_open_chain(ptrs.as_ptr(), ptrs.len());
+        assert!(!book.is_null());
+
+        // The personal book answers at the root and after e4, the theory book after d4
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let d4 = "rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1";
+        assert_eq!(probe(book, start), (1, "e2e4".to_string()));
+        assert_eq!(probe(book, d4), (1, "d7d5".to_string()));
+        probe(book, start);
+
+        let mut answered = [0; 2];
+        assert_eq!(rustyglot_answer_stats(book, answered.as_mut_ptr(), 2), 2);
+        assert_eq!(answered, [2, 1]);
+
+        rustyglot_close(book);
+    }
+
+    for path in paths {
+        std::fs::remove_file(path);
+    }
+}
+
+#[test]
+fn t_probe_threads() {
+    use std::ffi::CString;
+
+    // The book is shared with the threads as C engines share it, through its pointer
+    struct Shared(*mut RustyglotBook);
+    unsafe impl Send for Shared {}
+    unsafe impl Sync for Shared {}
+
+    let path = std::env::temp_dir().join(format!("rustyglot-threads-{}.tree", std::process::id()));
+    std::fs::write(&path, "3 e4, e5\n1 d4\n").unwrap();
+    let path_c = CString::new(path.to_str().unwrap()).unwrap();
+    let book = Shared(unsafe { rustyglot_open(path_c.as_ptr()) });
+    assert!(!book.0.is_null());
+
+    std::thread::scope(|scope| {
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+        ]
+        .iter()
+        .cycle()
+        .take(4)
+        {
+            let book = &book;
+            scope.spawn(move || {
+                let fen = CString::new(*fen).unwrap();
+                for _ in 0..100 {
+                    unsafe { rustyglot_probe(book.0, fen.as_ptr(), ptr::null_mut(), 0) };
+                }
+            });
+        }
+    });
+
+    unsafe {
+        let (mut hits, mut misses) = (0, 0);
+        rustyglot_cache_stats(book.0, &mut hits, &mut misses);
+        assert_eq!((hits, misses), (398, 2));
+
+        let mut answered = [0];
+        rustyglot_answer_stats(book.0, answered.as_mut_ptr(), 1);
+        assert_eq!(answered, [400]);
+
+        rustyglot_close(book.0);
+    }
+    std::fs::remove_file(path);
+}
+
+#[test]
+fn t_pack_reload() {
+    use std::ffi::CString;
+
+    let dir = std::env::temp_dir().join(format!("rustyglot-pack-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("pack.txt"), "default = wide.tree\n").unwrap();
+    std::fs::write(dir.join("wide.tree"), "3 e4\n").unwrap();
+
+    let path_c = CString::new(dir.join("pack.txt").to_str().unwrap()).unwrap();
+    let fen = CString::new("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+    let mut moves = [RustyglotMove {
+        uci: [0; 6],
+        weight: 0,
+        learn: 0,
+    }];
+
+    unsafe {
+        let pack = rustyglot_pack_open(path_c.as_ptr());
+        assert!(!pack.is_null());
+        let book = rustyglot_pack_select(pack, ptr::null(), -1);
+        rustyglot_probe(book, fen.as_ptr(), moves.as_mut_ptr(), 1);
+        assert_eq!(moves[0].weight, 3);
+
+        // The selected book answers with the reread moves
+        assert_eq!(rustyglot_pack_reload(pack), 0);
+        std::fs::write(dir.join("wide.tree"), "5 d4\n").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(dir.join("wide.tree"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(rustyglot_pack_reload(pack), 1);
+        rustyglot_probe(book, fen.as_ptr(), moves.as_mut_ptr(), 1);
+        assert_eq!(CStr::from_ptr(moves[0].uci.as_ptr()).to_str(), Ok("d2d4"));
+
+        rustyglot_pack_close(pack);
+    }
+    std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,29 @@
+#![allow(unused_must_use)]
+#![allow(dead_code)]
+#![allow(clippy::new_without_default)]
+
+// Without the default features, only the book formats, parsers and pgn reading are built, which
+// compile for targets such as wasm32-unknown-unknown that can not spawn processes.
+
+#[cfg(feature = "cli")]
+#[macro_use]
+mod log;
+
+#[cfg(feature = "cli")]
+pub mod args;
+pub mod books;
+pub mod cache;
+#[cfg(feature = "cli")]
+mod commands;
+pub mod conversions;
+pub mod engine;
+pub mod error;
+pub mod expr;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod pack;
+pub mod pattern;
+pub mod pgn;
+pub mod rng;
+#[cfg(feature = "cli")]
+mod streams;
@@ -0,0 +1,84 @@
+// Status messages go to stderr so that books written to stdout are never mixed with them.
+// -q silences them, and -v adds details about each file and stage.
+
+use serde_json::{json, Map, Value};
+
+use std::fs::File;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+pub const QUIET: u8 = 0;
+pub const NORMAL: u8 = 1;
+pub const VERBOSE: u8 = 2;
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(NORMAL);
+
+// Counts recorded by each stage, in order, for -log-json
+static STAGES: Mutex<Vec<(String, Map<String, Value>)>> = Mutex::new(Vec::new());
+
+pub fn init(args: &[String]) {
+    let level = if args.iter().any(|x| x == "-q") {
+        QUIET
+    } else if args.iter().any(|x| x == "-v") {
+        VERBOSE
+    } else {
+        NORMAL
+    };
+
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn enabled(level: u8) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level
+}
+
+/// Records a count for a stage of the run, adding the stage if it is not the latest one
+pub fn record(stage: &str, name: &str, count: usize) {
+    let mut stages = STAGES.lock().unwrap();
+
+    if stages.last().is_none_or(|s| s.0 != stage) {
+        stages.push((stage.to_string(), Map::new()));
+    }
+
+    stages
+        .last_mut()
+        .unwrap()
+        .1
+        .insert(name.to_string(), json!(count));
+}
+
+/// Writes the recorded stages as a JSON array of objects, each with its name under "stage"
+pub fn write_json(filename: &str) {
+    let stages = STAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(stage, counts)| {
+            let mut out = Map::new();
+            out.insert("stage".to_string(), json!(stage));
+            out.extend(counts.clone());
+            Value::Object(out)
+        })
+        .collect::<Vec<_>>();
+
+    let file =
+        File::create(filename).unwrap_or_else(|_| panic!("Failure creating file {}", filename));
+    serde_json::to_writer_pretty(file, &stages)
+        .unwrap_or_else(|_| panic!("Failure writing file {}", filename));
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if crate::log::enabled(crate::log::NORMAL) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if crate::log::enabled(crate::log::VERBOSE) {
+            eprintln!($($arg)*);
+        }
+    };
+}
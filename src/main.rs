@@ -1,10 +1,4 @@
-#![allow(unused_must_use)]
-#![allow(dead_code)]
-
-mod args;
-mod books;
-mod conversions;
-mod pgn;
+use rustyglot::{args, error};
 
 fn main() {
     // let mut reader = BufReader::new(File::open("out2.bin.blob").unwrap());
@@ -13,5 +7,9 @@ fn main() {
     // let mut book = BookMap::read_txt(&mut reader);
     // book.write_blob(&mut File::create("out1.bin.blob").unwrap());
 
-    args::run();
+    error::install_hook();
+
+    if let Err(payload) = std::panic::catch_unwind(args::run) {
+        std::process::exit(error::report(payload));
+    }
 }
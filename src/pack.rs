@@ -0,0 +1,124 @@
+// Book packs, manifests choosing the book to play from by opponent, for playing prepared lines in
+// engine tournaments. Each line of a manifest maps an opponent name, a rating band such as
+// 2400-2600, or "default" to the path of a book, as in
+//
+//     Stockfish = anti-stockfish.bin
+//     3000-3600 = solid.bin
+//     default = wide.bin
+//
+// with ';' starting comments. Names are compared ignoring case and take precedence over bands,
+// which are tried in order.
+
+use std::io::BufRead;
+
+enum Selector {
+    Name(String),
+    Ratings(u32, u32),
+    Default,
+}
+
+pub struct BookPack {
+    members: Vec<(Selector, String)>,
+}
+
+impl BookPack {
+    /// Reads a manifest, failing with the line number and a message for malformed lines
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, String> {
+        let mut members = Vec::new();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| format!("{}: {}", i + 1, e))?;
+            let line = line[..line.find(';').unwrap_or(line.len())].trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let (selector, path) = line
+                .split_once('=')
+                .map(|(s, p)| (s.trim(), p.trim()))
+                .filter(|(s, p)| !s.is_empty() && !p.is_empty())
+                .ok_or_else(|| format!("{}: Expected <opponent> = <book>", i + 1))?;
+
+            let band = selector.split_once('-').and_then(|(low, high)| {
+                Some((
+                    low.trim().parse::<u32>().ok()?,
+                    high.trim().parse::<u32>().ok()?,
+                ))
+            });
+
+            let selector = match band {
+                Some((low, high)) if low <= high => Selector::Ratings(low, high),
+                Some(_) => return Err(format!("{}: Empty rating band {}", i + 1, selector)),
+                None if selector.eq_ignore_ascii_case("default") => Selector::Default,
+                None => Selector::Name(selector.to_string()),
+            };
+
+            members.push((selector, path.to_string()));
+        }
+
+        Ok(BookPack { members })
+    }
+
+    /// The paths of the member books, each listed once, in the order they first appear
+    pub fn paths(&self) -> Vec<&str> {
+        let mut out = Vec::new();
+
+        for (_, path) in &self.members {
+            if !out.contains(&&path[..]) {
+                out.push(&path[..]);
+            }
+        }
+        out
+    }
+
+    /// The path of the book for the opponent with the given name and rating, either of which may
+    /// be unknown: the book named for the opponent, else that of the first band containing the
+    /// rating, else the default book
+    pub fn select(&self, opponent: Option<&str>, rating: Option<u32>) -> Option<&str> {
+        let name = self.members.iter().find(|(s, _)| match (s, opponent) {
+            (Selector::Name(name), Some(opponent)) => name.eq_ignore_ascii_case(opponent.trim()),
+            _ => false,
+        });
+        let band = || {
+            self.members.iter().find(|(s, _)| match (s, rating) {
+                (Selector::Ratings(low, high), Some(rating)) => (*low..=*high).contains(&rating),
+                _ => false,
+            })
+        };
+        let default = || {
+            self.members
+                .iter()
+                .find(|(s, _)| matches!(s, Selector::Default))
+        };
+
+        name.or_else(band)
+            .or_else(default)
+            .map(|(_, path)| &path[..])
+    }
+}
+
+#[test]
+fn t_book_pack() {
+    let manifest = "; tournament pack\n\
+                    Stockfish = anti-stockfish.bin\n\
+                    2400-2999 = sharp.bin ; club engines\n\
+                    3000-3600 = solid.bin\n\
+                    default = wide.bin\n\
+                    Leela = solid.bin\n";
+    let pack = BookPack::read(manifest.as_bytes()).unwrap();
+
+    assert_eq!(
+        pack.select(Some("stockfish"), Some(3500)),
+        Some("anti-stockfish.bin")
+    );
+    assert_eq!(pack.select(Some("Other"), Some(3100)), Some("solid.bin"));
+    assert_eq!(pack.select(None, Some(2500)), Some("sharp.bin"));
+    assert_eq!(pack.select(None, None), Some("wide.bin"));
+    assert_eq!(pack.paths().len(), 4);
+
+    assert_eq!(
+        BookPack::read("wide.bin\n".as_bytes()).err().unwrap(),
+        "1: Expected <opponent> = <book>"
+    );
+}
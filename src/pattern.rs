@@ -0,0 +1,171 @@
+// A small regular expression matcher for filtering games by header values, supporting
+// alternatives separated by '|', the anchors '^' and '$', '.', character classes such as [a-z]
+// and [^0-9], the repetitions '*', '+' and '?', and '\' escapes. Matching ignores case and finds
+// the pattern anywhere in the text unless it is anchored.
+
+#[derive(Clone, Debug)]
+enum Atom {
+    Char(char),
+    Any,
+    // Whether the class is negated, and its ranges of characters
+    Class(bool, Vec<(char, char)>),
+    Start,
+    End,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Repeat {
+    One,
+    Optional,
+    Star,
+    Plus,
+}
+
+#[derive(Clone, Debug)]
+pub struct Regex {
+    alternatives: Vec<Vec<(Atom, Repeat)>>,
+}
+
+impl Atom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Atom::Char(x) => *x == c,
+            Atom::Any => true,
+            Atom::Class(negated, ranges) => {
+                ranges.iter().any(|(low, high)| (*low..=*high).contains(&c)) != *negated
+            }
+            Atom::Start | Atom::End => false,
+        }
+    }
+}
+
+// Parses a class after its '[', returning it and the index after its ']'
+fn parse_class(chars: &[char], mut i: usize) -> Result<(Atom, usize), String> {
+    let negated = chars.get(i) == Some(&'^');
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = Vec::new();
+    let start = i;
+
+    loop {
+        let mut c = *chars.get(i).ok_or("Unclosed [")?;
+
+        if c == ']' && i > start {
+            return Ok((Atom::Class(negated, ranges), i + 1));
+        }
+        if c == '\\' {
+            i += 1;
+            c = *chars.get(i).ok_or("Trailing \\")?;
+        }
+
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|x| *x != ']') {
+            let high = chars[i + 2];
+            if high < c {
+                return Err(format!("Invalid range {}-{}", c, high));
+            }
+            ranges.push((c, high));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+}
+
+fn parse_alternative(pattern: &str) -> Result<Vec<(Atom, Repeat)>, String> {
+    let chars = pattern.chars().collect::<Vec<_>>();
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let (atom, next) = match chars[i] {
+            '.' => (Atom::Any, i + 1),
+            '^' => (Atom::Start, i + 1),
+            '$' => (Atom::End, i + 1),
+            '[' => parse_class(&chars, i + 1)?,
+            '\\' => (Atom::Char(*chars.get(i + 1).ok_or("Trailing \\")?), i + 2),
+            '*' | '+' | '?' => return Err(format!("Nothing to repeat before {}", chars[i])),
+            c => (Atom::Char(c), i + 1),
+        };
+
+        let repeat = match chars.get(next) {
+            Some('?') => Repeat::Optional,
+            Some('*') => Repeat::Star,
+            Some('+') => Repeat::Plus,
+            _ => Repeat::One,
+        };
+
+        if repeat != Repeat::One && matches!(atom, Atom::Start | Atom::End) {
+            return Err("Anchors can not be repeated".to_string());
+        }
+
+        i = next + (repeat != Repeat::One) as usize;
+        out.push((atom, repeat));
+    }
+
+    Ok(out)
+}
+
+// Whether nodes match text from i on
+fn match_here(nodes: &[(Atom, Repeat)], text: &[char], i: usize) -> bool {
+    let Some(((atom, repeat), rest)) = nodes.split_first() else {
+        return true;
+    };
+
+    match (atom, repeat) {
+        (Atom::Start, _) => i == 0 && match_here(rest, text, i),
+        (Atom::End, _) => i == text.len() && match_here(rest, text, i),
+        (_, Repeat::One) => {
+            text.get(i).is_some_and(|c| atom.matches(*c)) && match_here(rest, text, i + 1)
+        }
+        (_, Repeat::Optional) => {
+            (text.get(i).is_some_and(|c| atom.matches(*c)) && match_here(rest, text, i + 1))
+                || match_here(rest, text, i)
+        }
+        (_, Repeat::Star | Repeat::Plus) => {
+            let count = text[i..].iter().take_while(|c| atom.matches(**c)).count();
+            let min = (*repeat == Repeat::Plus) as usize;
+
+            (min..=count).rev().any(|n| match_here(rest, text, i + n))
+        }
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let alternatives = pattern
+            .to_lowercase()
+            .split('|')
+            .map(parse_alternative)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Regex { alternatives })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let text = text.to_lowercase().chars().collect::<Vec<_>>();
+
+        self.alternatives
+            .iter()
+            .any(|nodes| (0..=text.len()).any(|i| match_here(nodes, &text, i)))
+    }
+}
+
+#[test]
+fn t_regex() {
+    let re = |pattern: &str| Regex::new(pattern).unwrap();
+
+    assert!(re("olympiad|candidates").is_match("44th Chess Olympiad"));
+    assert!(re("^World Ch").is_match("World Championship 2023"));
+    assert!(!re("^World Ch").is_match("Junior World Championship"));
+    assert!(re("qualifier [0-9]+$").is_match("Grand Swiss Qualifier 12"));
+    assert!(!re("qualifier [0-9]+$").is_match("Qualifier final"));
+    assert!(re("a.?c").is_match("ac") && re("a.?c").is_match("abc"));
+    assert!(re("[^a-z]x").is_match("1x") && !re("^[^a-z]x").is_match("bx"));
+    assert!(re("\\.").is_match("a.b") && !re("\\.").is_match("ab"));
+
+    assert!(Regex::new("*a").is_err());
+    assert!(Regex::new("[a-").is_err());
+}
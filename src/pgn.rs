@@ -1,4 +1,8 @@
 use crate::conversions::*;
+use crate::engine::MATE_SCORE;
+use crate::expr::Expr;
+use crate::pattern::Regex;
+use crate::rng::Rng;
 
 #[derive(Clone)]
 pub struct BinEntry {
@@ -16,8 +20,25 @@ pub struct PgnGame {
     black_elo: Option<usize>,
     time: Option<usize>,
     increment: Option<usize>,
+    // Days since 1970-01-01
+    date: Option<i64>,
     pub outcome: Outcome,
     pub moves: Vec<SanPlus>,
+    // White's eval after each move in centipawns, from [%eval] comments, only as far as the last
+    // commented move so that games without comments need no space for them
+    evals: Vec<Option<i32>>,
+    // Clock of the side that moved after each move in seconds, from [%clk] comments, also only as
+    // far as the last commented move
+    clocks: Vec<Option<f64>>,
+    // Starting position from a FEN header, for chess960 games
+    start: Option<Chess>,
+    // The first number of the Round header, as in 3 for "3.1", and the Event and Site headers
+    round: Option<usize>,
+    event: Option<String>,
+    site: Option<String>,
+    // The names of the players, and whether either is marked as a bot or a program by its headers
+    players: Vec<String>,
+    bot: bool,
 }
 
 #[derive(Clone)]
@@ -34,11 +55,65 @@ pub struct PgnFilter {
     max_increment: usize,
     min_game_length: usize,
     max_game_length: usize,
+    max_eval_swing: usize,
+    min_round: usize,
+    max_round: usize,
+    // Patterns the Event and Site headers must match, and patterns they must not match
+    event: Option<Regex>,
+    site: Option<Regex>,
+    exclude_event: Option<Regex>,
+    exclude_site: Option<Regex>,
+    // Names of known engine accounts, given when bot games are excluded, and lowercase names of
+    // players whose games are excluded
+    bot_names: Option<Regex>,
+    excluded_players: HashSet<String>,
+    // An expression over the EXPR_VARIABLES of a game that it must match
+    expr: Option<Expr>,
     draws: bool,
     white_wins: bool,
     black_wins: bool,
+    // Lowercase names of the variants accepted besides standard chess
+    variants: Vec<String>,
+    // Whether games keep their headers, which building books doesn't need
+    headers: bool,
+    // Games passed on by each read, and the share of matching games picked at random to be passed
+    // on, with the seed picking them
+    max_games: usize,
+    sample: f64,
+    seed: u64,
 }
 
+// Values of a game that -filter-expr expressions can use, in the order of expr_variable
+const EXPR_VARIABLES: &[&str] = &[
+    "white_elo",
+    "black_elo",
+    "min_elo",
+    "max_elo",
+    "elo_diff",
+    "time",
+    "increment",
+    "plies",
+    "round",
+    "eval_swing",
+    "draw",
+    "white_win",
+    "black_win",
+    "bot",
+];
+
+// Names of engine accounts and engines playing under their own names, matched by -no-bots along
+// with the BOT titles of lichess and the program types of the pgn standard. Engine names must be
+// the whole name, up to a version such as "Stockfish 16", so that players sharing a name with an
+// engine, such as "Sämisch, Fritz", are kept.
+const ENGINE_NAMES: &str = concat!(
+    "^stockfish[ _.v0-9-]*$|^lc0[ _.v0-9-]*$|^leela chess zero[ _.v0-9-]*$|",
+    "^komodo[ _.v0-9-]*$|^komodo dragon[ _.v0-9-]*$|^houdini[ _.v0-9-]*$|^rybka[ _.v0-9-]*$|",
+    "^fritz[ _.v0-9-]*$|^maia[0-9]+$|[-_]bot$|^bot[-_]"
+);
+
+// Variants whose games can be read with standard rules from their starting positions
+const SUPPORTED_VARIANTS: &[&str] = &["chess960"];
+
 struct PgnVisitor {
     game: PgnGame,
     filter: PgnFilter,
@@ -53,8 +128,111 @@ impl PgnGame {
             black_elo: Some(0),
             time: Some(0),
             increment: Some(0),
+            date: None,
             outcome: Outcome::Draw,
             moves: Vec::new(),
+            evals: Vec::new(),
+            clocks: Vec::new(),
+            start: None,
+            round: None,
+            event: None,
+            site: None,
+            players: Vec::new(),
+            bot: false,
+        }
+    }
+
+    /// Returns the position the game starts from, which is only non-standard in chess960 games
+    pub fn start_position(&self) -> Chess {
+        self.start.clone().unwrap_or_default()
+    }
+
+    /// Returns the date the game was played as a number of days since 1970-01-01
+    pub fn date(&self) -> Option<i64> {
+        self.date
+    }
+
+    /// Returns the rating of the player with the given color, if one was given
+    pub fn elo(&self, color: Color) -> Option<usize> {
+        color
+            .fold_wb(self.white_elo, self.black_elo)
+            .filter(|elo| *elo > 0)
+    }
+
+    /// The expected score of color against its opponent from their Elo ratings, if both are known
+    pub fn expected_score(&self, color: Color) -> Option<f64> {
+        let diff = self.elo(color.other())? as f64 - self.elo(color)? as f64;
+        Some(1.0 / (1.0 + 10f64.powf(diff / 400.0)))
+    }
+
+    /// Returns the seconds spent on each move, from the clock before and after it and the time
+    /// control's increment. Times are None for moves without clock comments, and for the first
+    /// move of each side if the game has no time control.
+    pub fn move_times(&self) -> Vec<Option<f64>> {
+        let initial = self.time.filter(|t| *t > 0).map(|t| t as f64);
+        let increment = self.increment.unwrap_or(0) as f64;
+
+        let clock = |i: usize| self.clocks.get(i).copied().flatten();
+
+        (0..self.moves.len())
+            .map(|i| {
+                let before = if i >= 2 { clock(i - 2) } else { initial }?;
+                Some((before - clock(i)? + increment).max(0.0))
+            })
+            .collect()
+    }
+
+    /// Returns the largest change in eval between two consecutive evaluated moves, with evals
+    /// clamped to ten pawns so that mate announcements count as a single large swing.
+    pub fn eval_swing(&self) -> usize {
+        let evals = self
+            .evals
+            .iter()
+            .flatten()
+            .copied()
+            .map(|e| e.clamp(-1000, 1000))
+            .collect::<Vec<_>>();
+
+        evals
+            .windows(2)
+            .map(|w| (w[1] - w[0]).unsigned_abs() as usize)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // The value of the variable at index i of EXPR_VARIABLES, if the game has it
+    fn expr_variable(&self, i: usize) -> Option<i64> {
+        let (white, black) = (self.elo(Color::White), self.elo(Color::Black));
+        let winner = match self.outcome {
+            Outcome::Decisive { winner } => Some(winner),
+            Outcome::Draw => None,
+        };
+
+        let value = match EXPR_VARIABLES[i] {
+            "white_elo" => white,
+            "black_elo" => black,
+            "min_elo" => white.zip(black).map(|(w, b)| w.min(b)),
+            "max_elo" => white.zip(black).map(|(w, b)| w.max(b)),
+            "elo_diff" => white.zip(black).map(|(w, b)| w.abs_diff(b)),
+            "time" => self.time,
+            "increment" => self.increment,
+            "plies" => Some(self.moves.len()),
+            "round" => self.round,
+            "eval_swing" => Some(self.eval_swing()),
+            "draw" => Some((winner.is_none()) as usize),
+            "white_win" => Some((winner == Some(Color::White)) as usize),
+            "black_win" => Some((winner == Some(Color::Black)) as usize),
+            _ => Some(self.bot as usize),
+        };
+
+        value.map(|x| x as i64)
+    }
+
+    pub fn from_moves(headers: Vec<(String, String)>, moves: Vec<SanPlus>) -> Self {
+        PgnGame {
+            headers,
+            moves,
+            ..Self::new()
         }
     }
 }
@@ -88,6 +266,18 @@ impl fmt::Display for PgnGame {
     }
 }
 
+// Whether a header matches a required pattern, which a missing header never does, and doesn't
+// match an excluded pattern
+fn header_matches_patterns(
+    header: &Option<String>,
+    pattern: &Option<Regex>,
+    exclude: &Option<Regex>,
+) -> bool {
+    let matches = |re: &Regex| header.as_ref().is_some_and(|x| re.is_match(x));
+
+    pattern.as_ref().is_none_or(matches) && !exclude.as_ref().is_some_and(matches)
+}
+
 impl PgnFilter {
     pub fn new() -> Self {
         PgnFilter {
@@ -103,12 +293,50 @@ impl PgnFilter {
             max_increment: usize::MAX,
             min_game_length: 0,
             max_game_length: usize::MAX,
+            max_eval_swing: usize::MAX,
+            min_round: 0,
+            max_round: usize::MAX,
+            event: None,
+            site: None,
+            exclude_event: None,
+            exclude_site: None,
+            bot_names: None,
+            excluded_players: HashSet::new(),
+            expr: None,
             draws: true,
             white_wins: true,
             black_wins: true,
+            variants: Vec::new(),
+            headers: true,
+            max_games: usize::MAX,
+            sample: 1.0,
+            seed: 1,
         }
     }
 
+    pub fn max_games(&self) -> usize {
+        self.max_games
+    }
+
+    /// Stops reading after max_games games have been passed on
+    pub fn with_max_games(mut self, max_games: usize) -> Self {
+        self.max_games = max_games;
+        self
+    }
+
+    /// Reads games without their headers, for folding over large dumps when only the moves,
+    /// ratings, time controls, dates and results are needed
+    pub fn without_headers(mut self) -> Self {
+        self.headers = false;
+        self
+    }
+
+    pub fn accepts_variant(&self, variant: &str) -> bool {
+        let variant = variant.to_lowercase();
+
+        variant == "standard" || self.variants.contains(&variant)
+    }
+
     fn header_matches(&self, game: &PgnGame) -> bool {
         match game.outcome {
             Outcome::Decisive {
@@ -150,11 +378,38 @@ impl PgnFilter {
             }
         }
 
-        true
+        if let Some(round) = game.round {
+            if round < self.min_round || round > self.max_round {
+                return false;
+            }
+        }
+
+        if let Some(bot_names) = &self.bot_names {
+            if game.bot || game.players.iter().any(|x| bot_names.is_match(x)) {
+                return false;
+            }
+        }
+
+        if game
+            .players
+            .iter()
+            .any(|x| self.excluded_players.contains(&x.to_lowercase()))
+        {
+            return false;
+        }
+
+        header_matches_patterns(&game.event, &self.event, &self.exclude_event)
+            && header_matches_patterns(&game.site, &self.site, &self.exclude_site)
     }
 
     fn moves_match(&self, game: &PgnGame) -> bool {
-        game.moves.len() >= self.min_game_length && game.moves.len() <= self.max_game_length
+        game.moves.len() >= self.min_game_length
+            && game.moves.len() <= self.max_game_length
+            && game.eval_swing() <= self.max_eval_swing
+            && self
+                .expr
+                .as_ref()
+                .is_none_or(|expr| expr.matches(|i| game.expr_variable(i)))
     }
 
     pub fn matches(&self, game: &PgnGame) -> bool {
@@ -170,10 +425,81 @@ impl PgnFilter {
                 "-no-draws" => out.draws = false,
                 "-no-white-wins" => out.white_wins = false,
                 "-no-black-wins" => out.black_wins = false,
+                "-filter-expr" if i + 1 < args.len() => {
+                    out.expr = Some(Expr::parse(&args[i + 1], EXPR_VARIABLES).unwrap_or_else(
+                        |e| {
+                            usage(format!(
+                                "Invalid value {:?} for -filter-expr: {}",
+                                args[i + 1],
+                                e
+                            ))
+                        },
+                    ));
+                    i += 1;
+                }
+                "-no-bots" => out.bot_names = Some(Regex::new(ENGINE_NAMES).unwrap()),
+                "-exclude-players" if i + 1 < args.len() => {
+                    let text = std::fs::read_to_string(&args[i + 1])
+                        .unwrap_or_else(|e| io(format!("Failure reading {}: {}", args[i + 1], e)));
+
+                    out.excluded_players.extend(
+                        text.lines()
+                            .map(|x| x.trim().to_lowercase())
+                            .filter(|x| !x.is_empty() && !x.starts_with('#')),
+                    );
+                    i += 1;
+                }
                 "-no-wins" => {
                     out.white_wins = false;
                     out.black_wins = false
                 }
+                "-sample" if i + 1 < args.len() => {
+                    out.sample = args[i + 1]
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|x| (0.0..=1.0).contains(x))
+                        .unwrap_or_else(|| {
+                            usage(format!("Invalid value {:?} for -sample", args[i + 1]))
+                        });
+                    i += 1;
+                }
+                flag @ ("-event-regex"
+                | "-site-regex"
+                | "-exclude-event-regex"
+                | "-exclude-site-regex")
+                    if i + 1 < args.len() =>
+                {
+                    let pattern = Some(Regex::new(&args[i + 1]).unwrap_or_else(|e| {
+                        usage(format!(
+                            "Invalid value {:?} for {}: {}",
+                            args[i + 1],
+                            flag,
+                            e
+                        ))
+                    }));
+
+                    match flag {
+                        "-event-regex" => out.event = pattern,
+                        "-site-regex" => out.site = pattern,
+                        "-exclude-event-regex" => out.exclude_event = pattern,
+                        _ => out.exclude_site = pattern,
+                    }
+                    i += 1;
+                }
+                "-accept-variants" if i + 1 < args.len() => {
+                    for variant in args[i + 1].split(',') {
+                        let variant = variant.trim().to_lowercase();
+
+                        if !SUPPORTED_VARIANTS.contains(&&variant[..]) {
+                            usage(format!(
+                                "Unsupported variant {:?} for -accept-variants",
+                                variant
+                            ));
+                        }
+                        out.variants.push(variant);
+                    }
+                    i += 1;
+                }
                 _ => {
                     if i + 1 < args.len() {
                         if let Ok(num) = args[i + 1].parse::<usize>() {
@@ -189,6 +515,14 @@ impl PgnFilter {
                                 "-min-game-length" => out.min_game_length = num,
                                 "-max-game-length" => out.max_game_length = num,
 
+                                "-max-eval-swing" => out.max_eval_swing = num,
+
+                                "-min-round" => out.min_round = num,
+                                "-max-round" => out.max_round = num,
+
+                                "-max-games" => out.max_games = num,
+                                "-seed" => out.seed = num as u64,
+
                                 "-min-time" => out.min_time = num,
                                 "-max-time" => out.max_time = num,
                                 "-min-increment" => out.min_increment = num,
@@ -209,6 +543,107 @@ impl PgnFilter {
     }
 }
 
+// Picks the matching games that a read passes on
+struct Sampler {
+    left: usize,
+    sample: f64,
+    rng: Rng,
+}
+
+impl Sampler {
+    fn new(filter: &PgnFilter) -> Self {
+        Sampler {
+            left: filter.max_games,
+            sample: filter.sample,
+            rng: Rng::new(filter.seed),
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.left == 0
+    }
+
+    fn take(&mut self) -> bool {
+        if self.sample < 1.0 && self.rng.next_f64() >= self.sample {
+            return false;
+        }
+
+        self.left -= 1;
+        true
+    }
+}
+
+// Parses a pgn date such as "2021.07.14" into days since 1970-01-01, treating an unknown month or
+// day as the first
+fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.split('.');
+
+    let year = parts.next()?.parse::<i64>().ok()?;
+    let month = parts
+        .next()
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(1);
+    let day = parts
+        .next()
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(1);
+
+    // Civil date to day number conversion with years starting in March
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    Some(era * 146097 + day_of_era - 719468)
+}
+
+// Chess960 castling mode reads standard positions the same way, and also accepts the castling
+// rights of shuffled starting positions
+fn parse_start(fen: &str) -> Option<Chess> {
+    fen.parse::<Fen>().ok()?.into_position(Chess960).ok()
+}
+
+// Returns the argument of a [%name argument] command embedded in a comment
+fn comment_command<'a>(comment: &'a str, name: &str) -> Option<&'a str> {
+    let tag = format!("[%{} ", name);
+    let start = comment.find(&tag)? + tag.len();
+    let end = comment[start..].find(']')? + start;
+
+    Some(comment[start..end].trim())
+}
+
+// Parses a [%clk] argument such as "0:03:00" or "1:02:03.5" into seconds
+fn parse_clock(clock: &str) -> Option<f64> {
+    clock.split(':').try_fold(0.0, |total, part| {
+        let part = part.parse::<f64>().ok()?;
+        Some(total * 60.0 + part)
+    })
+}
+
+// Parses a [%eval] argument such as "0.31" or "#-4" into centipawns for white, scoring mates by
+// the plies to them as engine scores are
+fn parse_eval(eval: &str) -> Option<i32> {
+    if let Some(mate) = eval.strip_prefix('#') {
+        let n = mate.parse::<i32>().ok()?;
+
+        Some(if n > 0 {
+            MATE_SCORE - 2 * n + 1
+        } else {
+            -MATE_SCORE - 2 * n
+        })
+    } else {
+        eval.parse::<f64>()
+            .ok()
+            .map(|pawns| (pawns * 100.0).round() as i32)
+    }
+}
+
 impl PgnVisitor {
     fn new() -> Self {
         PgnVisitor {
@@ -231,7 +666,7 @@ impl PgnVisitor {
     }
 }
 
-use pgn_reader::{BufferedReader, Skip, Visitor};
+use pgn_reader::{BufferedReader, RawComment, Skip, Visitor};
 
 impl Visitor for PgnVisitor {
     type Result = PgnGame;
@@ -242,10 +677,10 @@ impl Visitor for PgnVisitor {
     }
 
     fn header(&mut self, key: &[u8], value: pgn_reader::RawHeader) {
-        let k = std::str::from_utf8(key).unwrap().to_string();
-        let v = value.decode_utf8().unwrap().to_string();
+        let k = std::str::from_utf8(key).unwrap();
+        let v = value.decode_utf8().unwrap();
 
-        match &k[..] {
+        match k {
             "TimeControl" => {
                 let vs: Vec<&str> = v.split(|c| "/+-?*".contains(c)).collect();
                 let mut nums = Vec::new();
@@ -276,6 +711,17 @@ impl Visitor for PgnVisitor {
                     _ => {}
                 }
             }
+            "Round" => self.game.round = v.split('.').next().and_then(|x| x.parse().ok()),
+            "Event" => self.game.event = Some(v.to_string()),
+            "Site" => self.game.site = Some(v.to_string()),
+            "White" | "Black" => self.game.players.push(v.to_string()),
+            "WhiteTitle" | "BlackTitle" if v.eq_ignore_ascii_case("bot") => self.game.bot = true,
+            "WhiteType" | "BlackType" if v.eq_ignore_ascii_case("program") => self.game.bot = true,
+            "Date" | "UTCDate" => {
+                if let Some(date) = parse_date(&v) {
+                    self.game.date = Some(date);
+                }
+            }
             "WhiteElo" => {
                 if let Ok(e) = v.parse::<usize>() {
                     self.game.white_elo = Some(e);
@@ -304,15 +750,17 @@ impl Visitor for PgnVisitor {
                 _ => {}
             },
             // Useful when dealing with Lichess exports
-            "Variant" => {
-                if v != "Standard" {
-                    self.skip = true
-                }
-            }
+            "Variant" if !self.filter.accepts_variant(&v) => self.skip = true,
+            "FEN" => match parse_start(&v) {
+                Some(pos) => self.game.start = Some(pos),
+                None => self.skip = true,
+            },
             _ => {}
         }
 
-        self.game.headers.push((k, v));
+        if self.filter.headers {
+            self.game.headers.push((k.to_string(), v.into_owned()));
+        }
     }
 
     fn end_headers(&mut self) -> Skip {
@@ -323,6 +771,25 @@ impl Visitor for PgnVisitor {
         self.game.moves.push(san);
     }
 
+    fn comment(&mut self, comment: RawComment<'_>) {
+        let comment = String::from_utf8_lossy(comment.as_bytes());
+        let len = self.game.moves.len();
+
+        if len == 0 {
+            return;
+        }
+
+        if let Some(eval) = comment_command(&comment, "eval").and_then(parse_eval) {
+            self.game.evals.resize(len, None);
+            self.game.evals[len - 1] = Some(eval);
+        }
+
+        if let Some(clock) = comment_command(&comment, "clk").and_then(parse_clock) {
+            self.game.clocks.resize(len, None);
+            self.game.clocks[len - 1] = Some(clock);
+        }
+    }
+
     fn begin_variation(&mut self) -> Skip {
         Skip(true)
     }
@@ -332,16 +799,19 @@ impl Visitor for PgnVisitor {
     }
 }
 
-use std::io::{Read, Write};
+use crate::error::{input, io, usage};
 
-pub fn read_games<R: Read>(filter: PgnFilter, read: R) -> Vec<PgnGame> {
-    let mut visitor = PgnVisitor::with_filter(filter.clone());
+use serde_json::Value;
+
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
-    BufferedReader::new(read)
-        .into_iter(&mut visitor)
-        .map(|x| x.unwrap())
-        .filter(|game| filter.matches(game))
-        .collect()
+pub fn read_games<R: Read>(filter: PgnFilter, read: R) -> Vec<PgnGame> {
+    let mut out = Vec::new();
+    fold_games(filter, read, &mut |game| out.push(game));
+    out
 }
 
 pub fn fold_games<R, F>(filter: PgnFilter, read: R, f: &mut F)
@@ -350,14 +820,238 @@ where
     F: FnMut(PgnGame),
 {
     let mut visitor = PgnVisitor::with_filter(filter.clone());
+    let mut sampler = Sampler::new(&filter);
+
+    if sampler.done() {
+        return;
+    }
 
     for game in BufferedReader::new(read).into_iter(&mut visitor) {
-        let game = game.unwrap();
+        let game = game.unwrap_or_else(|e| io(format!("Failure reading pgn: {}", e)));
+
+        if filter.matches(&game) && sampler.take() {
+            f(game);
+
+            // Going on to the next game would parse it, reading further into the input
+            if sampler.done() {
+                break;
+            }
+        }
+    }
+}
+
+// Converts a game exported by the Lichess API as a line of ndjson, returning None for games
+// from variants that are not accepted, and for games with illegal moves or starting positions.
+fn ndjson_game(record: &Value, filter: &PgnFilter) -> Option<PgnGame> {
+    let mut game = PgnGame::new();
+    let str_field = |v: &Value| v.as_str().map(|x| x.to_string());
+
+    if record["variant"]
+        .as_str()
+        .is_some_and(|v| !filter.accepts_variant(v))
+    {
+        return None;
+    }
+
+    if let Some(fen) = record["initialFen"].as_str() {
+        game.start = Some(parse_start(fen)?);
+        game.headers.push(("FEN".to_string(), fen.to_string()));
+    }
+
+    if let Some(id) = record["id"].as_str() {
+        let site = format!("https://lichess.org/{}", id);
+        game.site = Some(site.clone());
+        game.headers.push(("Site".to_string(), site));
+    }
+
+    for (color, name, elo) in [
+        ("white", "White", &mut game.white_elo),
+        ("black", "Black", &mut game.black_elo),
+    ] {
+        let player = &record["players"][color];
+        if player["aiLevel"].is_u64() || player["user"]["title"].as_str() == Some("BOT") {
+            game.bot = true;
+        }
+
+        let user = str_field(&player["user"]["name"]).or_else(|| {
+            player["aiLevel"]
+                .as_u64()
+                .map(|x| format!("Stockfish level {}", x))
+        });
+
+        let user = user.unwrap_or_else(|| "?".to_string());
+        game.players.push(user.clone());
+        game.headers.push((name.to_string(), user));
+
+        if let Some(rating) = player["rating"].as_u64() {
+            *elo = Some(rating as usize);
+            game.headers
+                .push((format!("{}Elo", name), rating.to_string()));
+        }
+    }
+
+    if let Some(ms) = record["createdAt"].as_i64() {
+        game.date = Some(ms.div_euclid(86_400_000));
+    }
+
+    let clock = &record["clock"];
+    if let (Some(initial), Some(increment)) =
+        (clock["initial"].as_u64(), clock["increment"].as_u64())
+    {
+        game.time = Some(initial as usize);
+        game.increment = Some(increment as usize);
+        game.headers.push((
+            "TimeControl".to_string(),
+            format!("{}+{}", initial, increment),
+        ));
+    }
+
+    let opening = &record["opening"];
+    for (key, name) in [("eco", "ECO"), ("name", "Opening")] {
+        if let Some(value) = str_field(&opening[key]) {
+            game.headers.push((name.to_string(), value));
+        }
+    }
+
+    game.outcome = match record["winner"].as_str() {
+        Some("white") => Outcome::Decisive {
+            winner: Color::White,
+        },
+        Some("black") => Outcome::Decisive {
+            winner: Color::Black,
+        },
+        _ => Outcome::Draw,
+    };
+    game.headers
+        .push(("Result".to_string(), game.outcome.to_string()));
+
+    for word in record["moves"].as_str().unwrap_or("").split_whitespace() {
+        game.moves.push(word.parse::<SanPlus>().ok()?);
+    }
+
+    // Analysed games carry an eval or a mate count for white after each move
+    let analysis = record["analysis"].as_array().map(|x| &x[..]).unwrap_or(&[]);
+    game.evals = (0..analysis.len().min(game.moves.len()))
+        .map(|i| {
+            let eval = &analysis[i];
 
-        if filter.matches(&game) {
-            f(game)
+            if let Some(mate) = eval["mate"].as_i64() {
+                parse_eval(&format!("#{}", mate))
+            } else {
+                eval["eval"].as_i64().map(|x| x as i32)
+            }
+        })
+        .collect();
+
+    // Clocks in centiseconds after each move, included by the api with clocks=true
+    let clocks = record["clocks"].as_array().map(|x| &x[..]).unwrap_or(&[]);
+    game.clocks = clocks
+        .iter()
+        .take(game.moves.len())
+        .map(|x| Some(x.as_f64()? / 100.0))
+        .collect();
+
+    // Records are parsed whole, so their headers are only dropped once built
+    if !filter.headers {
+        game.headers = Vec::new();
+    }
+
+    Some(game)
+}
+
+/// Like fold_games, for the ndjson exports of the Lichess API
+pub fn fold_ndjson_games<R, F>(filter: PgnFilter, read: R, f: &mut F)
+where
+    R: Read,
+    F: FnMut(PgnGame),
+{
+    let mut sampler = Sampler::new(&filter);
+
+    if sampler.done() {
+        return;
+    }
+
+    for (i, line) in BufReader::new(read).lines().enumerate() {
+        let line = line.unwrap_or_else(|e| io(format!("Failure reading ndjson: {}", e)));
+
+        if line.trim().is_empty() {
+            continue;
         }
+
+        let record = serde_json::from_str::<Value>(&line)
+            .unwrap_or_else(|e| input(format!("Invalid ndjson on line {}: {}", i + 1, e)));
+
+        if let Some(game) = ndjson_game(&record, &filter) {
+            if filter.matches(&game) && sampler.take() {
+                f(game);
+
+                if sampler.done() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Calls f on threads worker threads with each game that produce passes to its callback, parsing
+// a few games per worker ahead of them
+fn par_fold<F, P>(threads: usize, f: &F, produce: P)
+where
+    F: Fn(PgnGame) + Sync,
+    P: FnOnce(&mut dyn FnMut(PgnGame)),
+{
+    if threads <= 1 {
+        produce(&mut |game| f(game));
+        return;
     }
+
+    let (sender, receiver) = mpsc::sync_channel::<PgnGame>(threads * 4);
+    // Dropped with the last worker, so that parsing stops blocking if the workers panic
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+
+            scope.spawn(move || loop {
+                let game = receiver.lock().unwrap().recv();
+
+                match game {
+                    Ok(game) => f(game),
+                    Err(_) => break,
+                }
+            });
+        }
+        drop(receiver);
+
+        produce(&mut |game| {
+            sender.send(game);
+        });
+        drop(sender);
+    });
+}
+
+/// Like fold_games, calling f from threads worker threads while games are parsed, for per-game
+/// work slower than parsing. Games reach f in no particular order.
+pub fn par_fold_games<R, F>(filter: PgnFilter, read: R, threads: usize, f: &F)
+where
+    R: Read,
+    F: Fn(PgnGame) + Sync,
+{
+    par_fold(threads, f, |send| {
+        fold_games(filter, read, &mut |game| send(game))
+    });
+}
+
+/// Like par_fold_games, for the ndjson exports of the Lichess API
+pub fn par_fold_ndjson_games<R, F>(filter: PgnFilter, read: R, threads: usize, f: &F)
+where
+    R: Read,
+    F: Fn(PgnGame) + Sync,
+{
+    par_fold(threads, f, |send| {
+        fold_ndjson_games(filter, read, &mut |game| send(game))
+    });
 }
 
 pub fn write_games<W: Write>(w: &mut W, games: &[PgnGame]) {
@@ -365,3 +1059,196 @@ pub fn write_games<W: Write>(w: &mut W, games: &[PgnGame]) {
         writeln!(w, "{}", g).expect("Unable to write games!");
     }
 }
+
+#[test]
+fn t_comments_and_dates() {
+    let pgn = "1. e4 { [%eval 0.3] } e5 { [%eval 0.25] [%clk 0:03:00] } 2. Qh5 { [%eval -0.5] } \
+               Ke7 { [%eval 4.8] } 3. Qxe5# { [%eval #1] } 1-0";
+
+    let games = read_games(PgnFilter::new(), pgn.as_bytes());
+    assert_eq!(games[0].eval_swing(), 530);
+
+    assert_eq!(
+        comment_command("[%clk 0:03:00] [%eval -1.2]", "eval"),
+        Some("-1.2")
+    );
+    assert_eq!(parse_eval("-1.2"), Some(-120));
+    assert_eq!(parse_clock("1:02:03.5"), Some(3723.5));
+
+    let pgn = "[TimeControl \"180+2\"]\n\n1. e4 { [%clk 0:02:58] } e5 { [%clk 0:03:01] } \
+               2. Nf3 { [%clk 0:02:50] } Nc6 *";
+    let games = read_games(PgnFilter::new(), pgn.as_bytes());
+    assert_eq!(
+        games[0].move_times(),
+        [Some(4.0), Some(1.0), Some(10.0), None]
+    );
+    assert_eq!(parse_eval("#-2"), Some(-MATE_SCORE + 4));
+}
+
+#[test]
+fn t_without_headers() {
+    let pgn = "[White \"a\"]\n[WhiteElo \"1500\"]\n[BlackElo \"1600\"]\n\n\
+               1. e4 e5 2. Nf3 { [%clk 0:02:50] } Nc6 1-0";
+    let games = read_games(PgnFilter::new().without_headers(), pgn.as_bytes());
+
+    assert!(games[0].headers.is_empty());
+    assert_eq!(games[0].elo(Color::Black), Some(1600));
+    // Moves after the last comment take no space for evals or clocks
+    assert_eq!((games[0].evals.len(), games[0].clocks.len()), (0, 3));
+    assert_eq!(games[0].move_times().len(), 4);
+}
+
+#[test]
+fn t_par_fold_games() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let pgn = "1. e4 e5 *\n\n1. d4 *\n\n1. c4 c5 2. Nc3 *\n\n".repeat(50);
+
+    for threads in [1, 4] {
+        let (games, moves) = (AtomicUsize::new(0), AtomicUsize::new(0));
+
+        par_fold_games(PgnFilter::new(), pgn.as_bytes(), threads, &|game| {
+            games.fetch_add(1, Ordering::Relaxed);
+            moves.fetch_add(game.moves.len(), Ordering::Relaxed);
+        });
+
+        assert_eq!(games.into_inner(), 150);
+        assert_eq!(moves.into_inner(), 300);
+    }
+}
+
+#[test]
+fn t_sampling() {
+    let pgn = "1. e4 *\n\n".repeat(1000);
+    let args = |x: &str| x.split(' ').map(|x| x.to_string()).collect::<Vec<_>>();
+    let count = |x: &str| read_games(PgnFilter::from_args(&args(x)), pgn.as_bytes()).len();
+
+    assert_eq!(count("-max-games 10"), 10);
+
+    let sampled = count("-sample 0.1");
+    assert!((50..150).contains(&sampled));
+    assert_eq!(count("-sample 0.1"), sampled);
+    assert_ne!(count("-sample 0.1 -seed 7"), sampled);
+    assert_eq!(count("-sample 0.1 -max-games 20"), 20);
+}
+
+#[test]
+fn t_ndjson_games() {
+    let ndjson = r#"{"id":"abc","variant":"standard","createdAt":86400000,"winner":"white",
+        "players":{"white":{"user":{"name":"a"},"rating":1500},"black":{"aiLevel":3}},
+        "clock":{"initial":180,"increment":2},"opening":{"eco":"C20","name":"King's Pawn Game"},
+        "moves":"e4 e5 Qh5 Nc6 Bc4 Nf6 Qxf7#","analysis":[{"eval":30},{"eval":25}]}
+
+{"id":"def","variant":"chess960","moves":"e4"}"#
+        .replace(",\n        ", ",");
+
+    let mut games = Vec::new();
+    fold_ndjson_games(PgnFilter::new(), ndjson.as_bytes(), &mut |g| games.push(g));
+
+    assert_eq!(games.len(), 1);
+    assert_eq!(games[0].date(), Some(1));
+    assert_eq!(games[0].elo(Color::White), Some(1500));
+    assert_eq!(games[0].elo(Color::Black), None);
+    assert_eq!(games[0].moves.len(), 7);
+    assert_eq!(games[0].eval_swing(), 5);
+    assert!(games[0]
+        .headers
+        .contains(&("Black".to_string(), "Stockfish level 3".to_string())));
+}
+
+#[test]
+fn t_chess960_games() {
+    let pgn = "[Variant \"Chess960\"]\n\
+               [FEN \"bqnbrkrn/pppppppp/8/8/8/8/PPPPPPPP/BQNBRKRN w GEge - 0 1\"]\n\n\
+               1. e4 e5 2. O-O O-O 0-1";
+
+    // Games from skipped variants are read without their moves
+    assert!(read_games(PgnFilter::new(), pgn.as_bytes())[0]
+        .moves
+        .is_empty());
+
+    let filter = PgnFilter::from_args(&["-accept-variants".to_string(), "chess960".to_string()]);
+    let games = read_games(filter, pgn.as_bytes());
+
+    let mut pos = games[0].start_position();
+    assert_ne!(pos, Chess::default());
+
+    for san in &games[0].moves {
+        let mov = san.san.to_move(&pos).unwrap();
+        pos = pos.play(&mov).unwrap();
+    }
+    assert_eq!(pos.fullmoves().get(), 3);
+}
+
+#[test]
+fn t_round_and_event() {
+    let pgn = "[Event \"Candidates 2024\"]\n[Round \"3.2\"]\n\n1. e4 *\n\n\
+               [Event \"Club Open\"]\n[Round \"7\"]\n\n1. d4 *\n\n\
+               [Round \"?\"]\n\n1. c4 *\n\n";
+    let args = |x: &str| x.split(' ').map(|x| x.to_string()).collect::<Vec<_>>();
+    let count = |x: &str| read_games(PgnFilter::from_args(&args(x)), pgn.as_bytes()).len();
+
+    assert_eq!(count("-min-round 4"), 2);
+    assert_eq!(count("-max-round 3"), 2);
+    assert_eq!(count("-min-round 3 -max-round 3"), 2);
+    assert_eq!(count("-event-regex candidates|olympiad"), 1);
+    assert_eq!(count("-event-regex ^club -max-round 5"), 0);
+}
+
+#[test]
+fn t_site_regex() {
+    let pgn = "[Event \"Titled Arena\"]\n[Site \"https://lichess.org/abc\"]\n\n1. e4 *\n\n\
+               [Event \"Simul\"]\n[Site \"Berlin GER\"]\n\n1. d4 *\n\n\
+               [Event \"Rated Blitz game\"]\n\n1. c4 *\n\n";
+    let args = |x: &str| x.split(' ').map(|x| x.to_string()).collect::<Vec<_>>();
+    let count = |x: &str| read_games(PgnFilter::from_args(&args(x)), pgn.as_bytes()).len();
+
+    assert_eq!(count("-site-regex lichess"), 1);
+    assert_eq!(count("-exclude-event-regex simul"), 2);
+    assert_eq!(count("-exclude-site-regex lichess"), 2);
+    assert_eq!(
+        count("-event-regex arena|blitz -exclude-site-regex lichess"),
+        1
+    );
+}
+
+#[test]
+fn t_no_bots() {
+    let pgn = "[White \"Magnus\"]\n[Black \"Hikaru\"]\n\n1. e4 *\n\n\
+               [White \"Magnus\"]\n[Black \"someone_BOT\"]\n[BlackTitle \"BOT\"]\n\n1. d4 *\n\n\
+               [White \"Stockfish 16\"]\n[Black \"Hikaru\"]\n\n1. c4 *\n\n\
+               [White \"Fritz\"]\n[WhiteType \"program\"]\n[Black \"Magnus\"]\n\n1. Nf3 *\n\n\
+               [White \"Sämisch, Fritz\"]\n[Black \"Leela\"]\n\n1. g3 *\n\n\
+               [White \"Lc0 v0.30\"]\n[Black \"Komodo Dragon 3\"]\n\n1. b3 *\n\n";
+    let args = |x: &str| x.split(' ').map(|x| x.to_string()).collect::<Vec<_>>();
+    let count = |x: &str| read_games(PgnFilter::from_args(&args(x)), pgn.as_bytes()).len();
+
+    // Players named like engines are kept
+    assert_eq!(count(""), 6);
+    assert_eq!(count("-no-bots"), 2);
+
+    let path = std::env::temp_dir().join(format!(
+        "rustyglot-excluded-players-{}.txt",
+        std::process::id()
+    ));
+    std::fs::write(&path, "# cheaters\nhikaru\n").unwrap();
+    assert_eq!(count(&format!("-exclude-players {}", path.display())), 4);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn t_filter_expr() {
+    let pgn = "[WhiteElo \"2500\"]\n[BlackElo \"2300\"]\n[TimeControl \"600+5\"]\n\
+               [Result \"1-0\"]\n\n1. e4 1-0\n\n\
+               [WhiteElo \"2450\"]\n[BlackElo \"2450\"]\n[TimeControl \"180+2\"]\n\
+               [Result \"1/2-1/2\"]\n\n1. d4 1/2-1/2\n\n";
+    let count = |x: &str| {
+        let args = ["-filter-expr".to_string(), x.to_string()];
+        read_games(PgnFilter::from_args(&args), pgn.as_bytes()).len()
+    };
+
+    assert_eq!(count("white_elo > 2400 && time >= 600 && !draw"), 1);
+    assert_eq!(count("min_elo >= 2400 || elo_diff > 100"), 2);
+    assert_eq!(count("draw && increment == 2 && plies == 1"), 1);
+    assert_eq!(count("round > 0"), 0);
+}
@@ -0,0 +1,48 @@
+// Seeded randomness for sampling games and picking lines. Every random choice goes through Rng so
+// that runs given the same -seed make the same choices.
+
+/// A xorshift generator, which is enough for sampling games and picking moves
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift never leaves a zero state
+        Rng(seed | 1)
+    }
+
+    /// Seeded by the time, for choices that should differ between runs without -seed
+    pub fn from_time() -> Self {
+        Rng::new(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(1, |d| d.as_nanos() as u64),
+        )
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Picks an index with probability proportional to its weight
+    pub fn pick(&mut self, weights: &[f64]) -> usize {
+        let total = weights.iter().sum::<f64>();
+        let mut x = self.next_f64() * total;
+
+        for (i, weight) in weights.iter().enumerate() {
+            if x < *weight {
+                return i;
+            }
+            x -= weight;
+        }
+
+        weights.len() - 1
+    }
+}
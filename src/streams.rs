@@ -0,0 +1,146 @@
+// Inputs given as http(s) urls are streamed through curl, and compressed inputs through the
+// matching decompressor, so large game dumps never need an intermediate copy on disk. Zip
+// archives, such as the weekly TWIC downloads, are extracted with unzip, or with funzip when
+// streamed from urls, which reads only their first file.
+
+use crate::error::io;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+const COMPRESSIONS: &[(&str, &str)] = &[
+    (".gz", "gzip"),
+    (".bz2", "bzip2"),
+    (".xz", "xz"),
+    (".zst", "zstd"),
+    (".zip", "unzip"),
+];
+
+pub fn is_url(filename: &str) -> bool {
+    filename.starts_with("http://") || filename.starts_with("https://")
+}
+
+// Drops the query and fragment from urls
+fn path(filename: &str) -> &str {
+    if is_url(filename) {
+        filename.split(['?', '#']).next().unwrap()
+    } else {
+        filename
+    }
+}
+
+/// The filename without a compression extension or url query, for detecting its format
+pub fn strip_compression(filename: &str) -> &str {
+    let name = path(filename);
+
+    COMPRESSIONS
+        .iter()
+        .find_map(|(ext, _)| name.strip_suffix(ext))
+        .unwrap_or(name)
+}
+
+/// Whether filename is a zip archive, which holds pgn files under names of its own, such as
+/// twic1500g.zip
+pub fn is_zip(filename: &str) -> bool {
+    path(filename).ends_with(".zip")
+}
+
+fn decompressor(filename: &str) -> Option<&'static str> {
+    COMPRESSIONS
+        .iter()
+        .find(|(ext, _)| path(filename).ends_with(ext))
+        .map(|x| x.1)
+}
+
+/// Reads the output of a pipeline of commands, which fails if any of them exits unsuccessfully
+struct CommandReader {
+    name: String,
+    children: Vec<(&'static str, Child)>,
+    output: ChildStdout,
+}
+
+impl Read for CommandReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.output.read(buf)?;
+
+        if n == 0 && !buf.is_empty() {
+            for (program, child) in &mut self.children {
+                if !child.wait()?.success() {
+                    io(format!("Failure reading {}: {} failed", self.name, program));
+                }
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+fn spawn(program: &'static str, args: &[&str], input: Stdio) -> Child {
+    Command::new(program)
+        .args(args)
+        .stdin(input)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|_| io(format!("Failure starting {}", program)))
+}
+
+/// Opens urls and compressed files, returning None for plain local files
+pub fn open_stream(filename: &str) -> Option<Box<dyn Read>> {
+    let decompress = decompressor(filename);
+
+    if !is_url(filename) && decompress.is_none() {
+        return None;
+    }
+
+    let mut children = Vec::new();
+    let mut output = None;
+
+    if is_url(filename) {
+        let mut curl = spawn("curl", &["-sSfL", filename], Stdio::null());
+        output = curl.stdout.take();
+        children.push(("curl", curl));
+    }
+
+    if let (Some("unzip"), None) = (decompress, &output) {
+        let mut child = spawn("unzip", &["-p", filename], Stdio::null());
+        output = child.stdout.take();
+        children.push(("unzip", child));
+    } else if let Some(program) = decompress {
+        // unzip needs to seek to the archive's directory, while funzip streams its first file
+        let (program, args) = match program {
+            "unzip" => ("funzip", &[][..]),
+            _ => (program, &["-dc"][..]),
+        };
+        let input = match output.take() {
+            Some(output) => Stdio::from(output),
+            None => Stdio::from(
+                File::open(filename)
+                    .unwrap_or_else(|_| io(format!("Failure reading file {}", filename))),
+            ),
+        };
+
+        let mut child = spawn(program, args, input);
+        output = child.stdout.take();
+        children.push((program, child));
+    }
+
+    Some(Box::new(CommandReader {
+        name: filename.to_string(),
+        children,
+        output: output.unwrap(),
+    }))
+}
+
+#[test]
+fn t_strip_compression() {
+    assert_eq!(strip_compression("games.pgn.zst"), "games.pgn");
+    assert_eq!(strip_compression("book.bin"), "book.bin");
+    assert_eq!(
+        strip_compression("https://example.com/twic.pgn.gz?x=1"),
+        "https://example.com/twic.pgn"
+    );
+    assert_eq!(decompressor("https://example.com/db.pgn.zst"), Some("zstd"));
+    assert_eq!(decompressor("https://example.com/db.pgn"), None);
+    assert!(is_zip("https://theweekinchess.com/zips/twic1500g.zip"));
+}